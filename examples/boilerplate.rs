@@ -0,0 +1,72 @@
+//! The least a game needs to do to run on rbrb, without any rendering.
+//!
+//! Run one process per player, e.g.
+//! `cargo run --example boilerplate -- --local-port 7000 --local-index 0 127.0.0.1:7001` and
+//! `cargo run --example boilerplate -- --local-port 7001 --local-index 1 127.0.0.1:7000`.
+
+use rbrb::{BasicUdpSocket, PlayerId, Request, SessionBuilder, TypedRequest, TypedSession};
+
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, ops::ControlFlow, time::Duration};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+struct Options {
+    #[structopt(long, default_value = "7000")]
+    local_port: u16,
+
+    #[structopt(long)]
+    local_index: PlayerId,
+
+    remote_players: Vec<SocketAddr>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct GameState {
+    total: i64,
+}
+
+fn main() {
+    env_logger::init();
+    let options = Options::from_args();
+
+    let session = SessionBuilder::default()
+        .remote_players(&options.remote_players)
+        .local_player(options.local_index)
+        .step_size(Duration::from_millis(17))
+        .default_inputs(bincode::serialize(&0i8).unwrap())
+        .with_socket(BasicUdpSocket::bind(options.local_port).unwrap())
+        .start()
+        .unwrap();
+    let mut session = TypedSession::<i8>::new(session).unwrap();
+
+    let mut game_state = GameState::default();
+    let mut tick = 0u64;
+
+    loop {
+        while let ControlFlow::Continue(()) = session.next_request(|request| match request {
+            TypedRequest::Other(Request::SaveTo(buf)) => {
+                bincode::serialize_into(buf, &game_state).unwrap()
+            }
+            TypedRequest::Other(Request::LoadFrom(buf)) => {
+                // States can come from peers, so don't trust them to parse.
+                if let Ok(s) = bincode::deserialize(buf) {
+                    game_state = s;
+                }
+            }
+            TypedRequest::CaptureLocalInput(input) => {
+                tick += 1;
+                *input = if tick % 60 < 30 { 1 } else { -1 };
+            }
+            TypedRequest::Advance { amount, inputs, .. } => {
+                for (_, input) in inputs.into_values().iter() {
+                    game_state.total += *input as i64 * amount.as_millis() as i64;
+                }
+            }
+            _ => {}
+        }) {}
+
+        println!("total: {}", game_state.total);
+        std::thread::sleep(Duration::from_millis(17));
+    }
+}
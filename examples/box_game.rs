@@ -1,11 +1,11 @@
 use rbrb::{
-    BadSocket, BandwidthRecordingSocket, BasicUdpSocket, PlayerId, PlayerInputs, Request,
-    SessionBuilder,
+    determinism::OrderedMap, BadSocket, BandwidthRecordingSocket, BasicUdpSocket, PlayerId,
+    PlayerInputs, Request, SessionBuilder,
 };
 
 use macroquad::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::{collections::BTreeMap, net::SocketAddr, ops::ControlFlow, time::Duration};
+use std::{hash::Hasher, net::SocketAddr, ops::ControlFlow, str::FromStr, time::Duration};
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
@@ -13,18 +13,50 @@ struct Options {
     #[structopt(long, default_value = "7000")]
     local_port: u16,
 
-    #[structopt(long)]
-    local_index: PlayerId,
+    #[structopt(long, required_unless = "spectate")]
+    local_index: Option<PlayerId>,
 
     #[structopt(long)]
     bad_network: bool,
 
+    /// Watch the match instead of playing. List the players in order of their index.
+    #[structopt(long)]
+    spectate: bool,
+
+    #[structopt(long)]
+    spectators: Vec<SocketAddr>,
+
+    /// Also play this player from here, steered with WASD.
+    #[structopt(long)]
+    local_guest: Option<PlayerId>,
+
+    /// Another peer's `--local-guest`, as `ID@ADDR`.
+    #[structopt(long)]
+    remote_guests: Vec<RemoteGuest>,
+
     remote_players: Vec<SocketAddr>,
 }
 
+struct RemoteGuest {
+    id: PlayerId,
+    addr: SocketAddr,
+}
+
+impl FromStr for RemoteGuest {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (id, addr) = s.split_once('@').ok_or("expected ID@ADDR")?;
+        Ok(RemoteGuest {
+            id: id.parse().map_err(|e| format!("bad id: {}", e))?,
+            addr: addr.parse().map_err(|e| format!("bad address: {}", e))?,
+        })
+    }
+}
+
 #[derive(Default, Serialize, Deserialize)]
 struct GameState {
-    box_positions: BTreeMap<PlayerId, Vec2>,
+    box_positions: OrderedMap<PlayerId, Vec2>,
 }
 
 #[derive(Default, Serialize, Deserialize)]
@@ -33,6 +65,30 @@ struct Vec2 {
     y: f32,
 }
 
+/// The arrow keys, or WASD for a local guest.
+fn read_input(guest: bool) -> Vec2 {
+    let [up, down, left, right] = if guest {
+        [KeyCode::W, KeyCode::S, KeyCode::A, KeyCode::D]
+    } else {
+        [KeyCode::Up, KeyCode::Down, KeyCode::Left, KeyCode::Right]
+    };
+    let mut input = Vec2::default();
+
+    if is_key_down(up) {
+        input.y -= 1.;
+    }
+    if is_key_down(down) {
+        input.y += 1.;
+    }
+    if is_key_down(left) {
+        input.x -= 1.;
+    }
+    if is_key_down(right) {
+        input.x += 1.;
+    }
+    input
+}
+
 fn window_conf() -> Conf {
     let options = Options::from_args();
 
@@ -56,9 +112,19 @@ async fn main() {
 
     let builder = SessionBuilder::default()
         .remote_players(&options.remote_players)
-        .local_player(options.local_index)
+        .spectators(&options.spectators)
         .step_size(Duration::from_millis(17))
+        .partial_advances()
         .default_inputs(bincode::serialize(&Vec2::default()).unwrap());
+    let builder = options
+        .remote_guests
+        .iter()
+        .fold(builder, |b, guest| b.remote_guests(guest.addr, &[guest.id]));
+    let builder = match (options.local_index, options.local_guest) {
+        (Some(index), Some(guest)) if !options.spectate => builder.local_players(&[index, guest]),
+        (Some(index), None) if !options.spectate => builder.local_player(index),
+        _ => builder.spectator(),
+    };
 
     let builder = if options.bad_network {
         let s = BandwidthRecordingSocket::new(BadSocket::bind(options.local_port).unwrap());
@@ -82,42 +148,39 @@ async fn main() {
             match request {
                 Request::SaveTo(vec) => bincode::serialize_into(vec, &game_state).unwrap(),
                 Request::LoadFrom(buf) => {
-                    match bincode::deserialize(buf) {
-                        Ok(s) => game_state = s,
-                        // Need to handle parsing errors as state could be from malicious peers.
-                        Err(_) => {}
+                    // Need to handle parsing errors as state could be from malicious peers.
+                    if let Ok(s) = bincode::deserialize(buf) {
+                        game_state = s;
                     }
                 }
-                Request::CaptureLocalInput(vec) => {
-                    let mut input = Vec2::default();
-
-                    if is_key_down(KeyCode::Up) {
-                        input.y -= 1.;
-                    }
-                    if is_key_down(KeyCode::Down) {
-                        input.y += 1.;
+                Request::ChecksumState(hasher) => {
+                    for (id, pos) in &game_state.box_positions {
+                        hasher.write_u16(*id);
+                        hasher.write_u32(pos.x.to_bits());
+                        hasher.write_u32(pos.y.to_bits());
                     }
-                    if is_key_down(KeyCode::Left) {
-                        input.x -= 1.;
-                    }
-                    if is_key_down(KeyCode::Right) {
-                        input.x += 1.;
-                    }
-
-                    bincode::serialize_into(vec, &input).unwrap();
                 }
-                Request::Advance {
-                    amount: dt, inputs, ..
-                } => {
-                    let inputs: PlayerInputs<Vec2> =
-                        inputs.map(|vec| bincode::deserialize_from(&vec.into_inner()[..]).unwrap());
+                Request::CaptureLocalInput(vec) => {
+                    bincode::serialize_into(vec, &read_input(false)).unwrap();
+                }
+                // Only issued with a local guest, who plays with WASD.
+                Request::CaptureInput { input, player, .. } => {
+                    let guest = options.local_guest == Some(player);
+                    bincode::serialize_into(input, &read_input(guest)).unwrap();
+                }
+                Request::Advance { amount, inputs, .. } => {
+                    let inputs: PlayerInputs<Vec2> = inputs
+                        .deep_map(|vec| bincode::deserialize(&vec).unwrap())
+                        .into_values();
                     let speed = 100.;
                     for (player_id, input) in inputs.iter() {
-                        let mut pos = game_state.box_positions.get_mut(player_id).unwrap();
-                        pos.x += input.x * dt.as_secs_f32() * speed;
-                        pos.y += input.y * dt.as_secs_f32() * speed;
+                        let pos = game_state.box_positions.get_mut(player_id).unwrap();
+                        pos.x += input.x * amount.as_secs_f32() * speed;
+                        pos.y += input.y * amount.as_secs_f32() * speed;
                     }
                 }
+                // Boxes are drawn where they are, snapping is fine for this example.
+                Request::PredictionCorrected { .. } => {}
                 unhandled => {
                     log::warn!("unhandled request: {:?}", unhandled);
                 }
@@ -133,12 +196,21 @@ async fn main() {
             format!("Elapsed: {:?}", network_stats.elapsed),
             format!("Drift: {:?}", network_stats.drift),
         ];
-        match network_stats.socket {
-            Some(stats) => {
-                texts.push(format!("Out: {:?}/s", stats.outgoing_bytes));
-                texts.push(format!("In: {:?}/s", stats.incoming_bytes));
+        if let Some(progress) = session.frame_progress() {
+            texts.push(format!("Sim behind realtime: {} frames", progress.behind()));
+        }
+        let blocking = session.blocking_players();
+        if !blocking.is_empty() {
+            texts.push(format!("Waiting on players: {:?}", blocking));
+        }
+        for (id, link) in &network_stats.players {
+            if let Some(rtt) = link.average_rtt {
+                texts.push(format!("Ping to {}: {:?}", id, rtt));
             }
-            None => {}
+        }
+        if let Some(stats) = network_stats.socket {
+            texts.push(format!("Out: {:?}/s", stats.outgoing_bytes));
+            texts.push(format!("In: {:?}/s", stats.incoming_bytes));
         }
         for (i, text) in texts.into_iter().enumerate() {
             draw_text(&text, 0., 16. * (i + 1) as f32, 16., WHITE);
@@ -0,0 +1,71 @@
+//! Testing game logic offline: the same requests as online, with scripted inputs and a clock
+//! stepped by hand, so every run plays out alike.
+//!
+//! `cargo run --example offline`
+
+use rbrb::{ManualClock, Request, SessionBuilder, TypedRequest, TypedSession};
+
+use serde::{Deserialize, Serialize};
+use std::{ops::ControlFlow, time::Duration};
+
+const STEP: Duration = Duration::from_millis(10);
+
+#[derive(Default, Serialize, Deserialize)]
+struct GameState {
+    frame: u32,
+    position: i64,
+}
+
+/// Walk right for a second, then left for half a second.
+fn scripted(frame: u32) -> i8 {
+    if frame <= 100 {
+        1
+    } else {
+        -1
+    }
+}
+
+fn main() {
+    env_logger::init();
+
+    let clock = ManualClock::new();
+    let session = SessionBuilder::default()
+        .offline()
+        .local_player(0)
+        .frame_numbers()
+        .step_size(STEP)
+        .default_inputs(bincode::serialize(&0i8).unwrap())
+        .with_clock(clock.clone())
+        .start()
+        .unwrap();
+    let mut session = TypedSession::<i8>::new(session).unwrap();
+
+    let mut game_state = GameState::default();
+    while game_state.frame < 150 {
+        clock.advance(STEP);
+        while let ControlFlow::Continue(()) = session.next_request(|request| match request {
+            TypedRequest::Other(Request::SaveFrame { state, .. }) => {
+                bincode::serialize_into(state, &game_state).unwrap()
+            }
+            TypedRequest::Other(Request::LoadFrame { state, .. }) => {
+                game_state = bincode::deserialize(state).unwrap();
+            }
+            TypedRequest::CaptureInput { input, frame, .. } => *input = scripted(frame),
+            TypedRequest::Advance {
+                inputs,
+                current_frame,
+                ..
+            } => {
+                for (_, input) in inputs.into_values().iter() {
+                    game_state.position += *input as i64;
+                }
+                game_state.frame = current_frame + 1;
+            }
+            _ => {}
+        }) {}
+    }
+
+    // Frame 0 has the default input, then 100 frames right and 49 left.
+    assert_eq!(game_state.position, 51);
+    println!("position: {}", game_state.position);
+}
@@ -0,0 +1,434 @@
+//! Running a session inside a Bevy app, with the `bevy` feature.
+//!
+//! Add `RbrbPlugin` to the app and insert an `RbrbSession` once the session is built, such as
+//! after a lobby. Every `PreUpdate` the plugin then handles the session's requests until it has
+//! nothing left to do:
+//!
+//! - `Request::CaptureLocalInput` and `Request::CaptureInput` call the plugin's capture
+//!   function, which reads `ButtonInput<KeyCode>` or whatever else the game takes input from out
+//!   of the world.
+//! - `Request::Advance` puts the step in the `RbrbStep` resource and runs `RbrbSchedule` once.
+//!   The game's simulation goes in that schedule rather than `Update` or `FixedUpdate`, as it is
+//!   run as many times as the session asks for, including to resimulate after a rollback.
+//! - `Request::SaveTo` and `Request::LoadFrom`, or their `frame_numbers` counterparts, snapshot
+//!   the entities marked `Rollback` by reflection, with every component registered with
+//!   `App::register_type` that reflects `Component`, along with the resources given to
+//!   `RbrbPlugin::rollback_resource`.
+//! - `Request::ChecksumState` hashes the state as it would be saved.
+//!
+//! Every other request goes to the function given to `RbrbPlugin::on_other_request`, if any.
+//! `RbrbSession` and `RbrbConfig` are taken out of the world while requests are handled, so
+//! systems in `RbrbSchedule` can't reach them.
+//!
+//! A state that fails to load is logged and sent as an `RbrbLoadFailed` event.
+//!
+//! States are saved as a Bevy scene in RON. Entities are saved by their `RollbackId` rather than
+//! their `Entity`, which differs between peers, so spawning rollback entities in the same order
+//! on every peer, such as only from `RbrbSchedule`, keeps states and checksums alike. Components
+//! that refer to other entities, such as `Parent`, aren't supported. Saved components are stored
+//! as is: types that don't serialize, such as asset handles, are left out with
+//! `RbrbPlugin::skip_component`. `SessionBuilder::delta_saves` isn't supported.
+
+use bevy::{
+    ecs::{
+        component::ComponentId, entity::EntityHashMap, schedule::ScheduleLabel,
+        world::DeferredWorld,
+    },
+    prelude::*,
+    reflect::TypeRegistry,
+    scene::{ron, serde::SceneDeserializer, DynamicScene, DynamicSceneBuilder, SceneFilter},
+    utils::HashSet,
+};
+use serde::de::DeserializeSeed;
+use std::{any::TypeId, hash::Hasher, ops::ControlFlow, time::Duration};
+
+use crate::{Confirmation, PlayerId, PlayerInputs, Request, SerializedInput, Session};
+
+/// Reads local `player`'s input for the next frame out of the world.
+pub type CaptureInput = fn(&World, PlayerId) -> SerializedInput;
+
+/// Handles a request the plugin doesn't.
+pub type OtherRequest = fn(&mut World, Request<'_>);
+
+/// Drives an `RbrbSession` from `PreUpdate`, see the module docs.
+pub struct RbrbPlugin {
+    config: RbrbConfig,
+}
+
+impl RbrbPlugin {
+    pub fn new(capture: CaptureInput) -> Self {
+        RbrbPlugin {
+            config: RbrbConfig {
+                capture,
+                other: None,
+                components: SceneFilter::allow_all(),
+                resources: SceneFilter::deny_all().allow::<RollbackIds>(),
+            },
+        }
+    }
+
+    /// Hand requests the plugin doesn't handle itself, like `Request::Seed` and
+    /// `Request::PlayerDisconnected`, to `handle`.
+    pub fn on_other_request(mut self, handle: OtherRequest) -> Self {
+        self.config.other = Some(handle);
+        self
+    }
+
+    /// Save and load resource `R` with the rollback entities. It must be registered with
+    /// `App::register_type` and reflect `Resource`.
+    pub fn rollback_resource<R: Resource>(mut self) -> Self {
+        self.config.resources = self.config.resources.allow::<R>();
+        self
+    }
+
+    /// Leave component `C` out of saved states, and alone when loading them.
+    pub fn skip_component<C: Component>(mut self) -> Self {
+        self.config.components = self.config.components.deny::<C>();
+        self
+    }
+}
+
+impl Plugin for RbrbPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<Rollback>()
+            .register_type::<RollbackId>()
+            .register_type::<RollbackIds>()
+            .init_resource::<RollbackIds>()
+            .add_event::<RbrbLoadFailed>()
+            .init_schedule(RbrbSchedule)
+            .insert_resource(self.config.clone())
+            .add_systems(
+                PreUpdate,
+                drive_session.run_if(resource_exists::<RbrbSession>),
+            );
+        app.world_mut()
+            .register_component_hooks::<Rollback>()
+            .on_add(assign_rollback_id);
+    }
+}
+
+/// The session driven by `RbrbPlugin`.
+#[derive(Resource)]
+pub struct RbrbSession(pub Session);
+
+/// How `RbrbPlugin` handles requests, as configured on it.
+#[derive(Resource, Clone)]
+pub struct RbrbConfig {
+    capture: CaptureInput,
+    other: Option<OtherRequest>,
+    components: SceneFilter,
+    resources: SceneFilter,
+}
+
+/// Marks an entity as part of the game state, saved and restored on rollback.
+#[derive(Component, Reflect, Default, Debug, Clone, Copy)]
+#[reflect(Component)]
+pub struct Rollback;
+
+/// Identifies a rollback entity in saved states, given out in the order entities are marked
+/// `Rollback`.
+#[derive(Component, Reflect, Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[reflect(Component)]
+pub struct RollbackId(pub u32);
+
+/// The next `RollbackId` to give out, saved and restored with the rollback entities so
+/// resimulated frames hand out the same ids.
+#[derive(Resource, Reflect, Default, Debug)]
+#[reflect(Resource)]
+struct RollbackIds {
+    next: u32,
+}
+
+/// Present while a state is written to the world, whose entities already have their ids.
+#[derive(Resource)]
+struct Loading;
+
+/// Sent when a saved state couldn't be loaded, leaving the world out of sync with the session.
+#[derive(Event, Debug, Clone)]
+pub struct RbrbLoadFailed(pub String);
+
+/// Runs once for every `Request::Advance`, with the step in `RbrbStep`.
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RbrbSchedule;
+
+/// The step `RbrbSchedule` is advancing through, the fields of `Request::Advance`.
+#[derive(Resource)]
+pub struct RbrbStep {
+    pub frame: u32,
+    pub amount: Duration,
+    pub inputs: PlayerInputs,
+    pub confirmed: Confirmation,
+    pub resimulation: bool,
+}
+
+fn assign_rollback_id(mut world: DeferredWorld<'_>, entity: Entity, _: ComponentId) {
+    if world.contains_resource::<Loading>() || world.get::<RollbackId>(entity).is_some() {
+        return;
+    }
+    let mut ids = world.resource_mut::<RollbackIds>();
+    let id = RollbackId(ids.next);
+    ids.next += 1;
+    world.commands().entity(entity).insert(id);
+}
+
+fn drive_session(world: &mut World) {
+    world.resource_scope(|world, mut session: Mut<RbrbSession>| {
+        world.resource_scope(|world, config: Mut<RbrbConfig>| {
+            let local = session.0.local_player_id();
+            while let ControlFlow::Continue(()) = session
+                .0
+                .next_request(|request: Request<'_>| handle(world, &config, local, request))
+            {
+            }
+        })
+    });
+}
+
+/// `local` is the player `Request::CaptureLocalInput` is for.
+fn handle(world: &mut World, config: &RbrbConfig, local: PlayerId, request: Request<'_>) {
+    match request {
+        Request::CaptureLocalInput(input) => *input = (config.capture)(world, local),
+        Request::CaptureInput { input, player, .. } => {
+            *input = (config.capture)(world, player);
+        }
+        Request::Advance {
+            amount,
+            inputs,
+            confirmed,
+            current_frame,
+            resimulation,
+            ..
+        } => {
+            world.insert_resource(RbrbStep {
+                frame: current_frame,
+                amount,
+                inputs,
+                confirmed,
+                resimulation,
+            });
+            world.run_schedule(RbrbSchedule);
+            world.flush();
+        }
+        Request::SaveTo(state) | Request::SaveFrame { state, .. } => *state = save(world, config),
+        Request::LoadFrom(state) | Request::LoadFrame { state, .. } => {
+            if let Err(e) = load(world, config, state) {
+                log::error!("failed to load a state: {}", e);
+                world.send_event(RbrbLoadFailed(e));
+            }
+        }
+        Request::ChecksumState(hasher) => hasher.write(&save(world, config)),
+        other => {
+            if let Some(handle) = config.other {
+                handle(world, other);
+            }
+        }
+    }
+}
+
+fn save(world: &mut World, config: &RbrbConfig) -> Vec<u8> {
+    world.flush();
+    let mut entities = world
+        .query_filtered::<(&RollbackId, Entity), With<Rollback>>()
+        .iter(world)
+        .map(|(id, entity)| (*id, entity))
+        .collect::<Vec<_>>();
+    entities.sort();
+
+    let mut scene = DynamicSceneBuilder::from_world(world)
+        .with_filter(config.components.clone())
+        .with_resource_filter(config.resources.clone())
+        .extract_entities(entities.iter().map(|(_, entity)| *entity))
+        .extract_resources()
+        .build();
+    for (saved, (id, _)) in scene.entities.iter_mut().zip(&entities) {
+        saved.entity = saved_entity(*id);
+    }
+    let registry = world.resource::<AppTypeRegistry>().read();
+    scene
+        .serialize(&registry)
+        .expect("rollback state must serialize, see `RbrbPlugin::skip_component`")
+        .into_bytes()
+}
+
+fn load(world: &mut World, config: &RbrbConfig, bytes: &[u8]) -> Result<(), String> {
+    let registry = world.resource::<AppTypeRegistry>().clone();
+    let scene = {
+        let registry = registry.read();
+        let mut deserializer = ron::Deserializer::from_bytes(bytes).map_err(|e| e.to_string())?;
+        SceneDeserializer {
+            type_registry: &registry,
+        }
+        .deserialize(&mut deserializer)
+        .map_err(|e| e.to_string())?
+    };
+
+    world.flush();
+    let in_state = scene
+        .entities
+        .iter()
+        .map(|saved| saved.entity)
+        .collect::<HashSet<_>>();
+    let mut restored = EntityHashMap::default();
+    let mut stale = Vec::new();
+    for (id, live) in world
+        .query_filtered::<(&RollbackId, Entity), With<Rollback>>()
+        .iter(world)
+    {
+        if in_state.contains(&saved_entity(*id)) {
+            restored.insert(saved_entity(*id), live);
+        } else {
+            stale.push(live);
+        }
+    }
+    for entity in stale {
+        world.despawn(entity);
+    }
+    remove_unsaved_components(world, config, &scene, &restored, &registry.read());
+
+    world.insert_resource(Loading);
+    let result = scene
+        .write_to_world_with(world, &mut restored, &registry)
+        .map_err(|e| e.to_string());
+    world.remove_resource::<Loading>();
+    result
+}
+
+/// Stands in for the entity with `id` in saved states.
+fn saved_entity(id: RollbackId) -> Entity {
+    Entity::from_raw(id.0)
+}
+
+/// Components added since the state was saved aren't in it, so would be left in place by
+/// writing the state over the entities.
+fn remove_unsaved_components(
+    world: &mut World,
+    config: &RbrbConfig,
+    scene: &DynamicScene,
+    restored: &EntityHashMap<Entity>,
+    registry: &TypeRegistry,
+) {
+    for saved in &scene.entities {
+        let Some(&live) = restored.get(&saved.entity) else {
+            continue;
+        };
+        let in_state = saved
+            .components
+            .iter()
+            .filter_map(|c| Some(c.get_represented_type_info()?.type_id()))
+            .collect::<HashSet<TypeId>>();
+        let unsaved = world
+            .entity(live)
+            .archetype()
+            .components()
+            .filter_map(|id| world.components().get_info(id)?.type_id())
+            .filter(|type_id| {
+                config.components.is_allowed_by_id(*type_id) && !in_state.contains(type_id)
+            })
+            .filter_map(|type_id| registry.get(type_id)?.data::<ReflectComponent>().cloned())
+            .collect::<Vec<_>>();
+        let mut entity = world.entity_mut(live);
+        for component in unsaved {
+            component.remove(&mut entity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StateHasher;
+
+    #[derive(Component, Reflect, Default, Debug, PartialEq)]
+    #[reflect(Component)]
+    struct Health(u32);
+
+    #[derive(Component, Reflect, Default)]
+    #[reflect(Component)]
+    struct Poisoned;
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.add_plugins(RbrbPlugin::new(|_, _| Vec::new()))
+            .register_type::<Health>()
+            .register_type::<Poisoned>();
+        app
+    }
+
+    #[test]
+    fn restores_rollback_entities() {
+        let mut app = app();
+        let config = app.world().resource::<RbrbConfig>().clone();
+        let world = app.world_mut();
+
+        let hero = world.spawn((Rollback, Health(3))).id();
+        let scenery = world.spawn(Health(10)).id();
+        let saved = save(world, &config);
+
+        world.entity_mut(hero).insert((Health(1), Poisoned));
+        world.entity_mut(scenery).insert(Health(9));
+        let minion = world.spawn((Rollback, Health(2))).id();
+        load(world, &config, &saved).unwrap();
+
+        assert_eq!(world.get::<Health>(hero), Some(&Health(3)));
+        assert!(world.get::<Poisoned>(hero).is_none());
+        assert!(world.get_entity(minion).is_none());
+        assert_eq!(world.get::<Health>(scenery), Some(&Health(9)));
+
+        // Despawned since, so comes back as a new entity, which later loads reuse.
+        world.despawn(hero);
+        load(world, &config, &saved).unwrap();
+        load(world, &config, &saved).unwrap();
+        let mut heroes = world.query_filtered::<&Health, With<Rollback>>();
+        assert_eq!(heroes.iter(world).collect::<Vec<_>>(), [&Health(3)]);
+    }
+
+    #[test]
+    fn saves_alike_whatever_the_entity_ids() {
+        let mut first = app();
+        let mut second = app();
+        let config = first.world().resource::<RbrbConfig>().clone();
+
+        first.world_mut().spawn(Health(10));
+        for app in [&mut first, &mut second] {
+            app.world_mut().spawn((Rollback, Health(3)));
+            app.world_mut().spawn((Rollback, Health(2)));
+        }
+
+        let saved = save(first.world_mut(), &config);
+        assert_eq!(saved, save(second.world_mut(), &config));
+
+        let mut hasher = StateHasher::default();
+        handle(
+            second.world_mut(),
+            &config,
+            0,
+            Request::ChecksumState(&mut hasher),
+        );
+        assert!(hasher.checksum().is_some());
+
+        // Ids are restored with the state, so entities spawned again get the same ones.
+        let world = first.world_mut();
+        world.spawn((Rollback, Health(1)));
+        load(world, &config, &saved).unwrap();
+        world.spawn((Rollback, Health(1)));
+        let resimulated = save(world, &config);
+        let world = second.world_mut();
+        world.spawn((Rollback, Health(1)));
+        assert_eq!(resimulated, save(world, &config));
+    }
+
+    #[test]
+    fn reports_states_that_fail_to_load() {
+        let mut app = app();
+        let config = app.world().resource::<RbrbConfig>().clone();
+        handle(
+            app.world_mut(),
+            &config,
+            0,
+            Request::LoadFrom(b"not a scene"),
+        );
+
+        let failures = app.world().resource::<Events<RbrbLoadFailed>>();
+        assert_eq!(failures.len(), 1);
+    }
+}
@@ -1,85 +1,850 @@
 use crate::{
-    time::SharedClock, Frame, Interval, NonBlockingSocket, PlayerId, Session, SessionPlugin,
+    catch_up::CatchUpLimiter,
+    fragment::{Outbox, Reassembler},
+    input_delay::InputDelayNegotiation,
+    inputs::{InputSource, InputValidator},
+    liveness::Liveness,
+    membership::Membership,
+    redundancy::RedundancyController,
+    replay::{ReplayHeader, ReplayRecorder},
+    resume::SessionSnapshot,
+    send_rate::SendRateController,
+    snapshots::SnapshotStore,
+    socket::OfflineSocket,
+    step_schedule::{self, StepSchedule, MAX_STEP_SIZE},
+    time::{self, Clock, ClockHandle, ClockTuning, LogThrottle, SharedClock},
+    AllDisconnected, BincodeCodec, CatchUp, Codec, ConfirmationMode, Frame, Interval,
+    NonBlockingSocket, PlayerId, SerializedInput, Session, SessionPlugin, Spectator,
 };
 
-use std::{collections::BTreeMap, net::SocketAddr, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    io::{self, Write},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+/// The most players a session accepts unless raised with `SessionBuilder::max_players`. Bandwidth
+/// and confirmation latency grow with every player, and beyond this many peers a session
+/// struggles to keep up with realtime.
+///
+/// Peers form a full mesh: every peer sends its inputs, acks and clock messages to every other
+/// peer, so each uploads in proportion to the number of players and the session as a whole in
+/// proportion to its square. A frame is only confirmed once the slowest link has delivered, so the
+/// worst connection among all of them sets the rollback distance for everyone. In practice 4 to 8
+/// players over the internet is comfortable, and 16 is reachable on a LAN or with
+/// `SessionBuilder::compress_inputs` and longer `SessionBuilder::send_interval_bounds`.
+pub const DEFAULT_MAX_PLAYERS: usize = 16;
+
+/// How many spectators may watch at once unless set with `SessionBuilder::max_spectators`.
+pub const DEFAULT_MAX_SPECTATORS: usize = 8;
+
+/// Why `SessionBuilder::start` could not start a session.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SessionBuildError {
+    MissingLocalPlayer,
+    MissingStepSize,
+    MissingDefaultInputs,
+    MissingSocket,
+    SpectatorWithLocalPlayer,
+    /// The same id is given to more than one player.
+    DuplicatePlayerId(PlayerId),
+    /// `remote_guests` names an address that isn't one of the `remote_players`.
+    UnknownGuestHost(SocketAddr),
+    UnknownRelayHub(PlayerId),
+    TooManyPlayers {
+        players: usize,
+        max: usize,
+    },
+    Recording(io::Error),
+    /// The snapshot given to `resume_from` can't be resumed from.
+    BadSnapshot(String),
+    /// `default_inputs` isn't as long as `fixed_input_size`.
+    DefaultInputSize {
+        len: usize,
+        expected: usize,
+    },
+    /// An `offline` session is given remote players or spectators, or is a spectator.
+    OfflineWithRemotes,
+    /// `step_size` is zero or longer than `MAX_STEP_SIZE`.
+    BadStepSize(Duration),
+}
+
+impl fmt::Display for SessionBuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use SessionBuildError::*;
+        match self {
+            MissingLocalPlayer => write!(f, "must provide local_player"),
+            MissingStepSize => write!(f, "must provide step_size"),
+            MissingDefaultInputs => write!(f, "must provide default_inputs"),
+            MissingSocket => write!(f, "must provide socket"),
+            SpectatorWithLocalPlayer => write!(f, "spectators cannot have a local_player"),
+            DuplicatePlayerId(id) => write!(f, "player id {} is given to more than one player", id),
+            UnknownRelayHub(hub) => write!(f, "relay hub {} is not a player", hub),
+            UnknownGuestHost(addr) => write!(f, "guests' host {} is not a remote player", addr),
+            TooManyPlayers { players, max } => write!(
+                f,
+                "{} players is more than the maximum of {}, see SessionBuilder::max_players",
+                players, max
+            ),
+            Recording(e) => write!(f, "failed to start recording: {}", e),
+            BadSnapshot(e) => write!(f, "cannot resume from snapshot: {}", e),
+            DefaultInputSize { len, expected } => write!(
+                f,
+                "default_inputs is {} bytes, but fixed_input_size is {}",
+                len, expected
+            ),
+            OfflineWithRemotes => write!(f, "offline sessions cannot have remotes or spectators"),
+            BadStepSize(step) => write!(
+                f,
+                "step_size {:?} is not between zero and {:?}",
+                step, MAX_STEP_SIZE
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SessionBuildError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SessionBuildError::Recording(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Default)]
 pub struct SessionBuilder {
     remote_players: Vec<SocketAddr>,
-    local_player: Option<PlayerId>,
+    remote_guests: Vec<(SocketAddr, Vec<PlayerId>)>,
+    local_players: Vec<PlayerId>,
     step_size: Option<Duration>,
+    prediction_grace: Duration,
+    max_prediction: Option<u32>,
+    input_delay: u32,
+    negotiated_input_delay: Option<u32>,
+    lockstep: bool,
     default_inputs: Option<Vec<u8>>,
     socket: Option<Box<dyn NonBlockingSocket>>,
+    codec: Option<Box<dyn Codec>>,
+    clock: Option<ClockHandle>,
+    confirmation_mode: ConfirmationMode,
+    reconnection_tokens: HashMap<PlayerId, u64>,
+    reconnection_secret: Option<u64>,
+    max_redundancy: Option<u32>,
+    compress_snapshots: Option<usize>,
+    delta_saves: Option<u32>,
+    saveless: bool,
+    frame_numbers: bool,
+    interrupted_after: Option<Duration>,
+    disconnect_after: Option<Duration>,
+    sync_timeout: Option<Duration>,
+    clock_tuning: ClockTuning,
+    spectator: bool,
+    spectators: Vec<SocketAddr>,
+    accept_spectators: bool,
+    max_spectators: Option<usize>,
+    record_to: Option<Box<dyn Write + Send + Sync>>,
+    check_determinism: bool,
+    verify_confirmed_advances: bool,
+    relay_hub: Option<PlayerId>,
+    all_disconnected: AllDisconnected,
+    catch_up: CatchUp,
+    max_players: Option<usize>,
+    partial_advances: bool,
+    fill_absent_inputs: bool,
+    validate_input: Option<InputValidator>,
+    input_source: Option<InputSource>,
+    fixed_size_inputs: bool,
+    fixed_input_size: Option<usize>,
+    compress_inputs: bool,
+    send_interval_bounds: Option<(Duration, Duration)>,
+    plugins: Vec<Box<dyn SessionPlugin>>,
+    without_checksum_plugin: bool,
+    diff_desynced_states: bool,
+    resume_from: Option<Vec<u8>>,
+    offline: bool,
 }
 
 impl SessionBuilder {
+    /// The other peers, numbered in the order given from the lowest id no local player or
+    /// `remote_guests` has. With `local_player(1)`, `[a, b]` are players 0 and 2. Every peer
+    /// lists the same peers in the same order, less itself, to agree on who is who; see
+    /// `Session::players` for the result.
     pub fn remote_players(mut self, players: &[SocketAddr]) -> Self {
         self.remote_players = players.to_vec();
         self
     }
 
     pub fn local_player(mut self, index: PlayerId) -> Self {
-        self.local_player = Some(index);
+        self.local_players = vec![index];
+        self
+    }
+
+    /// Play several players from this machine, such as for couch co-op. Each captures its own
+    /// input with `Request::CaptureInput`, which names the player. The first stands for this peer
+    /// on the network, in `local_player_id`, reconnection and snapshots. Remote peers list this one
+    /// in `remote_players` as the first, and name the others with `remote_guests`.
+    pub fn local_players(mut self, ids: &[PlayerId]) -> Self {
+        self.local_players = ids.to_vec();
         self
     }
 
+    /// The peer at `addr`, one of `remote_players`, also plays `players` through
+    /// `local_players`. Remote players are numbered around these ids as well as the local ones.
+    pub fn remote_guests(mut self, addr: SocketAddr, players: &[PlayerId]) -> Self {
+        self.remote_guests.push((addr, players.to_vec()));
+        self
+    }
+
+    /// How long each frame is, until changed by `Session::set_step_size`.
     pub fn step_size(mut self, size: Duration) -> Self {
         self.step_size = Some(size);
         self
     }
 
+    /// When a frame is due but some remote's input for it hasn't arrived, wait up to `grace` past
+    /// the frame boundary before predicting it. Frames with every input present are never held.
+    /// Helps with inputs that arrive in bursts just late, at the cost of occasional local delay.
+    pub fn prediction_grace(mut self, grace: Duration) -> Self {
+        self.prediction_grace = grace;
+        self
+    }
+
+    /// Apply local inputs `frames` after the frame they are captured at, giving them that much
+    /// longer to reach the remotes before they are needed. Fewer and shallower rollbacks, for
+    /// `frames * step_size` more input latency. Every input is still tied to an exact frame, so
+    /// peers may use different delays without desyncing, though the same delay is fairest.
+    /// `Request::CaptureInput` gives the delayed frame. None by default.
+    pub fn input_delay(mut self, frames: u32) -> Self {
+        self.input_delay = frames;
+        self
+    }
+
+    /// Agree on an input delay with each remote from the round-trip time to them, up to
+    /// `max_frames`, so a high-ping player gets more delay automatically. Our own inputs take the
+    /// largest delay agreed with anyone, and never less than `input_delay`. The delay follows the
+    /// RTT over the match a frame at a time, which like any input delay can't desync peers. Every
+    /// peer should enable it, as the lower player id of each pair decides for both. See
+    /// `NetworkStats::input_delays`.
+    pub fn negotiate_input_delay(mut self, max_frames: u32) -> Self {
+        self.negotiated_input_delay = Some(max_frames);
+        self
+    }
+
+    /// Predict at most `frames` past the last confirmed frame. Past that the session stalls,
+    /// issuing `Request::PredictionStalled`, until inputs arrive, rather than predicting ever
+    /// further and paying for it in one huge rollback. Unlimited by default.
+    pub fn max_prediction_frames(mut self, frames: u32) -> Self {
+        self.max_prediction = Some(frames);
+        self
+    }
+
+    /// Never predict: only advance once every player's input for the frame is confirmed, so
+    /// there are no rollbacks and every `Request::Advance` is `Confirmation::First`. The game
+    /// lags behind realtime by however long inputs take to arrive. Suits turn-based and other
+    /// slower paced games. Partial advances are not issued in this mode.
+    pub fn lockstep(mut self) -> Self {
+        self.lockstep = true;
+        self
+    }
+
     pub fn default_inputs(mut self, inputs: Vec<u8>) -> Self {
         self.default_inputs = Some(inputs);
         self
     }
 
+    /// Only wait on `authority`'s inputs to confirm frames, rather than every player's. The
+    /// authority confirms on whatever inputs it has for everyone else, predicted ones included,
+    /// and sends them out for every other peer to confirm on in turn. Inputs that reach the
+    /// authority too late are dropped.
+    pub fn authority(mut self, authority: PlayerId) -> Self {
+        self.confirmation_mode = ConfirmationMode::Authority(authority);
+        self
+    }
+
+    /// The token remote `player` must present to reclaim their slot with
+    /// `Session::reconnect_player`, `reconnection_token_for` their secret and our player.
+    ///
+    /// Also lets a peer whose address changes mid-match, such as after NAT rebinding, carry on:
+    /// packets from an unknown address are answered with a challenge nonce, and a peer that proves
+    /// its player's token with it is moved to that address. The token itself is never sent.
+    pub fn reconnection_token(mut self, player: PlayerId, token: u64) -> Self {
+        self.reconnection_tokens.insert(player, token);
+        self
+    }
+
+    /// Our player's secret, to answer the challenges of the peers given the tokens derived from
+    /// it, see `reconnection_token`. Keep it to this machine.
+    pub fn reconnection_secret(mut self, secret: u64) -> Self {
+        self.reconnection_secret = Some(secret);
+        self
+    }
+
+    /// Send inputs every `min` to `max`, half the average round-trip time to the slowest remote,
+    /// rather than every 50ms. A LAN then gets inputs sooner and a slow, congested link fewer
+    /// resends. The average is over recent pings, so a single spike barely moves it, and the
+    /// interval is adjusted at most once a second, halfway towards the target and only when off
+    /// by more than a fifth, so it settles instead of oscillating.
+    pub fn send_interval_bounds(mut self, min: Duration, max: Duration) -> Self {
+        self.send_interval_bounds = Some((min, max));
+        self
+    }
+
+    /// The most copies of each input message to send when the network is lossy. Defaults to 3.
+    pub fn max_redundancy(mut self, copies: u32) -> Self {
+        self.max_redundancy = Some(copies);
+        self
+    }
+
+    /// Compress older snapshots on a background thread, or as they age on wasm, keeping the
+    /// `keep_recent` most recent ones uncompressed for fast rollback. Worthwhile for large game
+    /// states.
+    pub fn compress_snapshots(mut self, keep_recent: usize) -> Self {
+        self.compress_snapshots = Some(keep_recent);
+        self
+    }
+
+    /// Save most confirmed states with `Request::SaveDelta` against a full `Request::SaveTo`
+    /// keyframe, taken at most every `keyframe_every` frames, and roll back to them with
+    /// `Request::LoadDelta`. Worthwhile for large states the game can diff cheaply.
+    ///
+    /// Checksums for desync detection come from `Request::ChecksumState`. If that hashes nothing,
+    /// each delta save also needs a full `SaveTo` to hash.
+    pub fn delta_saves(mut self, keyframe_every: u32) -> Self {
+        self.delta_saves = Some(keyframe_every);
+        self
+    }
+
+    /// Save only the first state, and roll back by loading it and resimulating every frame since.
+    /// For games whose state is so small and quick to advance that saving it often isn't worth
+    /// the memory, and for checking determinism, as every rollback replays the whole match.
+    ///
+    /// Inputs are kept from the first state on, so memory still grows with the length of the
+    /// match. With no later states to checksum, remote desyncs go undetected; see
+    /// `SessionBuilder::check_determinism` instead. A remote that resyncs is sent the first state.
+    pub fn saveless_rollback(mut self) -> Self {
+        self.saveless = true;
+        self
+    }
+
+    /// Issue `Request::SaveFrame`, `Request::LoadFrame` and `Request::CaptureInput` in place of
+    /// `SaveTo`, `LoadFrom` and `CaptureLocalInput`, telling the handler which frame each is for,
+    /// such as to tag saved states or log inputs per frame.
+    pub fn frame_numbers(mut self) -> Self {
+        self.frame_numbers = true;
+        self
+    }
+
+    /// Run `plugin` alongside the session. Plugins are keyed by their `id`, and a later plugin
+    /// replaces an earlier one with the same id, including the built-in checksum plugin.
+    pub fn with_plugin(mut self, plugin: impl SessionPlugin) -> Self {
+        self.plugins.push(Box::new(plugin));
+        self
+    }
+
+    /// Don't exchange confirmed checksums with the remotes. Saves a little bandwidth, but desyncs
+    /// go unnoticed: no `Request::DesyncDetected` is ever issued.
+    pub fn without_checksum_plugin(mut self) -> Self {
+        self.without_checksum_plugin = true;
+        self
+    }
+
+    /// When a desync is detected, exchange the whole confirmed states with the remote and log the
+    /// first byte where they differ, see `WarnRemoteMismatchedChecksum::with_state_diffs`. For
+    /// debugging, as it costs memory and a whole state's worth of bandwidth.
+    pub fn diff_desynced_states(mut self) -> Self {
+        self.diff_desynced_states = true;
+        self
+    }
+
+    /// Start at most `timeout` after the session is built, even without enough round-trip times
+    /// to be confident every peer hears of the start in time. A late start on a lossy link is
+    /// corrected like any other drift, at the cost of rollbacks early on. Unlimited by default,
+    /// see `Session::is_synchronizing`.
+    pub fn sync_timeout(mut self, timeout: Duration) -> Self {
+        self.sync_timeout = Some(timeout);
+        self
+    }
+
+    /// How the shared clock corrects drift from the remotes, or `ClockTuning::disabled` not to.
+    /// See `ClockTuning` for how the correction works and `NetworkStats::drift_rate` to watch it.
+    pub fn clock_tuning(mut self, tuning: ClockTuning) -> Self {
+        self.clock_tuning = tuning;
+        self
+    }
+
+    /// Disconnect remote players that have not been heard from for `timeout`.
+    ///
+    /// Every message successfully decoded from a player resets their timer, whatever its type
+    /// (inputs, confirmations, clock pings, plugin traffic, ...). Packets from unknown addresses
+    /// or that fail to decode do not. Once a player has been silent for the interruption
+    /// threshold a `Request::ConnectionInterrupted` is issued and their inputs keep being
+    /// predicted; the next message from them issues `Request::ConnectionResumed`. Only after
+    /// `timeout` are they disconnected as with `Session::disconnect_player`.
+    pub fn disconnect_after(mut self, timeout: Duration) -> Self {
+        self.disconnect_after = Some(timeout);
+        self
+    }
+
+    /// How long a player can be silent before `Request::ConnectionInterrupted`. Only used with
+    /// `disconnect_after`, defaults to a quarter of its timeout.
+    pub fn interrupted_after(mut self, threshold: Duration) -> Self {
+        self.interrupted_after = Some(threshold);
+        self
+    }
+
+    /// Watch a match without playing in it. The spectator never captures local input or predicts,
+    /// it only advances through confirmed frames a little behind the shared clock.
+    ///
+    /// `remote_players` must list every player in order of their `PlayerId`, and each of them
+    /// must name this spectator in `spectators` or `accept_spectators`, along with any
+    /// `remote_guests`. Don't provide a `local_player`. Joining a match in progress starts from
+    /// the latest confirmed state the players have, and `Session::leave` stops watching.
+    pub fn spectator(mut self) -> Self {
+        self.spectator = true;
+        self
+    }
+
+    /// Send our inputs and the shared time to these spectators. They have no influence on the
+    /// session.
+    pub fn spectators(mut self, spectators: &[SocketAddr]) -> Self {
+        self.spectators = spectators.to_vec();
+        self
+    }
+
+    /// Let spectators from any address drop in mid-match, besides those in `spectators`. A new
+    /// spectator first echoes back a cookie, proving the address is really its own, then is sent
+    /// our latest confirmed state to start watching from and follows along on our inputs like
+    /// any other until it leaves or goes quiet. See `Session::add_spectator`.
+    pub fn accept_spectators(mut self) -> Self {
+        self.accept_spectators = true;
+        self
+    }
+
+    /// Turn away new spectators once `count` are watching, counting those in `spectators`, to
+    /// budget the uplink. See `NetworkStats::spectator_outgoing` for what they cost. Defaults to
+    /// `DEFAULT_MAX_SPECTATORS`.
+    pub fn max_spectators(mut self, count: usize) -> Self {
+        self.max_spectators = Some(count);
+        self
+    }
+
+    /// Record every confirmed frame's inputs to `writer` as a replay, see the `replay` module.
+    /// Each frame is flushed as soon as it is written.
+    pub fn record_to(mut self, writer: Box<dyn Write + Send + Sync>) -> Self {
+        self.record_to = Some(writer);
+        self
+    }
+
+    /// Checksum the state around every advance and panic if re-simulating a frame from the same
+    /// state with the same inputs ever produces a different state. Costs extra checksums (or
+    /// saves, if the handler doesn't support `Request::ChecksumState`) every frame. See
+    /// `Session::determinism_coverage` for how often the check actually fires.
+    pub fn check_determinism(mut self) -> Self {
+        self.check_determinism = true;
+        self
+    }
+
+    /// Advance every frame twice from the same saved state as it is confirmed, and compare the
+    /// two saves byte for byte, issuing `Request::NondeterministicAdvance` with the first offset
+    /// that differs. Catches nondeterminism in `Request::Advance` locally, before it shows up as
+    /// a desync with a peer, and without waiting for rollbacks like `check_determinism` does.
+    /// Costs an extra advance, three saves and two loads per confirmed frame, so it is meant for
+    /// debugging.
+    pub fn verify_confirmed_advances(mut self) -> Self {
+        self.verify_confirmed_advances = true;
+        self
+    }
+
+    /// Route inputs through `hub` rather than sending them to every peer. Other players send
+    /// their inputs only to the hub, which forwards everyone's inputs to each of them. Cuts the
+    /// number of input packets from quadratic to linear in the number of players, at the cost
+    /// of an extra hop of latency. Every player must name the same hub.
+    pub fn relay_through(mut self, hub: PlayerId) -> Self {
+        self.relay_hub = Some(hub);
+        self
+    }
+
+    /// What to do once every remote player has left. Defaults to
+    /// `AllDisconnected::ContinueSolo`.
+    pub fn on_all_disconnected(mut self, policy: AllDisconnected) -> Self {
+        self.all_disconnected = policy;
+        self
+    }
+
+    /// How many frames to advance per update when behind realtime. Defaults to
+    /// `CatchUp::Unlimited`.
+    pub fn catch_up(mut self, policy: CatchUp) -> Self {
+        self.catch_up = policy;
+        self
+    }
+
+    /// Refuse to start with more than `max` players, counting the local player but not
+    /// spectators, and refuse `Session::join_player` past it. Defaults to `DEFAULT_MAX_PLAYERS`.
+    pub fn max_players(mut self, max: usize) -> Self {
+        self.max_players = Some(max);
+        self
+    }
+
+    /// Between whole steps, advance the rest of the way to realtime with a
+    /// `Confirmation::Partial` advance so rendering moves smoothly instead of in `step_size`
+    /// jumps. The simulation itself still only moves in whole steps: the state is saved before
+    /// each partial advance and loaded back before the next request that needs it. Costs a save,
+    /// a load and a short advance per update.
+    pub fn partial_advances(mut self) -> Self {
+        self.partial_advances = true;
+        self
+    }
+
+    /// Send our input history in a compact encoding instead of plain bincode: frame numbers as
+    /// small gaps, and recurring inputs by reference. Saves the most with small inputs that flip
+    /// between a few values, see `NetworkStats::input_bytes` for the difference it makes. Remotes
+    /// understand either encoding.
+    pub fn compress_inputs(mut self) -> Self {
+        self.compress_inputs = true;
+        self
+    }
+
+    /// Check every input received from a remote with `valid`, such as whether it decodes.
+    /// Rejected inputs are replaced with the predicted input, the one before it, so a buggy or
+    /// malicious peer can't crash the handler. Give every peer the same check, or the peers
+    /// that replace an input desync from the ones that don't.
+    pub fn validate_input(mut self, valid: impl Fn(&[u8]) -> bool + Send + Sync + 'static) -> Self {
+        self.validate_input = Some(Box::new(valid));
+        self
+    }
+
+    /// Take local players' inputs from `source`, given the player and frame, instead of issuing
+    /// `Request::CaptureLocalInput`, for scripted tests and bots. The inputs are stored, sent and
+    /// confirmed exactly as captured ones are. `Session::set_local_input` still takes precedence
+    /// for the frames it is given.
+    pub fn input_source(
+        mut self,
+        source: impl FnMut(PlayerId, u32) -> SerializedInput + Send + Sync + 'static,
+    ) -> Self {
+        self.input_source = Some(Box::new(source));
+        self
+    }
+
+    /// Reject remote inputs that aren't as long as `default_inputs`, like `validate_input`. For
+    /// encodings where every input has the same length, so the handler can rely on it. Checked
+    /// before `validate_input`, if both are given.
+    pub fn fixed_size_inputs(mut self) -> Self {
+        self.fixed_size_inputs = true;
+        self
+    }
+
+    /// Every input is exactly `len` bytes. Like `fixed_size_inputs`, and also checks
+    /// `default_inputs` when starting and every local input as it is captured or set, panicking
+    /// on one of the wrong length. Catches an input encoding that has changed size before it
+    /// reaches the remotes, where it would only be rejected.
+    pub fn fixed_input_size(mut self, len: usize) -> Self {
+        self.fixed_input_size = Some(len);
+        self
+    }
+
+    /// Give every player an input on every frame, so `PlayerInputs::iter` lists the same players
+    /// from frame to frame while membership is unchanged. A player none of whose inputs have
+    /// arrived yet gets the `default_inputs`, as `ConfirmationStatus::Unconfirmed`. Otherwise
+    /// they are left out of `Request::Advance` until their first input arrives.
+    pub fn fill_absent_inputs(mut self) -> Self {
+        self.fill_absent_inputs = true;
+        self
+    }
+
+    /// Continue a session from a `Session::snapshot`, such as after a crash. Configure everything
+    /// else as for the original session. See the `resume` module for what is restored.
+    pub fn resume_from(mut self, snapshot: &[u8]) -> Self {
+        self.resume_from = Some(snapshot.to_vec());
+        self
+    }
+
+    /// Play without a network, needing no socket. Every frame is confirmed as soon as its inputs
+    /// are captured, through the same requests as online, so offline modes and tests of game
+    /// logic share the online code path. Give it a `ManualClock` to step frames deterministically.
+    /// Fails to start with remote players, guests or spectators.
+    pub fn offline(mut self) -> Self {
+        self.offline = true;
+        self
+    }
+
     pub fn with_socket(mut self, socket: impl NonBlockingSocket + 'static) -> Self {
         self.socket = Some(Box::new(socket));
         self
     }
 
-    pub fn start(self) -> Result<Session, String> {
-        let local_id = self.local_player.ok_or("must provide local_player")?;
+    /// How messages to peers are encoded. Defaults to `BincodeCodec`.
+    pub fn with_codec(mut self, codec: impl Codec + 'static) -> Self {
+        self.codec = Some(Box::new(codec));
+        self
+    }
+
+    /// Where the session reads the time from. Defaults to `SystemClock`, or `PerformanceClock` in
+    /// the browser; a `ManualClock` makes time advance only when a test says so.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
 
+    pub fn start(self) -> Result<Session, SessionBuildError> {
+        let local_id = match (self.spectator, self.local_players.first()) {
+            (false, Some(&id)) => id,
+            (false, None) => return Err(SessionBuildError::MissingLocalPlayer),
+            (true, None) => PlayerId::MAX,
+            (true, Some(_)) => return Err(SessionBuildError::SpectatorWithLocalPlayer),
+        };
+        let remote_guests = self
+            .remote_guests
+            .iter()
+            .flat_map(|(addr, ids)| ids.iter().map(move |&id| (id, *addr)))
+            .collect::<Vec<_>>();
+        let remotes = !self.remote_players.is_empty() || !remote_guests.is_empty();
+        let spectated = !self.spectators.is_empty() || self.accept_spectators;
+        if self.offline && (remotes || spectated || self.spectator) {
+            return Err(SessionBuildError::OfflineWithRemotes);
+        }
+
+        let players = self.remote_players.len() + self.local_players.len() + remote_guests.len();
+        // `PlayerId::MAX` is reserved for spectators.
+        let max = self
+            .max_players
+            .unwrap_or(DEFAULT_MAX_PLAYERS)
+            .min(PlayerId::MAX as usize);
+        if players > max {
+            return Err(SessionBuildError::TooManyPlayers { players, max });
+        }
+
+        let taken = self
+            .local_players
+            .iter()
+            .copied()
+            .chain(remote_guests.iter().map(|(id, _)| *id))
+            .collect::<Vec<_>>();
         let remote_players = self
             .remote_players
             .iter()
-            .enumerate()
-            .map(|(i, &addr)| {
-                let i = i as u16;
-                if i >= local_id {
-                    (addr, i + 1)
-                } else {
-                    (addr, i)
-                }
-            })
-            .collect();
+            .copied()
+            .zip((0..PlayerId::MAX).filter(|id| !taken.contains(id)))
+            .collect::<HashMap<_, _>>();
 
-        Ok(Session {
-            confirmed_states: BTreeMap::default(),
-            inputs: crate::InputStorage::with_default(
-                self.default_inputs.ok_or("must provide default_inputs")?,
-            ),
+        let mut guests = HashMap::new();
+        for &guest in &self.local_players[self.local_players.len().min(1)..] {
+            guests.insert(guest, local_id);
+        }
+        for &(guest, addr) in &remote_guests {
+            let host = remote_players
+                .get(&addr)
+                .ok_or(SessionBuildError::UnknownGuestHost(addr))?;
+            guests.insert(guest, *host);
+        }
+
+        let all = taken.iter().chain(remote_players.values()).copied();
+        let mut seen = HashSet::new();
+        if let Some(duplicate) = all.clone().find(|&id| !seen.insert(id)) {
+            return Err(SessionBuildError::DuplicatePlayerId(duplicate));
+        }
+        let membership = Membership::from_start(all);
+
+        if let Some(hub) = self.relay_hub {
+            if !membership.players_at(Frame(0)).contains(&hub) {
+                return Err(SessionBuildError::UnknownRelayHub(hub));
+            }
+        }
+
+        let step_size = self.step_size.ok_or(SessionBuildError::MissingStepSize)?;
+        if step_schedule::check(step_size).is_err() {
+            return Err(SessionBuildError::BadStepSize(step_size));
+        }
+        let default_inputs = self
+            .default_inputs
+            .ok_or(SessionBuildError::MissingDefaultInputs)?;
+        if let Some(expected) = self.fixed_input_size {
+            if default_inputs.len() != expected {
+                return Err(SessionBuildError::DefaultInputSize {
+                    len: default_inputs.len(),
+                    expected,
+                });
+            }
+        }
+        let fixed_size = self.fixed_size_inputs || self.fixed_input_size.is_some();
+        let validator: Option<InputValidator> = match (fixed_size, self.validate_input) {
+            (false, valid) => valid,
+            (true, valid) => {
+                let len = default_inputs.len();
+                Some(Box::new(move |input| {
+                    input.len() == len && valid.as_ref().is_none_or(|valid| valid(input))
+                }))
+            }
+        };
+        let recorder = match self.record_to {
+            Some(writer) => {
+                let header = ReplayHeader {
+                    step_size,
+                    players: membership.players_at(Frame(0)).into_iter().collect(),
+                    default_input: default_inputs.clone(),
+                };
+                let recorder =
+                    ReplayRecorder::start(writer, &header).map_err(SessionBuildError::Recording)?;
+                Some(recorder)
+            }
+            None => None,
+        };
+
+        let clock = self.clock.unwrap_or_else(time::default_clock);
+        let mut shared_clock = if self.spectator {
+            SharedClock::following(self.remote_players.iter().cloned(), clock.clone())
+        } else {
+            SharedClock::among_remotes(self.remote_players.iter().cloned(), clock.clone())
+        };
+        if let Some(timeout) = self.sync_timeout {
+            shared_clock = shared_clock.with_sync_timeout(timeout);
+        }
+        shared_clock = shared_clock.with_tuning(self.clock_tuning);
+        for &addr in &self.spectators {
+            shared_clock.add_follower(addr);
+        }
+
+        let mut session = Session {
+            confirmed_checksums: Default::default(),
+            confirmed_states: {
+                let store = match self.compress_snapshots {
+                    Some(keep_recent) => SnapshotStore::compressing(keep_recent),
+                    None => SnapshotStore::uncompressed(),
+                };
+                match self.delta_saves {
+                    Some(every) => store.with_deltas(every),
+                    None => store,
+                }
+            },
+            inputs: crate::InputStorage::with_default(default_inputs).validated_by(validator),
             host_at: Duration::ZERO,
-            step_size: self.step_size.ok_or("must provide step_size")?,
+            partial_advances: self.partial_advances,
+            fill_absent_inputs: self.fill_absent_inputs,
+            compress_inputs: self.compress_inputs,
+            input_bytes: (0, 0),
+            partial_base: None,
+            steps: StepSchedule::new(step_size),
+            prediction_grace: self.prediction_grace,
+            max_prediction: self.max_prediction,
+            input_delay: self.input_delay,
+            input_delay_negotiation: self
+                .negotiated_input_delay
+                .map(|max| InputDelayNegotiation::new(local_id, max, &clock)),
+            lockstep: self.lockstep,
+            prediction_stalled: false,
+            prediction_stalls: 0,
+            received: Default::default(),
+            confirmation_mode: self.confirmation_mode,
+            authority_through: None,
             local_id,
-            socket: self.socket.ok_or("must provide socket")?,
+            guests,
+            socket: match (self.socket, self.offline) {
+                (Some(socket), _) => socket,
+                (None, true) => Box::new(OfflineSocket),
+                (None, false) => return Err(SessionBuildError::MissingSocket),
+            },
+            codec: self.codec.unwrap_or_else(|| Box::new(BincodeCodec)),
+            version_warned: Default::default(),
+            challenged: lru::LruCache::new(crate::CHALLENGED_ADDRESSES),
+            challenge_secret: rand::random(),
+            next_fragmented_id: 0,
+            fragments: Reassembler::new(Duration::from_secs(5), clock.clone()),
+            outbox: Outbox::new(Duration::from_secs(5), clock.clone()),
             player_addresses: remote_players,
+            reconnection_tokens: self.reconnection_tokens,
+            reconnection_secret: self.reconnection_secret,
             unconfirmed: Frame(1),
             remote_unconfirmed: Default::default(),
-            send_interval: Interval::new(Duration::from_millis(50)),
-            shared_clock: SharedClock::among_remotes(self.remote_players.iter().cloned()),
+            load_confirmed: false,
+            received_state: None,
+            saveless: self.saveless,
+            frame_numbers: self.frame_numbers,
+            fixed_input_size: self.fixed_input_size,
+            input_source: self.input_source,
+            max_players: max,
+            resyncing_from: None,
+            resync_asked_at: Duration::ZERO,
+            states_sent: HashMap::new(),
+            seed_proposal: (!self.spectator).then(rand::random),
+            seed_proposals: Default::default(),
+            seed: None,
+            seeded: false,
+            resumed_at: None,
+            announced_rollback: None,
+            mispredicted_simulation: false,
+            simulated_until: Frame(0),
+            membership,
+            notifications: Default::default(),
+            mispredicted: Default::default(),
+            all_disconnected: self.all_disconnected,
+            waiting_since: None,
+            paused_for_reconnect: false,
+            ended: false,
+            recorder,
+            determinism: self.check_determinism.then(Default::default),
+            verify_confirmed_advances: self.verify_confirmed_advances,
+            verify_restore: None,
+            liveness: self.disconnect_after.map(|timeout| {
+                Liveness::new(
+                    self.interrupted_after.unwrap_or(timeout / 4),
+                    timeout,
+                    clock.clone(),
+                )
+            }),
+            spectating: self.spectator,
+            relay_hub: self.relay_hub,
+            spectators: self
+                .spectators
+                .iter()
+                .map(|&a| (a, Spectator::new()))
+                .collect(),
+            accept_spectators: self.accept_spectators,
+            max_spectators: self.max_spectators.unwrap_or(DEFAULT_MAX_SPECTATORS),
+            spectate_secret: rand::random(),
+            spectate_cookies: HashMap::new(),
+            outgoing: crate::stats::OutgoingTraffic::new(&clock),
+            send_interval: Interval::new(Duration::from_millis(50), &clock),
+            send_rate: {
+                let default = Duration::from_millis(50);
+                let (min, max) = self.send_interval_bounds.unwrap_or((default, default));
+                SendRateController::between(min, max, &clock)
+            },
+            frame_advantage: crate::frame_advantage::FrameAdvantage::new(&clock),
+            pause: Default::default(),
+            log_throttle: LogThrottle::new(Duration::from_secs(1), &clock),
+            redundancy: RedundancyController::up_to(self.max_redundancy.unwrap_or(3), &clock),
+            shared_clock,
             plugins: {
-                [
-                    Box::new(crate::plugin::WarnRemoteMismatchedChecksum::with_addrs(
+                let checksums = (!self.without_checksum_plugin).then(|| {
+                    let plugin = crate::plugin::WarnRemoteMismatchedChecksum::with_addrs(
                         self.remote_players.iter().cloned(),
-                    )) as Box<dyn SessionPlugin>,
-                ]
-                .into_iter()
-                .map(|p| (p.id().to_owned(), p))
-                .collect()
+                        &clock,
+                    );
+                    match self.diff_desynced_states {
+                        true => Box::new(plugin.with_state_diffs()) as Box<dyn SessionPlugin>,
+                        false => Box::new(plugin),
+                    }
+                });
+                checksums
+                    .into_iter()
+                    .chain(self.plugins)
+                    .map(|p| (p.id().to_owned(), p))
+                    .collect()
             },
-        })
+            catch_up: CatchUpLimiter::new(self.catch_up, clock.clone()),
+            clock,
+        };
+        if let Some(bytes) = self.resume_from {
+            let snapshot =
+                SessionSnapshot::decode(&bytes).map_err(SessionBuildError::BadSnapshot)?;
+            session
+                .restore(snapshot)
+                .map_err(SessionBuildError::BadSnapshot)?;
+        }
+        Ok(session)
     }
 }
@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+use crate::time::ClockHandle;
+
+/// How many frames a session may advance in one update when it has fallen behind realtime.
+///
+/// An update is every `next_request` call up to the one that reports there is nothing left to
+/// do. The first frame of an update is always advanced, so the session keeps making progress
+/// under any policy; it reaches realtime as long as updates advance more frames than realtime
+/// moves on between them. Rollback re-simulation is never limited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CatchUp {
+    /// Advance as many frames as it takes to reach realtime.
+    #[default]
+    Unlimited,
+    /// Advance at most this many frames per update.
+    MaxFrames(u32),
+    /// Stop advancing once this much time has been spent in the update.
+    Budget(Duration),
+}
+
+pub(crate) struct CatchUpLimiter {
+    policy: CatchUp,
+    clock: ClockHandle,
+    started: Option<Duration>,
+    frames: u32,
+    throttled: u64,
+}
+
+impl CatchUpLimiter {
+    pub fn new(policy: CatchUp, clock: ClockHandle) -> Self {
+        CatchUpLimiter {
+            policy,
+            clock,
+            started: None,
+            frames: 0,
+            throttled: 0,
+        }
+    }
+
+    /// Whether this update may advance another frame towards realtime, counting it if so.
+    pub fn take_frame(&mut self) -> bool {
+        let started = *self.started.get_or_insert_with(|| self.clock.now());
+        let allowed = self.frames == 0
+            || match self.policy {
+                CatchUp::Unlimited => true,
+                CatchUp::MaxFrames(max) => self.frames < max,
+                CatchUp::Budget(budget) => self.clock.since(started) < budget,
+            };
+
+        if allowed {
+            self.frames += 1;
+        } else {
+            self.throttled += 1;
+        }
+        allowed
+    }
+
+    pub fn end_update(&mut self) {
+        self.started = None;
+        self.frames = 0;
+    }
+
+    /// How many updates have stopped short of realtime because of the policy.
+    pub fn throttled(&self) -> u64 {
+        self.throttled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+    use std::sync::Arc;
+
+    fn frames_allowed(limiter: &mut CatchUpLimiter, clock: &ManualClock) -> u32 {
+        let mut frames = 0;
+        while frames < 100 && limiter.take_frame() {
+            clock.advance(Duration::from_millis(4));
+            frames += 1;
+        }
+        limiter.end_update();
+        frames
+    }
+
+    #[test]
+    fn limits_frames_per_update() {
+        let clock = ManualClock::new();
+        let mut limiter = CatchUpLimiter::new(CatchUp::MaxFrames(3), Arc::new(clock.clone()));
+
+        assert_eq!(frames_allowed(&mut limiter, &clock), 3);
+        assert_eq!(frames_allowed(&mut limiter, &clock), 3);
+        assert_eq!(limiter.throttled(), 2);
+    }
+
+    #[test]
+    fn budget_always_allows_one_frame() {
+        let clock = ManualClock::new();
+        let mut limiter = CatchUpLimiter::new(
+            CatchUp::Budget(Duration::from_millis(10)),
+            Arc::new(clock.clone()),
+        );
+        assert_eq!(frames_allowed(&mut limiter, &clock), 3);
+
+        let mut limiter =
+            CatchUpLimiter::new(CatchUp::Budget(Duration::ZERO), Arc::new(clock.clone()));
+        assert_eq!(frames_allowed(&mut limiter, &clock), 1);
+    }
+}
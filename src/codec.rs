@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+
+use crate::Message;
+
+pub type CodecError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The version of the messages between peers, bumped whenever they change incompatibly. Every
+/// packet starts with it, and packets from a peer on another version are dropped.
+pub const PROTOCOL_VERSION: u16 = 23;
+
+const MAGIC: [u8; 2] = *b"rb";
+const HEADER_LEN: usize = MAGIC.len() + 3;
+
+/// Whether a packet holds a whole message or one fragment of a larger one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PacketKind {
+    Whole = 0,
+    Fragment = 1,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum HeaderError {
+    NotRbrb,
+    Version(u16),
+}
+
+pub(crate) fn with_header(kind: PacketKind, body: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(HEADER_LEN + body.len());
+    packet.extend_from_slice(&MAGIC);
+    packet.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+    packet.push(kind as u8);
+    packet.extend_from_slice(body);
+    packet
+}
+
+pub(crate) fn strip_header(packet: &[u8]) -> Result<(PacketKind, &[u8]), HeaderError> {
+    if packet.len() < HEADER_LEN || packet[..MAGIC.len()] != MAGIC {
+        return Err(HeaderError::NotRbrb);
+    }
+    match u16::from_le_bytes([packet[2], packet[3]]) {
+        PROTOCOL_VERSION => {}
+        other => return Err(HeaderError::Version(other)),
+    }
+    let kind = match packet[4] {
+        0 => PacketKind::Whole,
+        1 => PacketKind::Fragment,
+        _ => return Err(HeaderError::NotRbrb),
+    };
+    Ok((kind, &packet[HEADER_LEN..]))
+}
+
+/// How messages between peers are turned into packets and back.
+///
+/// Every peer in a session must use the same codec. Packets come straight off the network, so
+/// `decode` must reject malformed input with an error rather than panic.
+pub trait Codec: Send + Sync {
+    fn encode(&self, message: &WireMessage) -> Result<Vec<u8>, CodecError>;
+    fn decode(&self, bytes: &[u8]) -> Result<WireMessage, CodecError>;
+}
+
+/// A message between peers. Its contents are private, but it can go through any serde format.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(transparent)]
+pub struct WireMessage(pub(crate) Message);
+
+/// The default codec.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    fn encode(&self, message: &WireMessage) -> Result<Vec<u8>, CodecError> {
+        Ok(bincode::serialize(message)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<WireMessage, CodecError> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Frame;
+
+    #[test]
+    fn bincode_round_trips_and_rejects_garbage() {
+        let codec = BincodeCodec;
+        let bytes = codec
+            .encode(&WireMessage(Message::Unconfirmed(Frame(7))))
+            .unwrap();
+        assert!(matches!(
+            codec.decode(&bytes),
+            Ok(WireMessage(Message::Unconfirmed(Frame(7))))
+        ));
+        assert!(codec.decode(&[0xff; 3]).is_err());
+    }
+
+    #[test]
+    fn header_checks_magic_and_version() {
+        let packet = with_header(PacketKind::Fragment, &[1, 2, 3]);
+        assert_eq!(
+            strip_header(&packet),
+            Ok((PacketKind::Fragment, &[1, 2, 3][..]))
+        );
+
+        let mut other_version = packet.clone();
+        other_version[2] = other_version[2].wrapping_add(1);
+        assert!(matches!(
+            strip_header(&other_version),
+            Err(HeaderError::Version(v)) if v != PROTOCOL_VERSION
+        ));
+        assert_eq!(strip_header(&[1, 2, 3, 4, 5]), Err(HeaderError::NotRbrb));
+        assert_eq!(strip_header(b"rb"), Err(HeaderError::NotRbrb));
+
+        let mut unknown_kind = packet;
+        unknown_kind[4] = 9;
+        assert_eq!(strip_header(&unknown_kind), Err(HeaderError::NotRbrb));
+    }
+}
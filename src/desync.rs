@@ -0,0 +1,100 @@
+//! Tools for triaging desyncs between peers.
+//!
+//! When two peers diverge, first compare their confirmed inputs. If the inputs match, the game's
+//! simulation is nondeterministic. If they differ, the inputs were not delivered consistently.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{PlayerId, SerializedInput};
+
+/// The confirmed inputs a peer simulated, as exported by `Session::export_confirmed_inputs`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputExport {
+    pub frames: BTreeMap<u32, BTreeMap<PlayerId, SerializedInput>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputMismatch {
+    pub frame: u32,
+    pub player: PlayerId,
+    pub ours: Option<SerializedInput>,
+    pub theirs: Option<SerializedInput>,
+}
+
+/// Finds the earliest frame where a player's confirmed input differs between two exports.
+///
+/// Only frames present in both exports are compared, so exports covering different ranges can
+/// still be diffed.
+pub fn first_input_mismatch(ours: &InputExport, theirs: &InputExport) -> Option<InputMismatch> {
+    for (frame, our_inputs) in &ours.frames {
+        let their_inputs = match theirs.frames.get(frame) {
+            Some(i) => i,
+            None => continue,
+        };
+
+        let players = our_inputs.keys().chain(their_inputs.keys());
+        for player in players.collect::<BTreeSet<_>>() {
+            let (a, b) = (our_inputs.get(player), their_inputs.get(player));
+            if a != b {
+                return Some(InputMismatch {
+                    frame: *frame,
+                    player: *player,
+                    ours: a.cloned(),
+                    theirs: b.cloned(),
+                });
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn export(frames: &[(u32, &[(PlayerId, u8)])]) -> InputExport {
+        InputExport {
+            frames: frames
+                .iter()
+                .map(|(f, inputs)| (*f, inputs.iter().map(|(p, i)| (*p, vec![*i])).collect()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn matching_exports() {
+        let a = export(&[(0, &[(0, 0), (1, 0)]), (1, &[(0, 1), (1, 2)])]);
+        assert_eq!(first_input_mismatch(&a, &a.clone()), None);
+    }
+
+    #[test]
+    fn reports_first_differing_frame() {
+        let a = export(&[
+            (0, &[(0, 0), (1, 0)]),
+            (1, &[(0, 1), (1, 2)]),
+            (2, &[(0, 3)]),
+        ]);
+        let b = export(&[
+            (0, &[(0, 0), (1, 0)]),
+            (1, &[(0, 1), (1, 5)]),
+            (2, &[(0, 4)]),
+        ]);
+        assert_eq!(
+            first_input_mismatch(&a, &b),
+            Some(InputMismatch {
+                frame: 1,
+                player: 1,
+                ours: Some(vec![2]),
+                theirs: Some(vec![5]),
+            })
+        );
+    }
+
+    #[test]
+    fn ignores_frames_missing_from_one_side() {
+        let a = export(&[(0, &[(0, 9)]), (1, &[(0, 1)])]);
+        let b = export(&[(1, &[(0, 1)])]);
+        assert_eq!(first_input_mismatch(&a, &b), None);
+    }
+}
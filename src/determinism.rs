@@ -0,0 +1,549 @@
+//! Helpers for keeping game state deterministic.
+//!
+//! Iterating a `HashMap` or `HashSet` visits entries in a different order on every peer, which
+//! is one of the most common causes of desyncs. Prefer the ordered collections re-exported here
+//! for anything that is simulated or serialized.
+//!
+//! Floating point is the other: the same `f32` arithmetic can round differently between x86 and
+//! ARM, or between builds that fuse multiplies and adds differently. Simulate with `Fixed` instead,
+//! converting to floats only to render. Checksumming state through `Deterministic` hashes it the
+//! same on every architecture, and has no implementation for floats or unordered collections, so
+//! using one in the state fails to compile. With `SessionBuilder::check_determinism`,
+//! `SessionBuilder::verify_confirmed_advances` and the default checksum exchange, a divergence is
+//! reported at the first frame it happens.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    hash::Hasher,
+    ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssign},
+};
+
+use crate::{Frame, PlayerInputs, StateHasher};
+
+pub use std::collections::{BTreeMap as OrderedMap, BTreeSet as OrderedSet};
+
+const ROUND_TRIPS: usize = 8;
+
+/// Panics if serializing `value` is not stable across deserialization round trips.
+///
+/// Unordered collections are reseeded every time they are deserialized, so their serialized
+/// form changes between round trips. This is a heuristic: collections with fewer than two
+/// entries cannot be caught.
+pub fn assert_no_hashmap<T: Serialize + DeserializeOwned>(value: &T) {
+    let expected = bincode::serialize(value).expect("failed to serialize value");
+
+    let mut bytes = expected.clone();
+    for _ in 0..ROUND_TRIPS {
+        let round_tripped: T = bincode::deserialize(&bytes).expect("failed to deserialize value");
+        bytes = bincode::serialize(&round_tripped).expect("failed to serialize value");
+        assert_eq!(
+            expected, bytes,
+            "serialization changed after a round trip, does the value contain an unordered collection?"
+        );
+    }
+}
+
+/// A signed fixed-point number with 16 fractional bits, for simulating without floating point.
+///
+/// Ranges over about ±32768 with a precision of 1/65536. Arithmetic is on integers, so gives the
+/// same result on every platform. Every operation panics on overflow, in release builds too, so
+/// an overflow can't wrap on one peer and go unnoticed. Multiplication and division round towards
+/// negative infinity.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Fixed(i32);
+
+impl Fixed {
+    const FRACTION_BITS: u32 = 16;
+
+    pub const ZERO: Fixed = Fixed(0);
+    pub const ONE: Fixed = Fixed(1 << Self::FRACTION_BITS);
+
+    pub const fn from_int(value: i32) -> Self {
+        assert!(
+            value >= i32::MIN >> Self::FRACTION_BITS && value <= i32::MAX >> Self::FRACTION_BITS,
+            "integer out of fixed-point range"
+        );
+        Fixed(value << Self::FRACTION_BITS)
+    }
+
+    /// `numerator / denominator`, for constants like a half without going through floats.
+    pub const fn from_ratio(numerator: i32, denominator: i32) -> Self {
+        Fixed((((numerator as i64) << Self::FRACTION_BITS) / denominator as i64) as i32)
+    }
+
+    /// The nearest value to `value`. Converting is deterministic, so this is safe for constants
+    /// and configuration, just not for simulating in floats and converting the result.
+    pub fn from_f32(value: f32) -> Self {
+        Fixed((value as f64 * Self::ONE.0 as f64).round() as i32)
+    }
+
+    /// For rendering, never for feeding back into the simulation.
+    pub fn to_f32(self) -> f32 {
+        self.0 as f32 / Self::ONE.0 as f32
+    }
+
+    pub const fn from_bits(bits: i32) -> Self {
+        Fixed(bits)
+    }
+
+    pub const fn to_bits(self) -> i32 {
+        self.0
+    }
+
+    /// The integer part, rounded towards negative infinity.
+    pub const fn floor(self) -> i32 {
+        self.0 >> Self::FRACTION_BITS
+    }
+
+    pub fn abs(self) -> Self {
+        Fixed(self.0.checked_abs().expect("fixed-point abs overflowed"))
+    }
+
+    /// The bits scaled up by another `ONE`, for dividing without losing the fraction.
+    const fn widened(self) -> i64 {
+        (self.0 as i64) << Self::FRACTION_BITS
+    }
+}
+
+impl fmt::Debug for Fixed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_f32())
+    }
+}
+
+impl fmt::Display for Fixed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.to_f32(), f)
+    }
+}
+
+impl From<i32> for Fixed {
+    fn from(value: i32) -> Self {
+        Fixed::from_int(value)
+    }
+}
+
+impl Add for Fixed {
+    type Output = Fixed;
+
+    fn add(self, other: Fixed) -> Fixed {
+        Fixed(
+            self.0
+                .checked_add(other.0)
+                .expect("fixed-point addition overflowed"),
+        )
+    }
+}
+
+impl Sub for Fixed {
+    type Output = Fixed;
+
+    fn sub(self, other: Fixed) -> Fixed {
+        Fixed(
+            self.0
+                .checked_sub(other.0)
+                .expect("fixed-point subtraction overflowed"),
+        )
+    }
+}
+
+impl Mul for Fixed {
+    type Output = Fixed;
+
+    fn mul(self, other: Fixed) -> Fixed {
+        let product = (self.0 as i64 * other.0 as i64) >> Self::FRACTION_BITS;
+        Fixed(i32::try_from(product).expect("fixed-point multiplication overflowed"))
+    }
+}
+
+impl Div for Fixed {
+    type Output = Fixed;
+
+    fn div(self, other: Fixed) -> Fixed {
+        let (dividend, divisor) = (self.widened(), other.0 as i64);
+        let mut quotient = dividend / divisor;
+        if dividend % divisor != 0 && (dividend < 0) != (divisor < 0) {
+            quotient -= 1;
+        }
+        Fixed(i32::try_from(quotient).expect("fixed-point division overflowed"))
+    }
+}
+
+impl Neg for Fixed {
+    type Output = Fixed;
+
+    fn neg(self) -> Fixed {
+        Fixed(
+            self.0
+                .checked_neg()
+                .expect("fixed-point negation overflowed"),
+        )
+    }
+}
+
+impl AddAssign for Fixed {
+    fn add_assign(&mut self, other: Fixed) {
+        *self = *self + other;
+    }
+}
+
+impl SubAssign for Fixed {
+    fn sub_assign(&mut self, other: Fixed) {
+        *self = *self - other;
+    }
+}
+
+impl MulAssign for Fixed {
+    fn mul_assign(&mut self, other: Fixed) {
+        *self = *self * other;
+    }
+}
+
+impl DivAssign for Fixed {
+    fn div_assign(&mut self, other: Fixed) {
+        *self = *self / other;
+    }
+}
+
+/// State that checksums the same on every peer, for `Request::ChecksumState`.
+///
+/// Unlike `Hash`, which writes native-endian bytes and a platform-sized length, implementations
+/// write fixed-size little-endian bytes. There are deliberately none for floats or unordered
+/// collections. Implement it for game state by checksumming each field in turn:
+///
+/// ```
+/// use rbrb::{determinism::{Deterministic, Fixed}, StateHasher};
+///
+/// struct Player {
+///     position: (Fixed, Fixed),
+///     health: u8,
+/// }
+///
+/// impl Deterministic for Player {
+///     fn checksum(&self, hasher: &mut StateHasher) {
+///         self.position.checksum(hasher);
+///         self.health.checksum(hasher);
+///     }
+/// }
+/// ```
+pub trait Deterministic {
+    fn checksum(&self, hasher: &mut StateHasher);
+}
+
+macro_rules! deterministic_int {
+    ($($t:ty),*) => {$(
+        impl Deterministic for $t {
+            fn checksum(&self, hasher: &mut StateHasher) {
+                hasher.write(&self.to_le_bytes());
+            }
+        }
+    )*};
+}
+
+deterministic_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl Deterministic for usize {
+    fn checksum(&self, hasher: &mut StateHasher) {
+        (*self as u64).checksum(hasher);
+    }
+}
+
+impl Deterministic for isize {
+    fn checksum(&self, hasher: &mut StateHasher) {
+        (*self as i64).checksum(hasher);
+    }
+}
+
+impl Deterministic for bool {
+    fn checksum(&self, hasher: &mut StateHasher) {
+        (*self as u8).checksum(hasher);
+    }
+}
+
+impl Deterministic for char {
+    fn checksum(&self, hasher: &mut StateHasher) {
+        (*self as u32).checksum(hasher);
+    }
+}
+
+impl Deterministic for Fixed {
+    fn checksum(&self, hasher: &mut StateHasher) {
+        self.0.checksum(hasher);
+    }
+}
+
+impl Deterministic for str {
+    fn checksum(&self, hasher: &mut StateHasher) {
+        self.as_bytes().checksum(hasher);
+    }
+}
+
+impl Deterministic for String {
+    fn checksum(&self, hasher: &mut StateHasher) {
+        self.as_str().checksum(hasher);
+    }
+}
+
+impl<T: Deterministic + ?Sized> Deterministic for &T {
+    fn checksum(&self, hasher: &mut StateHasher) {
+        (**self).checksum(hasher);
+    }
+}
+
+impl<T: Deterministic + ?Sized> Deterministic for Box<T> {
+    fn checksum(&self, hasher: &mut StateHasher) {
+        (**self).checksum(hasher);
+    }
+}
+
+impl<T: Deterministic> Deterministic for [T] {
+    fn checksum(&self, hasher: &mut StateHasher) {
+        self.len().checksum(hasher);
+        for item in self {
+            item.checksum(hasher);
+        }
+    }
+}
+
+impl<T: Deterministic, const N: usize> Deterministic for [T; N] {
+    fn checksum(&self, hasher: &mut StateHasher) {
+        self.as_slice().checksum(hasher);
+    }
+}
+
+impl<T: Deterministic> Deterministic for Vec<T> {
+    fn checksum(&self, hasher: &mut StateHasher) {
+        self.as_slice().checksum(hasher);
+    }
+}
+
+impl<T: Deterministic> Deterministic for Option<T> {
+    fn checksum(&self, hasher: &mut StateHasher) {
+        match self {
+            None => 0u8.checksum(hasher),
+            Some(value) => {
+                1u8.checksum(hasher);
+                value.checksum(hasher);
+            }
+        }
+    }
+}
+
+impl<K: Deterministic, V: Deterministic> Deterministic for BTreeMap<K, V> {
+    fn checksum(&self, hasher: &mut StateHasher) {
+        self.len().checksum(hasher);
+        for entry in self {
+            entry.checksum(hasher);
+        }
+    }
+}
+
+impl<T: Deterministic> Deterministic for BTreeSet<T> {
+    fn checksum(&self, hasher: &mut StateHasher) {
+        self.len().checksum(hasher);
+        for item in self {
+            item.checksum(hasher);
+        }
+    }
+}
+
+macro_rules! deterministic_tuple {
+    ($(($($name:ident),+)),*) => {$(
+        impl<$($name: Deterministic),+> Deterministic for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn checksum(&self, hasher: &mut StateHasher) {
+                let ($($name,)+) = self;
+                $($name.checksum(hasher);)+
+            }
+        }
+    )*};
+}
+
+deterministic_tuple!((A), (A, B), (A, B, C), (A, B, C, D));
+
+/// How much of the simulation the determinism check has verified, see
+/// `SessionBuilder::check_determinism`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DeterminismCoverage {
+    /// Frames advanced with confirmed inputs.
+    pub confirmed_frames: u64,
+    /// Of those, frames that were simulated more than once from the same state with the same
+    /// inputs, and produced the same state each time.
+    pub verified_frames: u64,
+}
+
+impl DeterminismCoverage {
+    /// The fraction of confirmed frames that were verified. Low coverage means rollbacks are
+    /// rare, so the check has had little to compare.
+    pub fn ratio(&self) -> f64 {
+        if self.confirmed_frames == 0 {
+            return 0.;
+        }
+        self.verified_frames as f64 / self.confirmed_frames as f64
+    }
+}
+
+/// Checks that re-simulating a frame from the same state with the same inputs, as happens when
+/// rolling back, always produces the same state.
+#[derive(Default)]
+pub(crate) struct DeterminismCheck {
+    /// `(before, inputs, after)` checksums of every simulation of each unconfirmed frame.
+    simulated: BTreeMap<Frame, Vec<(u64, u64, u64)>>,
+    verified: BTreeSet<Frame>,
+    coverage: DeterminismCoverage,
+}
+
+impl DeterminismCheck {
+    pub fn record(&mut self, frame: Frame, before: u64, inputs: u64, after: u64) {
+        let simulations = self.simulated.entry(frame).or_default();
+        let previous = simulations
+            .iter()
+            .find(|(b, i, _)| (*b, *i) == (before, inputs));
+        match previous {
+            Some((_, _, previous_after)) => {
+                assert_eq!(
+                    *previous_after, after,
+                    "nondeterministic simulation of frame {:?}, same state and inputs produced different states",
+                    frame
+                );
+                self.verified.insert(frame);
+            }
+            None => simulations.push((before, inputs, after)),
+        }
+    }
+
+    /// `frame` will not be simulated again from a different state.
+    pub fn confirm(&mut self, frame: Frame) {
+        self.coverage.confirmed_frames += 1;
+        if self.verified.contains(&frame) {
+            self.coverage.verified_frames += 1;
+        }
+
+        self.simulated = self.simulated.split_off(&(frame + 1));
+        self.verified = self.verified.split_off(&(frame + 1));
+    }
+
+    pub fn coverage(&self) -> DeterminismCoverage {
+        self.coverage
+    }
+}
+
+/// The first offset at which `a` and `b` differ, `None` if they are equal.
+pub(crate) fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    match a.iter().zip(b).position(|(a, b)| a != b) {
+        Some(offset) => Some(offset),
+        None if a.len() != b.len() => Some(a.len().min(b.len())),
+        None => None,
+    }
+}
+
+/// A checksum of `inputs` that doesn't depend on player order.
+pub(crate) fn inputs_checksum(inputs: &PlayerInputs) -> u64 {
+    let sorted = inputs
+        .iter()
+        .map(|(p, input)| (*p, input.as_inner()))
+        .collect::<BTreeMap<_, _>>();
+    seahash::hash(&bincode::serialize(&sorted).expect("failed to serialize inputs"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn fixed_point_arithmetic() {
+        let half = Fixed::from_ratio(1, 2);
+        assert_eq!(half, Fixed::from_f32(0.5));
+        assert_eq!(half * Fixed::from_int(3), Fixed::from_ratio(3, 2));
+        assert_eq!(
+            Fixed::from_int(3) / Fixed::from_int(4),
+            Fixed::from_ratio(3, 4)
+        );
+        assert_eq!((Fixed::from_int(-3) / Fixed::from_int(2)).floor(), -2);
+        assert_eq!((Fixed::from_int(3) / Fixed::from_int(-2)).floor(), -2);
+        assert_eq!(
+            Fixed::from_bits(1) / Fixed::from_int(-2),
+            Fixed::from_bits(-1)
+        );
+        assert_eq!((-half).abs() + Fixed::ONE, Fixed::from_ratio(3, 2));
+        assert_eq!(Fixed::from_ratio(-1, 3).to_bits(), -21845);
+    }
+
+    #[test]
+    #[should_panic(expected = "fixed-point addition overflowed")]
+    fn fixed_point_addition_panics_on_overflow() {
+        let _ = Fixed::from_int(32767) + Fixed::ONE;
+    }
+
+    #[test]
+    fn checksums_are_little_endian() {
+        let mut hasher = StateHasher::default();
+        (BTreeMap::from([(1u16, Fixed::ONE)]), vec![true], Some('a')).checksum(&mut hasher);
+
+        let mut expected = StateHasher::default();
+        for bytes in [
+            &1u64.to_le_bytes()[..],
+            &1u16.to_le_bytes(),
+            &65536i32.to_le_bytes(),
+            &1u64.to_le_bytes(),
+            &[1],
+            &[1],
+            &97u32.to_le_bytes(),
+        ] {
+            expected.write(bytes);
+        }
+        assert_eq!(hasher.finish(), expected.finish());
+    }
+
+    #[test]
+    fn finds_the_first_difference() {
+        assert_eq!(first_difference(&[1, 2, 3], &[1, 2, 3]), None);
+        assert_eq!(first_difference(&[1, 2, 3], &[1, 5, 3]), Some(1));
+        assert_eq!(first_difference(&[1, 2], &[1, 2, 3]), Some(2));
+    }
+
+    #[test]
+    fn ordered_map_is_stable() {
+        let map = (0..64)
+            .map(|i| (i, i * 2))
+            .collect::<OrderedMap<u32, u32>>();
+        assert_no_hashmap(&map);
+    }
+
+    #[test]
+    #[should_panic(expected = "unordered collection")]
+    fn hashmap_is_detected() {
+        let map = (0..64).map(|i| (i, i * 2)).collect::<HashMap<u32, u32>>();
+        assert_no_hashmap(&map);
+    }
+
+    #[test]
+    fn resimulation_counts_towards_coverage() {
+        let mut check = DeterminismCheck::default();
+        check.record(Frame(1), 10, 20, 30);
+        check.confirm(Frame(1));
+        check.record(Frame(2), 30, 20, 40);
+        check.record(Frame(2), 30, 20, 40);
+        check.confirm(Frame(2));
+
+        assert_eq!(
+            check.coverage(),
+            DeterminismCoverage {
+                confirmed_frames: 2,
+                verified_frames: 1,
+            }
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "nondeterministic simulation")]
+    fn differing_resimulation_panics() {
+        let mut check = DeterminismCheck::default();
+        check.record(Frame(1), 10, 20, 30);
+        check.record(Frame(1), 10, 20, 31);
+    }
+}
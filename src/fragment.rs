@@ -0,0 +1,295 @@
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+use lru::LruCache;
+
+use crate::time::ClockHandle;
+
+/// The most message bytes to put in one packet, leaving room for headers under a typical
+/// 1500 byte MTU. Larger messages, like full states, are split into fragments.
+pub(crate) const MAX_FRAGMENT: usize = 1200;
+
+/// The largest message that may be fragmented, so a forged fragment count can't make the
+/// receiver set aside room for more.
+pub(crate) const MAX_MESSAGE: usize = 8 * 1024 * 1024;
+
+const MAX_FRAGMENTS: usize = MAX_MESSAGE.div_ceil(MAX_FRAGMENT);
+
+const FRAGMENT_HEADER: usize = 8;
+
+/// How long an incomplete message may go without a new fragment before the missing ones are
+/// asked for again.
+const NACK_AFTER: Duration = Duration::from_millis(100);
+
+/// The most missing fragments to ask for at once, keeping the request to one packet. The rest
+/// are asked for once those arrive.
+const MAX_NACKED: usize = 256;
+
+/// How many fragmented messages are kept to resend from.
+const OUTBOX_MESSAGES: usize = 16;
+
+/// Split `body`, at most `MAX_MESSAGE` long, into fragments of message `id`, each prefixed with
+/// the id, its index and the fragment count.
+pub(crate) fn split(id: u32, body: &[u8]) -> Vec<Vec<u8>> {
+    assert!(body.len() <= MAX_MESSAGE, "message too large");
+    let count = body.len().div_ceil(MAX_FRAGMENT) as u16;
+    body.chunks(MAX_FRAGMENT)
+        .zip(0u16..)
+        .map(|(chunk, index)| {
+            let mut fragment = Vec::with_capacity(FRAGMENT_HEADER + chunk.len());
+            fragment.extend_from_slice(&id.to_le_bytes());
+            fragment.extend_from_slice(&index.to_le_bytes());
+            fragment.extend_from_slice(&count.to_le_bytes());
+            fragment.extend_from_slice(chunk);
+            fragment
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct MalformedFragment;
+
+struct Partial {
+    started: Duration,
+    /// When the latest fragment arrived or the missing ones were last asked for.
+    quiet_since: Duration,
+    chunks: Vec<Option<Vec<u8>>>,
+    missing: usize,
+}
+
+/// Collects fragments per sender until a message is whole. Fragments that don't show up are
+/// asked for again, see `missing`, and sets that stay incomplete for longer than `timeout` are
+/// dropped.
+pub(crate) struct Reassembler {
+    partial: HashMap<(SocketAddr, u32), Partial>,
+    timeout: Duration,
+    clock: ClockHandle,
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration, clock: ClockHandle) -> Self {
+        Reassembler {
+            partial: Default::default(),
+            timeout,
+            clock,
+        }
+    }
+
+    /// Returns the whole message once `fragment` completes it.
+    pub fn receive(
+        &mut self,
+        from: SocketAddr,
+        fragment: &[u8],
+    ) -> Result<Option<Vec<u8>>, MalformedFragment> {
+        if fragment.len() < FRAGMENT_HEADER {
+            return Err(MalformedFragment);
+        }
+        let (header, chunk) = fragment.split_at(FRAGMENT_HEADER);
+        let id = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let index = u16::from_le_bytes(header[4..6].try_into().unwrap()) as usize;
+        let count = u16::from_le_bytes(header[6..8].try_into().unwrap()) as usize;
+        if index >= count || count > MAX_FRAGMENTS {
+            return Err(MalformedFragment);
+        }
+
+        let now = self.clock.now();
+        let partial = self.partial.entry((from, id)).or_insert_with(|| Partial {
+            started: now,
+            quiet_since: now,
+            chunks: vec![None; count],
+            missing: count,
+        });
+        if partial.chunks.len() != count {
+            return Err(MalformedFragment);
+        }
+        if partial.chunks[index].is_none() {
+            partial.chunks[index] = Some(chunk.to_vec());
+            partial.missing -= 1;
+            partial.quiet_since = now;
+        }
+        if partial.missing > 0 {
+            return Ok(None);
+        }
+
+        let partial = self.partial.remove(&(from, id)).unwrap();
+        Ok(Some(
+            partial.chunks.into_iter().flatten().flatten().collect(),
+        ))
+    }
+
+    /// The fragments to ask each sender for again, of messages that have gone `NACK_AFTER`
+    /// without one arriving.
+    pub fn missing(&mut self) -> Vec<(SocketAddr, u32, Vec<u16>)> {
+        let now = self.clock.now();
+        self.partial
+            .iter_mut()
+            .filter(|(_, partial)| now - partial.quiet_since >= NACK_AFTER)
+            .map(|((from, id), partial)| {
+                partial.quiet_since = now;
+                let missing = (0..partial.chunks.len() as u16)
+                    .filter(|i| partial.chunks[*i as usize].is_none())
+                    .take(MAX_NACKED)
+                    .collect();
+                (*from, *id, missing)
+            })
+            .collect()
+    }
+
+    /// Drop incomplete messages that have waited too long for their remaining fragments.
+    pub fn expire(&mut self) {
+        let (clock, timeout) = (&self.clock, self.timeout);
+        self.partial.retain(|(from, id), partial| {
+            let keep = clock.since(partial.started) < timeout;
+            if !keep {
+                log::debug!(
+                    "dropping message {} from {} missing {} of {} fragments",
+                    id,
+                    from,
+                    partial.missing,
+                    partial.chunks.len()
+                );
+            }
+            keep
+        });
+    }
+}
+
+struct Sent {
+    at: Duration,
+    resent_at: Option<Duration>,
+    packets: Vec<Vec<u8>>,
+}
+
+/// The packets of the latest fragmented messages we sent, to resend the fragments a receiver
+/// reports missing for up to `timeout`.
+pub(crate) struct Outbox {
+    sent: LruCache<u32, Sent>,
+    timeout: Duration,
+    clock: ClockHandle,
+}
+
+impl Outbox {
+    pub fn new(timeout: Duration, clock: ClockHandle) -> Self {
+        Outbox {
+            sent: LruCache::new(OUTBOX_MESSAGES),
+            timeout,
+            clock,
+        }
+    }
+
+    pub fn keep(&mut self, id: u32, packets: Vec<Vec<u8>>) {
+        let at = self.clock.now();
+        self.sent.put(
+            id,
+            Sent {
+                at,
+                resent_at: None,
+                packets,
+            },
+        );
+    }
+
+    /// The packets of message `id`'s `missing` fragments, unless they were just resent, as a
+    /// spoofed request could otherwise have them sent over and over.
+    pub fn resend(&mut self, id: u32, missing: &[u16]) -> Vec<Vec<u8>> {
+        let now = self.clock.now();
+        let sent = match self.sent.get_mut(&id) {
+            Some(sent) if now - sent.at < self.timeout => sent,
+            _ => return Vec::new(),
+        };
+        if sent.resent_at.is_some_and(|at| now - at < NACK_AFTER / 2) {
+            return Vec::new();
+        }
+        sent.resent_at = Some(now);
+        missing
+            .iter()
+            .filter_map(|i| sent.packets.get(*i as usize).cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BadSocketBuilder, Latency, ManualClock, MemoryNetwork, NonBlockingSocket};
+    use std::sync::Arc;
+
+    #[test]
+    fn asks_again_for_fragments_lost_on_the_way() {
+        let network = MemoryNetwork::new();
+        let (a, b) = (
+            SocketAddr::from(([127, 0, 0, 1], 1)),
+            SocketAddr::from(([127, 0, 0, 1], 2)),
+        );
+        let lossy = || {
+            BadSocketBuilder::default()
+                .drop_chance(0.2)
+                .latency(Latency::Fixed(Duration::ZERO))
+                .seed(7)
+        };
+        let mut sender = lossy().build(network.socket(a));
+        let mut receiver = lossy().build(network.socket(b));
+        let clock = ManualClock::new();
+        let mut reassembler = Reassembler::new(Duration::from_secs(5), Arc::new(clock.clone()));
+        let mut outbox = Outbox::new(Duration::from_secs(5), Arc::new(clock.clone()));
+
+        let state = (0..100_000)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect::<Vec<_>>();
+        let fragments = split(3, &state);
+        assert!(fragments
+            .iter()
+            .all(|f| f.len() <= FRAGMENT_HEADER + MAX_FRAGMENT));
+
+        // Sent once, with only what goes missing sent again.
+        for fragment in &fragments {
+            sender.send(fragment, b);
+        }
+        outbox.keep(3, fragments);
+        let mut received = None;
+        for _ in 0..500 {
+            if received.is_some() {
+                break;
+            }
+            clock.advance(Duration::from_millis(10));
+            network.advance(Duration::from_millis(10));
+            while let Some((from, packet)) = receiver.recv() {
+                if let Some(message) = reassembler.receive(from, packet).unwrap() {
+                    received = Some(message);
+                }
+            }
+            for (_, id, missing) in reassembler.missing() {
+                for fragment in outbox.resend(id, &missing) {
+                    sender.send(&fragment, b);
+                }
+            }
+        }
+        assert_eq!(received, Some(state));
+    }
+
+    #[test]
+    fn drops_stale_and_malformed_fragments() {
+        let clock = ManualClock::new();
+        let mut reassembler = Reassembler::new(Duration::from_secs(1), Arc::new(clock.clone()));
+        let from = SocketAddr::from(([127, 0, 0, 1], 1));
+        let fragments = split(1, &[0; MAX_FRAGMENT * 2]);
+
+        assert_eq!(reassembler.receive(from, &fragments[0]), Ok(None));
+        clock.advance(Duration::from_secs(2));
+        reassembler.expire();
+        assert_eq!(reassembler.receive(from, &fragments[1]), Ok(None));
+
+        assert_eq!(
+            reassembler.receive(from, &[1, 2, 3]),
+            Err(MalformedFragment)
+        );
+        let mut bad_index = fragments[0].clone();
+        bad_index[4..6].copy_from_slice(&5u16.to_le_bytes());
+        assert_eq!(
+            reassembler.receive(from, &bad_index),
+            Err(MalformedFragment)
+        );
+        let mut too_many = fragments[0].clone();
+        too_many[6..8].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert_eq!(reassembler.receive(from, &too_many), Err(MalformedFragment));
+    }
+}
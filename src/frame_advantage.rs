@@ -0,0 +1,127 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use crate::{
+    time::{ClockHandle, Interval},
+    PlayerId,
+};
+
+/// How many samples of our advantage over each remote to average.
+const WINDOW: usize = 20;
+
+/// Differences under this many frames are left to drift correction.
+const THRESHOLD: f32 = 0.75;
+
+/// GGPO style frame advantage: how many frames our inputs run ahead of each remote's, as seen
+/// from here. Peers exchange their averages so that latency that looks the same from both ends
+/// cancels out, and each side makes up half the difference, the one ahead waiting and the one
+/// behind catching up.
+pub(crate) struct FrameAdvantage {
+    local: HashMap<PlayerId, VecDeque<f32>>,
+    remote: HashMap<PlayerId, f32>,
+    steer: Interval,
+}
+
+impl FrameAdvantage {
+    pub fn new(clock: &ClockHandle) -> Self {
+        FrameAdvantage {
+            local: Default::default(),
+            remote: Default::default(),
+            steer: Interval::new(Duration::from_secs(2), clock),
+        }
+    }
+
+    pub fn record(&mut self, player: PlayerId, advantage: f32) {
+        let samples = self.local.entry(player).or_default();
+        if samples.len() == WINDOW {
+            samples.pop_front();
+        }
+        samples.push_back(advantage);
+    }
+
+    /// `advantage` is the remote's own average advantage over us.
+    pub fn receive(&mut self, player: PlayerId, advantage: f32) {
+        self.remote.insert(player, advantage);
+    }
+
+    pub fn forget(&mut self, player: PlayerId) {
+        self.local.remove(&player);
+        self.remote.remove(&player);
+    }
+
+    /// Our average advantage over `player`.
+    pub fn over(&self, player: PlayerId) -> Option<f32> {
+        let samples = self.local.get(&player)?;
+        (!samples.is_empty()).then(|| samples.iter().sum::<f32>() / samples.len() as f32)
+    }
+
+    /// Our average advantage over all remotes, positive when ahead.
+    pub fn average(&self) -> Option<f32> {
+        let all = self
+            .local
+            .keys()
+            .filter_map(|p| self.over(*p))
+            .collect::<Vec<_>>();
+        (!all.is_empty()).then(|| all.iter().sum::<f32>() / all.len() as f32)
+    }
+
+    /// How many frames to hold the local clock back by, once in a while, negative to move it
+    /// ahead instead. Zero once we are even with the remotes, to ease off earlier steering.
+    /// Samples taken before a change no longer apply, so they are dropped.
+    pub fn steering(&mut self) -> Option<f32> {
+        if !self.steer.is_time() {
+            return None;
+        }
+        let differences = self
+            .remote
+            .iter()
+            .filter_map(|(p, theirs)| Some((self.over(*p)? - theirs) / 2.))
+            .collect::<Vec<_>>();
+        if differences.is_empty() {
+            return None;
+        }
+        let difference = differences.iter().sum::<f32>() / differences.len() as f32;
+        if difference.abs() < THRESHOLD {
+            return Some(0.);
+        }
+
+        self.local.clear();
+        self.remote.clear();
+        Some((difference / 2.).clamp(-1., 1.))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+    use std::sync::Arc;
+
+    #[test]
+    fn gives_up_half_the_difference() {
+        let clock = ManualClock::new();
+        let mut advantage = FrameAdvantage::new(&(Arc::new(clock.clone()) as ClockHandle));
+        assert_eq!(advantage.steering(), None);
+
+        // Both ends see the other two frames late, which is latency and nothing to steer.
+        advantage.record(1, 2.);
+        advantage.receive(1, 2.);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(advantage.steering(), Some(0.));
+        assert_eq!(advantage.average(), Some(2.));
+
+        // The one behind catches up its half.
+        advantage.record(1, -4.);
+        advantage.receive(1, 1.);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(advantage.steering(), Some(-0.5));
+
+        advantage.record(1, 20.);
+        advantage.receive(1, 1.);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(advantage.steering(), Some(1.));
+        assert_eq!(advantage.average(), None);
+    }
+}
@@ -0,0 +1,121 @@
+//! A compact wire encoding for one player's inputs, see `SessionBuilder::compress_inputs`.
+//!
+//! Inputs are already sent sparsely, one entry per change, so what is left to save is the fixed
+//! width framing bincode gives each entry and inputs that recur, like a button being tapped.
+//! Frames are written as varint gaps from the previous entry, and each input either in full or as
+//! a reference to an identical one earlier in the message.
+
+use std::collections::BTreeMap;
+
+use crate::{Frame, SerializedInput};
+
+pub(crate) fn compress(inputs: &BTreeMap<Frame, SerializedInput>) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_varint(&mut out, inputs.len() as u64);
+
+    let mut seen: Vec<&SerializedInput> = Vec::new();
+    let mut previous = 0;
+    for (frame, input) in inputs {
+        write_varint(&mut out, (frame.0 - previous) as u64);
+        previous = frame.0;
+
+        match seen.iter().position(|s| *s == input) {
+            Some(index) => write_varint(&mut out, index as u64 + 1),
+            None => {
+                write_varint(&mut out, 0);
+                write_varint(&mut out, input.len() as u64);
+                out.extend_from_slice(input);
+                seen.push(input);
+            }
+        }
+    }
+    out
+}
+
+/// `None` if `bytes` didn't come from `compress`.
+pub(crate) fn decompress(mut bytes: &[u8]) -> Option<BTreeMap<Frame, SerializedInput>> {
+    let count = read_varint(&mut bytes)?;
+    // Every entry takes at least two bytes, so a huge count can't be honest.
+    if count > bytes.len() as u64 {
+        return None;
+    }
+
+    let mut inputs = BTreeMap::new();
+    let mut seen: Vec<SerializedInput> = Vec::new();
+    let mut frame = 0u32;
+    for _ in 0..count {
+        let gap = u32::try_from(read_varint(&mut bytes)?).ok()?;
+        frame = frame.checked_add(gap)?;
+
+        let input = match read_varint(&mut bytes)? {
+            0 => {
+                let len = usize::try_from(read_varint(&mut bytes)?).ok()?;
+                if len > bytes.len() {
+                    return None;
+                }
+                let (input, rest) = bytes.split_at(len);
+                bytes = rest;
+                seen.push(input.to_vec());
+                input.to_vec()
+            }
+            index => seen.get(usize::try_from(index - 1).ok()?)?.clone(),
+        };
+        inputs.insert(Frame(frame), input);
+    }
+    bytes.is_empty().then_some(inputs)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        value |= ((byte & 0x7f) as u64).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[quickcheck_macros::quickcheck]
+    fn round_trips(inputs: BTreeMap<u32, Vec<u8>>) -> bool {
+        let inputs = inputs
+            .into_iter()
+            .map(|(f, i)| (Frame(f), i))
+            .collect::<BTreeMap<_, _>>();
+        decompress(&compress(&inputs)) == Some(inputs)
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn rejects_garbage_without_panicking(bytes: Vec<u8>) -> bool {
+        let _ = decompress(&bytes);
+        true
+    }
+
+    #[test]
+    fn smaller_than_bincode_for_tapped_buttons() {
+        let inputs = (1000..1040)
+            .map(|f| (Frame(f), vec![(f % 2) as u8, 0, 0, 0]))
+            .collect::<BTreeMap<_, _>>();
+        let compressed = compress(&inputs).len();
+        let plain = bincode::serialize(&inputs).unwrap().len();
+        assert!(compressed * 4 < plain, "{} vs {}", compressed, plain);
+    }
+}
@@ -0,0 +1,127 @@
+use std::{collections::HashMap, time::Duration};
+
+use crate::{
+    time::{ClockHandle, Interval},
+    PlayerId,
+};
+
+/// Agrees on an input delay with each remote from the round-trip time between us, see
+/// `SessionBuilder::negotiate_input_delay`.
+///
+/// Both ends measure the link, but never exactly alike, so the lower player id decides for the
+/// link and the other adopts whatever it is told. The decision moves one frame at a time, so a
+/// change in RTT mid-match eases the delay over rather than jumping it. Our own inputs then take
+/// the largest delay agreed with anyone, as they have to reach everyone.
+pub(crate) struct InputDelayNegotiation {
+    local: PlayerId,
+    max: u32,
+    agreed: HashMap<PlayerId, u32>,
+    decide: Interval,
+}
+
+impl InputDelayNegotiation {
+    pub fn new(local: PlayerId, max: u32, clock: &ClockHandle) -> Self {
+        InputDelayNegotiation {
+            local,
+            max,
+            agreed: Default::default(),
+            decide: Interval::new(Duration::from_secs(1), clock),
+        }
+    }
+
+    /// Whether it's time to decide again, once a second.
+    pub fn is_time(&mut self) -> bool {
+        self.decide.is_time()
+    }
+
+    /// Decides the delay for the link to `player` if it is ours to decide, from the `rtt` to them
+    /// and how long inputs wait to be sent. Returns the delay to tell them, sent every time in
+    /// case the last one was lost.
+    pub fn decide(
+        &mut self,
+        player: PlayerId,
+        rtt: Duration,
+        step_size: Duration,
+        send_interval: Duration,
+    ) -> Option<u32> {
+        if player < self.local {
+            return None;
+        }
+        let needed = (rtt / 2 + send_interval).as_secs_f64() / step_size.as_secs_f64();
+        let target = (needed.ceil() as u32).min(self.max);
+
+        let current = self.agreed.entry(player).or_default();
+        // Dropping only once clearly over keeps a borderline RTT from flapping the delay.
+        if target > *current {
+            *current += 1;
+        } else if target + 1 < *current {
+            *current -= 1;
+        }
+        Some(*current)
+    }
+
+    /// `frames` is the delay `player` decided for our link.
+    pub fn receive(&mut self, player: PlayerId, frames: u32) {
+        if player < self.local {
+            self.agreed.insert(player, frames.min(self.max));
+        }
+    }
+
+    pub fn forget(&mut self, player: PlayerId) {
+        self.agreed.remove(&player);
+    }
+
+    /// The delay agreed with `player`, zero until there is one.
+    pub fn with(&self, player: PlayerId) -> u32 {
+        self.agreed.get(&player).copied().unwrap_or_default()
+    }
+
+    /// The delay our own inputs need, the largest agreed with anyone.
+    pub fn local(&self) -> u32 {
+        self.agreed.values().copied().max().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+    use std::sync::Arc;
+
+    #[test]
+    fn lower_id_decides_one_frame_at_a_time() {
+        let clock: ClockHandle = Arc::new(ManualClock::new());
+        let mut low = InputDelayNegotiation::new(0, 8, &clock);
+        let mut high = InputDelayNegotiation::new(1, 8, &clock);
+        let step = Duration::from_millis(10);
+        let send = Duration::from_millis(20);
+
+        assert_eq!(high.decide(0, Duration::from_millis(60), step, send), None);
+        let decided = (0..10)
+            .map(|_| {
+                let frames = low
+                    .decide(1, Duration::from_millis(60), step, send)
+                    .unwrap();
+                high.receive(0, frames);
+                frames
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(decided, [1, 2, 3, 4, 5, 5, 5, 5, 5, 5]);
+        assert_eq!((low.with(1), high.with(0), high.local()), (5, 5, 5));
+
+        // Just under no longer needs the fifth frame, but isn't worth dropping it.
+        assert_eq!(
+            low.decide(1, Duration::from_millis(40), step, send),
+            Some(5)
+        );
+        assert_eq!(
+            low.decide(1, Duration::from_millis(20), step, send),
+            Some(4)
+        );
+
+        // Capped at the maximum, even if told otherwise.
+        assert_eq!(low.decide(1, Duration::from_secs(1), step, send), Some(5));
+        high.receive(0, 100);
+        assert_eq!(high.with(0), 8);
+    }
+}
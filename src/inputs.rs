@@ -1,23 +1,49 @@
 use crate::{Frame, PlayerId};
 
 use derive_more::*;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
 pub type SerializedInput = Vec<u8>;
 
+pub(crate) type InputsByPlayer = BTreeMap<PlayerId, BTreeMap<Frame, SerializedInput>>;
+
+/// Whether a remote input is well formed, see `SessionBuilder::validate_input`.
+pub(crate) type InputValidator = Box<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+/// Local players' inputs by frame, see `SessionBuilder::input_source`.
+pub(crate) type InputSource = Box<dyn FnMut(PlayerId, u32) -> SerializedInput + Send + Sync>;
+
 pub(crate) struct InputStorage {
     inputs: HashMap<PlayerId, SparseInputs>,
+    joined_at: HashMap<PlayerId, Frame>,
     default: Vec<u8>,
+    pruned_before: Frame,
+    validator: Option<InputValidator>,
+    /// For each remote, the frame through which they have each player's inputs from us.
+    acks: HashMap<PlayerId, BTreeMap<PlayerId, Frame>>,
 }
 
 impl InputStorage {
     pub fn with_default(default: Vec<u8>) -> Self {
         InputStorage {
             inputs: Default::default(),
+            joined_at: Default::default(),
             default,
+            pruned_before: Frame(0),
+            validator: None,
+            acks: Default::default(),
         }
     }
 
+    pub fn validated_by(mut self, validator: Option<InputValidator>) -> Self {
+        self.validator = validator;
+        self
+    }
+
+    pub fn default_input(&self) -> &SerializedInput {
+        &self.default
+    }
+
     pub fn capture_into(&mut self, frame: Frame, local_id: PlayerId) -> Option<&mut Vec<u8>> {
         if frame == Frame(0) {
             return None;
@@ -41,7 +67,7 @@ impl InputStorage {
                 result.map.insert(*player, input.map(Clone::clone));
             }
         }
-        if result.map.len() == 0 {
+        if result.map.is_empty() {
             None
         } else {
             Some(result)
@@ -61,17 +87,241 @@ impl InputStorage {
             .collect()
     }
 
-    pub fn merge_remote(&mut self, player: PlayerId, map: BTreeMap<Frame, SerializedInput>) {
+    /// Like `player_since_frame`, leaving out what `remote` has acknowledged receiving.
+    pub fn unacknowledged(
+        &mut self,
+        remote: PlayerId,
+        player: PlayerId,
+        frame: Frame,
+    ) -> BTreeMap<Frame, SerializedInput> {
+        let acked = self.acks.get(&remote).and_then(|acks| acks.get(&player));
+        let from = match acked {
+            Some(&acked) => std::cmp::max(frame, acked + 1),
+            None => frame,
+        };
+        self.player_since_frame(player, from)
+    }
+
+    /// `remote`'s latest report of `received_through`. Replaces any earlier report, so an
+    /// acknowledgement that arrives late only costs a resend, and a remote that lost inputs, such
+    /// as by restarting, gets them again.
+    pub fn acknowledge(&mut self, remote: PlayerId, acks: BTreeMap<PlayerId, Frame>) {
+        self.acks.insert(remote, acks);
+    }
+
+    pub fn forget_acks(&mut self, remote: PlayerId) {
+        self.acks.remove(&remote);
+    }
+
+    /// The frame through which we have all of each of `players`' inputs. Every message carries
+    /// a player's inputs from where the last acknowledgement left off, so what has arrived never
+    /// has gaps.
+    pub fn received_through(
+        &self,
+        players: impl IntoIterator<Item = PlayerId>,
+    ) -> BTreeMap<PlayerId, Frame> {
+        players
+            .into_iter()
+            .filter_map(|p| Some((p, self.last_frame(p)?)))
+            .collect()
+    }
+
+    /// Start tracking `player` from `frame`, which every peer simulates with the default input
+    /// until the player's own inputs take over.
+    pub fn join(&mut self, player: PlayerId, frame: Frame) {
+        self.joined_at.insert(player, frame);
+
+        let sparse = self.inputs.entry(player).or_default();
+        sparse.split_off(&frame);
+        sparse.insert(frame, self.default.clone());
+    }
+
+    /// Forget inputs before `frame`, which must never be simulated or sent again. Each player
+    /// keeps their input at `frame` itself.
+    pub fn prune_before(&mut self, frame: Frame) {
+        if frame <= self.pruned_before {
+            return;
+        }
+        self.pruned_before = frame;
+        for sparse in self.inputs.values_mut() {
+            sparse.prune_before(frame);
+        }
+    }
+
+    /// The earliest frame inputs are still held for.
+    pub fn retained_from(&self) -> Frame {
+        self.pruned_before
+    }
+
+    /// How many inputs are held across all players.
+    pub fn len(&self) -> usize {
+        self.inputs.values().map(|sparse| sparse.len()).sum()
+    }
+
+    /// Every player's inputs from `frame` on, starting with the one in effect at `frame`, and the
+    /// frames they joined at.
+    pub fn export_from(&self, frame: Frame) -> (InputsByPlayer, HashMap<PlayerId, Frame>) {
+        let inputs = self
+            .inputs
+            .iter()
+            .map(|(player, sparse)| {
+                let from = sparse
+                    .range(..=frame)
+                    .next_back()
+                    .map_or(frame, |(f, _)| *f);
+                let kept = sparse.range(from..).map(|(f, i)| (*f, i.clone())).collect();
+                (*player, kept)
+            })
+            .collect();
+        (inputs, self.joined_at.clone())
+    }
+
+    /// Replace every input with ones from `export_from`. Nothing before `frame` is simulated again.
+    pub fn import(
+        &mut self,
+        frame: Frame,
+        inputs: InputsByPlayer,
+        joined_at: HashMap<PlayerId, Frame>,
+    ) {
+        self.inputs = inputs
+            .into_iter()
+            .map(|(player, map)| {
+                let sparse = SparseInputs {
+                    map,
+                    next_compact: Frame(0),
+                };
+                (player, sparse)
+            })
+            .collect();
+        self.joined_at = joined_at;
+        self.pruned_before = frame;
+    }
+
+    /// `player`'s input at `frame`, the latest one before it if it isn't known yet.
+    pub fn player_at(&self, player: PlayerId, frame: Frame) -> Option<&SerializedInput> {
+        Some(self.inputs.get(&player)?.at(frame)?.into_inner())
+    }
+
+    pub fn last_frame(&self, player: PlayerId) -> Option<Frame> {
+        self.inputs.get(&player)?.keys().next_back().copied()
+    }
+
+    pub fn set_input(&mut self, player: PlayerId, frame: Frame, input: SerializedInput) {
+        self.sparse_mut(player).insert(frame, input);
+    }
+
+    /// Every player's inputs over `from..=through`, starting with the one in effect at `from`.
+    pub fn decided(&self, from: Frame, through: Frame) -> InputsByPlayer {
+        self.inputs
+            .iter()
+            .filter_map(|(player, sparse)| {
+                let mut kept = sparse
+                    .range(from..=through)
+                    .map(|(f, i)| (*f, i.clone()))
+                    .collect::<BTreeMap<_, _>>();
+                if let Some((_, first)) = sparse.range(..=from).next_back() {
+                    kept.entry(from).or_insert_with(|| first.clone());
+                }
+                (!kept.is_empty()).then_some((*player, kept))
+            })
+            .collect()
+    }
+
+    /// Replace `player`'s inputs over `from..=through` with the ones the authority `decided`,
+    /// leaving later frames as they were. Returns the earliest frame whose input changed.
+    pub fn overrule(
+        &mut self,
+        player: PlayerId,
+        from: Frame,
+        through: Frame,
+        decided: BTreeMap<Frame, SerializedInput>,
+    ) -> Option<Frame> {
+        let first = decided.range(..=from).next_back()?.1.clone();
+        let frames = || (from.0..=through.0).map(Frame);
+        let sparse = self.inputs.entry(player).or_default();
+        let before = frames()
+            .map(|f| sparse.at(f).map(|i| i.into_inner().clone()))
+            .collect::<Vec<_>>();
+
+        let after = sparse.at(through + 1);
+        if let Some(ConfirmationStatus::Confirmed(after)) = after {
+            let after = after.clone();
+            sparse.entry(through + 1).or_insert(after);
+        }
+        let mut later = sparse.split_off(&(through + 1));
+        sparse.split_off(&from);
+        sparse.insert(from, first);
+        sparse.extend(
+            decided
+                .into_iter()
+                .filter(|(f, _)| *f > from && *f <= through),
+        );
+        sparse.append(&mut later);
+
+        frames()
+            .zip(before)
+            .find(|(f, before)| sparse.at(*f).map(|i| i.into_inner()) != before.as_ref())
+            .map(|(f, _)| f)
+    }
+
+    /// Inputs the validator rejects are replaced with the predicted input, which every peer
+    /// validating alike agrees on. Inputs after `latest` are dropped without being stored, so a
+    /// remote can't grow the storage without bound.
+    pub fn merge_remote(
+        &mut self,
+        player: PlayerId,
+        map: BTreeMap<Frame, SerializedInput>,
+        latest: Frame,
+    ) -> Merged {
+        let joined_at = self.joined_at.get(&player).copied();
+        let sparse = self.inputs.entry(player).or_default();
+        let mut mispredicted = None;
+        let mut out_of_range = 0;
         for (frame, input) in map {
-            self.inputs
-                .entry(player)
-                .or_default()
-                .entry(frame)
-                .or_insert(input);
+            if frame > latest {
+                out_of_range += 1;
+                continue;
+            }
+            // The join frame is always the agreed default input, never the remote's.
+            if joined_at.is_some_and(|joined| frame <= joined) {
+                continue;
+            }
+            if frame < self.pruned_before || sparse.contains_key(&frame) {
+                continue;
+            }
+
+            let predicted = sparse.range(..frame).next_back().map(|(_, i)| i);
+            let input = match &self.validator {
+                Some(valid) if !valid(&input) => {
+                    log::warn!(
+                        "replacing invalid input from player {} at {:?} with the predicted one",
+                        player,
+                        frame
+                    );
+                    predicted.unwrap_or(&self.default).clone()
+                }
+                _ => input,
+            };
+            if mispredicted.is_none() && predicted.is_some_and(|p| *p != input) {
+                mispredicted = Some(frame);
+            }
+            sparse.insert(frame, input);
+        }
+        Merged {
+            mispredicted,
+            out_of_range,
         }
     }
 }
 
+/// What `InputStorage::merge_remote` made of a remote's inputs.
+pub(crate) struct Merged {
+    /// The earliest newly learned frame whose input differs from what would have been predicted.
+    pub mispredicted: Option<Frame>,
+    /// How many inputs were dropped for being after the latest frame accepted.
+    pub out_of_range: u64,
+}
+
 #[derive(Deref, DerefMut)]
 pub(crate) struct SparseInputs {
     #[deref]
@@ -92,7 +342,9 @@ impl SparseInputs {
     }
 
     fn capture_into(&mut self, frame: Frame) -> Option<&mut SerializedInput> {
-        if self.map.contains_key(&frame) {
+        // Any input at or after this frame means it was already decided, either by an earlier
+        // capture or by inputs provided ahead of time.
+        if self.map.range(frame..).next().is_some() {
             return None;
         }
 
@@ -100,6 +352,20 @@ impl SparseInputs {
         Some(self.map.entry(frame).or_default())
     }
 
+    fn prune_before(&mut self, frame: Frame) {
+        // With nothing at or after `frame`, the latest input is still unconfirmed from there on,
+        // and moving it up to `frame` would make it look confirmed.
+        if self.map.range(frame..).next().is_none() {
+            return;
+        }
+        let kept = self.map.split_off(&frame);
+        let pruned = std::mem::replace(&mut self.map, kept);
+        if let Some((_, input)) = pruned.into_iter().next_back() {
+            self.map.entry(frame).or_insert(input);
+        }
+        self.next_compact = std::cmp::max(self.next_compact, frame + 1);
+    }
+
     fn compact(&mut self) -> Option<()> {
         loop {
             let mut at_or_after = self.map.range(self.next_compact..);
@@ -137,6 +403,16 @@ pub struct PlayerInputs<T = ConfirmationStatus<SerializedInput>> {
 }
 
 impl PlayerInputs {
+    pub fn is_confirmed_under(&self, mode: ConfirmationMode, remote_count: usize) -> bool {
+        match mode {
+            ConfirmationMode::Unanimous => self.is_fully_confirmed(remote_count),
+            ConfirmationMode::Authority(id) => self
+                .map
+                .get(&id)
+                .is_some_and(ConfirmationStatus::is_confirmed),
+        }
+    }
+
     pub fn is_fully_confirmed(&self, remote_count: usize) -> bool {
         self.is_fully_populated(remote_count)
             && self.map.values().all(ConfirmationStatus::is_confirmed)
@@ -156,13 +432,54 @@ impl<T> PlayerInputs<ConfirmationStatus<T>> {
             map: self
                 .map
                 .into_iter()
-                .map(move |(k, v)| (k, v.map(|v| f(v))))
+                .map(move |(k, v)| (k, v.map(&mut f)))
                 .collect(),
         }
     }
+
+    /// Borrow the inputs, to decode them with `deep_map` while keeping the raw bytes around.
+    pub fn as_ref(&self) -> PlayerInputs<ConfirmationStatus<&T>> {
+        PlayerInputs {
+            map: self.map.iter().map(|(k, v)| (*k, v.as_ref())).collect(),
+        }
+    }
+
+    /// The input to use for each player, whether or not it is confirmed yet.
+    pub fn into_values(self) -> PlayerInputs<T> {
+        self.map(ConfirmationStatus::into_inner)
+    }
+
+    /// Like `into_values`, also giving `fallback` to any of `players` without an input.
+    pub fn into_values_or(
+        self,
+        players: impl IntoIterator<Item = PlayerId>,
+        fallback: T,
+    ) -> PlayerInputs<T>
+    where
+        T: Clone,
+    {
+        let mut values = self.into_values();
+        for player in players {
+            values.map.entry(player).or_insert_with(|| fallback.clone());
+        }
+        values
+    }
 }
 
 impl<T> PlayerInputs<T> {
+    pub(crate) fn retain_players(&mut self, players: &BTreeSet<PlayerId>) {
+        self.map.retain(|p, _| players.contains(p));
+    }
+
+    pub(crate) fn fill_players(&mut self, players: &BTreeSet<PlayerId>, fallback: T)
+    where
+        T: Clone,
+    {
+        for player in players {
+            self.map.entry(*player).or_insert_with(|| fallback.clone());
+        }
+    }
+
     pub fn map<U>(self, mut f: impl FnMut(T) -> U) -> PlayerInputs<U> {
         PlayerInputs {
             map: self.map.into_iter().map(|(k, v)| (k, f(v))).collect(),
@@ -178,6 +495,14 @@ impl<T> PlayerInputs<T> {
     }
 }
 
+impl<T> FromIterator<(PlayerId, T)> for PlayerInputs<T> {
+    fn from_iter<I: IntoIterator<Item = (PlayerId, T)>>(iter: I) -> Self {
+        PlayerInputs {
+            map: iter.into_iter().collect(),
+        }
+    }
+}
+
 impl<T> Default for PlayerInputs<T> {
     fn default() -> Self {
         PlayerInputs {
@@ -186,6 +511,16 @@ impl<T> Default for PlayerInputs<T> {
     }
 }
 
+/// Whose inputs must be confirmed before a frame is considered confirmed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConfirmationMode {
+    /// Every player's input must be confirmed (peer-to-peer).
+    #[default]
+    Unanimous,
+    /// Only the designated authority's input must be confirmed.
+    Authority(PlayerId),
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum ConfirmationStatus<T> {
     Confirmed(T),
@@ -200,10 +535,17 @@ impl<T> ConfirmationStatus<T> {
         }
     }
 
+    pub fn as_ref(&self) -> ConfirmationStatus<&T> {
+        match self {
+            ConfirmationStatus::Confirmed(t) => ConfirmationStatus::Confirmed(t),
+            ConfirmationStatus::Unconfirmed(t) => ConfirmationStatus::Unconfirmed(t),
+        }
+    }
+
     pub fn as_inner(&self) -> &T {
         match self {
-            ConfirmationStatus::Confirmed(t) => &t,
-            ConfirmationStatus::Unconfirmed(t) => &t,
+            ConfirmationStatus::Confirmed(t) => t,
+            ConfirmationStatus::Unconfirmed(t) => t,
         }
     }
 
@@ -221,3 +563,194 @@ impl<T> ConfirmationStatus<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn into_values_strips_status_and_fills_missing() {
+        let inputs = [
+            (0, ConfirmationStatus::Confirmed("1")),
+            (1, ConfirmationStatus::Unconfirmed("2")),
+        ]
+        .into_iter()
+        .collect::<PlayerInputs<_>>();
+
+        let values = inputs
+            .as_ref()
+            .deep_map(|s| s.parse::<u8>().unwrap())
+            .into_values_or([0, 1, 2], 0);
+        assert_eq!(inputs.get(&1), Some(&ConfirmationStatus::Unconfirmed("2")));
+        let mut values = values.iter().map(|(p, v)| (*p, *v)).collect::<Vec<_>>();
+        values.sort();
+        assert_eq!(values, vec![(0, 1), (1, 2), (2, 0)]);
+    }
+
+    fn inputs(statuses: &[(PlayerId, bool)]) -> PlayerInputs {
+        PlayerInputs {
+            map: statuses
+                .iter()
+                .map(|&(id, confirmed)| {
+                    let status = if confirmed {
+                        ConfirmationStatus::Confirmed(Vec::new())
+                    } else {
+                        ConfirmationStatus::Unconfirmed(Vec::new())
+                    };
+                    (id, status)
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn unanimous_requires_everyone() {
+        let inputs = inputs(&[(0, true), (1, false), (2, true)]);
+        assert!(!inputs.is_confirmed_under(ConfirmationMode::Unanimous, 2));
+    }
+
+    #[test]
+    fn authority_ignores_slow_clients() {
+        let inputs = inputs(&[(0, true), (1, false)]);
+        assert!(inputs.is_confirmed_under(ConfirmationMode::Authority(0), 2));
+        assert!(!inputs.is_confirmed_under(ConfirmationMode::Authority(1), 2));
+    }
+
+    #[test]
+    fn overruling_keeps_later_inputs() {
+        let mut storage = InputStorage::with_default(vec![0]);
+        for (frame, input) in [(0, 1), (3, 2), (8, 3)] {
+            storage.set_input(1, Frame(frame), vec![input]);
+        }
+
+        let decided = [(Frame(2), vec![4]), (Frame(5), vec![5])].into();
+        assert_eq!(
+            storage.overrule(1, Frame(2), Frame(6), decided),
+            Some(Frame(2))
+        );
+
+        let at = |frame| storage.player_at(1, Frame(frame)).unwrap()[0];
+        assert_eq!(
+            (0..10).map(at).collect::<Vec<_>>(),
+            [1, 1, 4, 4, 4, 5, 5, 2, 3, 3]
+        );
+    }
+
+    #[test]
+    fn authority_requires_authority_input() {
+        let inputs = inputs(&[(1, true)]);
+        assert!(!inputs.is_confirmed_under(ConfirmationMode::Authority(0), 1));
+    }
+
+    #[test]
+    fn joined_player_uses_default_from_join_frame() {
+        let mut storage = InputStorage::with_default(vec![0]);
+        storage.join(1, Frame(10));
+        storage.merge_remote(
+            1,
+            [(Frame(8), vec![8]), (Frame(10), vec![10])].into(),
+            Frame(20),
+        );
+
+        assert!(storage.at_frame(Frame(9)).is_none());
+        let at_join = storage.at_frame(Frame(10)).unwrap();
+        assert_eq!(
+            at_join.get(&1),
+            Some(&ConfirmationStatus::Confirmed(vec![0]))
+        );
+
+        storage.merge_remote(1, [(Frame(12), vec![12])].into(), Frame(20));
+        let before_first = storage.at_frame(Frame(11)).unwrap();
+        assert_eq!(
+            before_first.get(&1),
+            Some(&ConfirmationStatus::Confirmed(vec![0]))
+        );
+    }
+
+    #[test]
+    fn replaces_invalid_inputs_with_predicted() {
+        let validator: InputValidator = Box::new(|input| input.len() == 1);
+        let mut storage = InputStorage::with_default(vec![0]).validated_by(Some(validator));
+        let merged = storage.merge_remote(
+            1,
+            [(Frame(0), vec![0]), (Frame(1), vec![1]), (Frame(2), vec![])].into(),
+            Frame(20),
+        );
+        assert_eq!(merged.mispredicted, Some(Frame(1)));
+
+        let at = storage.at_frame(Frame(2)).unwrap();
+        assert_eq!(at.get(&1), Some(&ConfirmationStatus::Confirmed(vec![1])));
+    }
+
+    #[test]
+    fn resends_only_unacknowledged_inputs() {
+        let mut storage = InputStorage::with_default(vec![0]);
+        for frame in 1..=5 {
+            storage.set_input(0, Frame(frame), vec![frame as u8]);
+        }
+        assert_eq!(storage.unacknowledged(1, 0, Frame(2)).len(), 4);
+
+        storage.acknowledge(1, [(0, Frame(3))].into());
+        let unacked = storage.unacknowledged(1, 0, Frame(2));
+        assert_eq!(
+            unacked.keys().copied().collect::<Vec<_>>(),
+            [Frame(4), Frame(5)]
+        );
+        assert_eq!(storage.unacknowledged(2, 0, Frame(2)).len(), 4);
+
+        // A remote that restarted reports less than before.
+        storage.acknowledge(1, [(0, Frame(1))].into());
+        assert_eq!(storage.unacknowledged(1, 0, Frame(2)).len(), 4);
+    }
+
+    #[test]
+    fn reports_earliest_misprediction() {
+        let mut storage = InputStorage::with_default(vec![0]);
+        storage.merge_remote(
+            1,
+            [(Frame(0), vec![0]), (Frame(2), vec![2])].into(),
+            Frame(20),
+        );
+
+        let merged = storage.merge_remote(
+            1,
+            [
+                (Frame(3), vec![2]),
+                (Frame(4), vec![4]),
+                (Frame(5), vec![5]),
+            ]
+            .into(),
+            Frame(20),
+        );
+        assert_eq!(merged.mispredicted, Some(Frame(4)));
+        let merged = storage.merge_remote(1, [(Frame(4), vec![9])].into(), Frame(20));
+        assert_eq!(merged.mispredicted, None);
+    }
+
+    #[test]
+    fn drops_inputs_past_the_latest_frame() {
+        let mut storage = InputStorage::with_default(vec![0]);
+        let merged = storage.merge_remote(
+            1,
+            [
+                (Frame(2), vec![2]),
+                (Frame(3), vec![3]),
+                (Frame(u32::MAX), vec![9]),
+            ]
+            .into(),
+            Frame(2),
+        );
+        assert_eq!(merged.out_of_range, 2);
+        assert_eq!(storage.last_frame(1), Some(Frame(2)));
+    }
+
+    #[test]
+    fn does_not_capture_provided_frames() {
+        let mut storage = InputStorage::with_default(vec![0]);
+        storage.set_input(0, Frame(5), vec![5]);
+
+        assert!(storage.capture_into(Frame(3), 0).is_none());
+        assert!(storage.capture_into(Frame(5), 0).is_none());
+        assert!(storage.capture_into(Frame(6), 0).is_some());
+    }
+}
@@ -16,12 +16,12 @@
 //! ## Core Functionality
 //!
 //! - [x] Multi-party sync
-//! - [ ] Consistent disconnection
+//! - [x] Consistent disconnection
 //! - [ ] Reconnect disconnected player
 //!
 //! ## Robustness
 //!
-//! - [ ] Determinism checks
+//! - [x] Determinism checks
 //! - [x] Checksum propagation
 //! - [ ] Debugging failed checks
 //! - [x] Fake a bad network
@@ -30,17 +30,17 @@
 //! ## Features
 //!
 //! - [ ] In-game replays
-//! - [ ] Out of game replays
-//!   - [ ] Headless
-//! - [ ] Spectators
+//! - [x] Out of game replays
+//!   - [x] Headless
+//! - [x] Spectators
 //!   - [ ] Drop in/out
-//! - [ ] Multiple local players
+//! - [x] Multiple local players
 //!
 //! ## Performance
 //!
 //! - [x] Sparse inputs
 //! - [ ] Input delta encoding
-//! - [ ] Hub and spoke network
+//! - [x] Hub and spoke network
 
 // Internal TODO list
 // - handle corrupted packets
@@ -50,29 +50,84 @@
 use serde::{Deserialize, Serialize};
 use std::{
     cmp::Ordering,
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    hash::{Hash, Hasher},
     net::SocketAddr,
     ops::ControlFlow,
-    time::Duration,
+    task::Waker,
+    time::{Duration, Instant},
 };
 
+#[cfg(feature = "bevy")]
+mod bevy_plugin;
+#[cfg(feature = "bevy")]
+pub use bevy_plugin::{
+    CaptureInput, OtherRequest, RbrbConfig, RbrbLoadFailed, RbrbPlugin, RbrbSchedule, RbrbSession,
+    RbrbStep, Rollback, RollbackId,
+};
 mod builder;
-pub use builder::SessionBuilder;
+pub use builder::{SessionBuildError, SessionBuilder, DEFAULT_MAX_PLAYERS, DEFAULT_MAX_SPECTATORS};
+mod catch_up;
+pub use catch_up::CatchUp;
+use catch_up::CatchUpLimiter;
+mod codec;
+use codec::PacketKind;
+pub use codec::{BincodeCodec, Codec, CodecError, WireMessage, PROTOCOL_VERSION};
+pub mod desync;
+pub mod determinism;
 mod exponential_keeping;
+mod fragment;
+mod frame_advantage;
+use frame_advantage::FrameAdvantage;
+mod input_compression;
+mod input_delay;
+use input_delay::InputDelayNegotiation;
 mod inputs;
-use inputs::InputStorage;
-pub use inputs::{ConfirmationStatus, PlayerInputs, SerializedInput};
+pub use inputs::{ConfirmationMode, ConfirmationStatus, PlayerInputs, SerializedInput};
+use inputs::{InputSource, InputStorage, InputsByPlayer};
+mod liveness;
+use liveness::{Liveness, LivenessEvent};
+mod membership;
+pub use membership::AllDisconnected;
+use membership::{Membership, MembershipChange};
+mod pause;
+use pause::PauseState;
 mod plugin;
-use plugin::SessionPlugin;
+pub use plugin::{ChecksumMismatch, PluginRequest, SessionPlugin};
+mod redundancy;
+use redundancy::RedundancyController;
+pub mod replay;
+pub mod resume;
+use replay::ReplayRecorder;
 mod request_handler;
-use request_handler::ControlFlowExt;
-pub use request_handler::{Confirmation, Request, RequestHandler};
+pub use request_handler::{Confirmation, Request, RequestHandler, StateHasher};
+use request_handler::{ControlFlowExt, MaybeMessage};
+mod send_rate;
+use send_rate::SendRateController;
+mod snapshots;
+use snapshots::SnapshotStore;
 mod socket;
-pub use socket::{BadSocket, BasicUdpSocket, NonBlockingSocket};
+#[cfg(feature = "wasm")]
+pub use socket::WebRtcSocket;
+pub use socket::{
+    BadSocket, BadSocketBuilder, BasicUdpSocket, BasicUdpSocketBuilder, Latency, MemoryNetwork,
+    MemorySocket, NonBlockingSocket, SocketErrors,
+};
+mod step_schedule;
+use step_schedule::StepSchedule;
+pub use step_schedule::MAX_STEP_SIZE;
 mod stats;
-pub use stats::{BandwidthRecordingSocket, NetworkStats};
+pub use stats::{
+    BandwidthRecordingSocket, FrameProgress, Interpolation, LinkStats, NetworkStats,
+    ReceivedPackets, SyncState,
+};
 mod time;
-use time::Interval;
+#[cfg(feature = "wasm")]
+pub use time::PerformanceClock;
+pub use time::{Clock, ClockTuning, ManualClock, SystemClock};
+mod typed;
+use time::{ClockHandle, Interval, LogThrottle};
+pub use typed::{TypedRequest, TypedSession};
 mod utils;
 use utils::div_duration;
 
@@ -81,439 +136,5280 @@ pub type SimulationInstant = Duration;
 pub type PlayerId = u16;
 
 pub struct Session {
-    confirmed_states: BTreeMap<Frame, SerializedState>,
+    confirmed_states: SnapshotStore,
+    confirmed_checksums: BTreeMap<Frame, u64>,
     inputs: InputStorage,
 
-    step_size: Duration,
+    steps: StepSchedule,
+    prediction_grace: Duration,
+    max_prediction: Option<u32>,
+    /// How many frames after realtime local inputs take effect, see `SessionBuilder::input_delay`.
+    input_delay: u32,
+    input_delay_negotiation: Option<InputDelayNegotiation>,
+    prediction_stalled: bool,
+    prediction_stalls: u64,
+    received: ReceivedPackets,
+    /// Only ever advance confirmed frames, see `SessionBuilder::lockstep`.
+    lockstep: bool,
+    confirmation_mode: ConfirmationMode,
+    /// The last frame the authority has sent the inputs it confirmed with through, see
+    /// `Message::AuthorityInputs`.
+    authority_through: Option<Frame>,
     local_id: PlayerId,
+    /// Players playing on another player's machine, by that player, including our own other
+    /// `SessionBuilder::local_players`. Their inputs travel with the host's.
+    guests: HashMap<PlayerId, PlayerId>,
     player_addresses: HashMap<SocketAddr, PlayerId>,
+    /// What each remote player proves they know to move address, see
+    /// `SessionBuilder::reconnection_token`.
+    reconnection_tokens: HashMap<PlayerId, u64>,
+    /// See `SessionBuilder::reconnection_secret`.
+    reconnection_secret: Option<u64>,
     socket: Box<dyn NonBlockingSocket>,
+    codec: Box<dyn Codec>,
+    /// Peers already warned about being on another protocol version.
+    version_warned: HashSet<SocketAddr>,
+    /// When each unknown address was last asked to identify itself, see `CHALLENGE_EVERY`.
+    challenged: lru::LruCache<SocketAddr, Duration>,
+    /// Keys the nonce an unknown address must prove a token with, see `Session::challenge_nonce`.
+    challenge_secret: u64,
+    next_fragmented_id: u32,
+    fragments: fragment::Reassembler,
+    outbox: fragment::Outbox,
 
     host_at: SimulationInstant,
+    partial_advances: bool,
+    /// Send our inputs with `input_compression`, see `SessionBuilder::compress_inputs`.
+    compress_inputs: bool,
+    /// Bytes of our own inputs sent so far, as `(uncompressed, sent)`.
+    input_bytes: (u64, u64),
+    /// Give players without an input the default one, see `SessionBuilder::fill_absent_inputs`.
+    fill_absent_inputs: bool,
+    /// The host state at its whole frame while a partial advance is applied on top of it.
+    partial_base: Option<SerializedState>,
     unconfirmed: Frame,
     remote_unconfirmed: HashMap<PlayerId, Frame>,
+    load_confirmed: bool,
+    /// A `Message::ConfirmedState` to check against its checksum before adopting it.
+    received_state: Option<ReceivedState>,
+    /// Keep only the first state, see `SessionBuilder::saveless_rollback`.
+    saveless: bool,
+    /// See `SessionBuilder::frame_numbers`.
+    frame_numbers: bool,
+    /// See `SessionBuilder::fixed_input_size`.
+    fixed_input_size: Option<usize>,
+    input_source: Option<InputSource>,
+    /// See `SessionBuilder::max_players`.
+    max_players: usize,
+    resyncing_from: Option<PlayerId>,
+    /// When we last sent `resyncing_from` a `Message::StateRequest`.
+    resync_asked_at: Duration,
+    /// The frame of the state each reconnected player was sent, and when, until they confirm it.
+    states_sent: HashMap<PlayerId, (Frame, Duration)>,
+    /// Our proposal towards the seed, unless spectating.
+    seed_proposal: Option<u64>,
+    seed_proposals: BTreeMap<PlayerId, u64>,
+    /// The agreed seed, once every proposal is in.
+    seed: Option<u64>,
+    seeded: bool,
+    /// The frame a `resume` snapshot was taken at, until the remotes have caught us up from it.
+    resumed_at: Option<Frame>,
+    /// A `Request::Rollback` to this frame was issued, and its load is still to come.
+    announced_rollback: Option<Frame>,
+    /// An input, step size or player of a frame already simulated changed since, so the next
+    /// rollback re-simulates it differently.
+    mispredicted_simulation: bool,
+    /// The frame after the furthest the host has advanced through.
+    simulated_until: Frame,
+    membership: Membership,
+    notifications: VecDeque<Notification>,
+    /// The earliest simulated frame each remote's inputs were mispredicted at, until corrected.
+    mispredicted: BTreeMap<PlayerId, Frame>,
+    all_disconnected: AllDisconnected,
+    waiting_since: Option<Duration>,
+    /// Whether waiting for a reconnect paused the simulation, rather than the game already having
+    /// done so, and so resuming is ours to do once someone is back.
+    paused_for_reconnect: bool,
+    ended: bool,
+    liveness: Option<Liveness>,
+    recorder: Option<ReplayRecorder>,
+    determinism: Option<determinism::DeterminismCheck>,
+    verify_confirmed_advances: bool,
+    /// The state to load back after verifying an advance, while the game is away from it.
+    verify_restore: Option<SerializedState>,
+    spectating: bool,
+    relay_hub: Option<PlayerId>,
+    /// Each spectator watching this session.
+    spectators: HashMap<SocketAddr, Spectator>,
+    /// Whether unknown addresses may join as spectators, see `SessionBuilder::accept_spectators`.
+    accept_spectators: bool,
+    max_spectators: usize,
+    /// Keys the cookie a new spectator must echo back, see `Session::spectate_cookie`.
+    spectate_secret: u64,
+    /// The cookie each player gave us to echo in `Message::Spectate`, while spectating.
+    spectate_cookies: HashMap<PlayerId, u64>,
+    outgoing: stats::OutgoingTraffic,
 
     send_interval: Interval,
+    send_rate: SendRateController,
+    frame_advantage: FrameAdvantage,
+    pause: PauseState,
+    log_throttle: LogThrottle,
+    redundancy: RedundancyController,
     shared_clock: time::SharedClock,
+    clock: ClockHandle,
+    catch_up: CatchUpLimiter,
 
     plugins: HashMap<String, Box<dyn SessionPlugin>>,
 }
 
 impl Session {
+    /// Everyone playing, local and remote, in order of id. Spectators aren't players.
     pub fn players(&self) -> impl Iterator<Item = (PlayerId, Player)> + '_ {
         let remote = self
             .player_addresses
             .iter()
             .map(|(s, id)| (*id, Player::Remote(*s)));
-        [(self.local_id, Player::Local)].into_iter().chain(remote)
+        let guests = self.guests.iter().filter_map(|(&guest, &host)| {
+            let player = match self.address_of(host) {
+                Some(addr) => Player::Remote(addr),
+                None if host == self.local_id => Player::Local,
+                None => return None,
+            };
+            Some((guest, player))
+        });
+        let local = (!self.spectating).then_some((self.local_id, Player::Local));
+        let mut players = local
+            .into_iter()
+            .chain(remote)
+            .chain(guests)
+            .collect::<Vec<_>>();
+        players.sort_unstable_by_key(|(id, _)| *id);
+        players.into_iter()
+    }
+
+    pub fn player_count(&self) -> usize {
+        self.players().count()
+    }
+
+    /// Whether `player` plays on this machine, as the local player or one of its
+    /// `SessionBuilder::local_players`.
+    pub fn is_local(&self, player: PlayerId) -> bool {
+        match self.guests.get(&player) {
+            Some(&host) => host == self.local_id,
+            None => !self.spectating && player == self.local_id,
+        }
+    }
+
+    /// Whether the session is still agreeing on a start time with the remotes, or counting down to
+    /// it. Until then, `next_request` issues nothing. See `SessionBuilder::sync_timeout`.
+    pub fn is_synchronizing(&self) -> bool {
+        self.shared_clock.elapsed().is_none()
+    }
+
+    /// Progress towards starting, for showing while `is_synchronizing`: the round trips measured
+    /// to each remote, how long until the start once agreed, and who has yet to confirm it.
+    pub fn sync_state(&self) -> SyncState {
+        let mut rtt_samples = self
+            .shared_clock
+            .rtt_samples()
+            .filter_map(|(addr, samples)| Some((*self.player_addresses.get(&addr)?, samples)))
+            .collect::<Vec<_>>();
+        rtt_samples.sort();
+        let mut unacked = self
+            .shared_clock
+            .unacked()
+            .filter_map(|addr| self.player_addresses.get(&addr).copied())
+            .collect::<Vec<_>>();
+        unacked.sort();
+        SyncState {
+            rtt_samples,
+            starts_in: self.shared_clock.starts_in(),
+            unacked,
+        }
+    }
+
+    /// Whether this session only watches confirmed frames, as built with
+    /// `SessionBuilder::spectator`.
+    pub fn is_spectator(&self) -> bool {
+        self.spectating
     }
 
     pub fn network_stats(&self) -> NetworkStats {
         NetworkStats {
             drift: self.shared_clock.drift(),
+            drift_rate: self.shared_clock.drift_rate(),
             elapsed: self.shared_clock.signed_elapsed().unwrap_or_default(),
             socket: self.socket.stats(),
+            socket_errors: self.socket.errors(),
+            redundancy: self.redundancy.current(),
+            catch_up_throttled: self.catch_up.throttled(),
+            prediction_stalls: self.prediction_stalls,
+            confirmed_checksum: self
+                .confirmed_checksums
+                .iter()
+                .next_back()
+                .map(|(frame, checksum)| (frame.0, *checksum)),
+            remote_checksums: self
+                .plugins
+                .values()
+                .flat_map(|p| p.remote_checksums())
+                .filter_map(|(addr, frame, checksum)| {
+                    Some((*self.player_addresses.get(&addr)?, frame.0, checksum))
+                })
+                .collect(),
+            players: {
+                let mut players = self
+                    .player_addresses
+                    .iter()
+                    .filter_map(|(addr, id)| Some((*id, self.shared_clock.link_stats(*addr)?)))
+                    .collect::<Vec<_>>();
+                players.sort_by_key(|(id, _)| *id);
+                players
+            },
+            frame_advantage: self.frame_advantage.average(),
+            steering: self.shared_clock.steering(),
+            input_bytes: self.input_bytes,
+            send_interval: self.send_rate.current(),
+            retained_state_bytes: self.retained_states().map(|(_, len)| len).sum(),
+            player_outgoing: self.outgoing.players(),
+            spectator_outgoing: self.outgoing.spectators(),
+            received: self.received.clone(),
+            input_delays: {
+                let mut delays = self
+                    .players()
+                    .map(|(id, player)| {
+                        let delay = match (player, &self.input_delay_negotiation) {
+                            (Player::Local, _) => self.local_input_delay(),
+                            // Guests share their host's link.
+                            (Player::Remote(addr), Some(negotiation)) => {
+                                negotiation.with(self.player_addresses[&addr])
+                            }
+                            (Player::Remote(_), None) => 0,
+                        };
+                        (id, delay)
+                    })
+                    .collect::<Vec<_>>();
+                delays.sort_by_key(|(id, _)| *id);
+                delays
+            },
         }
     }
 
-    pub fn local_player_id(&self) -> PlayerId {
-        self.local_id
+    /// The average round-trip time to `player`, for a ping display. `None` for the local player,
+    /// and for remotes until a few pings have come back.
+    pub fn player_rtt(&self, player: PlayerId) -> Option<Duration> {
+        let addr = self.address_of(player)?;
+        self.shared_clock.link_stats(addr)?.average_rtt
     }
 
-    pub fn next_request<H: RequestHandler>(&mut self, handler: H) -> ControlFlow<(), H::Break> {
-        match self.next_request_flow_inverted(handler) {
-            ControlFlow::Break(Some(m)) => ControlFlow::Continue(m),
-            ControlFlow::Break(None) => ControlFlow::Break(()),
-            ControlFlow::Continue(()) => ControlFlow::Break(()),
-        }
+    /// Each frame whose confirmed state is kept for rolling back to, with the bytes it takes as
+    /// stored: compressed with `SessionBuilder::compress_snapshots`, or just the delta with
+    /// `SessionBuilder::delta_saves`. In frame order.
+    pub fn retained_states(&self) -> impl Iterator<Item = (u32, usize)> + '_ {
+        self.confirmed_states
+            .sizes()
+            .map(|(frame, len)| (frame.0, len))
     }
 
-    fn next_request_flow_inverted<H: RequestHandler>(
-        &mut self,
-        mut handler: H,
-    ) -> ControlFlow<Option<H::Break>> {
-        loop {
-            self.process_incoming_messages();
-            self.send_messages();
-            self.capture_inputs(&mut handler)?;
-            self.save_frame_zero(&mut handler).map_break(Some)?;
-            self.advance_confirmed_horizon(&mut handler)?;
+    /// The checksum of the confirmed state at `frame`, as shared with peers to detect desyncs.
+    /// This is the `Request::ChecksumState` hash, or the hash of the serialized state if the
+    /// handler hashed nothing. Only available for frames whose state is still kept, which thins
+    /// out exponentially with age.
+    pub fn confirmed_checksum(&self, frame: u32) -> Option<u64> {
+        self.confirmed_checksums.get(&Frame(frame)).copied()
+    }
 
-            if !self.step_towards_realtime(&mut handler)? {
-                return ControlFlow::Continue(());
-            }
-        }
+    /// The frame realtime has reached against the frame the host has simulated to. Returns `None`
+    /// while synchronizing.
+    pub fn frame_progress(&self) -> Option<FrameProgress> {
+        let realtime = match self.clock_frame::<()>() {
+            ControlFlow::Continue(frame) => frame,
+            ControlFlow::Break(_) => return None,
+        };
+        Some(FrameProgress {
+            realtime: realtime.0,
+            simulated: self.host_frame().into_frame().0,
+        })
     }
 
-    fn step_towards_realtime<H: RequestHandler>(
-        &mut self,
-        handler: &mut H,
-    ) -> ControlFlow<Option<H::Break>, bool> {
-        let frame = self.host_frame();
-        let clock_frame = self.clock_frame()?;
-        match (frame.into_frame().cmp(&clock_frame), frame) {
-            (Ordering::Greater, _) => {
-                unreachable!("advanced too far: {:?} > {:?}", frame, clock_frame);
-            }
-            (Ordering::Equal, _) => return ControlFlow::Continue(false),
-            (Ordering::Less, FrameState::At(_)) => {
-                self.try_advance(handler, self.step_size).map_break(Some)?;
-                // TODO(shelbyd): Do partial advance?
-            }
-            (Ordering::Less, FrameState::After(f, _)) => {
-                self.navigate_to(f, handler).map_break(Some)?;
-                self.try_advance(handler, self.step_size).map_break(Some)?;
-                // TODO(shelbyd): Do partial advance?
-            }
+    /// The players whose confirmed input is missing for the next frame to confirm, holding up the
+    /// confirmed horizon. Empty while the host hasn't simulated past that frame.
+    pub fn blocking_players(&self) -> Vec<PlayerId> {
+        let frame = self.unconfirmed - 1;
+        if self.host_frame().into_frame() <= frame {
+            return Vec::new();
         }
-        ControlFlow::Continue(true)
+
+        let waiting_on = match self.confirmation_mode {
+            ConfirmationMode::Unanimous => self.membership.players_at(frame),
+            ConfirmationMode::Authority(id) => [id].into(),
+        };
+        let inputs = self.inputs(frame).unwrap_or_default();
+        waiting_on
+            .into_iter()
+            .filter(|p| !inputs.get(p).is_some_and(ConfirmationStatus::is_confirmed))
+            .collect()
     }
 
-    fn save_frame_zero<H: RequestHandler>(&mut self, handler: &mut H) -> ControlFlow<H::Break> {
-        if self.confirmed_states.len() == 0 {
-            assert_eq!(self.host_frame(), FrameState::At(Frame(0)));
+    /// The first frame whose state is not yet confirmed. The state at `unconfirmed_frame() - 1` is
+    /// the latest confirmed one, and every advance from a frame before that was on confirmed
+    /// inputs only and will not be rolled back.
+    pub fn unconfirmed_frame(&self) -> u32 {
+        self.unconfirmed.0
+    }
 
-            let state = self.confirmed_states.entry(Frame(0)).or_default();
-            handler.handle_request(Request::SaveTo(state))?;
+    /// Whether `frame` was simulated on confirmed or predicted inputs, as its `Request::Advance`
+    /// would say now. Never `Confirmation::First`, which only marks the advance that confirmed it.
+    /// Returns `None` for frames not simulated yet.
+    pub fn confirmation_of(&self, frame: u32) -> Option<Confirmation> {
+        let frame = Frame(frame);
+        if frame < self.unconfirmed - 1 {
+            return Some(Confirmation::Subsequent);
         }
-        ControlFlow::Continue(())
+        if frame >= self.host_frame().into_frame() {
+            return None;
+        }
+        let inputs = self.inputs(frame)?;
+        Some(if self.is_confirmed(&inputs, frame) {
+            Confirmation::Subsequent
+        } else {
+            Confirmation::Unconfirmed
+        })
     }
 
-    fn should_save(&self, frame: Frame) -> bool {
-        exponential_keeping::kept_set(self.unconfirmed.0).contains(&frame.0)
-            && !self.confirmed_states.contains_key(&frame)
+    /// How far the local simulation has advanced, including predicted frames.
+    pub fn sim_time(&self) -> Duration {
+        self.host_at
     }
 
-    fn clear_states(&mut self) {
-        let kept = exponential_keeping::kept_set(self.unconfirmed.0);
-        for key in self.confirmed_states.keys().cloned().collect::<Vec<_>>() {
-            if !kept.contains(&key.0) {
-                self.confirmed_states.remove(&key);
-            }
-        }
+    /// How far the simulation has advanced on confirmed inputs only.
+    pub fn confirmed_sim_time(&self) -> Duration {
+        self.steps.start_of(self.unconfirmed - 1)
     }
 
-    fn capture_inputs<H: RequestHandler>(
-        &mut self,
-        handler: &mut H,
-    ) -> ControlFlow<Option<H::Break>> {
-        let realtime = self.clock_frame()?;
-        if let Some(vec) = self.inputs.capture_into(realtime, self.local_id) {
-            handler
-                .handle_request(Request::CaptureLocalInput(vec))
-                .map_break(Some)?;
-        }
-        ControlFlow::Continue(())
+    /// The latest frame `player` has reported advancing on confirmed inputs, or `None` if they
+    /// haven't reported one yet. A peer far behind the others is holding up the confirmed horizon.
+    ///
+    /// An input frame, like `confirmation_of`: their latest confirmed state is the one after it.
+    /// Peers report the frame whose inputs they are waiting on, their `unconfirmed_frame() - 1`,
+    /// so this is their `unconfirmed_frame() - 2` as of their latest report.
+    pub fn remote_confirmed_frame(&self, player: PlayerId) -> Option<u32> {
+        self.remote_unconfirmed.get(&player)?.0.checked_sub(1)
     }
 
-    fn advance_confirmed_horizon<H: RequestHandler>(
-        &mut self,
-        handler: &mut H,
-    ) -> ControlFlow<Option<H::Break>> {
-        loop {
-            let last_confirmed = self.unconfirmed - 1;
-            let host_frame = self.host_frame().into_frame();
+    /// The simulation time the session expects at `instant`, including drift correction, time
+    /// spent paused and a spectator's delay. Returns `None` while synchronizing or before the
+    /// session starts. `instant` is placed on the session's clock by its distance from the real
+    /// time now.
+    pub fn sim_time_at(&self, instant: Instant) -> Option<Duration> {
+        let (now, real_now) = (self.clock.now(), Instant::now());
+        let at = if instant >= real_now {
+            now + (instant - real_now)
+        } else {
+            now.saturating_sub(real_now - instant)
+        };
+        Some(self.sim_elapsed(self.shared_clock.signed_elapsed_at(at)?.pos()?))
+    }
 
-            let should_advance = host_frame < self.clock_frame()?;
-            if !should_advance {
-                return ControlFlow::Continue(());
-            }
+    /// Offset the local clock by `offset_millis` (positive runs fast, negative runs slow) to watch
+    /// drift correction pull it back in line with the remotes. Replaces any earlier offset.
+    #[cfg(feature = "debug-clock")]
+    pub fn inject_clock_offset(&mut self, offset_millis: i64) {
+        let offset = Duration::from_millis(offset_millis.unsigned_abs());
+        self.shared_clock.inject_offset(if offset_millis < 0 {
+            utils::Signed::Neg(offset)
+        } else {
+            utils::Signed::Pos(offset)
+        });
+    }
 
-            let behind = (host_frame.0 - last_confirmed.0) * self.step_size;
-            if behind > Duration::from_secs(1) {
-                log::warn!("confirmation horizon {:?} behind", behind);
-            }
+    /// How much of the simulation has been verified deterministic. Only available with
+    /// `SessionBuilder::check_determinism`.
+    pub fn determinism_coverage(&self) -> Option<determinism::DeterminismCoverage> {
+        self.determinism.as_ref().map(|d| d.coverage())
+    }
 
-            match self.inputs(last_confirmed) {
-                None => return ControlFlow::Continue(()),
-                Some(inputs) if !inputs.is_fully_confirmed(self.player_addresses.len()) => {
-                    return ControlFlow::Continue(())
-                }
-                Some(inputs) => {
-                    let inputs = inputs.clone();
-                    self.navigate_to(last_confirmed, handler).map_break(Some)?;
+    /// How many inputs are held in the input buffer, across all players. Inputs are dropped once
+    /// every peer has confirmed past them, so this stays bounded over a long match.
+    pub fn input_buffer_len(&self) -> usize {
+        self.inputs.len()
+    }
 
-                    self.advance_with(inputs, handler, self.step_size, last_confirmed, true)
-                        .always(|| self.unconfirmed = self.unconfirmed + 1)
-                        .map_break(Some)?;
-                }
-            }
+    /// `confirmed_inputs` gathered for comparing with another peer's, leaving out the default
+    /// inputs filled in for absent players.
+    pub fn export_confirmed_inputs(&self) -> desync::InputExport {
+        let frames = self
+            .confirmed_inputs()
+            .map(|(f, inputs)| {
+                let confirmed = inputs
+                    .iter()
+                    .filter(|(_, input)| input.is_confirmed())
+                    .map(|(player, input)| (*player, input.as_inner().clone()))
+                    .collect();
+                (f, confirmed)
+            })
+            .collect();
+        desync::InputExport { frames }
+    }
+
+    /// Provide the local input for `frame` ahead of time. The handler will not be asked to
+    /// capture input for that frame or any before it.
+    pub fn set_local_input(&mut self, frame: u32, input: SerializedInput) {
+        if self.spectating {
+            log::warn!("spectators have no local input to set");
+            return;
         }
+        self.check_input_size(self.local_id, frame, input.len());
+        self.inputs.set_input(self.local_id, Frame(frame), input);
     }
 
-    fn navigate_to<H: RequestHandler>(
-        &mut self,
-        frame: Frame,
-        handler: &mut H,
-    ) -> ControlFlow<H::Break> {
-        loop {
-            let current_frame = self.host_frame().into_frame();
+    /// The local input captured at the current clock frame, for rendering the local player
+    /// without waiting for it to be simulated. With input delay, that input is for the frame that
+    /// many frames ahead. Until `next_request` captures it, this is the one captured before.
+    /// `None` for spectators and until the clock has started.
+    pub fn local_input(&self) -> Option<&SerializedInput> {
+        if self.spectating {
+            return None;
+        }
+        let frame = self.clock_frame::<()>().continue_value()? + self.local_input_delay();
+        self.inputs.player_at(self.local_id, frame)
+    }
 
-            if self.should_save(current_frame) {
-                self.clear_states();
+    /// The inputs of every confirmed frame still held, in order, as `Request::Advance` gave them,
+    /// such as to export a match's inputs without recording a replay. Inputs are pruned once no
+    /// peer could roll back to or ask for them, so this only reaches back a little way: reading it
+    /// every so often and keeping the frames not seen yet covers the whole match.
+    pub fn confirmed_inputs(&self) -> impl Iterator<Item = (u32, PlayerInputs)> + '_ {
+        // Frames before the latest confirmed state, which was advanced from each of them.
+        (self.inputs.retained_from().0..(self.unconfirmed - 1).0)
+            .filter_map(|frame| Some((frame, self.inputs(Frame(frame))?)))
+    }
 
-                let state = self.confirmed_states.entry(current_frame).or_default();
-                handler.handle_request(Request::SaveTo(state)).always(|| {
-                    for plugin in self.plugins.values_mut() {
-                        plugin.on_confirmed_frame(current_frame, state);
-                    }
-                })?;
-            }
+    /// Meaningless for spectators, which have no player of their own.
+    pub fn local_player_id(&self) -> PlayerId {
+        self.local_id
+    }
 
-            match current_frame.cmp(&frame) {
-                Ordering::Equal => return ControlFlow::Continue(()),
-                Ordering::Greater => {
-                    let (roll_to, state) = self
-                        .confirmed_states
-                        .range(..=frame)
-                        .next_back()
-                        .expect("should have at least one confirmed state");
+    /// Add a remote player starting at `join_frame`. The join is shared with the other peers, and
+    /// the player is simulated with the default input from that frame until their own inputs
+    /// arrive. The other peers only take it from the host, the player with the lowest id in the
+    /// session, so call this there.
+    pub fn join_player(
+        &mut self,
+        id: PlayerId,
+        addr: SocketAddr,
+        join_frame: u32,
+    ) -> Result<(), String> {
+        let join_frame = Frame(join_frame);
+        if join_frame < self.unconfirmed {
+            return Err(format!("cannot join at already confirmed {:?}", join_frame));
+        }
+        let taken = id == self.local_id || self.guests.contains_key(&id);
+        if taken || self.player_addresses.values().any(|&p| p == id) {
+            return Err(format!("player {} is already in the session", id));
+        }
+        let players = self.player_count();
+        if players >= self.max_players {
+            return Err(format!(
+                "the session already has the maximum of {} players",
+                players
+            ));
+        }
 
-                    let delta = current_frame.0 - roll_to.0;
-                    if delta * self.step_size > Duration::from_millis(300) {
-                        log::info!("rolling back {} frames to {:?}", delta, roll_to);
-                    }
+        self.schedule_membership_change(join_frame, MembershipChange::Join(id, addr));
+        Ok(())
+    }
 
-                    handler
-                        .handle_request(Request::LoadFrom(&state))
-                        .always(|| self.host_at = self.step_size * roll_to.0)?;
+    /// A joining player is routed to immediately, while who counts towards confirmation only
+    /// changes once the confirmed horizon reaches `frame`. A leaving one is still routed to until
+    /// then, see `forget_departed`.
+    fn schedule_membership_change(&mut self, frame: Frame, change: MembershipChange) {
+        if let MembershipChange::Join(id, addr) = change {
+            match self.address_of(id) {
+                Some(old) if old != addr => {
+                    if let Err(e) = self.set_remote_address(id, addr) {
+                        log::warn!("could not move joining player {} to {}: {}", id, addr, e);
+                    }
                 }
-                Ordering::Less => {
-                    self.do_advance(handler)?;
+                Some(_) => {}
+                None => {
+                    self.player_addresses.insert(addr, id);
+                    self.shared_clock.add_remote(addr);
                 }
             }
+            // We count ourselves in from the start, so hold the default input until our join
+            // even if nothing has been captured yet to start our inputs off.
+            if self.is_local(id) {
+                self.inputs.sparse_mut(id);
+            }
+            self.inputs.join(id, frame);
         }
+        self.membership.schedule(frame, change);
+        self.note_resimulation(frame);
     }
 
-    fn advance_with<H: RequestHandler>(
-        &mut self,
-        inputs: PlayerInputs,
-        handler: &mut H,
-        amount: Duration,
-        current_frame: Frame,
-        first_confirm: bool,
-    ) -> ControlFlow<H::Break> {
-        handler
-            .handle_request(Request::Advance {
-                amount,
-                current_frame: current_frame.0,
-                confirmed: if first_confirm {
-                    Confirmation::First
-                } else if inputs.is_fully_confirmed(self.player_addresses.len()) {
-                    Confirmation::Subsequent
-                } else {
-                    Confirmation::Unconfirmed
-                },
-                inputs,
-            })
-            .always(|| self.host_at += amount)
+    /// `frame` will be simulated differently from now on, so if it already was, the next rollback
+    /// isn't routine.
+    fn note_resimulation(&mut self, frame: Frame) {
+        if frame < self.host_frame().into_frame() {
+            self.mispredicted_simulation = true;
+        }
     }
 
-    fn do_advance<H: RequestHandler>(&mut self, handler: &mut H) -> ControlFlow<H::Break> {
-        let frame = match self.host_frame() {
-            FrameState::At(f) => f,
-            FrameState::After(_, _) => unimplemented!("FrameState::After"),
-        };
-        let inputs = self
-            .inputs(frame)
-            .expect(&format!("did not have inputs for frame: {:?}", frame));
+    /// Stop routing to `id` once their leave is confirmed, unless they are set to rejoin.
+    fn forget_departed(&mut self, id: PlayerId) {
+        let rejoining = self
+            .membership
+            .pending()
+            .any(|(_, c)| matches!(c, MembershipChange::Join(p, _) if p == id));
+        if let (false, Some(addr)) = (rejoining, self.address_of(id)) {
+            self.player_addresses.remove(&addr);
+            self.shared_clock.remove_remote(addr);
+            self.remote_unconfirmed.remove(&id);
+            self.inputs.forget_acks(id);
+        }
+    }
 
-        self.advance_with(inputs, handler, self.step_size, frame, false)
+    /// The player whose word on who joins and leaves the others take: the one with the lowest id
+    /// still in the session.
+    fn host(&self) -> Option<PlayerId> {
+        self.membership
+            .players_at(self.unconfirmed - 1)
+            .first()
+            .copied()
     }
 
-    fn try_advance<H: RequestHandler>(
-        &mut self,
-        handler: &mut H,
-        amount: Duration,
-    ) -> ControlFlow<H::Break> {
-        let frame = match self.host_frame() {
-            FrameState::At(f) => f,
-            FrameState::After(_, _) => unimplemented!("FrameState::After"),
+    /// Whether `sender` may announce `change`: the host, or whoever it is about, themselves or
+    /// through the player whose machine they play on.
+    fn may_announce(&self, sender: PlayerId, change: MembershipChange) -> bool {
+        let subject = match change {
+            MembershipChange::Join(id, _) | MembershipChange::Leave(id) => id,
         };
+        Some(sender) == self.host()
+            || sender == subject
+            || self.guests.get(&subject) == Some(&sender)
+    }
 
-        if let Some(inputs) = self.inputs(frame) {
-            self.advance_with(inputs, handler, amount, frame, false)?;
+    /// Stop waiting on inputs from `id`, and any `SessionBuilder::remote_guests` playing with
+    /// them. Each leaves after the last frame we have their input for, and
+    /// `Request::PlayerDisconnected` is issued once every earlier frame is confirmed.
+    pub fn disconnect_player(&mut self, id: PlayerId) -> Result<(), String> {
+        if self.address_of(id).is_none() {
+            return Err(format!("player {} is not a connected remote player", id));
         }
-        ControlFlow::Continue(())
+
+        for player in self.guests_of(id).into_iter().chain([id]) {
+            let after_last_input = self.inputs.last_frame(player).map(|f| f + 1);
+            self.schedule_leave(player, after_last_input.unwrap_or(self.unconfirmed));
+        }
+        Ok(())
     }
 
-    fn clock_frame<T>(&self) -> ControlFlow<Option<T>, Frame> {
-        match self.shared_clock.elapsed() {
-            Some(dur) => ControlFlow::Continue(self.calculate_frame_state(dur).into_frame()),
-            None => ControlFlow::Break(None),
+    /// Have `player` leave at `frame`, unless they are already set to leave.
+    fn schedule_leave(&mut self, player: PlayerId, frame: Frame) {
+        let leaving = self
+            .membership
+            .pending()
+            .any(|(_, c)| c == MembershipChange::Leave(player));
+        if leaving {
+            return;
         }
+        // The frame before `unconfirmed` is still to be confirmed, so it can be left at.
+        let frame = std::cmp::max(frame, self.unconfirmed - 1);
+        self.schedule_membership_change(frame, MembershipChange::Leave(player));
     }
 
-    fn host_frame(&self) -> FrameState {
-        self.calculate_frame_state(self.host_at)
+    /// Pause every peer, for a menu or dialog. The simulation stops a few frames ahead, about as
+    /// far as it takes the remotes to hear of it, and no more frames are requested until
+    /// `resume`. Messages are still handled meanwhile, so keep calling `next_request`. Either
+    /// call is ignored if already in that state, and by spectators.
+    ///
+    /// A remote that hears of the pause only after passing that frame stops where it is. It is
+    /// ahead in time but not in error, as inputs are per frame, so nothing is rolled back and it
+    /// waits there for the others once resumed. The decision is resent until shortly after the
+    /// resume, and pausing from several peers at once merges into one pause.
+    pub fn pause(&mut self) {
+        let (Some(shared), false) = (self.shared_clock.elapsed(), self.spectating) else {
+            log::warn!("only players can pause, once the session has started");
+            return;
+        };
+        let rtt = self.worst_rtt().unwrap_or_default();
+        let step = self.step_size();
+        let (ahead, _) = div_duration(rtt / 2 + step, step);
+        let frame = self.calculate_frame_state(self.pause.elapsed(shared, &self.steps));
+        if self
+            .pause
+            .pause(frame.into_frame() + ahead, shared, &self.steps)
+        {
+            log::info!("pausing at {:?}", frame.into_frame() + ahead);
+            self.announce_pause();
+        }
     }
 
-    fn calculate_frame_state(&self, at: Duration) -> FrameState {
-        let (n, rem) = div_duration(at, self.step_size);
-        if rem == Duration::ZERO {
-            FrameState::At(Frame(n))
-        } else {
-            FrameState::After(Frame(n), rem)
+    /// Continue after `pause`, or after a remote paused. The simulation picks up where it stopped,
+    /// a round trip from now so the remotes continue at the same time, rather than catching up on
+    /// the time spent paused.
+    pub fn resume(&mut self) {
+        let (Some(shared), false) = (self.shared_clock.elapsed(), self.spectating) else {
+            return;
+        };
+        let rtt = self.worst_rtt().unwrap_or_default();
+        if self
+            .pause
+            .resume(shared + rtt + self.step_size(), &self.steps)
+        {
+            log::info!("resuming");
+            self.announce_pause();
         }
     }
 
-    fn inputs(&self, at: Frame) -> Option<PlayerInputs> {
-        self.inputs.at_frame(at)
+    /// While paused, let the simulation on by one frame, for debugging frame by frame. Every
+    /// peer steps, and the frame is requested as usual: captured, saved and advanced, then
+    /// confirmed once the remotes' inputs for it arrive, so keep calling `next_request`. Offline,
+    /// with no remotes, it is confirmed straight away. Fails if not paused, or already resuming.
+    pub fn step_once(&mut self) -> Result<(), String> {
+        if !self.pause.step() {
+            return Err("can only step while paused".to_string());
+        }
+        self.announce_pause();
+        Ok(())
     }
 
-    fn send_messages(&mut self) {
-        while let Some((addr, message)) = self.shared_clock.message() {
-            self.send_to_addr(&Message::Clock(message), addr);
+    /// The length of the frame being simulated now, the builder's `step_size` unless changed by
+    /// `set_step_size`.
+    pub fn step_size(&self) -> Duration {
+        self.steps.at(self.host_frame().into_frame())
+    }
+
+    /// Change the length of frames, such as for slow motion, from a frame a little ahead, far
+    /// enough for the remotes to hear of it before reaching it, as with `pause`. Every peer
+    /// switches at that frame, and `Request::Advance` gives the new `amount` from it on. Frames
+    /// keep their numbers, so inputs and confirmation carry on as before, and the shared clock
+    /// doesn't jump: frames just come at the new rate. Ignored by spectators.
+    ///
+    /// A remote that has already predicted past the frame when it hears of the change treats those
+    /// frames as mispredicted, and simulates them again at the new length once they confirm. If
+    /// peers pick the same frame, the longer step wins.
+    ///
+    /// Fails if `step_size` is zero or longer than `MAX_STEP_SIZE`, or if too many changes are
+    /// still to take effect.
+    pub fn set_step_size(&mut self, step_size: Duration) -> Result<(), String> {
+        if self.spectating {
+            log::warn!("spectators cannot change the step size");
+            return Ok(());
         }
+        let realtime = match self.clock_frame::<()>() {
+            ControlFlow::Continue(frame) => frame,
+            ControlFlow::Break(_) => self.host_frame().into_frame(),
+        };
+        let step = self.step_size();
+        let rtt = self.worst_rtt().unwrap_or_default();
+        let (ahead, _) = div_duration(rtt + step, step);
+        let frame = std::cmp::max(
+            realtime + self.local_input_delay() + ahead,
+            self.unconfirmed,
+        );
+        self.change_step_size(frame, step_size)?;
+        log::info!("changing the step size to {:?} at {:?}", step_size, frame);
+        self.announce_step_sizes();
+        Ok(())
+    }
 
-        let plugin_messages = self
-            .plugins
-            .iter_mut()
-            .flat_map(|(id, p)| p.messages().into_iter().map(move |m| (id.clone(), m)))
+    /// Frames from the change on that were already simulated keep their numbers. They were
+    /// simulated at the old length, but are all predictions, so rolled back over anyway.
+    fn change_step_size(&mut self, frame: Frame, step_size: Duration) -> Result<(), String> {
+        let host = self.host_frame();
+        if self.steps.try_change(frame, step_size, self.unconfirmed)? {
+            self.note_resimulation(frame);
+            self.host_at = match host {
+                FrameState::At(f) => self.steps.start_of(f),
+                FrameState::After(f, into) => self.steps.start_of(f) + into.min(self.steps.at(f)),
+            };
+        }
+        Ok(())
+    }
+
+    /// Resend step size changes until everyone has confirmed past them.
+    fn announce_step_sizes(&mut self) {
+        let changes = self
+            .steps
+            .changes_from(self.unconfirmed)
             .collect::<Vec<_>>();
-        for (id, (addr, message)) in plugin_messages {
-            self.send_to_addr(&Message::Plugin(id, message), addr);
+        for &(frame, step) in &changes {
+            self.send(Message::StepSize(frame, step));
+        }
+        let spectators = self
+            .spectators
+            .iter()
+            .map(|(addr, spectator)| (*addr, spectator.unconfirmed))
+            .collect::<Vec<_>>();
+        for (addr, unconfirmed) in spectators {
+            for (frame, step) in self.steps.changes_from(unconfirmed).collect::<Vec<_>>() {
+                if frame > Frame(0) {
+                    self.send_to_addr(Message::StepSize(frame, step), addr);
+                }
+            }
         }
+    }
 
-        if !self.send_interval.is_time() {
+    /// Whether the session is paused by us or a remote, or about to stop at the paused frame.
+    pub fn is_paused(&self) -> bool {
+        self.shared_clock
+            .elapsed()
+            .is_some_and(|shared| self.pause.is_paused(shared))
+    }
+
+    /// Quit the session on purpose, telling the remotes so they can disconnect us, and any
+    /// `SessionBuilder::local_players` with us, right after our last input rather than waiting
+    /// for `SessionBuilder::disconnect_after`. The goodbye is sent once, along with every input
+    /// the remotes might be missing, and issues `Request::SessionEnded` locally. Every remote
+    /// that hears of it, from us or passed on by the host, disconnects us at that same frame, so
+    /// one still missing some of our inputs waits for them. A remote that hears of it from
+    /// nobody falls back to the timeout.
+    pub fn leave(&mut self) {
+        if self.ended {
             return;
         }
+        if self.spectating {
+            log::info!("no longer spectating");
+            self.send(Message::Leaving(self.unconfirmed - 1));
+            self.ended = true;
+            self.notifications.push_back(Notification::SessionEnded);
+            return;
+        }
+        let last_frame = self
+            .inputs
+            .last_frame(self.local_id)
+            .unwrap_or(self.unconfirmed - 1);
+        log::info!("leaving the session after {:?}", last_frame);
 
-        for (player, unc) in self.remote_unconfirmed.clone() {
-            let inputs = self.inputs.player_since_frame(self.local_id, unc);
-            self.send_to(&Message::Inputs(inputs), player);
+        let remotes = self
+            .player_addresses
+            .iter()
+            .map(|(addr, player)| (*addr, *player))
+            .collect::<Vec<_>>();
+        for (addr, player) in remotes {
+            let unconfirmed = self
+                .remote_unconfirmed
+                .get(&player)
+                .copied()
+                .unwrap_or(Frame(0));
+            let packets = self
+                .own_inputs(unconfirmed, None)
+                .into_iter()
+                .chain([Message::Leaving(last_frame)])
+                .flat_map(|m| self.encode(m))
+                .collect::<Vec<_>>();
+            for _ in 0..self.redundancy.current() {
+                for packet in &packets {
+                    self.send_packet(packet, addr);
+                }
+            }
         }
 
-        self.send(Message::Unconfirmed(self.unconfirmed - 1));
+        self.ended = true;
+        self.notifications.push_back(Notification::SessionEnded);
     }
 
-    fn send(&mut self, message: Message) {
-        let message = bincode::serialize(&message).expect("failed to serialize message");
-        for player in self.player_addresses.keys() {
-            self.socket.send(&message, *player);
+    /// The spectators currently watching this session.
+    pub fn spectators(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.spectators.keys().copied()
+    }
+
+    /// Start sending our inputs and the shared time to a spectator at `addr`, along with our
+    /// latest confirmed state for it to start watching from. Spectators never count towards
+    /// confirmation, so this doesn't affect the players.
+    pub fn add_spectator(&mut self, addr: SocketAddr) -> Result<(), String> {
+        if self.player_addresses.contains_key(&addr) {
+            return Err(format!("{} is a player", addr));
         }
+        if self.spectators.contains_key(&addr) {
+            return Ok(());
+        }
+        if self.spectators.len() >= self.max_spectators {
+            return Err(format!(
+                "turning away spectator {}, already at the limit of {}",
+                addr, self.max_spectators
+            ));
+        }
+        log::info!("spectator {} joined", addr);
+        self.spectators.insert(addr, Spectator::new());
+        self.shared_clock.add_follower(addr);
+        self.send_spectator_start(addr);
+        Ok(())
     }
 
-    fn send_to(&mut self, message: &Message, player: PlayerId) {
-        let addr = *self
-            .player_addresses
+    /// Stop sending to the spectator at `addr`. Only fails if it isn't a spectator.
+    pub fn remove_spectator(&mut self, addr: SocketAddr) -> Result<(), String> {
+        self.spectators
+            .remove(&addr)
+            .ok_or_else(|| format!("{} is not a spectator", addr))?;
+        log::info!("spectator {} left", addr);
+        self.shared_clock.remove_remote(addr);
+        Ok(())
+    }
+
+    /// What a spectator at `addr` must echo in `Message::Spectate` to be let in, proving it
+    /// receives at that address. Derived rather than stored, so that spoofed requests cost
+    /// nothing to answer.
+    fn spectate_cookie(&self, addr: SocketAddr) -> u64 {
+        let mut hasher = seahash::SeaHasher::new();
+        self.spectate_secret.hash(&mut hasher);
+        addr.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// The nonce to prove a reconnection token with from `addr`, good for one or two
+    /// `CHALLENGE_LIFETIME`s. Derived rather than stored like `spectate_cookie`, so a flood of
+    /// spoofed packets can't push a real challenge out.
+    fn challenge_nonce(&self, addr: SocketAddr, epochs_ago: u32) -> u64 {
+        let epoch = self.clock.now().as_millis() / CHALLENGE_LIFETIME.as_millis();
+        let mut hasher = seahash::SeaHasher::new();
+        self.challenge_secret.hash(&mut hasher);
+        addr.hash(&mut hasher);
+        epoch.saturating_sub(epochs_ago as u128).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Drop spectators that have gone quiet for `SPECTATOR_TIMEOUT`, so a vanished one isn't
+    /// sent to forever. Ones we were told about that haven't shown up yet are kept.
+    fn drop_silent_spectators(&mut self) {
+        let now = self.clock.now();
+        let silent = self
+            .spectators
             .iter()
-            .find(|(_, &id)| id == player)
-            .unwrap()
-            .0;
-        self.send_to_addr(message, addr);
+            .filter(|(_, s)| s.heard_at.is_some_and(|at| now - at > SPECTATOR_TIMEOUT))
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<_>>();
+        for addr in silent {
+            log::info!("spectator {} went silent", addr);
+            // Only fails if it is already gone.
+            let _ = self.remove_spectator(addr);
+        }
     }
 
-    fn send_to_addr(&mut self, message: &Message, addr: SocketAddr) {
-        let message = bincode::serialize(&message).expect("failed to serialize message");
-        self.socket.send(&message, addr);
+    /// What a spectator needs before it can follow our inputs: the seed, or our part of it, and
+    /// our latest confirmed state if it joined after the start.
+    fn send_spectator_start(&mut self, addr: SocketAddr) {
+        if let Some(message) = self.seed_message() {
+            self.send_to_addr(message, addr);
+        }
+        self.catch_up_spectator(addr);
     }
 
-    fn process_incoming_messages(&mut self) {
-        while let Some((addr, buffer)) = self.socket.recv() {
-            let player = match self.player_addresses.get(&addr) {
-                Some(p) => p,
-                None => {
-                    log::warn!("got message from non-player: {}", addr);
-                    continue;
-                }
-            };
-            let message = match bincode::deserialize(buffer) {
-                Ok(m) => m,
-                Err(e) => {
-                    log::warn!("failed to decode message: {:?}", e);
-                    continue;
+    /// Send the spectator at `addr` our latest confirmed state if the inputs it needs have been
+    /// pruned, such as when it joined late or went quiet for a while, or if the last one we sent
+    /// seems lost.
+    fn catch_up_spectator(&mut self, addr: SocketAddr) {
+        let (now, retained) = (self.clock.now(), self.inputs.retained_from());
+        let spectator = match self.spectators.get(&addr) {
+            Some(s) => s,
+            None => return,
+        };
+        let lost = spectator
+            .state_sent
+            .is_some_and(|(f, at)| spectator.unconfirmed < f && now - at >= STATE_RESEND);
+        if spectator.inputs_from() >= retained && !lost {
+            return;
+        }
+        let message = match self.confirmed_state_message() {
+            Some(m) => m,
+            None => return,
+        };
+        if let (Message::ConfirmedState { frame, .. }, Some(spectator)) =
+            (&message, self.spectators.get_mut(&addr))
+        {
+            spectator.state_sent = Some((*frame, now));
+        }
+        self.send_to_addr(message, addr);
+    }
+
+    /// Our latest whole confirmed state with its checksum, for a peer to start from. `None` until
+    /// a checksummed one has been saved.
+    fn confirmed_state_message(&mut self) -> Option<Message> {
+        let (frame, state) = self.confirmed_states.latest_full()?;
+        let checksum = *self.confirmed_checksums.get(&frame)?;
+        Some(Message::ConfirmedState {
+            frame,
+            checksum,
+            state: state.into_owned(),
+        })
+    }
+
+    /// The players on `host`'s machine besides `host` itself, in id order.
+    fn guests_of(&self, host: PlayerId) -> Vec<PlayerId> {
+        let mut guests = self
+            .guests
+            .iter()
+            .filter(|(_, &h)| h == host)
+            .map(|(&guest, _)| guest)
+            .collect::<Vec<_>>();
+        guests.sort_unstable();
+        guests
+    }
+
+    /// Correct the address of a remote player without disturbing the game state. Clock
+    /// synchronization data for the old address is discarded so it re-converges on the new path.
+    pub fn set_remote_address(&mut self, player: PlayerId, addr: SocketAddr) -> Result<(), String> {
+        let old = self
+            .address_of(player)
+            .ok_or_else(|| format!("player {} is not a remote player", player))?;
+        if old == addr {
+            return Ok(());
+        }
+        if self.player_addresses.contains_key(&addr) {
+            return Err(format!("{} is already used by another player", addr));
+        }
+
+        self.player_addresses.remove(&old);
+        self.player_addresses.insert(addr, player);
+        self.shared_clock.replace_remote(old, addr);
+        for plugin in self.plugins.values_mut() {
+            plugin.on_address_changed(old, addr);
+        }
+        Ok(())
+    }
+
+    /// Move `player` to `addr` if `token` matches the one they were given at join, then send them
+    /// the latest confirmed state so they can resume.
+    pub fn reconnect_player(
+        &mut self,
+        player: PlayerId,
+        addr: SocketAddr,
+        token: u64,
+    ) -> Result<(), String> {
+        if self.reconnection_tokens.get(&player) != Some(&token) {
+            log::warn!(
+                "rejected reconnection of player {} from {}: invalid token",
+                player,
+                addr
+            );
+            return Err(format!("invalid reconnection token for player {}", player));
+        }
+
+        self.set_remote_address(player, addr)?;
+        self.remote_unconfirmed.remove(&player);
+        self.inputs.forget_acks(player);
+        self.send_state_to(player);
+        Ok(())
+    }
+
+    /// Send `player` our latest confirmed state, again every `STATE_RESEND` until they confirm
+    /// past it.
+    fn send_state_to(&mut self, player: PlayerId) {
+        let addr = match self.address_of(player) {
+            Some(addr) => addr,
+            None => return,
+        };
+        if let Some(message @ Message::ConfirmedState { frame, .. }) =
+            self.confirmed_state_message()
+        {
+            self.states_sent.insert(player, (frame, self.clock.now()));
+            self.send_to_addr(message, addr);
+        }
+    }
+
+    /// Resend what hasn't arrived of state transfers: states to players that haven't confirmed
+    /// them, and our request for one while resyncing.
+    fn resend_states(&mut self) {
+        let now = self.clock.now();
+        let remote_unconfirmed = &self.remote_unconfirmed;
+        self.states_sent.retain(|player, (frame, _)| {
+            remote_unconfirmed.get(player).is_none_or(|unc| unc < frame)
+        });
+        let due = self
+            .states_sent
+            .iter()
+            .filter(|(_, (_, at))| now - *at >= STATE_RESEND)
+            .map(|(player, _)| *player)
+            .collect::<Vec<_>>();
+        for player in due {
+            self.send_state_to(player);
+        }
+
+        if let Some(player) = self.resyncing_from {
+            if now - self.resync_asked_at >= STATE_RESEND {
+                // Only fails if they have left, which `resyncing_from` outlasts harmlessly.
+                let _ = self.resync_from(player);
+            }
+        }
+    }
+
+    /// Ask `player` for their latest confirmed state and adopt it in place of ours, such as after
+    /// a `Request::DesyncDetected`. Frames after it are re-simulated from the inputs we have. The
+    /// request is repeated until a state arrives.
+    pub fn resync_from(&mut self, player: PlayerId) -> Result<(), String> {
+        let addr = self
+            .address_of(player)
+            .ok_or_else(|| format!("no address for player {}", player))?;
+        log::info!("resyncing from player {}", player);
+        self.resyncing_from = Some(player);
+        self.resync_asked_at = self.clock.now();
+        self.send_to_addr(Message::StateRequest, addr);
+        Ok(())
+    }
+
+    fn address_of(&self, player: PlayerId) -> Option<SocketAddr> {
+        self.player_addresses
+            .iter()
+            .find(|(_, &id)| id == player)
+            .map(|(addr, _)| *addr)
+    }
+
+    /// How long until the session has more to do unless a packet arrives first, such as the next
+    /// frame or the next send. Only meaningful once `next_request` has broken with nothing left
+    /// to do.
+    ///
+    /// For driving the session from an async scheduler instead of calling `next_request` in a
+    /// loop: after draining it, wait for this long or until the waker given to `wake_on_receive`
+    /// is woken, whichever comes first, then drain it again.
+    ///
+    /// Sockets that can't wake a waker, `BasicUdpSocket` among them, return `false` from
+    /// `wake_on_receive`. Packets on those are only read when the session is next drained, so
+    /// poll it more often than this, such as every millisecond, to keep their latency down.
+    pub fn next_wake(&self) -> Duration {
+        let frame = self
+            .clock_elapsed()
+            .map(|elapsed| match self.calculate_frame_state(elapsed) {
+                FrameState::At(frame) => self.steps.at(frame),
+                FrameState::After(frame, into) => self.steps.at(frame) - into,
+            });
+        [
+            self.shared_clock.next_message_in(),
+            self.send_interval.time_until(),
+        ]
+        .into_iter()
+        .chain(frame)
+        .min()
+        .unwrap()
+    }
+
+    /// Have `waker` woken when a packet arrives, see `next_wake`. Returns `false` if the socket
+    /// doesn't support it, see `NonBlockingSocket::wake_on_receive`.
+    pub fn wake_on_receive(&mut self, waker: &Waker) -> bool {
+        self.socket.wake_on_receive(waker)
+    }
+
+    /// Handles every request due by now with `handler`, then returns where realtime is between
+    /// frames for rendering. Breaks early with the handler's message if it gives one, after
+    /// which `update` can be called again to carry on.
+    ///
+    /// Call once per rendered frame, whatever the render rate. No time delta is needed, as the
+    /// session steps by its own clock: it advances as many frames as realtime has moved on by,
+    /// like calling `next_request` until it breaks.
+    pub fn update<F, M>(&mut self, mut handler: F) -> ControlFlow<M::Message, Interpolation>
+    where
+        F: FnMut(Request<'_>) -> M,
+        M: MaybeMessage,
+    {
+        match self.next_request(&mut handler) {
+            ControlFlow::Continue(m) => ControlFlow::Break(m),
+            ControlFlow::Break(()) => ControlFlow::Continue(self.interpolation()),
+        }
+    }
+
+    /// Where realtime is between frames, see `Session::update`.
+    pub fn interpolation(&self) -> Interpolation {
+        let ahead = self.clock_elapsed().map_or(Duration::ZERO, |elapsed| {
+            elapsed.saturating_sub(self.host_at)
+        });
+        let frame = self.host_frame().into_frame();
+        Interpolation {
+            frame: frame.0,
+            alpha: (ahead.as_secs_f32() / self.steps.at(frame).as_secs_f32()).min(1.),
+        }
+    }
+
+    pub fn next_request<H: RequestHandler>(&mut self, handler: H) -> ControlFlow<(), H::Break> {
+        match self.next_request_flow_inverted(handler) {
+            ControlFlow::Break(Some(m)) => ControlFlow::Continue(m),
+            ControlFlow::Break(None) | ControlFlow::Continue(()) => {
+                self.catch_up.end_update();
+                ControlFlow::Break(())
+            }
+        }
+    }
+
+    fn next_request_flow_inverted<H: RequestHandler>(
+        &mut self,
+        mut handler: H,
+    ) -> ControlFlow<Option<H::Break>> {
+        loop {
+            self.process_incoming_messages();
+            self.take_plugin_requests();
+            self.check_liveness();
+            self.drop_silent_spectators();
+            self.check_waiting_for_reconnect();
+            self.emit_notifications(&mut handler).map_break(Some)?;
+            if self.ended {
+                return ControlFlow::Continue(());
+            }
+            self.send_messages();
+            self.undo_partial_advance(&mut handler).map_break(Some)?;
+            self.restore_verified_state(&mut handler).map_break(Some)?;
+            self.deliver_seed(&mut handler)?;
+            self.load_received_state(&mut handler).map_break(Some)?;
+            self.capture_inputs(&mut handler)?;
+            self.save_frame_zero(&mut handler).map_break(Some)?;
+            self.verify_received_state(&mut handler).map_break(Some)?;
+            self.advance_confirmed_horizon(&mut handler)?;
+            if self.ended {
+                return ControlFlow::Continue(());
+            }
+
+            if !self.step_towards_realtime(&mut handler)? {
+                self.report_corrections();
+                self.emit_notifications(&mut handler).map_break(Some)?;
+                self.partial_advance(&mut handler).map_break(Some)?;
+                return ControlFlow::Continue(());
+            }
+        }
+    }
+
+    fn step_towards_realtime<H: RequestHandler>(
+        &mut self,
+        handler: &mut H,
+    ) -> ControlFlow<Option<H::Break>, bool> {
+        // Spectators and lockstep sessions never predict, so stop at the confirmed horizon.
+        if self.spectating || self.lockstep {
+            return ControlFlow::Continue(false);
+        }
+
+        let frame = self.host_frame();
+        let clock_frame = self.clock_frame()?;
+        match (frame.into_frame().cmp(&clock_frame), frame) {
+            (Ordering::Greater, _) => {
+                unreachable!("advanced too far: {:?} > {:?}", frame, clock_frame);
+            }
+            (Ordering::Equal, _) => return ControlFlow::Continue(false),
+            _ if self.in_prediction_grace(frame.into_frame()) => {
+                return ControlFlow::Continue(false)
+            }
+            _ if self.at_prediction_barrier(frame.into_frame()) => {
+                return ControlFlow::Continue(false)
+            }
+            _ if !self.catch_up.take_frame() => return ControlFlow::Continue(false),
+            (Ordering::Less, FrameState::At(f)) => {
+                self.try_advance(handler, self.steps.at(f))
+                    .map_break(Some)?;
+            }
+            (Ordering::Less, FrameState::After(f, _)) => {
+                self.navigate_to(f, handler).map_break(Some)?;
+                self.try_advance(handler, self.steps.at(f))
+                    .map_break(Some)?;
+            }
+        }
+        ControlFlow::Continue(true)
+    }
+
+    /// Once caught up, step the host from its whole frame the rest of the way to realtime, for
+    /// rendering. The whole frame's state is saved first and loaded back by
+    /// `undo_partial_advance` before anything else touches the state.
+    fn partial_advance<H: RequestHandler>(&mut self, handler: &mut H) -> ControlFlow<H::Break> {
+        if !self.partial_advances || self.spectating || self.lockstep {
+            return ControlFlow::Continue(());
+        }
+        let realtime = self.clock_elapsed().map(|e| self.calculate_frame_state(e));
+        let (frame, remainder) = match realtime {
+            Some(FrameState::After(f, rem)) if self.host_frame() == FrameState::At(f) => (f, rem),
+            _ => return ControlFlow::Continue(()),
+        };
+        let inputs = match self.inputs(frame) {
+            Some(i) => i,
+            None => return ControlFlow::Continue(()),
+        };
+
+        let mut base = SerializedState::new();
+        handler.handle_request(Request::save(&mut base, frame.0, self.frame_numbers))?;
+        handler
+            .handle_request(Request::Advance {
+                amount: remainder,
+                inputs,
+                confirmed: Confirmation::Partial,
+                current_frame: frame.0,
+                resimulation: false,
+            })
+            .always(|| self.partial_base = Some(base))
+    }
+
+    fn undo_partial_advance<H: RequestHandler>(
+        &mut self,
+        handler: &mut H,
+    ) -> ControlFlow<H::Break> {
+        match self.partial_base.take() {
+            Some(base) => handler.handle_request(Request::load(
+                &base,
+                self.host_frame().into_frame().0,
+                self.frame_numbers,
+            )),
+            None => ControlFlow::Continue(()),
+        }
+    }
+
+    fn restore_verified_state<H: RequestHandler>(
+        &mut self,
+        handler: &mut H,
+    ) -> ControlFlow<H::Break> {
+        match self.verify_restore.take() {
+            Some(state) => handler.handle_request(Request::load(
+                &state,
+                self.host_frame().into_frame().0,
+                self.frame_numbers,
+            )),
+            None => ControlFlow::Continue(()),
+        }
+    }
+
+    /// Advance `frame` again from `before` with the same `inputs`, and compare against the state
+    /// the game is in now, see `SessionBuilder::verify_confirmed_advances`.
+    fn verify_advance<H: RequestHandler>(
+        &mut self,
+        frame: Frame,
+        before: &[u8],
+        inputs: PlayerInputs,
+        handler: &mut H,
+    ) -> ControlFlow<H::Break> {
+        let mut after = SerializedState::new();
+        handler.handle_request(Request::save(&mut after, frame.0 + 1, self.frame_numbers))?;
+
+        let load = Request::load(before, frame.0, self.frame_numbers);
+        handler
+            .handle_request(load)
+            .always(|| self.verify_restore = Some(after))?;
+        handler.handle_request(Request::Advance {
+            amount: self.steps.at(frame),
+            inputs,
+            confirmed: Confirmation::Subsequent,
+            current_frame: frame.0,
+            resimulation: true,
+        })?;
+        let mut again = SerializedState::new();
+        handler.handle_request(Request::save(&mut again, frame.0 + 1, self.frame_numbers))?;
+
+        let after = self.verify_restore.take().expect("set before loading");
+        let offset = determinism::first_difference(&after, &again);
+        handler.handle_request(Request::load(&after, frame.0 + 1, self.frame_numbers))?;
+        match offset {
+            Some(offset) => {
+                log::error!(
+                    "nondeterministic advance of {:?}, states differ from byte {}",
+                    frame,
+                    offset
+                );
+                handler.handle_request(Request::NondeterministicAdvance {
+                    frame: frame.0,
+                    offset,
+                })
+            }
+            None => ControlFlow::Continue(()),
+        }
+    }
+
+    /// Whether to hold off on predicting `frame` because some of its inputs are missing, but might
+    /// still arrive within the grace period after its boundary.
+    fn in_prediction_grace(&self, frame: Frame) -> bool {
+        if self.prediction_grace.is_zero() {
+            return false;
+        }
+        let all_present = self
+            .inputs(frame)
+            .is_some_and(|inputs| self.is_confirmed(&inputs, frame));
+        if all_present {
+            return false;
+        }
+
+        let grace_ends = self.steps.start_of(frame + 1) + self.prediction_grace;
+        self.clock_elapsed()
+            .is_some_and(|elapsed| elapsed < grace_ends)
+    }
+
+    /// Whether simulating `frame` would predict further past the confirmed horizon than allowed.
+    /// Notifies the game once each time the barrier is hit.
+    fn at_prediction_barrier(&mut self, frame: Frame) -> bool {
+        let predicted = frame.0.saturating_sub(self.unconfirmed.0);
+        let stalled = self.max_prediction.is_some_and(|max| predicted >= max);
+        if stalled && !self.prediction_stalled {
+            self.prediction_stalls += 1;
+            self.notifications
+                .push_back(Notification::PredictionStalled(self.unconfirmed - 1));
+        }
+        self.prediction_stalled = stalled;
+        stalled
+    }
+
+    /// Issues `Request::Seed` once every player's proposal is in, holding off everything after it
+    /// until then.
+    fn deliver_seed<H: RequestHandler>(
+        &mut self,
+        handler: &mut H,
+    ) -> ControlFlow<Option<H::Break>> {
+        if self.seeded {
+            return ControlFlow::Continue(());
+        }
+        let seed = match self.seed {
+            Some(seed) => seed,
+            None => match self.agree_on_seed() {
+                Some(seed) => seed,
+                None => return ControlFlow::Break(None),
+            },
+        };
+        handler
+            .handle_request(Request::Seed(seed))
+            .always(|| self.seeded = true)
+            .map_break(Some)
+    }
+
+    /// The seed hashes the proposals of each machine at the start, whoever has come or gone
+    /// since. Latecomers get it whole from `Message::AgreedSeed` instead.
+    fn agree_on_seed(&mut self) -> Option<u64> {
+        let proposals = self
+            .membership
+            .players_at(Frame(0))
+            .into_iter()
+            .filter(|p| !self.guests.contains_key(p))
+            .map(|p| match p == self.local_id {
+                true => Some((p, self.seed_proposal?)),
+                false => Some((p, *self.seed_proposals.get(&p)?)),
+            })
+            .collect::<Option<BTreeMap<_, _>>>()?;
+
+        let mut hasher = seahash::SeaHasher::new();
+        for (player, proposal) in proposals {
+            hasher.write(&player.to_le_bytes());
+            hasher.write(&proposal.to_le_bytes());
+        }
+        let seed = hasher.finish();
+        log::info!("agreed on seed {:x}", seed);
+        self.settle_seed(seed);
+        Some(seed)
+    }
+
+    fn settle_seed(&mut self, seed: u64) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.record_seed(seed) {
+                log::error!("failed to record replay, stopping recording: {}", e);
+                self.recorder = None;
+            }
+        }
+        self.seed = Some(seed);
+    }
+
+    fn save_frame_zero<H: RequestHandler>(&mut self, handler: &mut H) -> ControlFlow<H::Break> {
+        if self.confirmed_states.is_empty() {
+            assert_eq!(self.host_frame(), FrameState::At(Frame(0)));
+
+            let state = self.confirmed_states.save_slot(Frame(0));
+            handler.handle_request(Request::save(state, 0, self.frame_numbers))?;
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn load_received_state<H: RequestHandler>(&mut self, handler: &mut H) -> ControlFlow<H::Break> {
+        if !std::mem::take(&mut self.load_confirmed) {
+            return ControlFlow::Continue(());
+        }
+
+        let (frame, state) = self
+            .confirmed_states
+            .latest_full()
+            .expect("received state should be stored");
+        handler
+            .handle_request(Request::load(&state, frame.0, self.frame_numbers))
+            .always(|| self.host_at = self.steps.start_of(frame))
+    }
+
+    /// The frames whose confirmed states are worth keeping.
+    fn kept_frames(&self) -> HashSet<u32> {
+        if self.saveless {
+            let first = self.confirmed_states.earliest_frame().unwrap_or(Frame(0));
+            return HashSet::from([first.0]);
+        }
+        exponential_keeping::kept_set(self.unconfirmed.0)
+    }
+
+    fn should_save(&self, frame: Frame) -> bool {
+        self.kept_frames().contains(&frame.0) && !self.confirmed_states.contains(frame)
+    }
+
+    fn clear_states(&mut self) {
+        let kept = self.kept_frames();
+        self.confirmed_states
+            .retain(|frame| kept.contains(&frame.0));
+        self.confirmed_checksums
+            .retain(|frame, _| kept.contains(&frame.0));
+    }
+
+    fn capture_inputs<H: RequestHandler>(
+        &mut self,
+        handler: &mut H,
+    ) -> ControlFlow<Option<H::Break>> {
+        if self.spectating {
+            return ControlFlow::Continue(());
+        }
+
+        let frame = self.clock_frame()? + self.local_input_delay();
+        let guests = self.guests_of(self.local_id);
+        // `CaptureLocalInput` can't tell local players apart.
+        let numbered = self.frame_numbers || !guests.is_empty();
+        for player in std::iter::once(self.local_id).chain(guests) {
+            if let Some(input) = self.inputs.capture_into(frame, player) {
+                let flow = match &mut self.input_source {
+                    Some(source) => {
+                        *input = source(player, frame.0);
+                        ControlFlow::Continue(())
+                    }
+                    None if numbered => handler.handle_request(Request::CaptureInput {
+                        input,
+                        frame: frame.0,
+                        player,
+                    }),
+                    None => handler.handle_request(Request::CaptureLocalInput(input)),
+                };
+                if let Some(input) = self.inputs.sparse_mut(player).get(&frame) {
+                    let len = input.len();
+                    self.check_input_size(player, frame.0, len);
                 }
-            };
-            match message {
-                Message::Inputs(map) => {
-                    self.inputs.merge_remote(*player, map);
+                flow.map_break(Some)?;
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn check_input_size(&self, player: PlayerId, frame: u32, len: usize) {
+        if let Some(expected) = self.fixed_input_size {
+            assert_eq!(
+                len, expected,
+                "player {}'s input at frame {} is not the fixed_input_size",
+                player, frame
+            );
+        }
+    }
+
+    fn advance_confirmed_horizon<H: RequestHandler>(
+        &mut self,
+        handler: &mut H,
+    ) -> ControlFlow<Option<H::Break>> {
+        loop {
+            let last_confirmed = self.unconfirmed - 1;
+            let host_frame = self.host_frame().into_frame();
+
+            let clock_frame = self.clock_frame()?;
+            // Paused, the clock won't move on, so confirm up to it without waiting for that.
+            let should_advance =
+                host_frame < clock_frame || (self.is_paused() && last_confirmed < clock_frame);
+            if !should_advance {
+                return ControlFlow::Continue(());
+            }
+
+            let behind = self.steps.start_of(host_frame) - self.steps.start_of(last_confirmed);
+            if behind > Duration::from_secs(1) {
+                if let Some(suppressed) = self.log_throttle.check("horizon_behind") {
+                    log::warn!(
+                        "confirmation horizon {:?} behind, waiting on players {:?}{}",
+                        behind,
+                        self.blocking_players(),
+                        suppressed
+                    );
                 }
-                Message::Unconfirmed(frame) => {
-                    let unc = self.remote_unconfirmed.entry(*player).or_insert(frame);
-                    *unc = std::cmp::max(*unc, frame);
+            }
+
+            let mut someone_left = false;
+            for (frame, change) in self.membership.take_through(last_confirmed) {
+                if let MembershipChange::Leave(id) = change {
+                    self.forget_departed(id);
+                    if let Some(liveness) = &mut self.liveness {
+                        liveness.forget(id);
+                    }
+                    self.frame_advantage.forget(id);
+                    if let Some(negotiation) = &mut self.input_delay_negotiation {
+                        negotiation.forget(id);
+                    }
+                    self.notifications
+                        .push_back(Notification::PlayerDisconnected(id, frame - 1));
+                    someone_left = true;
                 }
-                Message::Clock(m) => {
-                    self.shared_clock.receive_message(addr, m);
+            }
+            if someone_left && self.membership.remote_count_at(last_confirmed) == 0 {
+                self.on_all_disconnected();
+            }
+            self.emit_notifications(handler).map_break(Some)?;
+            if self.ended {
+                return ControlFlow::Continue(());
+            }
+
+            match self.inputs(last_confirmed) {
+                None => return ControlFlow::Continue(()),
+                Some(inputs) if !self.is_confirmed(&inputs, last_confirmed) => {
+                    return ControlFlow::Continue(())
                 }
-                Message::Plugin(id, m) => {
-                    if let Some(p) = self.plugins.get_mut(&id) {
-                        p.receive(addr, m);
+                Some(inputs) => {
+                    let inputs = if self.is_authority() {
+                        self.decide_inputs(last_confirmed)
                     } else {
-                        log::warn!("received message for unrecognized plugin: {}", id);
+                        inputs
+                    };
+                    self.record_replay(last_confirmed, &inputs);
+                    self.navigate_to(last_confirmed, handler).map_break(Some)?;
+
+                    let amount = self.steps.at(last_confirmed);
+                    self.advance_with(inputs, handler, amount, last_confirmed, true)
+                        .always(|| self.unconfirmed = self.unconfirmed + 1)
+                        .map_break(Some)?;
+                }
+            }
+        }
+    }
+
+    fn record_replay(&mut self, frame: Frame, inputs: &PlayerInputs) {
+        let recorder = match &mut self.recorder {
+            Some(r) => r,
+            None => return,
+        };
+        if let Err(e) = recorder.record(frame, inputs, self.steps.at(frame)) {
+            log::error!("failed to record replay, stopping recording: {}", e);
+            self.recorder = None;
+        }
+    }
+
+    fn on_all_disconnected(&mut self) {
+        log::info!(
+            "all remote players disconnected, {:?}",
+            self.all_disconnected
+        );
+        self.notifications
+            .push_back(Notification::AllPlayersDisconnected);
+
+        match self.all_disconnected {
+            AllDisconnected::End => {
+                self.notifications.push_back(Notification::SessionEnded);
+                self.ended = true;
+            }
+            AllDisconnected::ContinueSolo => {
+                // The authority is gone, so only our own inputs are left to confirm on.
+                self.confirmation_mode = ConfirmationMode::Unanimous;
+            }
+            AllDisconnected::WaitForReconnect(_) => {
+                self.waiting_since = Some(self.clock.now());
+                self.paused_for_reconnect = self.pause_alone();
+            }
+        }
+    }
+
+    /// Stop the simulation time at the next frame, with nobody left to tell, so whoever comes
+    /// back continues from there rather than the time spent waiting being caught up on. Returns
+    /// `false` if already paused.
+    fn pause_alone(&mut self) -> bool {
+        let Some(shared) = self.shared_clock.elapsed() else {
+            return false;
+        };
+        let frame = self.calculate_frame_state(self.pause.elapsed(shared, &self.steps));
+        self.pause
+            .pause(frame.into_frame() + 1, shared, &self.steps)
+    }
+
+    fn check_waiting_for_reconnect(&mut self) {
+        let since = match self.waiting_since {
+            Some(s) => s,
+            None => return,
+        };
+
+        if !self.player_addresses.is_empty() {
+            log::info!("player joined, resuming");
+            self.waiting_since = None;
+            if std::mem::take(&mut self.paused_for_reconnect) {
+                self.resume();
+            }
+            return;
+        }
+        if let AllDisconnected::WaitForReconnect(timeout) = self.all_disconnected {
+            if self.clock.since(since) > timeout {
+                self.waiting_since = None;
+                self.ended = true;
+                self.notifications.push_back(Notification::SessionEnded);
+            }
+        }
+    }
+
+    fn emit_notifications<H: RequestHandler>(&mut self, handler: &mut H) -> ControlFlow<H::Break> {
+        while let Some(notification) = self.notifications.pop_front() {
+            let request = match notification {
+                Notification::PlayerDisconnected(id, last_frame) => Request::PlayerDisconnected {
+                    id,
+                    last_frame: last_frame.0,
+                },
+                Notification::ConnectionInterrupted(id, since) => {
+                    Request::ConnectionInterrupted { id, since }
+                }
+                Notification::ConnectionResumed(id) => Request::ConnectionResumed { id },
+                Notification::AllPlayersDisconnected => Request::AllPlayersDisconnected,
+                Notification::SessionEnded => Request::SessionEnded,
+                Notification::PredictionCorrected(id, frame) => Request::PredictionCorrected {
+                    id,
+                    since_frame: frame.0,
+                },
+                Notification::PredictionStalled(frame) => Request::PredictionStalled {
+                    last_confirmed: frame.0,
+                },
+                Notification::DesyncDetected {
+                    frame,
+                    id,
+                    local,
+                    remote,
+                } => Request::DesyncDetected {
+                    frame: frame.0,
+                    id,
+                    local,
+                    remote,
+                },
+            };
+            handler.handle_request(request)?;
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn take_plugin_requests(&mut self) {
+        let requests = self
+            .plugins
+            .values_mut()
+            .flat_map(|p| p.take_requests())
+            .collect::<Vec<_>>();
+        for request in requests {
+            match request {
+                PluginRequest::DesyncDetected(mismatch) => {
+                    if let Some(&id) = self.player_addresses.get(&mismatch.remote) {
+                        self.notifications.push_back(Notification::DesyncDetected {
+                            frame: mismatch.frame,
+                            id,
+                            local: mismatch.local,
+                            remote: mismatch.theirs,
+                        });
+                    }
+                }
+                PluginRequest::LoadConfirmed(frame, state) => {
+                    self.adopt_confirmed_state(frame, state);
+                }
+                PluginRequest::ResyncFrom(addr) => {
+                    let resync = match self.player_addresses.get(&addr).copied() {
+                        Some(player) => self.resync_from(player),
+                        None => Err(format!("unknown peer {}", addr)),
+                    };
+                    if let Err(e) = resync {
+                        log::warn!("could not resync as a plugin asked: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether to take a confirmed state at `frame` from `player`: one we asked them for to
+    /// resync, one to start from after resuming or before having confirmed anything, such as
+    /// when rejoining mid-match, or, spectating, one to skip ahead to.
+    fn expects_state_from(&self, player: PlayerId, frame: Frame) -> bool {
+        if self.resyncing_from == Some(player) {
+            return true;
+        }
+        frame >= self.unconfirmed
+            && (self.spectating || self.resumed_at.is_some() || self.unconfirmed == Frame(1))
+    }
+
+    /// Load the state a peer sent and adopt it if it checksums as they said, otherwise go back to
+    /// the state it replaced.
+    fn verify_received_state<H: RequestHandler>(
+        &mut self,
+        handler: &mut H,
+    ) -> ControlFlow<H::Break> {
+        let mut received = match self.received_state.take() {
+            Some(r) => r,
+            None => return ControlFlow::Continue(()),
+        };
+        if received.replaced.is_none() {
+            let mut replaced = SerializedState::new();
+            let saved = handler.handle_request(Request::save(
+                &mut replaced,
+                self.host_frame().into_frame().0,
+                self.frame_numbers,
+            ));
+            received.replaced = Some(replaced);
+            if let ControlFlow::Break(b) = saved {
+                self.received_state = Some(received);
+                return ControlFlow::Break(b);
+            }
+        }
+        let checksum = match self.load_and_checksum(handler, received.frame, &received.state) {
+            ControlFlow::Continue(c) => c,
+            ControlFlow::Break(b) => {
+                self.received_state = Some(received);
+                return ControlFlow::Break(b);
+            }
+        };
+
+        if checksum != received.checksum {
+            log::error!(
+                "confirmed state at {:?} from player {} does not match its checksum, ignoring it",
+                received.frame,
+                received.from
+            );
+            self.verify_restore = received.replaced;
+            return self.restore_verified_state(handler);
+        }
+
+        if self.resyncing_from == Some(received.from) {
+            self.resyncing_from = None;
+        }
+        log::info!(
+            "adopted confirmed state at {:?} from player {}",
+            received.frame,
+            received.from
+        );
+        self.confirmed_checksums.retain(|f, _| *f < received.frame);
+        self.confirmed_checksums.insert(received.frame, checksum);
+        self.confirmed_states
+            .reset_to(received.frame, received.state);
+        self.unconfirmed = received.frame + 1;
+        self.host_at = self.steps.start_of(received.frame);
+        ControlFlow::Continue(())
+    }
+
+    fn load_and_checksum<H: RequestHandler>(
+        &mut self,
+        handler: &mut H,
+        frame: Frame,
+        state: &[u8],
+    ) -> ControlFlow<H::Break, u64> {
+        handler.handle_request(Request::load(state, frame.0, self.frame_numbers))?;
+        self.checksum_state(handler, frame)
+    }
+
+    /// Adopt `state` as the confirmed state at `frame`, loaded before anything else is simulated.
+    /// Checksums from `frame` on were taken of the state it replaces.
+    fn adopt_confirmed_state(&mut self, frame: Frame, state: SerializedState) {
+        log::info!("resuming from confirmed state at {:?}", frame);
+        self.confirmed_checksums.retain(|f, _| *f < frame);
+        self.confirmed_states.reset_to(frame, state);
+        self.unconfirmed = frame + 1;
+        self.load_confirmed = true;
+    }
+
+    fn check_liveness(&mut self) {
+        let liveness = match &mut self.liveness {
+            Some(l) => l,
+            None => return,
+        };
+
+        let events = liveness.check(self.player_addresses.values().copied());
+        for event in events {
+            match event {
+                LivenessEvent::Interrupted(id, since) => {
+                    log::info!("connection to player {} interrupted for {:?}", id, since);
+                    self.notifications
+                        .push_back(Notification::ConnectionInterrupted(id, since));
+                }
+                LivenessEvent::Resumed(id) => {
+                    self.notifications
+                        .push_back(Notification::ConnectionResumed(id));
+                }
+                LivenessEvent::TimedOut(id) => {
+                    log::warn!("player {} timed out, disconnecting", id);
+                    // Only fails if the player is already gone.
+                    let _ = self.disconnect_player(id);
+                }
+            }
+        }
+    }
+
+    fn save_confirmed<H: RequestHandler>(
+        &mut self,
+        frame: Frame,
+        checksum: Option<u64>,
+        handler: &mut H,
+    ) -> ControlFlow<H::Break> {
+        let (base_frame, base) = match self.confirmed_states.delta_base(frame) {
+            Some(b) => b,
+            None => {
+                let state = self.confirmed_states.save_slot(frame);
+                let request = Request::save(state, frame.0, self.frame_numbers);
+                return handler.handle_request(request).always(|| {
+                    let checksum = checksum.unwrap_or_else(|| seahash::hash(state));
+                    self.confirmed_checksums.insert(frame, checksum);
+                    for plugin in self.plugins.values_mut() {
+                        plugin.on_confirmed_frame(frame, state);
+                        plugin.on_confirmed_checksum(frame, checksum);
+                    }
+                });
+            }
+        };
+
+        let checksum = match checksum {
+            Some(c) => c,
+            None => {
+                let mut state = SerializedState::new();
+                handler.handle_request(Request::save(&mut state, frame.0, self.frame_numbers))?;
+                seahash::hash(&state)
+            }
+        };
+        let mut delta = SerializedState::new();
+        let saved = handler.handle_request(Request::SaveDelta {
+            base: &base,
+            out: &mut delta,
+            frame: frame.0,
+        });
+        self.confirmed_states.insert_delta(frame, base_frame, delta);
+        self.confirmed_checksums.insert(frame, checksum);
+        for plugin in self.plugins.values_mut() {
+            plugin.on_confirmed_checksum(frame, checksum);
+        }
+        saved
+    }
+
+    fn navigate_to<H: RequestHandler>(
+        &mut self,
+        frame: Frame,
+        handler: &mut H,
+    ) -> ControlFlow<H::Break> {
+        loop {
+            let current_frame = self.host_frame().into_frame();
+
+            if self.should_save(current_frame) {
+                let mut hasher = StateHasher::default();
+                handler.handle_request(Request::ChecksumState(&mut hasher))?;
+
+                self.clear_states();
+                self.save_confirmed(current_frame, hasher.checksum(), handler)?;
+            }
+
+            match current_frame.cmp(&frame) {
+                Ordering::Equal => return ControlFlow::Continue(()),
+                Ordering::Greater => {
+                    let (roll_to, state) = self
+                        .confirmed_states
+                        .latest_at_or_before(frame)
+                        .expect("should have at least one confirmed state");
+
+                    let delta = current_frame.0 - roll_to.0;
+                    let span = self.steps.start_of(current_frame) - self.steps.start_of(roll_to);
+                    if span > Duration::from_millis(300) {
+                        if let Some(suppressed) = self.log_throttle.check("long_rollback") {
+                            log::info!(
+                                "rolling back {} frames to {:?}{}",
+                                delta,
+                                roll_to,
+                                suppressed
+                            );
+                        }
+                    }
+
+                    if self.announced_rollback != Some(roll_to) {
+                        let rollback = Request::Rollback {
+                            to_frame: roll_to.0,
+                            frames: delta,
+                            mispredicted: self.mispredicted_simulation,
+                        };
+                        handler
+                            .handle_request(rollback)
+                            .always(|| self.announced_rollback = Some(roll_to))?;
+                    }
+                    handler
+                        .handle_request(state.load_request(roll_to, self.frame_numbers))
+                        .always(|| {
+                            self.host_at = self.steps.start_of(roll_to);
+                            self.announced_rollback = None;
+                            self.mispredicted_simulation = false;
+                        })?;
+                }
+                Ordering::Less => {
+                    self.do_advance(handler)?;
+                }
+            }
+        }
+    }
+
+    fn advance_with<H: RequestHandler>(
+        &mut self,
+        inputs: PlayerInputs,
+        handler: &mut H,
+        amount: Duration,
+        current_frame: Frame,
+        first_confirm: bool,
+    ) -> ControlFlow<H::Break> {
+        let check = match self.determinism {
+            Some(_) => Some((
+                self.checksum_state(handler, current_frame)?,
+                determinism::inputs_checksum(&inputs),
+            )),
+            None => None,
+        };
+        let whole = amount == self.steps.at(current_frame);
+        let verify = if self.verify_confirmed_advances && first_confirm && whole {
+            let mut before = SerializedState::new();
+            handler.handle_request(Request::save(
+                &mut before,
+                current_frame.0,
+                self.frame_numbers,
+            ))?;
+            Some((before, inputs.clone()))
+        } else {
+            None
+        };
+
+        handler
+            .handle_request(Request::Advance {
+                amount,
+                current_frame: current_frame.0,
+                confirmed: if first_confirm {
+                    Confirmation::First
+                } else if self.is_confirmed(&inputs, current_frame) {
+                    Confirmation::Subsequent
+                } else {
+                    Confirmation::Unconfirmed
+                },
+                resimulation: current_frame < self.simulated_until,
+                inputs,
+            })
+            .always(|| {
+                self.host_at += amount;
+                self.simulated_until = self.simulated_until.max(current_frame + 1);
+            })?;
+
+        if let Some((before, inputs)) = check {
+            let after = self.checksum_state(handler, current_frame + 1)?;
+            if let Some(determinism) = &mut self.determinism {
+                determinism.record(current_frame, before, inputs, after);
+                if first_confirm {
+                    determinism.confirm(current_frame);
+                }
+            }
+        }
+        if let Some((before, inputs)) = verify {
+            self.verify_advance(current_frame, &before, inputs, handler)?;
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Falls back to hashing the whole serialized state if the handler doesn't hash anything.
+    fn checksum_state<H: RequestHandler>(
+        &mut self,
+        handler: &mut H,
+        frame: Frame,
+    ) -> ControlFlow<H::Break, u64> {
+        let mut hasher = StateHasher::default();
+        handler.handle_request(Request::ChecksumState(&mut hasher))?;
+        if let Some(checksum) = hasher.checksum() {
+            return ControlFlow::Continue(checksum);
+        }
+
+        let mut state = SerializedState::new();
+        handler.handle_request(Request::save(&mut state, frame.0, self.frame_numbers))?;
+        ControlFlow::Continue(seahash::hash(&state))
+    }
+
+    fn do_advance<H: RequestHandler>(&mut self, handler: &mut H) -> ControlFlow<H::Break> {
+        let frame = match self.host_frame() {
+            FrameState::At(f) => f,
+            FrameState::After(_, _) => unimplemented!("FrameState::After"),
+        };
+        let inputs = self
+            .inputs(frame)
+            .unwrap_or_else(|| panic!("did not have inputs for frame: {:?}", frame));
+
+        self.advance_with(inputs, handler, self.steps.at(frame), frame, false)
+    }
+
+    fn try_advance<H: RequestHandler>(
+        &mut self,
+        handler: &mut H,
+        amount: Duration,
+    ) -> ControlFlow<H::Break> {
+        let frame = match self.host_frame() {
+            FrameState::At(f) => f,
+            FrameState::After(_, _) => unimplemented!("FrameState::After"),
+        };
+
+        if let Some(inputs) = self.inputs(frame) {
+            self.advance_with(inputs, handler, amount, frame, false)?;
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn clock_frame<T>(&self) -> ControlFlow<Option<T>, Frame> {
+        match self.clock_elapsed() {
+            Some(dur) => ControlFlow::Continue(self.calculate_frame_state(dur).into_frame()),
+            None => ControlFlow::Break(None),
+        }
+    }
+
+    /// The simulation time realtime calls for, behind the shared clock for spectators.
+    fn clock_elapsed(&self) -> Option<Duration> {
+        Some(self.sim_elapsed(self.shared_clock.elapsed()?))
+    }
+
+    /// The simulation time at `shared` on the shared clock, see `clock_elapsed`.
+    fn sim_elapsed(&self, shared: Duration) -> Duration {
+        let elapsed = self.pause.elapsed(shared, &self.steps);
+        match self.spectating {
+            true => elapsed.saturating_sub(SPECTATOR_DELAY),
+            false => elapsed,
+        }
+    }
+
+    fn host_frame(&self) -> FrameState {
+        self.calculate_frame_state(self.host_at)
+    }
+
+    fn calculate_frame_state(&self, at: Duration) -> FrameState {
+        self.steps.frame_at(at)
+    }
+
+    fn is_confirmed(&self, inputs: &PlayerInputs, frame: Frame) -> bool {
+        match self.confirmation_mode {
+            // Only the authority's inputs count, whatever we have ourselves.
+            ConfirmationMode::Authority(_) if !self.is_authority() => self
+                .authority_through
+                .is_some_and(|through| frame <= through),
+            mode => inputs.is_confirmed_under(mode, self.membership.remote_count_at(frame)),
+        }
+    }
+
+    /// The player whose machine decides every confirmed input, if any.
+    fn authority_machine(&self) -> Option<PlayerId> {
+        match self.confirmation_mode {
+            ConfirmationMode::Unanimous => None,
+            ConfirmationMode::Authority(id) => Some(self.guests.get(&id).copied().unwrap_or(id)),
+        }
+    }
+
+    fn is_authority(&self) -> bool {
+        self.authority_machine() == Some(self.local_id)
+    }
+
+    /// As the authority, settle `frame` on the inputs we have, predictions included, so they are
+    /// the ones we send everyone to confirm with.
+    fn decide_inputs(&mut self, frame: Frame) -> PlayerInputs {
+        for player in self.membership.players_at(frame) {
+            let input = match self
+                .inputs
+                .at_frame(frame)
+                .and_then(|i| i.get(&player).cloned())
+            {
+                Some(ConfirmationStatus::Confirmed(_)) => continue,
+                Some(ConfirmationStatus::Unconfirmed(input)) => input,
+                None => self.inputs.default_input().clone(),
+            };
+            self.inputs.set_input(player, frame, input);
+        }
+        self.inputs(frame).unwrap_or_default()
+    }
+
+    /// The inputs we confirmed frames from `from` on with, as the authority.
+    fn authority_inputs(&self, from: Frame) -> Option<Message> {
+        if !self.is_authority() || self.unconfirmed < Frame(2) {
+            return None;
+        }
+        let through = self.unconfirmed - 2;
+        let from = std::cmp::max(from, self.inputs.retained_from());
+        if from > through {
+            return None;
+        }
+        Some(Message::AuthorityInputs {
+            from,
+            through,
+            inputs: self.inputs.decided(from, through),
+        })
+    }
+
+    fn take_authority_inputs(
+        &mut self,
+        sender: PlayerId,
+        from: Frame,
+        through: Frame,
+        inputs: InputsByPlayer,
+    ) {
+        if self.authority_machine() != Some(sender) || self.is_authority() {
+            log::warn!("ignoring authority inputs from player {}", sender);
+            return;
+        }
+        // Anything from before what we still need to confirm is already settled, and a gap
+        // before it can't be confirmed over.
+        let next = self.unconfirmed - 1;
+        if from > next || through < next || through > self.latest_remote_input_frame() {
+            return;
+        }
+        let players = self.membership.players_at(next);
+        let players = players
+            .union(&self.membership.players_at(through))
+            .copied()
+            .collect::<BTreeSet<_>>();
+        for (player, decided) in inputs {
+            if !players.contains(&player) {
+                continue;
+            }
+            let changed = self.inputs.overrule(player, next, through, decided);
+            if player != self.local_id {
+                self.note_misprediction(player, changed);
+            } else if let Some(frame) = changed {
+                self.note_resimulation(frame);
+            }
+        }
+        self.authority_through = std::cmp::max(self.authority_through, Some(through));
+    }
+
+    fn inputs(&self, at: Frame) -> Option<PlayerInputs> {
+        let mut inputs = self.inputs.at_frame(at)?;
+        let players = self.membership.players_at(at);
+        inputs.retain_players(&players);
+        if self.fill_absent_inputs {
+            let default = ConfirmationStatus::Unconfirmed(self.inputs.default_input().clone());
+            inputs.fill_players(&players, default);
+        }
+        Some(inputs)
+    }
+
+    /// Drop inputs from before every frame that could still be rolled back to, or asked for by a
+    /// remote or spectator in `player_since_frame`.
+    fn prune_inputs(&mut self) {
+        // The state asked for may take a while to arrive, and is re-simulated from.
+        if self.resyncing_from.is_some() {
+            return;
+        }
+        let mut keep_from = match self.confirmed_states.latest_frame() {
+            Some(f) => f,
+            None => return,
+        };
+        for player in self.player_addresses.values() {
+            match self.remote_unconfirmed.get(player) {
+                Some(&unc) => keep_from = std::cmp::min(keep_from, unc),
+                // They could still ask for anything.
+                None => return,
+            }
+        }
+        // A spectator that has gone quiet is sent a state to catch up from if it comes back.
+        let now = self.clock.now();
+        for spectator in self.spectators.values().filter(|s| s.is_keeping_up(now)) {
+            keep_from = std::cmp::min(keep_from, spectator.inputs_from());
+        }
+        self.inputs.prune_before(keep_from);
+    }
+
+    fn send_messages(&mut self) {
+        while let Some((addr, message)) = self.shared_clock.message() {
+            self.send_to_addr(Message::Clock(message), addr);
+        }
+
+        let plugin_messages = self
+            .plugins
+            .iter_mut()
+            .flat_map(|(id, p)| p.messages().into_iter().map(move |m| (id.clone(), m)))
+            .collect::<Vec<_>>();
+        for (id, (addr, message)) in plugin_messages {
+            self.send_to_addr(Message::Plugin { id, bytes: message }, addr);
+        }
+
+        self.send_rate.update(self.worst_rtt());
+        self.send_interval.set_every(self.send_rate.current());
+        if !self.send_interval.is_time() {
+            return;
+        }
+        self.prune_inputs();
+        self.resend_states();
+
+        self.send_seed_proposal();
+        self.announce_resume();
+        if self
+            .shared_clock
+            .elapsed()
+            .is_some_and(|s| self.pause.should_announce(s))
+        {
+            self.announce_pause();
+        }
+        self.update_frame_advantage();
+        self.negotiate_input_delay();
+        self.redundancy.update(self.shared_clock.worst_loss());
+        for (player, unc) in self.remote_unconfirmed.clone() {
+            let mut messages = match self.relay_hub {
+                Some(hub) if hub == self.local_id => vec![self.relayed_inputs(player, unc)],
+                Some(hub) if hub != player => continue,
+                _ => self.own_inputs(unc, Some(player)),
+            };
+            messages.extend(self.authority_inputs(unc));
+            let addr = self.address_of(player).unwrap();
+            let packets = messages
+                .into_iter()
+                .flat_map(|m| self.encode(m))
+                .collect::<Vec<_>>();
+            for _ in 0..self.redundancy.current() {
+                for packet in &packets {
+                    self.send_packet(packet, addr);
+                }
+            }
+        }
+
+        // Each spectator gets our inputs from where it is, encoded once for all that are at the
+        // same frame.
+        let spectators = self.spectators.keys().copied().collect::<Vec<_>>();
+        for &addr in &spectators {
+            self.catch_up_spectator(addr);
+        }
+        let retained = self.inputs.retained_from();
+        let mut by_frame = BTreeMap::<Frame, Vec<SocketAddr>>::new();
+        for (addr, spectator) in &self.spectators {
+            let from = std::cmp::max(spectator.inputs_from(), retained);
+            by_frame.entry(from).or_default().push(*addr);
+        }
+        for (from, addrs) in by_frame {
+            let packets = self
+                .own_inputs(from, None)
+                .into_iter()
+                .chain(self.authority_inputs(from))
+                .flat_map(|m| self.encode(m))
+                .collect::<Vec<_>>();
+            for addr in addrs {
+                for packet in &packets {
+                    self.send_packet(packet, addr);
+                }
+            }
+        }
+
+        self.send(Message::Unconfirmed(self.unconfirmed - 1));
+        if self.spectating && self.unconfirmed <= Frame(1) {
+            let players = self
+                .player_addresses
+                .iter()
+                .map(|(addr, player)| (*addr, self.spectate_cookies.get(player).copied()))
+                .collect::<Vec<_>>();
+            for (addr, cookie) in players {
+                self.send_to_addr(Message::Spectate(cookie), addr);
+            }
+        }
+
+        // Changes are resent until every player has confirmed past them, even once taken here.
+        let heard_from = self
+            .remote_unconfirmed
+            .values()
+            .min()
+            .copied()
+            .unwrap_or(Frame(0));
+        self.membership.forget_before(heard_from);
+        let changes = self
+            .membership
+            .changes_from(heard_from)
+            .filter(|(_, change)| self.may_announce(self.local_id, *change))
+            .collect::<Vec<_>>();
+        for (frame, change) in changes {
+            self.send(Message::Membership(frame, change));
+        }
+        if !self.spectating {
+            self.announce_step_sizes();
+        }
+    }
+
+    /// The average round-trip time to the slowest remote player.
+    fn worst_rtt(&self) -> Option<Duration> {
+        self.player_addresses
+            .keys()
+            .filter_map(|addr| self.shared_clock.link_stats(*addr)?.average_rtt)
+            .max()
+    }
+
+    /// Sample how many frames our inputs run ahead of each remote's, share our average with
+    /// them, and steer the local clock once we are consistently off from each other.
+    fn update_frame_advantage(&mut self) {
+        // Everyone waits at the paused frame, which would look like being ahead by the latency.
+        if self.spectating || self.is_paused() {
+            return;
+        }
+        let realtime = match self.clock_frame::<()>() {
+            ControlFlow::Continue(frame) => frame,
+            ControlFlow::Break(_) => return,
+        };
+        let remotes = self
+            .player_addresses
+            .iter()
+            .map(|(addr, player)| (*addr, *player))
+            .collect::<Vec<_>>();
+        for (addr, player) in remotes {
+            let latest = match self.inputs.last_frame(player) {
+                Some(frame) if frame > Frame(0) => frame,
+                _ => continue,
+            };
+            let rtt = match self.shared_clock.link_stats(addr) {
+                Some(LinkStats {
+                    average_rtt: Some(rtt),
+                    ..
+                }) => rtt,
+                _ => continue,
+            };
+            // The remote has moved on by about half a round trip since sending its latest input.
+            let remote_now =
+                latest.0 as f32 + (rtt / 2).as_secs_f32() / self.step_size().as_secs_f32();
+            self.frame_advantage
+                .record(player, realtime.0 as f32 - remote_now);
+            if let Some(ours) = self.frame_advantage.over(player) {
+                self.send_to_addr(Message::FrameAdvantage(ours), addr);
+            }
+        }
+
+        match self.frame_advantage.steering() {
+            None => {}
+            Some(0.) => self.shared_clock.relax_steering(self.step_size() / 2),
+            Some(frames) => {
+                log::debug!("off from the remotes, holding back {:.2} frames", frames);
+                let by = self.step_size().mul_f32(frames.abs());
+                self.shared_clock.steer(match frames > 0. {
+                    true => utils::Signed::Neg(by),
+                    false => utils::Signed::Pos(by),
+                });
+            }
+        }
+    }
+
+    /// How many frames after realtime our own inputs take effect.
+    fn local_input_delay(&self) -> u32 {
+        let negotiated = self.input_delay_negotiation.as_ref().map(|n| n.local());
+        self.input_delay.max(negotiated.unwrap_or_default())
+    }
+
+    /// Decide the input delay of the links that are ours to decide, see
+    /// `SessionBuilder::negotiate_input_delay`.
+    fn negotiate_input_delay(&mut self) {
+        let step_size = self.step_size();
+        let negotiation = match &mut self.input_delay_negotiation {
+            Some(n) => n,
+            None => return,
+        };
+        if !negotiation.is_time() {
+            return;
+        }
+        let mut decided = Vec::new();
+        for (addr, player) in &self.player_addresses {
+            let rtt = match self.shared_clock.link_stats(*addr) {
+                Some(LinkStats {
+                    average_rtt: Some(rtt),
+                    ..
+                }) => rtt,
+                _ => continue,
+            };
+            let send_interval = self.send_rate.current();
+            if let Some(frames) = negotiation.decide(*player, rtt, step_size, send_interval) {
+                decided.push((*addr, frames));
+            }
+        }
+        for (addr, frames) in decided {
+            self.send_to_addr(Message::InputDelay(frames), addr);
+        }
+    }
+
+    /// Keep telling the remotes where we resumed from until the confirmed horizon moves past it,
+    /// which takes everyone's inputs.
+    fn announce_resume(&mut self) {
+        let resumed_at = match self.resumed_at {
+            Some(frame) => frame,
+            None => return,
+        };
+        if self.unconfirmed > resumed_at + 1 {
+            self.resumed_at = None;
+            return;
+        }
+        self.send(Message::Resumed(resumed_at));
+    }
+
+    fn announce_pause(&mut self) {
+        self.send(Message::Pause(self.pause));
+        let spectators = self.spectators.keys().copied().collect::<Vec<_>>();
+        for addr in spectators {
+            self.send_to_addr(Message::Pause(self.pause), addr);
+        }
+    }
+
+    /// Once agreed, the seed itself, so a latecomer missing some of the proposals takes it
+    /// rather than hashing the ones it has. Until then, our proposal.
+    fn seed_message(&self) -> Option<Message> {
+        if self.spectating {
+            return None;
+        }
+        match self.seed {
+            Some(seed) => Some(Message::AgreedSeed(seed)),
+            None => self.seed_proposal.map(Message::Seed),
+        }
+    }
+
+    /// Keep sending the seed, or our proposal for it, to every peer that hasn't confirmed a frame
+    /// yet. Once they have, they must have had it.
+    fn send_seed_proposal(&mut self) {
+        if self.seed_message().is_none() {
+            return;
+        }
+        let unseeded = self
+            .player_addresses
+            .iter()
+            .filter(|(_, p)| self.remote_unconfirmed.get(p).is_none_or(|f| f.0 == 0))
+            .map(|(addr, _)| *addr)
+            .chain(
+                self.spectators
+                    .iter()
+                    .filter(|(_, s)| s.unconfirmed.0 == 0)
+                    .map(|(addr, _)| *addr),
+            )
+            .collect::<Vec<_>>();
+        for addr in unseeded {
+            if let Some(message) = self.seed_message() {
+                self.send_to_addr(message, addr);
+            }
+        }
+    }
+
+    /// The inputs of everyone playing on this machine from `unconfirmed` on, less what `remote`
+    /// has acknowledged, along with our acknowledgement of `remote`'s.
+    fn own_inputs(&mut self, unconfirmed: Frame, remote: Option<PlayerId>) -> Vec<Message> {
+        let ack = remote.map(|r| self.ack_for(r)).unwrap_or_default();
+        let since = |inputs: &mut InputStorage, player| match remote {
+            Some(remote) => inputs.unacknowledged(remote, player, unconfirmed),
+            None => inputs.player_since_frame(player, unconfirmed),
+        };
+        let own = since(&mut self.inputs, self.local_id);
+        let uncompressed = bincode::serialized_size(&own).unwrap_or_default();
+        let own = if self.compress_inputs {
+            let compressed = input_compression::compress(&own);
+            self.input_bytes.1 += compressed.len() as u64;
+            Message::CompressedInputs {
+                inputs: compressed,
+                ack,
+            }
+        } else {
+            self.input_bytes.1 += uncompressed;
+            Message::Inputs { inputs: own, ack }
+        };
+        self.input_bytes.0 += uncompressed;
+        let guests = self.guests_of(self.local_id);
+        if guests.is_empty() {
+            return vec![own];
+        }
+        let guests = guests
+            .into_iter()
+            .map(|p| (p, since(&mut self.inputs, p)))
+            .collect();
+        vec![own, Message::GuestInputs(guests)]
+    }
+
+    /// The frame through which we have all inputs of `remote` and their guests, for them to
+    /// leave out of what they send next.
+    fn ack_for(&self, remote: PlayerId) -> BTreeMap<PlayerId, Frame> {
+        let players = std::iter::once(remote).chain(self.guests_of(remote));
+        self.inputs.received_through(players)
+    }
+
+    /// Everyone's inputs that `spoke` hasn't confirmed, besides those from their machine.
+    fn relayed_inputs(&mut self, spoke: PlayerId, unconfirmed: Frame) -> Message {
+        let players = std::iter::once(self.local_id)
+            .chain(self.player_addresses.values().copied())
+            .chain(self.guests.keys().copied())
+            .filter(|&p| p != spoke && self.guests.get(&p) != Some(&spoke))
+            .collect::<Vec<_>>();
+        let inputs = players
+            .into_iter()
+            .map(|p| (p, self.inputs.player_since_frame(p, unconfirmed)))
+            .collect();
+        Message::RelayedInputs {
+            inputs,
+            ack: self.ack_for(spoke),
+        }
+    }
+
+    fn send(&mut self, message: Message) {
+        let packets = self.encode(message);
+        let players = self.player_addresses.keys().copied().collect::<Vec<_>>();
+        for player in players {
+            for packet in &packets {
+                self.send_packet(packet, player);
+            }
+        }
+    }
+
+    fn send_to_addr(&mut self, message: Message, addr: SocketAddr) {
+        for packet in self.encode(message) {
+            self.send_packet(&packet, addr);
+        }
+    }
+
+    fn send_packet(&mut self, packet: &[u8], addr: SocketAddr) {
+        let to_spectator = self.spectators.contains_key(&addr);
+        self.outgoing.record(packet.len(), to_spectator);
+        self.socket.send(packet, addr);
+    }
+
+    /// A packet from an address that is neither a player nor a spectator, maybe a player whose
+    /// address has changed. Anything but a valid `Message::Identify` gets a challenge back, at
+    /// most once per `CHALLENGE_EVERY` for each address.
+    fn receive_from_unknown(&mut self, addr: SocketAddr, packet: &[u8]) {
+        let message = match codec::strip_header(packet) {
+            Ok((PacketKind::Whole, body)) => self.codec.decode(body).ok(),
+            _ => None,
+        };
+        match message {
+            Some(WireMessage(Message::Spectate(cookie))) if self.accept_spectators => {
+                let expected = self.spectate_cookie(addr);
+                if cookie != Some(expected) {
+                    // No bigger than the request, so a spoofed one can't be amplified.
+                    self.send_to_addr(Message::SpectateCookie(expected), addr);
+                    return;
+                }
+                match self.add_spectator(addr) {
+                    Ok(()) => {
+                        if let Some(spectator) = self.spectators.get_mut(&addr) {
+                            spectator.heard_at = Some(self.clock.now());
+                        }
+                    }
+                    Err(e) => {
+                        if let Some(suppressed) = self.log_throttle.check("spectator_rejected") {
+                            log::warn!("{}{}", e, suppressed);
+                        }
+                    }
+                }
+            }
+            Some(WireMessage(Message::Identify { player, proof })) => {
+                let proven = self.reconnection_tokens.get(&player).is_some_and(|&token| {
+                    (0..2).any(|ago| {
+                        proof == identity_proof(token, player, self.challenge_nonce(addr, ago))
+                    })
+                });
+                if !proven {
+                    self.received.unknown_sender += 1;
+                    log::warn!(
+                        "{} claimed to be player {} without proving it",
+                        addr,
+                        player
+                    );
+                    return;
+                }
+                match self.set_remote_address(player, addr) {
+                    Ok(()) => log::info!("player {} moved to {}", player, addr),
+                    Err(e) => log::warn!("could not move player {} to {}: {}", player, addr, e),
+                }
+            }
+            Some(_) if self.reconnection_tokens.is_empty() => self.received.unknown_sender += 1,
+            Some(_) => {
+                self.received.unknown_sender += 1;
+                let now = self.clock.now();
+                let due = self
+                    .challenged
+                    .peek(&addr)
+                    .is_none_or(|&at| now - at >= CHALLENGE_EVERY);
+                if due {
+                    self.challenged.put(addr, now);
+                    let nonce = self.challenge_nonce(addr, 0);
+                    self.send_to_addr(Message::WhoAreYou(nonce), addr);
+                }
+            }
+            None => {
+                self.received.unknown_sender += 1;
+                if let Some(suppressed) = self.log_throttle.check("non_player") {
+                    log::warn!("got message from non-player: {}{}", addr, suppressed);
+                }
+            }
+        }
+    }
+
+    /// The packets to send `message` in, more than one if it has to be fragmented.
+    fn encode(&mut self, message: Message) -> Vec<Vec<u8>> {
+        let body = self
+            .codec
+            .encode(&WireMessage(message))
+            .expect("failed to encode message");
+        if body.len() <= fragment::MAX_FRAGMENT {
+            return vec![codec::with_header(PacketKind::Whole, &body)];
+        }
+        if body.len() > fragment::MAX_MESSAGE {
+            log::error!("dropping {} byte message, too large to send", body.len());
+            return Vec::new();
+        }
+
+        let id = self.next_fragmented_id;
+        self.next_fragmented_id = id.wrapping_add(1);
+        let packets = fragment::split(id, &body)
+            .iter()
+            .map(|f| codec::with_header(PacketKind::Fragment, f))
+            .collect::<Vec<_>>();
+        self.outbox.keep(id, packets.clone());
+        packets
+    }
+
+    /// Resend the fragments of message `id` that `addr` is missing.
+    fn resend_fragments(&mut self, addr: SocketAddr, id: u32, missing: &[u16]) {
+        for packet in self.outbox.resend(id, missing) {
+            self.send_packet(&packet, addr);
+        }
+    }
+
+    fn process_incoming_messages(&mut self) {
+        self.fragments.expire();
+        for (addr, id, missing) in self.fragments.missing() {
+            self.send_to_addr(Message::MissingFragments { id, missing }, addr);
+        }
+        while let Some((addr, buffer)) = self.socket.recv() {
+            self.received.packets += 1;
+            let player = match self.player_addresses.get(&addr) {
+                Some(p) => Some(*p),
+                None if self.spectators.contains_key(&addr) => None,
+                None => {
+                    let packet = buffer.to_vec();
+                    self.receive_from_unknown(addr, &packet);
+                    continue;
+                }
+            };
+            let (kind, body) = match codec::strip_header(buffer) {
+                Ok(packet) => packet,
+                Err(codec::HeaderError::Version(version)) => {
+                    self.received.wrong_version += 1;
+                    if self.version_warned.insert(addr) {
+                        log::warn!(
+                            "dropping packets from {} on protocol version {}, we are on {}",
+                            addr,
+                            version,
+                            PROTOCOL_VERSION
+                        );
+                    }
+                    continue;
+                }
+                Err(codec::HeaderError::NotRbrb) => {
+                    self.received.decode_failures += 1;
+                    if let Some(suppressed) = self.log_throttle.check("decode_failure") {
+                        log::warn!("dropping packet without rbrb header{}", suppressed);
+                    }
+                    continue;
+                }
+            };
+            let reassembled;
+            let body = match kind {
+                PacketKind::Whole => body,
+                PacketKind::Fragment => match self.fragments.receive(addr, body) {
+                    Ok(Some(whole)) => {
+                        reassembled = whole;
+                        &reassembled
+                    }
+                    Ok(None) => continue,
+                    Err(fragment::MalformedFragment) => {
+                        self.received.decode_failures += 1;
+                        if let Some(suppressed) = self.log_throttle.check("decode_failure") {
+                            log::warn!("dropping malformed fragment from {}{}", addr, suppressed);
+                        }
+                        continue;
+                    }
+                },
+            };
+            let message = match self.codec.decode(body) {
+                Ok(WireMessage(m)) => m,
+                Err(e) => {
+                    self.received.decode_failures += 1;
+                    if let Some(suppressed) = self.log_throttle.check("decode_failure") {
+                        log::warn!("failed to decode message: {:?}{}", e, suppressed);
+                    }
+                    continue;
+                }
+            };
+            *self.received.messages.entry(message.kind()).or_default() += 1;
+            let player = match player {
+                Some(p) => p,
+                None => {
+                    self.receive_from_spectator(addr, message);
+                    continue;
+                }
+            };
+            if let Some(LivenessEvent::Resumed(id)) =
+                self.liveness.as_mut().and_then(|l| l.heard_from(player))
+            {
+                self.notifications
+                    .push_back(Notification::ConnectionResumed(id));
+            }
+            match message {
+                Message::Inputs { inputs, ack } => {
+                    self.inputs.acknowledge(player, ack);
+                    self.merge_remote_inputs(player, inputs);
+                }
+                Message::CompressedInputs { inputs, ack } => {
+                    self.inputs.acknowledge(player, ack);
+                    match input_compression::decompress(&inputs) {
+                        Some(map) => self.merge_remote_inputs(player, map),
+                        None => log::warn!("dropping malformed inputs from player {}", player),
+                    }
+                }
+                Message::RelayedInputs { .. } if self.relay_hub != Some(player) => {
+                    log::warn!("ignoring relayed inputs from non-hub player {}", player);
+                }
+                Message::GuestInputs(by_player) => {
+                    for (guest, map) in by_player {
+                        if self.guests.get(&guest) != Some(&player) {
+                            log::warn!("ignoring inputs for {} from player {}", guest, player);
+                            continue;
+                        }
+                        self.merge_remote_inputs(guest, map);
+                    }
+                }
+                Message::RelayedInputs { inputs, ack } => {
+                    self.inputs.acknowledge(player, ack);
+                    for (relayed, map) in inputs {
+                        let ours = relayed == self.local_id
+                            || self.guests.get(&relayed) == Some(&self.local_id);
+                        if !ours {
+                            self.merge_remote_inputs(relayed, map);
+                        }
+                    }
+                }
+                Message::AuthorityInputs {
+                    from,
+                    through,
+                    inputs,
+                } => self.take_authority_inputs(player, from, through, inputs),
+                Message::Unconfirmed(frame) => {
+                    let unc = self.remote_unconfirmed.entry(player).or_insert(frame);
+                    *unc = std::cmp::max(*unc, frame);
+                }
+                Message::Clock(m) => {
+                    self.shared_clock.receive_message(addr, m);
+                }
+                Message::ConfirmedState {
+                    frame,
+                    checksum,
+                    state,
+                } => {
+                    if !self.expects_state_from(player, frame) {
+                        if let Some(suppressed) = self.log_throttle.check("unexpected_state") {
+                            log::warn!(
+                                "ignoring confirmed state at {:?} from player {} that we didn't \
+                                 ask for{}",
+                                frame,
+                                player,
+                                suppressed
+                            );
+                        }
+                        continue;
+                    }
+                    if frame < self.inputs.retained_from() {
+                        log::warn!(
+                            "ignoring confirmed state at {:?} from player {}, inputs from then on \
+                             are no longer held to catch up from it",
+                            frame,
+                            player
+                        );
+                        continue;
+                    }
+                    self.received_state = Some(ReceivedState {
+                        from: player,
+                        frame,
+                        checksum,
+                        state,
+                        replaced: None,
+                    });
+                }
+                Message::Leaving(last_frame) => {
+                    log::info!("player {} left after {:?}", player, last_frame);
+                    for p in self.guests_of(player).into_iter().chain([player]) {
+                        self.schedule_leave(p, last_frame + 1);
+                    }
+                }
+                Message::Resumed(frame) => {
+                    log::info!("player {} resumed from {:?}", player, frame);
+                    self.remote_unconfirmed.insert(player, frame);
+                    match self.confirmed_state_message() {
+                        Some(message @ Message::ConfirmedState { frame: ours, .. })
+                            if ours > frame =>
+                        {
+                            self.send_to_addr(message, addr);
+                        }
+                        _ => {}
+                    }
+                }
+                Message::FrameAdvantage(theirs) => {
+                    self.frame_advantage.receive(player, theirs);
+                }
+                Message::InputDelay(frames) => {
+                    if let Some(negotiation) = &mut self.input_delay_negotiation {
+                        negotiation.receive(player, frames);
+                    }
+                }
+                Message::Seed(proposal) => {
+                    self.seed_proposals.insert(player, proposal);
+                }
+                Message::AgreedSeed(seed) => {
+                    if self.seed.is_none() {
+                        log::info!("took seed {:x} from player {}", seed, player);
+                        self.settle_seed(seed);
+                    }
+                }
+                Message::MissingFragments { id, missing } => {
+                    self.resend_fragments(addr, id, &missing)
+                }
+                Message::StateRequest => {
+                    if let Some(message) = self.confirmed_state_message() {
+                        self.send_to_addr(message, addr);
+                    }
+                }
+                Message::StepSize(frame, step_size) => {
+                    if frame < self.unconfirmed {
+                        log::warn!("ignoring late step size change at {:?}", frame);
+                    } else if let Err(e) = self.change_step_size(frame, step_size) {
+                        if let Some(suppressed) = self.log_throttle.check("bad_step_size") {
+                            log::warn!(
+                                "ignoring step size change from player {}: {}{}",
+                                player,
+                                e,
+                                suppressed
+                            );
+                        }
+                    }
+                }
+                Message::Pause(state) => {
+                    if self.pause.merge(state) {
+                        log::info!("{} changed the pause to {:?}", player, self.pause);
+                    }
+                }
+                Message::Membership(frame, change) => {
+                    if !self.may_announce(player, change) {
+                        if let Some(suppressed) = self.log_throttle.check("membership_spoof") {
+                            log::warn!(
+                                "ignoring {:?} from player {}, who is neither the host nor the \
+                                 player it is about{}",
+                                change,
+                                player,
+                                suppressed
+                            );
+                        }
+                        continue;
+                    }
+                    // Where a joining player is comes from them if they say so, then from what
+                    // we know already, and only then from the host's view.
+                    let change = match change {
+                        MembershipChange::Join(id, _) if id == player => {
+                            MembershipChange::Join(id, addr)
+                        }
+                        MembershipChange::Join(id, claimed) => {
+                            MembershipChange::Join(id, self.address_of(id).unwrap_or(claimed))
+                        }
+                        other => other,
+                    };
+                    if frame < self.unconfirmed - 1 {
+                        log::warn!("ignoring late membership change at {:?}", frame);
+                    } else if !self.membership.knows(frame, change) {
+                        self.schedule_membership_change(frame, change);
+                    }
+                }
+                Message::WhoAreYou(nonce) => {
+                    if let Some(secret) = self.reconnection_secret {
+                        let token = reconnection_token_for(secret, player);
+                        let identity = Message::Identify {
+                            player: self.local_id,
+                            proof: identity_proof(token, self.local_id, nonce),
+                        };
+                        self.send_to_addr(identity, addr);
+                    }
+                }
+                Message::SpectateCookie(cookie) => {
+                    if self.spectating {
+                        self.spectate_cookies.insert(player, cookie);
+                    }
+                }
+                Message::Identify { .. } | Message::Spectate(_) => {}
+                Message::Plugin { id, bytes } => {
+                    if let Some(p) = self.plugins.get_mut(&id) {
+                        p.receive(addr, bytes);
+                    } else {
+                        log::warn!("received message for unrecognized plugin: {}", id);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Take `player`'s inputs from a remote, dropping any too far ahead to be genuine.
+    fn merge_remote_inputs(&mut self, player: PlayerId, map: BTreeMap<Frame, SerializedInput>) {
+        let latest = self.latest_remote_input_frame();
+        let merged = self.inputs.merge_remote(player, map, latest);
+        if merged.out_of_range > 0 {
+            self.received.out_of_range_inputs += merged.out_of_range;
+            if let Some(suppressed) = self.log_throttle.check("out_of_range_inputs") {
+                log::warn!(
+                    "dropping {} inputs from player {} after frame {:?}{}",
+                    merged.out_of_range,
+                    player,
+                    latest,
+                    suppressed
+                );
+            }
+        }
+        self.note_misprediction(player, merged.mispredicted);
+    }
+
+    /// The latest frame remote inputs are accepted for. Remotes capture at their own clock frame,
+    /// which is synchronized with ours, so only drift and a longer input delay put them ahead of
+    /// us, by `REMOTE_INPUT_WINDOW` at most, or `max_prediction_frames` if that's more.
+    fn latest_remote_input_frame(&self) -> Frame {
+        let now = match self.shared_clock.elapsed() {
+            Some(shared) => self.pause.elapsed(shared, &self.steps),
+            None => self.steps.start_of(self.unconfirmed),
+        };
+        let (window, _) = div_duration(REMOTE_INPUT_WINDOW, self.step_size());
+        let ahead = std::cmp::max(window, self.max_prediction.unwrap_or_default());
+        self.steps.frame_at(now).into_frame() + self.local_input_delay() + ahead
+    }
+
+    fn note_misprediction(&mut self, player: PlayerId, frame: Option<Frame>) {
+        let frame = match frame {
+            Some(f) if f < self.host_frame().into_frame() => f,
+            _ => return,
+        };
+        self.mispredicted_simulation = true;
+        let earliest = self.mispredicted.entry(player).or_insert(frame);
+        *earliest = std::cmp::min(*earliest, frame);
+    }
+
+    /// Once rollback has re-simulated past a misprediction, let the game know so it can smooth
+    /// over the visual correction.
+    fn report_corrections(&mut self) {
+        let corrected = self
+            .mispredicted
+            .iter()
+            .filter(|(_, frame)| **frame < self.unconfirmed)
+            .map(|(player, frame)| (*player, *frame))
+            .collect::<Vec<_>>();
+        for (player, frame) in corrected {
+            self.mispredicted.remove(&player);
+            self.notifications
+                .push_back(Notification::PredictionCorrected(player, frame));
+        }
+    }
+
+    /// Spectators only tell us how far they have confirmed, keep their clock in sync, and come and
+    /// go.
+    fn receive_from_spectator(&mut self, addr: SocketAddr, message: Message) {
+        let spectator = self.spectators.get_mut(&addr).expect("checked by caller");
+        spectator.heard_at = Some(self.clock.now());
+        match message {
+            Message::Unconfirmed(frame) => {
+                spectator.unconfirmed = std::cmp::max(spectator.unconfirmed, frame);
+            }
+            Message::Clock(m) => {
+                self.shared_clock.receive_message(addr, m);
+            }
+            // The start was lost, or it is a spectator we were told about up front.
+            Message::Spectate(_) => self.send_spectator_start(addr),
+            Message::MissingFragments { id, missing } => self.resend_fragments(addr, id, &missing),
+            Message::Leaving(_) => {
+                let _ = self.remove_spectator(addr);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Player {
+    Local,
+    Remote(SocketAddr),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize, Hash)]
+#[serde(transparent)]
+pub struct Frame(u32);
+
+impl Frame {
+    pub fn number(self) -> u32 {
+        self.0
+    }
+}
+
+impl core::ops::Add<u32> for Frame {
+    type Output = Frame;
+    fn add(self, other: u32) -> Frame {
+        Frame(self.0 + other)
+    }
+}
+
+impl core::ops::Sub<u32> for Frame {
+    type Output = Frame;
+    fn sub(self, other: u32) -> Frame {
+        Frame(self.0 - other)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum FrameState {
+    At(Frame),
+    After(Frame, Duration),
+}
+
+impl FrameState {
+    fn into_frame(self) -> Frame {
+        match self {
+            FrameState::At(f) => f,
+            FrameState::After(f, _) => f,
+        }
+    }
+}
+
+/// How far past our clock remote inputs may be, see `Session::latest_remote_input_frame`.
+const REMOTE_INPUT_WINDOW: Duration = Duration::from_secs(1);
+
+/// How far behind the shared clock spectators run, so inputs have time to arrive before the
+/// spectator reaches their frame.
+const SPECTATOR_DELAY: Duration = Duration::from_millis(200);
+
+/// How long a spectator may go without a word before it is dropped.
+const SPECTATOR_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a spectator may go quiet and still hold back input pruning.
+const SPECTATOR_QUIET: Duration = Duration::from_secs(1);
+
+/// How long to wait for a state to be confirmed, or a requested one to arrive, before sending
+/// again.
+const STATE_RESEND: Duration = Duration::from_secs(1);
+
+/// How often an unknown address is asked to identify itself at most.
+const CHALLENGE_EVERY: Duration = Duration::from_millis(100);
+
+/// How many unknown addresses are remembered for `CHALLENGE_EVERY`. Forgetting one only lets it
+/// be challenged again sooner.
+const CHALLENGED_ADDRESSES: usize = 256;
+
+/// How long a challenge nonce stays good for, at least.
+const CHALLENGE_LIFETIME: Duration = Duration::from_secs(5);
+
+/// The token `verifier` checks the player with `secret` against when they move address, see
+/// `SessionBuilder::reconnection_token`. Each verifier gets its own, so none of them learns what
+/// would convince another.
+pub fn reconnection_token_for(secret: u64, verifier: PlayerId) -> u64 {
+    let mut hasher = seahash::SeaHasher::new();
+    secret.hash(&mut hasher);
+    verifier.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Answers a challenge `nonce` as `player`, proving the token without sending it.
+fn identity_proof(token: u64, player: PlayerId, nonce: u64) -> u64 {
+    let mut hasher = seahash::SeaHasher::new();
+    token.hash(&mut hasher);
+    player.hash(&mut hasher);
+    nonce.hash(&mut hasher);
+    hasher.finish()
+}
+
+enum Notification {
+    PlayerDisconnected(PlayerId, Frame),
+    ConnectionInterrupted(PlayerId, Duration),
+    ConnectionResumed(PlayerId),
+    AllPlayersDisconnected,
+    SessionEnded,
+    PredictionCorrected(PlayerId, Frame),
+    PredictionStalled(Frame),
+    DesyncDetected {
+        frame: Frame,
+        id: PlayerId,
+        local: u64,
+        remote: u64,
+    },
+}
+
+struct Spectator {
+    /// The last confirmed frame it reported.
+    unconfirmed: Frame,
+    /// When we last heard from it, `None` if we were told about it and it hasn't shown up yet.
+    heard_at: Option<Duration>,
+    /// The frame of the last state we sent it to start from, and when.
+    state_sent: Option<(Frame, Duration)>,
+}
+
+impl Spectator {
+    fn new() -> Self {
+        Spectator {
+            unconfirmed: Frame(0),
+            heard_at: None,
+            state_sent: None,
+        }
+    }
+
+    /// The frame it needs our inputs from.
+    fn inputs_from(&self) -> Frame {
+        match self.state_sent {
+            Some((frame, _)) => std::cmp::max(frame, self.unconfirmed),
+            None => self.unconfirmed,
+        }
+    }
+
+    /// Whether it has been heard from lately enough to hold back pruning for.
+    fn is_keeping_up(&self, now: Duration) -> bool {
+        self.heard_at.is_some_and(|at| now - at <= SPECTATOR_QUIET)
+    }
+}
+
+/// See `Session::verify_received_state`.
+struct ReceivedState {
+    from: PlayerId,
+    frame: Frame,
+    checksum: u64,
+    state: SerializedState,
+    /// The host state before loading `state`, to go back to if it doesn't match.
+    replaced: Option<SerializedState>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Message {
+    /// The sender's inputs, and the frame through which it has all inputs of each player on the
+    /// receiver's machine, so the receiver can leave those out of what it sends next. Spectators
+    /// are sent no acknowledgements.
+    Inputs {
+        inputs: BTreeMap<Frame, Vec<u8>>,
+        ack: BTreeMap<PlayerId, Frame>,
+    },
+    /// Inputs forwarded by the relay hub, attributed to the player they came from, with the
+    /// hub's acknowledgement as in `Inputs`.
+    RelayedInputs {
+        inputs: BTreeMap<PlayerId, BTreeMap<Frame, Vec<u8>>>,
+        ack: BTreeMap<PlayerId, Frame>,
+    },
+    Unconfirmed(Frame),
+    Clock(time::ClockMessage),
+    /// Asks the receiver to identify itself with this nonce, having come from an unknown address.
+    WhoAreYou(u64),
+    /// Claims the sender's address for `player`, proven by `identity_proof` of their reconnection
+    /// token and the nonce from `WhoAreYou`.
+    Identify {
+        player: PlayerId,
+        proof: u64,
+    },
+    /// The sender restarted from a snapshot confirmed through this frame, and needs inputs from
+    /// there.
+    Resumed(Frame),
+    /// `Inputs` in the encoding of `SessionBuilder::compress_inputs`.
+    CompressedInputs {
+        inputs: Vec<u8>,
+        ack: BTreeMap<PlayerId, Frame>,
+    },
+    /// Inputs of the sender's `SessionBuilder::local_players` besides its own.
+    GuestInputs(BTreeMap<PlayerId, BTreeMap<Frame, Vec<u8>>>),
+    /// Asks to watch, sent by a spectator until it has confirmed a frame. Echoes the
+    /// `SpectateCookie` the receiver gave it, once it has one.
+    Spectate(Option<u64>),
+    /// What a new spectator must echo in `Spectate` to be let in.
+    SpectateCookie(u64),
+    /// The sender quit after its input for this frame, see `Session::leave`.
+    Leaving(Frame),
+    /// The input delay the sender decided for the link to the receiver, see
+    /// `SessionBuilder::negotiate_input_delay`.
+    InputDelay(u32),
+    /// Traffic between the copies of the plugin `id` on each peer.
+    Plugin {
+        id: String,
+        bytes: Vec<u8>,
+    },
+    /// The sender's confirmed state at `frame`, and its checksum there.
+    ConfirmedState {
+        frame: Frame,
+        checksum: u64,
+        state: SerializedState,
+    },
+    /// Asks for a `ConfirmedState` to resync from.
+    StateRequest,
+    /// The fragments of message `id` that haven't arrived, to be sent again.
+    MissingFragments {
+        id: u32,
+        missing: Vec<u16>,
+    },
+    /// The sender's proposal towards the session seed.
+    Seed(u64),
+    /// The seed the sender agreed on, for a peer that may not have every proposal.
+    AgreedSeed(u64),
+    Membership(Frame, MembershipChange),
+    /// The sender's average frame advantage over the receiver.
+    FrameAdvantage(f32),
+    /// The latest decision to pause or resume the sender knows of, see `Session::pause`.
+    Pause(PauseState),
+    /// Frames are `Duration` long from this frame on, see `Session::set_step_size`.
+    StepSize(Frame, Duration),
+    /// Every player's inputs the authority confirmed `from..=through` with, starting with the one
+    /// in effect at `from`, see `SessionBuilder::authority`.
+    AuthorityInputs {
+        from: Frame,
+        through: Frame,
+        inputs: InputsByPlayer,
+    },
+}
+
+impl Message {
+    /// The variant's name, for `ReceivedPackets::messages`.
+    fn kind(&self) -> &'static str {
+        match self {
+            Message::Inputs { .. } => "Inputs",
+            Message::RelayedInputs { .. } => "RelayedInputs",
+            Message::Unconfirmed(_) => "Unconfirmed",
+            Message::Clock(_) => "Clock",
+            Message::WhoAreYou(_) => "WhoAreYou",
+            Message::Identify { .. } => "Identify",
+            Message::Resumed(_) => "Resumed",
+            Message::AuthorityInputs { .. } => "AuthorityInputs",
+            Message::CompressedInputs { .. } => "CompressedInputs",
+            Message::GuestInputs(_) => "GuestInputs",
+            Message::Spectate(_) => "Spectate",
+            Message::SpectateCookie(_) => "SpectateCookie",
+            Message::Leaving(_) => "Leaving",
+            Message::InputDelay(_) => "InputDelay",
+            Message::Plugin { .. } => "Plugin",
+            Message::ConfirmedState { .. } => "ConfirmedState",
+            Message::StateRequest => "StateRequest",
+            Message::MissingFragments { .. } => "MissingFragments",
+            Message::Seed(_) => "Seed",
+            Message::AgreedSeed(_) => "AgreedSeed",
+            Message::Membership(..) => "Membership",
+            Message::FrameAdvantage(_) => "FrameAdvantage",
+            Message::Pause(_) => "Pause",
+            Message::StepSize(..) => "StepSize",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::Signed;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn session_is_sync() {
+        #[allow(clippy::extra_unused_type_parameters)]
+        fn is_sync<T: Sync>() -> bool {
+            true
+        }
+
+        assert!(is_sync::<Session>());
+    }
+
+    #[test]
+    fn session_is_send() {
+        #[allow(clippy::extra_unused_type_parameters)]
+        fn is_send<T: Send>() -> bool {
+            true
+        }
+
+        assert!(is_send::<Session>());
+    }
+
+    #[test]
+    fn offline_needs_no_socket_and_no_remotes() {
+        let builder = || {
+            SessionBuilder::default()
+                .offline()
+                .local_player(0)
+                .step_size(Duration::from_millis(10))
+                .default_inputs(vec![0])
+        };
+        assert!(builder().start().is_ok());
+
+        let remote = SocketAddr::from(([127, 0, 0, 1], 1000));
+        let result = builder().remote_players(&[remote]).start();
+        assert!(matches!(result, Err(SessionBuildError::OfflineWithRemotes)));
+    }
+
+    #[test]
+    fn rejects_too_many_players() {
+        let remotes = (0..100)
+            .map(|p| SocketAddr::from(([127, 0, 0, 1], 1000 + p)))
+            .collect::<Vec<_>>();
+        let result = SessionBuilder::default()
+            .remote_players(&remotes)
+            .local_player(0)
+            .step_size(Duration::from_millis(10))
+            .default_inputs(vec![0])
+            .with_socket(MemoryNetwork::new().socket(remotes[0]))
+            .start();
+
+        assert!(matches!(
+            result,
+            Err(SessionBuildError::TooManyPlayers {
+                players: 101,
+                max: DEFAULT_MAX_PLAYERS
+            })
+        ));
+
+        let mut session = SessionBuilder::default()
+            .remote_players(&remotes[..1])
+            .local_player(0)
+            .max_players(2)
+            .step_size(Duration::from_millis(10))
+            .default_inputs(vec![0])
+            .with_socket(MemoryNetwork::new().socket(remotes[0]))
+            .start()
+            .unwrap();
+        assert!(session.join_player(2, remotes[1], 10).is_err());
+    }
+
+    #[test]
+    fn partial_advances_are_rolled_back() {
+        let clock = ManualClock::new();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        let mut session = SessionBuilder::default()
+            .local_player(0)
+            .step_size(Duration::from_millis(10))
+            .default_inputs(vec![0])
+            .partial_advances()
+            .with_socket(MemoryNetwork::new().socket(addr))
+            .with_clock(clock.clone())
+            .start()
+            .unwrap();
+
+        let mut simulated = Duration::ZERO;
+        let mut update = |session: &mut Session| {
+            while let ControlFlow::Continue(()) =
+                session.next_request(|request: Request<'_>| match request {
+                    Request::SaveTo(state) => *state = bincode::serialize(&simulated).unwrap(),
+                    Request::LoadFrom(state) => simulated = bincode::deserialize(state).unwrap(),
+                    Request::Advance { amount, .. } => simulated += amount,
+                    _ => {}
+                })
+            {}
+            simulated
+        };
+
+        update(&mut session);
+        for ms in [25, 10, 3, 7, 1] {
+            clock.advance(Duration::from_millis(ms));
+            assert_eq!(update(&mut session), clock.now());
+        }
+        assert_eq!(session.frame_progress().unwrap().simulated, 4);
+    }
+
+    #[derive(Default)]
+    struct Game {
+        state: u64,
+        captured: u8,
+        confirmed: BTreeMap<u32, u64>,
+        amounts: BTreeMap<u32, Duration>,
+        drift: u64,
+        desyncs: Vec<(u32, PlayerId)>,
+        stalls: u32,
+        delta_loads: u32,
+        seed: Option<u64>,
+        rollbacks: u32,
+        mispredicted_rollbacks: u32,
+        rolling_back_to: Option<u32>,
+        /// Capture the same input every frame, so it is never mispredicted.
+        steady: bool,
+        resimulated: u32,
+        guests: Vec<PlayerId>,
+        disconnected: Vec<PlayerId>,
+    }
+
+    impl Game {
+        fn handle(&mut self, id: PlayerId, request: Request<'_>) {
+            match request {
+                Request::SaveTo(state) | Request::SaveFrame { state, .. } => {
+                    *state = self.state.to_le_bytes().to_vec()
+                }
+                Request::Rollback {
+                    to_frame,
+                    mispredicted,
+                    ..
+                } => {
+                    self.rollbacks += 1;
+                    self.mispredicted_rollbacks += mispredicted as u32;
+                    self.rolling_back_to = Some(to_frame);
+                }
+                Request::LoadFrom(state) => {
+                    self.rolling_back_to = None;
+                    self.state = u64::from_le_bytes(state.try_into().unwrap())
+                }
+                Request::LoadFrame { state, frame } => {
+                    if let Some(to) = self.rolling_back_to.take() {
+                        assert_eq!(to, frame);
+                    }
+                    self.state = u64::from_le_bytes(state.try_into().unwrap())
+                }
+                Request::SaveDelta { base, out, .. } => {
+                    let base = u64::from_le_bytes(base.try_into().unwrap());
+                    *out = (self.state ^ base).to_le_bytes().to_vec();
+                }
+                Request::LoadDelta { base, delta, frame } => {
+                    if let Some(to) = self.rolling_back_to.take() {
+                        assert_eq!(to, frame);
+                    }
+                    let base = u64::from_le_bytes(base.try_into().unwrap());
+                    self.state = base ^ u64::from_le_bytes(delta.try_into().unwrap());
+                    self.delta_loads += 1;
+                }
+                Request::CaptureLocalInput(input) | Request::CaptureInput { input, .. }
+                    if self.steady =>
+                {
+                    *input = vec![0]
+                }
+                Request::CaptureLocalInput(input) => {
+                    self.captured = self.captured.wrapping_add(1);
+                    *input = vec![self.captured % 5 + id as u8];
+                }
+                Request::CaptureInput { input, player, .. } => {
+                    assert!(player == id || !self.guests.is_empty());
+                    self.captured = self.captured.wrapping_add(1);
+                    *input = vec![self.captured % 5 + player as u8];
+                }
+                Request::Advance {
+                    amount,
+                    inputs,
+                    confirmed,
+                    current_frame,
+                    resimulation,
+                } => {
+                    assert!(self.seed.is_some(), "advanced before seeding");
+                    self.resimulated += resimulation as u32;
+                    let sum = inputs
+                        .iter()
+                        .map(|(p, i)| (*p as u64 + 1) * i.as_inner()[0] as u64)
+                        .sum::<u64>();
+                    self.state = self.state.wrapping_mul(31).wrapping_add(sum + self.drift);
+                    if confirmed == Confirmation::First {
+                        self.confirmed.insert(current_frame, self.state);
+                        self.amounts.insert(current_frame, amount);
+                    }
+                }
+                Request::Seed(seed) => self.seed = Some(seed),
+                Request::DesyncDetected { frame, id, .. } => self.desyncs.push((frame, id)),
+                Request::PredictionStalled { .. } => self.stalls += 1,
+                Request::PlayerDisconnected { id, .. } => self.disconnected.push(id),
+                _ => {}
+            }
+        }
+    }
+
+    fn mesh(count: usize, network: &MemoryNetwork, clock: &ManualClock) -> Vec<(Session, Game)> {
+        mesh_with(count, network, clock, |b| b)
+    }
+
+    fn mesh_with(
+        count: usize,
+        network: &MemoryNetwork,
+        clock: &ManualClock,
+        configure: impl Fn(SessionBuilder) -> SessionBuilder,
+    ) -> Vec<(Session, Game)> {
+        mesh_by_id(count, network, clock, |_, b| configure(b))
+    }
+
+    /// Like `mesh_with`, with the id of the player being configured. Player `id` is at
+    /// 127.0.0.1:`id + 1`.
+    fn mesh_by_id(
+        count: usize,
+        network: &MemoryNetwork,
+        clock: &ManualClock,
+        configure: impl Fn(PlayerId, SessionBuilder) -> SessionBuilder,
+    ) -> Vec<(Session, Game)> {
+        let addrs = (1..=count as u16)
+            .map(|p| SocketAddr::from(([127, 0, 0, 1], p)))
+            .collect::<Vec<_>>();
+
+        (0..count)
+            .map(|id| {
+                let remotes = addrs
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| *i != id)
+                    .map(|(_, a)| *a)
+                    .collect::<Vec<_>>();
+                let builder = SessionBuilder::default()
+                    .remote_players(&remotes)
+                    .local_player(id as PlayerId)
+                    .step_size(Duration::from_millis(10))
+                    .default_inputs(vec![0])
+                    .with_socket(network.socket(addrs[id]))
+                    .with_clock(clock.clone());
+                let session = configure(id as PlayerId, builder).start().unwrap();
+                (session, Game::default())
+            })
+            .collect()
+    }
+
+    fn tick(players: &mut [(Session, Game)], network: &MemoryNetwork, clock: &ManualClock) {
+        for (session, game) in players {
+            let id = session.local_player_id();
+            while let ControlFlow::Continue(()) =
+                session.next_request(|r: Request<'_>| game.handle(id, r))
+            {}
+        }
+        clock.advance(Duration::from_millis(1));
+        network.advance(Duration::from_millis(1));
+    }
+
+    #[test]
+    fn three_players_converge() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(3, &network, &clock);
+
+        while players.iter().any(|(_, game)| game.confirmed.len() < 30) {
+            assert!(clock.now() < Duration::from_secs(10), "did not converge");
+            tick(&mut players, &network, &clock);
+        }
+
+        let (_, first) = &players[0];
+        for (_, other) in &players[1..] {
+            for (frame, state) in &other.confirmed {
+                if let Some(expected) = first.confirmed.get(frame) {
+                    assert_eq!(state, expected, "desync at frame {}", frame);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn drift_correction_cancels_an_injected_offset() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(2, &network, &clock);
+
+        while players.iter().any(|(_, game)| game.confirmed.len() < 10) {
+            assert!(clock.now() < Duration::from_secs(10), "did not start");
+            tick(&mut players, &network, &clock);
+        }
+
+        let offset = |players: &[(Session, Game)]| {
+            let elapsed = |s: &Session| s.shared_clock.signed_elapsed().unwrap();
+            (elapsed(&players[0].0) - elapsed(&players[1].0)).abs()
+        };
+        assert!(offset(&players) < Duration::from_millis(5));
+
+        players[0]
+            .0
+            .shared_clock
+            .inject_offset(Signed::Pos(Duration::from_millis(50)));
+        let injected_at = clock.now();
+        while offset(&players) >= Duration::from_millis(5) {
+            assert!(
+                clock.now() - injected_at < Duration::from_secs(10),
+                "offset still {:?}",
+                offset(&players)
+            );
+            tick(&mut players, &network, &clock);
+        }
+    }
+
+    #[test]
+    fn plays_several_players_from_one_machine() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let addrs = [1, 2].map(|p| SocketAddr::from(([127, 0, 0, 1], p)));
+        let builder = |id: usize| {
+            SessionBuilder::default()
+                .step_size(Duration::from_millis(10))
+                .default_inputs(vec![0])
+                .with_socket(network.socket(addrs[id]))
+                .with_clock(clock.clone())
+        };
+        let couch = builder(0)
+            .local_players(&[0, 2])
+            .remote_players(&[addrs[1]])
+            .start()
+            .unwrap();
+        let remote = builder(1)
+            .local_player(1)
+            .remote_players(&[addrs[0]])
+            .remote_guests(addrs[0], &[2])
+            .start()
+            .unwrap();
+        let couch_game = Game {
+            guests: vec![2],
+            ..Game::default()
+        };
+        let mut players = vec![(couch, couch_game), (remote, Game::default())];
+
+        while players.iter().any(|(_, game)| game.confirmed.len() < 30) {
+            assert!(clock.now() < Duration::from_secs(10), "did not converge");
+            tick(&mut players, &network, &clock);
+        }
+        for (session, _) in &players {
+            let ids = session.players().map(|(id, _)| id).collect::<Vec<_>>();
+            assert_eq!(ids, [0, 1, 2]);
+            assert_eq!(session.player_count(), 3);
+        }
+        let local = |session: &Session| (0..3).map(|id| session.is_local(id)).collect::<Vec<_>>();
+        assert_eq!(local(&players[0].0), [true, false, true]);
+        assert_eq!(local(&players[1].0), [false, true, false]);
+        let (first, second) = (&players[0].1, &players[1].1);
+        for (frame, state) in &second.confirmed {
+            if let Some(expected) = first.confirmed.get(frame) {
+                assert_eq!(state, expected, "desync at frame {}", frame);
+            }
+        }
+    }
+
+    #[test]
+    fn converges_with_compressed_inputs() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh_with(2, &network, &clock, |b| b.compress_inputs());
+
+        while players.iter().any(|(_, game)| game.confirmed.len() < 30) {
+            assert!(clock.now() < Duration::from_secs(10), "did not converge");
+            tick(&mut players, &network, &clock);
+        }
+        let (uncompressed, sent) = players[0].0.network_stats().input_bytes;
+        assert!(sent * 2 < uncompressed, "{} vs {}", sent, uncompressed);
+        for (frame, state) in &players[1].1.confirmed {
+            if let Some(expected) = players[0].1.confirmed.get(frame) {
+                assert_eq!(state, expected, "desync at frame {}", frame);
+            }
+        }
+    }
+
+    #[test]
+    fn players_agree_on_seed() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(3, &network, &clock);
+
+        while players.iter().any(|(_, game)| game.confirmed.is_empty()) {
+            assert!(clock.now() < Duration::from_secs(10), "did not converge");
+            tick(&mut players, &network, &clock);
+        }
+
+        let seed = players[0].1.seed;
+        assert!(seed.is_some());
+        assert!(players.iter().all(|(_, game)| game.seed == seed));
+
+        // A new match gets a new seed.
+        let (network, clock) = (MemoryNetwork::new(), ManualClock::new());
+        let mut others = mesh(3, &network, &clock);
+        while others.iter().any(|(_, game)| game.seed.is_none()) {
+            tick(&mut others, &network, &clock);
+        }
+        assert_ne!(others[0].1.seed, seed);
+    }
+
+    #[test]
+    fn latecomers_get_the_agreed_seed() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh_with(3, &network, &clock, |b| b.accept_spectators());
+        while players.iter().any(|(_, game)| game.confirmed.len() < 20) {
+            tick(&mut players, &network, &clock);
+        }
+        let seed = players[0].1.seed;
+
+        players[2].0.leave();
+        players.pop();
+        while players.iter().any(|(_, game)| game.disconnected.is_empty()) {
+            assert!(clock.now() < Duration::from_secs(5), "did not disconnect");
+            tick(&mut players, &network, &clock);
+        }
+
+        // Only players 0 and 1 are left to hear from, and a new player 2 joins in.
+        let addrs = [1, 2, 3].map(|p| SocketAddr::from(([127, 0, 0, 1], p)));
+        let join_frame = players[0].0.unconfirmed_frame() + 10;
+        players[0].0.join_player(2, addrs[2], join_frame).unwrap();
+        let mut joiner = SessionBuilder::default()
+            .remote_players(&addrs[..2])
+            .local_player(2)
+            .step_size(Duration::from_millis(10))
+            .default_inputs(vec![0])
+            .with_socket(network.socket(addrs[2]))
+            .with_clock(clock.clone())
+            .start()
+            .unwrap();
+        joiner.resync_from(0).unwrap();
+        let spectator = SessionBuilder::default()
+            .remote_players(&addrs[..2])
+            .spectator()
+            .step_size(Duration::from_millis(10))
+            .default_inputs(vec![0])
+            .with_socket(network.socket(SocketAddr::from(([127, 0, 0, 1], 4))))
+            .with_clock(clock.clone())
+            .start()
+            .unwrap();
+        players.push((joiner, Game::default()));
+        players.push((spectator, Game::default()));
+        while players[2..]
+            .iter()
+            .any(|(_, game)| game.confirmed.is_empty())
+        {
+            assert!(
+                clock.now() < Duration::from_secs(10),
+                "latecomers did not start"
+            );
+            tick(&mut players, &network, &clock);
+        }
+
+        assert!(players.iter().all(|(_, game)| game.seed == seed));
+    }
+
+    /// Player 0 of a pair, left waiting for a reconnect of up to `timeout` by player 1 leaving.
+    fn left_waiting(
+        network: &MemoryNetwork,
+        clock: &ManualClock,
+        timeout: Duration,
+    ) -> (Session, Game) {
+        let policy = AllDisconnected::WaitForReconnect(timeout);
+        let mut players = mesh_with(2, network, clock, |b| b.on_all_disconnected(policy));
+        while players.iter().any(|(_, game)| game.confirmed.len() < 20) {
+            tick(&mut players, network, clock);
+        }
+        players[1].0.leave();
+        players.pop();
+        while players[0].0.waiting_since.is_none() {
+            assert!(clock.now() < Duration::from_secs(5), "did not disconnect");
+            tick(&mut players, network, clock);
+        }
+        players.pop().unwrap()
+    }
+
+    #[test]
+    fn waits_for_a_reconnect_with_the_simulation_stopped() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = vec![left_waiting(&network, &clock, Duration::from_secs(10))];
+
+        for _ in 0..10 {
+            tick(&mut players, &network, &clock);
+        }
+        let stopped = players[0].0.clock_elapsed().unwrap();
+        for _ in 0..2000 {
+            tick(&mut players, &network, &clock);
+        }
+        assert!(players[0].0.is_paused());
+        assert_eq!(players[0].0.clock_elapsed(), Some(stopped));
+
+        let addrs = [1, 3].map(|p| SocketAddr::from(([127, 0, 0, 1], p)));
+        let join_frame = players[0].0.unconfirmed_frame() + 10;
+        players[0].0.join_player(2, addrs[1], join_frame).unwrap();
+        let mut joiner = SessionBuilder::default()
+            .remote_players(&addrs[..1])
+            .local_player(2)
+            .step_size(Duration::from_millis(10))
+            .default_inputs(vec![0])
+            .with_socket(network.socket(addrs[1]))
+            .with_clock(clock.clone())
+            .start()
+            .unwrap();
+        joiner.resync_from(0).unwrap();
+        players.push((joiner, Game::default()));
+        let joined_at = clock.now();
+        while players[1].1.confirmed.len() < 20 {
+            assert!(
+                clock.now() < joined_at + Duration::from_secs(5),
+                "joiner did not start"
+            );
+            tick(&mut players, &network, &clock);
+        }
+
+        // Carried on from where it stopped, without the two seconds waited.
+        assert!(!players[0].0.is_paused());
+        let since_join = clock.now() - joined_at;
+        let elapsed = players[0].0.clock_elapsed().unwrap();
+        assert!(elapsed <= stopped + since_join, "{:?}", elapsed - stopped);
+        let (ours, theirs) = (&players[0].1.confirmed, &players[1].1.confirmed);
+        let last = theirs
+            .keys()
+            .filter(|f| ours.contains_key(f))
+            .max()
+            .unwrap();
+        assert_eq!(ours[last], theirs[last]);
+    }
+
+    #[test]
+    fn ends_when_nobody_reconnects_in_time() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = vec![left_waiting(&network, &clock, Duration::from_secs(1))];
+        let waiting_since = clock.now();
+
+        while !players[0].0.ended {
+            assert!(
+                clock.now() < waiting_since + Duration::from_secs(2),
+                "did not give up"
+            );
+            tick(&mut players, &network, &clock);
+        }
+        assert!(clock.now() >= waiting_since + Duration::from_millis(990));
+    }
+
+    #[test]
+    fn holds_back_when_ahead_of_a_slow_peer() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(2, &network, &clock);
+
+        // Player 1 only gets to run every 30ms, so its inputs come in late.
+        for ms in 0..8000 {
+            for (session, game) in &mut players {
+                let id = session.local_player_id();
+                if id == 1 && ms % 30 != 0 {
+                    continue;
+                }
+                while let ControlFlow::Continue(()) =
+                    session.next_request(|r: Request<'_>| game.handle(id, r))
+                {}
+            }
+            clock.advance(Duration::from_millis(1));
+            network.advance(Duration::from_millis(1));
+        }
+
+        let (fast, slow) = (players[0].0.network_stats(), players[1].0.network_stats());
+        // Each makes up half the difference.
+        assert!(matches!(fast.steering, Signed::Neg(by) if by >= Duration::from_millis(10)));
+        assert!(matches!(slow.steering, Signed::Pos(by) if by >= Duration::from_millis(10)));
+
+        // Eases off once it keeps up again.
+        for _ in 0..20_000 {
+            tick(&mut players, &network, &clock);
+        }
+        let eased = players[1].0.network_stats().steering;
+        assert!(
+            eased.abs() < slow.steering.abs(),
+            "still steering {:?}",
+            eased
+        );
+    }
+
+    #[test]
+    fn plugins_can_load_a_confirmed_state() {
+        struct Loader(Option<PluginRequest>);
+        impl SessionPlugin for Loader {
+            fn id(&self) -> &str {
+                "loader"
+            }
+            fn take_requests(&mut self) -> Vec<PluginRequest> {
+                self.0.take().into_iter().collect()
+            }
+        }
+
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh_with(2, &network, &clock, |b| b.frame_numbers());
+        while players[0].1.confirmed.len() < 20 {
+            tick(&mut players, &network, &clock);
+        }
+
+        let frame = players[0].0.unconfirmed - 5;
+        let state = 1234u64.to_le_bytes().to_vec();
+        players[0].0.plugins.insert(
+            "loader".to_owned(),
+            Box::new(Loader(Some(PluginRequest::LoadConfirmed(frame, state)))),
+        );
+        let (session, game) = &mut players[0];
+        let mut loads = Vec::new();
+        while let ControlFlow::Continue(()) = session.next_request(|r: Request<'_>| {
+            if let Request::LoadFrame { state, frame } = &r {
+                loads.push((state.to_vec(), *frame));
+            }
+            game.handle(0, r)
+        }) {}
+        assert_eq!(loads[0], (1234u64.to_le_bytes().to_vec(), frame.0));
+    }
+
+    #[test]
+    fn custom_plugins_talk_to_each_other() {
+        struct Greeter {
+            remote: SocketAddr,
+            greeted: bool,
+            heard: Arc<Mutex<Vec<SocketAddr>>>,
+        }
+        impl SessionPlugin for Greeter {
+            fn id(&self) -> &str {
+                "greeter"
+            }
+            fn messages(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+                if std::mem::replace(&mut self.greeted, true) {
+                    return Vec::new();
+                }
+                vec![(self.remote, b"hello".to_vec())]
+            }
+            fn receive(&mut self, from: SocketAddr, message: Vec<u8>) {
+                assert_eq!(message, b"hello");
+                self.heard.lock().unwrap().push(from);
+            }
+        }
+
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let addrs = [1, 2].map(|p| SocketAddr::from(([127, 0, 0, 1], p)));
+        let heard = Arc::new(Mutex::new(Vec::new()));
+        let mut players = mesh_by_id(2, &network, &clock, |id, b| {
+            b.without_checksum_plugin().with_plugin(Greeter {
+                remote: addrs[1 - id as usize],
+                greeted: false,
+                heard: heard.clone(),
+            })
+        });
+
+        while players.iter().any(|(_, game)| game.confirmed.len() < 50) {
+            assert!(clock.now() < Duration::from_secs(10), "did not converge");
+            tick(&mut players, &network, &clock);
+        }
+
+        let mut heard = heard.lock().unwrap().clone();
+        heard.sort();
+        assert_eq!(heard, addrs);
+        let stats = players[0].0.network_stats();
+        assert!(stats.remote_checksums.is_empty());
+    }
+
+    #[test]
+    fn checksum_plugin_exchanges_checksums() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(2, &network, &clock);
+
+        let matched = |players: &[(Session, Game)]| {
+            let stats = players[0].0.network_stats();
+            stats
+                .remote_checksums
+                .iter()
+                .any(|&(player, frame, theirs)| {
+                    player == 1 && players[0].0.confirmed_checksum(frame) == Some(theirs)
+                })
+        };
+        while !matched(&players) {
+            assert!(
+                clock.now() < Duration::from_secs(10),
+                "no checksums exchanged"
+            );
+            tick(&mut players, &network, &clock);
+        }
+        assert!(players.iter().all(|(_, game)| game.desyncs.is_empty()));
+    }
+
+    #[test]
+    fn follows_a_peer_to_a_new_address() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let addrs = [1, 2].map(|p| SocketAddr::from(([127, 0, 0, 1], p)));
+        let mut players = mesh_by_id(2, &network, &clock, |id, b| {
+            b.reconnection_secret(100 + id as u64)
+                .reconnection_token(1 - id, reconnection_token_for(101 - id as u64, id))
+        });
+        while players.iter().any(|(_, game)| game.confirmed.len() < 20) {
+            tick(&mut players, &network, &clock);
+        }
+
+        // Someone else can't take over player 1, even knowing the token player 1 would give
+        // another player.
+        let mut spoofer = network.socket(SocketAddr::from(([127, 0, 0, 1], 66)));
+        let send = |spoofer: &mut MemorySocket, message| {
+            let packet = BincodeCodec.encode(&WireMessage(message)).unwrap();
+            spoofer.send(&codec::with_header(PacketKind::Whole, &packet), addrs[0]);
+        };
+        send(&mut spoofer, Message::Unconfirmed(Frame(0)));
+        tick(&mut players, &network, &clock);
+        let nonce = loop {
+            let (_, packet) = spoofer.recv().expect("was not challenged");
+            let (_, body) = codec::strip_header(packet).unwrap();
+            if let Ok(WireMessage(Message::WhoAreYou(nonce))) = BincodeCodec.decode(body) {
+                break nonce;
+            }
+        };
+        let elsewhere = reconnection_token_for(101, 2);
+        let claim = Message::Identify {
+            player: 1,
+            proof: identity_proof(elsewhere, 1, nonce),
+        };
+        send(&mut spoofer, claim);
+        tick(&mut players, &network, &clock);
+        assert_eq!(players[0].0.address_of(1), Some(addrs[1]));
+
+        let moved = SocketAddr::from(([127, 0, 0, 1], 3));
+        players[1].0.socket = Box::new(network.socket(moved));
+        let confirmed = players[0].1.confirmed.len();
+        while players[0].1.confirmed.len() < confirmed + 20 {
+            assert!(clock.now() < Duration::from_secs(10), "lost the moved peer");
+            tick(&mut players, &network, &clock);
+        }
+        assert_eq!(players[0].0.address_of(1), Some(moved));
+    }
+
+    #[test]
+    fn resumes_from_a_snapshot() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(2, &network, &clock);
+        while players.iter().any(|(_, game)| game.confirmed.len() < 20) {
+            tick(&mut players, &network, &clock);
+        }
+
+        let snapshot = players[1].0.snapshot().unwrap();
+        for _ in 0..50 {
+            tick(&mut players[..1], &network, &clock);
+        }
+
+        let addr = SocketAddr::from(([127, 0, 0, 1], 2));
+        let session = SessionBuilder::default()
+            .remote_players(&[SocketAddr::from(([127, 0, 0, 1], 1))])
+            .local_player(1)
+            .step_size(Duration::from_millis(10))
+            .default_inputs(vec![0])
+            .with_socket(network.socket(addr))
+            .with_clock(clock.clone())
+            .resume_from(&snapshot)
+            .start()
+            .unwrap();
+        players[1] = (session, Game::default());
+
+        let confirmed = players[0].1.confirmed.len();
+        while players
+            .iter()
+            .any(|(_, game)| game.confirmed.len() < confirmed + 20)
+        {
+            assert!(clock.now() < Duration::from_secs(10), "did not resume");
+            tick(&mut players, &network, &clock);
+        }
+        let (first, resumed) = (&players[0].1, &players[1].1);
+        let compared = resumed
+            .confirmed
+            .iter()
+            .filter_map(|(frame, state)| Some((frame, state, first.confirmed.get(frame)?)))
+            .inspect(|(frame, state, expected)| assert_eq!(state, expected, "desync at {}", frame))
+            .count();
+        assert!(compared >= 20);
+
+        let wrong_player = SessionBuilder::default()
+            .remote_players(&[addr])
+            .local_player(0)
+            .step_size(Duration::from_millis(10))
+            .default_inputs(vec![0])
+            .with_socket(network.socket(SocketAddr::from(([127, 0, 0, 1], 3))))
+            .resume_from(&snapshot)
+            .start();
+        assert!(matches!(
+            wrong_player.err(),
+            Some(SessionBuildError::BadSnapshot(_))
+        ));
+    }
+
+    #[test]
+    fn announces_rollbacks() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        // Frame numbers check each rollback loads the frame it announced.
+        let mut players = mesh_with(2, &network, &clock, |b| b.frame_numbers());
+        for (_, game) in &mut players {
+            game.steady = true;
+        }
+        while players.iter().any(|(_, game)| game.confirmed.len() < 5) {
+            tick(&mut players, &network, &clock);
+        }
+
+        // Predicting ahead of player 1, then re-simulating once their inputs arrive.
+        let stall = |players: &mut Vec<(Session, Game)>| {
+            for _ in 0..50 {
+                tick(&mut players[..1], &network, &clock);
+            }
+            let before = (players[0].1.rollbacks, players[0].1.resimulated);
+            let confirmed = players[0].1.confirmed.len();
+            while players[0].1.confirmed.len() < confirmed + 10 {
+                assert!(clock.now() < Duration::from_secs(10), "did not recover");
+                tick(players, &network, &clock);
+            }
+            assert!(players[0].1.rollbacks > before.0);
+            assert!(players[0].1.resimulated > before.1);
+        };
+
+        // Every prediction was right, so nothing changed.
+        stall(&mut players);
+        assert_eq!(players[0].1.mispredicted_rollbacks, 0);
+
+        players[1].1.steady = false;
+        stall(&mut players);
+        assert!(players[0].1.mispredicted_rollbacks > 0);
+    }
+
+    #[test]
+    fn input_delay_avoids_rollbacks() {
+        let rollbacks = |delay| {
+            let network = MemoryNetwork::with_latency(Duration::from_millis(20));
+            let clock = ManualClock::new();
+            let mut players = mesh_with(2, &network, &clock, |b| b.input_delay(delay));
+            while players.iter().any(|(_, game)| game.confirmed.len() < 200) {
+                assert!(clock.now() < Duration::from_secs(10), "did not converge");
+                tick(&mut players, &network, &clock);
+            }
+            players[0].1.rollbacks
+        };
+
+        let undelayed = rollbacks(0);
+        let delayed = rollbacks(10);
+        assert!(delayed * 4 < undelayed, "{} vs {}", delayed, undelayed);
+    }
+
+    #[test]
+    fn negotiates_input_delay_from_rtt() {
+        let network = MemoryNetwork::with_latency(Duration::from_millis(40));
+        let clock = ManualClock::new();
+        let mut players = mesh_with(3, &network, &clock, |b| b.negotiate_input_delay(6));
+        while clock.now() < Duration::from_secs(15) {
+            tick(&mut players, &network, &clock);
+        }
+
+        // 40ms each way and 50ms waiting to be sent, over 10ms frames, capped at 6.
+        let delays = players
+            .iter()
+            .map(|(session, _)| session.network_stats().input_delays)
+            .collect::<Vec<_>>();
+        for ours in &delays {
+            assert_eq!(ours, &[(0, 6), (1, 6), (2, 6)]);
+        }
+
+        let (_, first) = &players[0];
+        let (_, second) = &players[1];
+        let mut compared = 0;
+        for (frame, state) in &second.confirmed {
+            if let Some(expected) = first.confirmed.get(frame) {
+                assert_eq!(state, expected, "desync at frame {}", frame);
+                compared += 1;
+            }
+        }
+        assert!(compared > 100);
+    }
+
+    #[test]
+    fn rolls_back_to_the_first_state_when_saveless() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh_with(2, &network, &clock, |b| b.saveless_rollback());
+        while players.iter().any(|(_, game)| game.confirmed.len() < 20) {
+            tick(&mut players, &network, &clock);
+        }
+        for _ in 0..50 {
+            tick(&mut players[..1], &network, &clock);
+        }
+        while players.iter().any(|(_, game)| game.confirmed.len() < 100) {
+            assert!(clock.now() < Duration::from_secs(10), "did not converge");
+            tick(&mut players, &network, &clock);
+        }
+
+        let (session, first) = &players[0];
+        assert!(first.rollbacks > 0);
+        assert!((1..session.unconfirmed_frame()).all(|f| session.confirmed_checksum(f).is_none()));
+        for (frame, state) in &players[1].1.confirmed {
+            if let Some(expected) = first.confirmed.get(frame) {
+                assert_eq!(state, expected, "desync at frame {}", frame);
+            }
+        }
+    }
+
+    #[test]
+    fn reports_sync_progress() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(2, &network, &clock);
+
+        let state = players[0].0.sync_state();
+        assert!(state.is_measuring());
+        assert_eq!(state.rtt_samples, [(1, 0)]);
+
+        while players[0].0.sync_state().is_measuring() {
+            assert!(
+                clock.now() < Duration::from_secs(10),
+                "never proposed a start"
+            );
+            tick(&mut players, &network, &clock);
+        }
+        let state = players[0].0.sync_state();
+        assert!(state.rtt_samples[0].1 >= 3);
+        assert!(state.starts_in.unwrap() > Duration::ZERO);
+        assert!(players[0].0.is_synchronizing());
+
+        while players.iter().any(|(s, _)| s.is_synchronizing()) {
+            assert!(clock.now() < Duration::from_secs(10), "never started");
+            tick(&mut players, &network, &clock);
+        }
+        // Either end may not have heard back yet, but soon will.
+        for _ in 0..500 {
+            tick(&mut players, &network, &clock);
+        }
+        for (session, _) in &players {
+            let state = session.sync_state();
+            assert_eq!(state.starts_in, Some(Duration::ZERO));
+            assert_eq!(state.unacked, []);
+        }
+    }
+
+    #[test]
+    fn runs_on_wakes_alone() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(2, &network, &clock);
+        while players.iter().any(|(s, _)| s.is_synchronizing()) {
+            assert!(clock.now() < Duration::from_secs(10), "never started");
+            tick(&mut players, &network, &clock);
+        }
+
+        let started = clock.now();
+        while players.iter().any(|(_, game)| game.confirmed.len() < 30) {
+            assert!(
+                clock.now() < started + Duration::from_secs(2),
+                "did not progress"
+            );
+            for (session, game) in &mut players {
+                let id = session.local_player_id();
+                while let ControlFlow::Continue(()) =
+                    session.next_request(|r: Request<'_>| game.handle(id, r))
+                {}
+            }
+            let wake = players.iter().map(|(s, _)| s.next_wake()).min().unwrap();
+            assert!(wake <= Duration::from_millis(10), "{:?}", wake);
+            clock.advance(wake);
+            network.advance(wake);
+        }
+    }
+
+    #[test]
+    fn fixed_input_size_must_fit_default_inputs() {
+        let network = MemoryNetwork::new();
+        assert!(matches!(
+            SessionBuilder::default()
+                .local_player(0)
+                .step_size(Duration::from_millis(10))
+                .default_inputs(vec![0, 0])
+                .fixed_input_size(1)
+                .with_socket(network.socket(SocketAddr::from(([127, 0, 0, 1], 9))))
+                .start(),
+            Err(SessionBuildError::DefaultInputSize {
+                len: 2,
+                expected: 1
+            })
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "is not the fixed_input_size")]
+    fn fixed_input_size_catches_mismatched_captures() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh_with(2, &network, &clock, |b| b.fixed_input_size(1));
+        loop {
+            assert!(clock.now() < Duration::from_secs(10), "never captured");
+            for (session, _) in &mut players {
+                while let ControlFlow::Continue(()) = session.next_request(|r: Request<'_>| {
+                    if let Request::CaptureLocalInput(input) = r {
+                        *input = vec![1, 2];
+                    }
+                }) {}
+            }
+            clock.advance(Duration::from_millis(1));
+            network.advance(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn counts_received_and_dropped_packets() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(2, &network, &clock);
+        while players[0].1.confirmed.len() < 10 {
+            assert!(clock.now() < Duration::from_secs(10), "did not converge");
+            tick(&mut players, &network, &clock);
+        }
+
+        let to = SocketAddr::from(([127, 0, 0, 1], 1));
+        network
+            .socket(SocketAddr::from(([127, 0, 0, 1], 9)))
+            .send(b"hello", to);
+        // From player 1's address, but not from their session.
+        network
+            .socket(SocketAddr::from(([127, 0, 0, 1], 2)))
+            .send(b"hello", to);
+        // An input far past anything player 1 could have captured yet.
+        let far = BTreeMap::from([(Frame(1_000_000), vec![1])]);
+        let inputs = Message::Inputs {
+            inputs: far,
+            ack: BTreeMap::new(),
+        };
+        players[1].0.send_to_addr(inputs, to);
+        let before = players[0].0.network_stats().received;
+        tick(&mut players, &network, &clock);
+
+        let after = players[0].0.network_stats().received;
+        assert_eq!(after.unknown_sender, before.unknown_sender + 1);
+        assert_eq!(after.decode_failures, before.decode_failures + 1);
+        assert_eq!(after.out_of_range_inputs, before.out_of_range_inputs + 1);
+        assert!(after.packets >= before.packets + 2);
+        assert!(after.messages["Inputs"] > 0 && after.messages["Clock"] > 0);
+    }
+
+    #[test]
+    fn update_interpolates_between_frames() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(2, &network, &clock);
+        while players[0].1.confirmed.len() < 10 {
+            assert!(clock.now() < Duration::from_secs(10), "did not converge");
+            tick(&mut players, &network, &clock);
+        }
+
+        let (session, game) = &mut players[0];
+        let mut update = || match session.update(|r: Request<'_>| game.handle(0, r)) {
+            ControlFlow::Continue(interpolation) => interpolation,
+            ControlFlow::Break(()) => unreachable!(),
+        };
+        let before = update();
+        clock.advance(Duration::from_millis(4));
+        let after = update();
+
+        assert!((0. ..=1.).contains(&after.alpha));
+        let moved = (after.frame - before.frame) as f32 + after.alpha - before.alpha;
+        assert!((moved - 0.4).abs() < 0.05, "{:?} then {:?}", before, after);
+    }
+
+    #[test]
+    fn exposes_the_latest_local_input() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(2, &network, &clock);
+        while players[0].1.confirmed.len() < 10 {
+            assert!(clock.now() < Duration::from_secs(10), "did not converge");
+            tick(&mut players, &network, &clock);
+        }
+
+        let latest = |players: &[(Session, Game)]| vec![players[0].1.captured % 5];
+        let before = latest(&players);
+        assert_eq!(players[0].0.local_input(), Some(&before));
+
+        // Not captured for the next frame until asked to.
+        clock.advance(Duration::from_millis(10));
+        assert_eq!(players[0].0.local_input(), Some(&before));
+        tick(&mut players, &network, &clock);
+        assert_ne!(latest(&players), before);
+        assert_eq!(players[0].0.local_input(), Some(&latest(&players)));
+    }
+
+    #[test]
+    fn pauses_and_resumes_together() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(2, &network, &clock);
+        while players[0].1.confirmed.len() < 10 {
+            assert!(clock.now() < Duration::from_secs(10), "did not converge");
+            tick(&mut players, &network, &clock);
+        }
+        let latest = |players: &[(Session, Game)]| {
+            players
+                .iter()
+                .map(|(_, game)| *game.confirmed.keys().next_back().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        players[0].0.pause();
+        for _ in 0..1000 {
+            tick(&mut players, &network, &clock);
+        }
+        assert!(players.iter().all(|(session, _)| session.is_paused()));
+        let stopped = latest(&players);
+        assert_eq!(stopped[0], stopped[1]);
+        let sim_time = players[0].0.sim_time_at(Instant::now());
+        assert_eq!(sim_time, players[0].0.clock_elapsed());
+        for _ in 0..100 {
+            tick(&mut players, &network, &clock);
+        }
+        assert_eq!(latest(&players), stopped);
+        assert_eq!(players[0].0.sim_time_at(Instant::now()), sim_time);
+
+        players[1].0.step_once().unwrap();
+        for _ in 0..100 {
+            tick(&mut players, &network, &clock);
+        }
+        let stopped = stopped.iter().map(|frame| frame + 1).collect::<Vec<_>>();
+        assert_eq!(latest(&players), stopped);
+
+        // Carries on from where it stopped rather than catching up on the pause.
+        players[1].0.resume();
+        for _ in 0..300 {
+            tick(&mut players, &network, &clock);
+        }
+        assert!(players.iter().all(|(session, _)| !session.is_paused()));
+        assert!(players[0].0.step_once().is_err());
+        for frame in latest(&players) {
+            assert!((20..=31).contains(&(frame - stopped[0])), "{}", frame);
+        }
+    }
+
+    #[test]
+    fn exports_confirmed_inputs() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(2, &network, &clock);
+        while players[0].1.confirmed.len() < 20 {
+            assert!(clock.now() < Duration::from_secs(10), "did not converge");
+            tick(&mut players, &network, &clock);
+        }
+
+        let exported = players
+            .iter()
+            .map(|(session, _)| session.confirmed_inputs().collect::<BTreeMap<_, _>>())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            exported[0].keys().next_back(),
+            players[0].1.confirmed.keys().next_back()
+        );
+        for inputs in exported[0].values() {
+            assert!(inputs.iter().all(|(_, input)| input.is_confirmed()));
+        }
+
+        let ours = players[0].0.export_confirmed_inputs();
+        let theirs = players[1].0.export_confirmed_inputs();
+        assert_eq!(
+            ours.frames.keys().collect::<Vec<_>>(),
+            exported[0].keys().collect::<Vec<_>>()
+        );
+        for (frame, inputs) in &ours.frames {
+            if let Some(theirs) = theirs.frames.get(frame) {
+                assert_eq!(inputs, theirs);
+            }
+        }
+    }
+
+    #[test]
+    fn step_size_changes_together() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(2, &network, &clock);
+        while players[0].1.confirmed.len() < 10 {
+            assert!(clock.now() < Duration::from_secs(10), "did not converge");
+            tick(&mut players, &network, &clock);
+        }
+
+        let (fast, slow) = (Duration::from_millis(10), Duration::from_millis(20));
+        assert!(players[1].0.set_step_size(Duration::ZERO).is_err());
+        players[1].0.set_step_size(slow).unwrap();
+        for _ in 0..1000 {
+            tick(&mut players, &network, &clock);
+        }
+        assert!(players
+            .iter()
+            .all(|(session, _)| session.step_size() == slow));
+        let (first, second) = (&players[0].1, &players[1].1);
+        assert!(first.desyncs.is_empty() && second.desyncs.is_empty());
+        for (frame, state) in &first.confirmed {
+            if let Some(theirs) = second.confirmed.get(frame) {
+                assert_eq!(state, theirs, "frame {}", frame);
+            }
+        }
+
+        let amounts = |game: &Game| game.amounts.values().copied().collect::<Vec<_>>();
+        for game in [first, second] {
+            let change = amounts(game).iter().position(|a| *a == slow).unwrap();
+            assert!(amounts(game)[..change].iter().all(|a| *a == fast));
+            assert!(amounts(game)[change..].iter().all(|a| *a == slow));
+        }
+        let change_at = |game: &Game| {
+            game.amounts
+                .iter()
+                .find(|(_, a)| **a == slow)
+                .map(|(f, _)| *f)
+        };
+        assert_eq!(change_at(first), change_at(second));
+
+        // A second of the new step size is fifty frames rather than a hundred.
+        let before = *first.confirmed.keys().next_back().unwrap();
+        for _ in 0..1000 {
+            tick(&mut players, &network, &clock);
+        }
+        let after = *players[0].1.confirmed.keys().next_back().unwrap();
+        assert!((45..=55).contains(&(after - before)), "{}", after - before);
+        // Nonsense from a remote is ignored rather than trusted.
+        let to = SocketAddr::from(([127, 0, 0, 1], 1));
+        let frame = players[0].0.unconfirmed + 10;
+        for step in [Duration::ZERO, Duration::MAX] {
+            players[1]
+                .0
+                .send_to_addr(Message::StepSize(frame, step), to);
+        }
+        for _ in 0..100 {
+            tick(&mut players, &network, &clock);
+        }
+        assert_eq!(players[0].0.step_size(), slow);
+    }
+
+    #[test]
+    fn input_source_replaces_capturing() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let script = |player: PlayerId, frame: u32| vec![(frame % 7) as u8 + player as u8];
+        let mut players = mesh_with(2, &network, &clock, |b| b.input_source(script));
+        while players.iter().any(|(_, game)| game.confirmed.len() < 20) {
+            assert!(clock.now() < Duration::from_secs(10), "did not converge");
+            tick(&mut players, &network, &clock);
+        }
+
+        assert!(players.iter().all(|(_, game)| game.captured == 0));
+        let (session, game) = &players[1];
+        let frame = *game.confirmed.keys().next_back().unwrap();
+        assert_eq!(
+            session.inputs.player_at(0, Frame(frame)),
+            Some(&script(0, frame))
+        );
+    }
+
+    #[test]
+    fn leaving_disconnects_promptly() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(3, &network, &clock);
+        while players.iter().any(|(_, game)| game.confirmed.len() < 20) {
+            tick(&mut players, &network, &clock);
+        }
+
+        players[2].0.leave();
+        let confirmed = players[0].1.confirmed.len();
+        while players[..2]
+            .iter()
+            .any(|(_, game)| game.disconnected.is_empty())
+        {
+            assert!(clock.now() < Duration::from_secs(5), "did not disconnect");
+            tick(&mut players[..2], &network, &clock);
+        }
+        while players[0].1.confirmed.len() < confirmed + 20 {
+            assert!(clock.now() < Duration::from_secs(5), "did not continue");
+            tick(&mut players[..2], &network, &clock);
+        }
+        for (_, game) in &players[..2] {
+            assert_eq!(game.disconnected, [2]);
+        }
+        for (frame, state) in &players[1].1.confirmed {
+            if let Some(expected) = players[0].1.confirmed.get(frame) {
+                assert_eq!(state, expected, "desync at frame {}", frame);
+            }
+        }
+
+        let before = players[2].1.confirmed.len();
+        tick(&mut players[2..], &network, &clock);
+        assert_eq!(players[2].1.confirmed.len(), before);
+    }
+
+    #[test]
+    fn leaves_at_the_announced_frame_everywhere() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(3, &network, &clock);
+        while players.iter().any(|(_, game)| game.confirmed.len() < 20) {
+            tick(&mut players, &network, &clock);
+        }
+
+        // Announced ahead of inputs the others don't have yet.
+        let last_frame = players[2].0.unconfirmed + 10;
+        for port in [1, 2] {
+            let to = SocketAddr::from(([127, 0, 0, 1], port));
+            players[2].0.send_to_addr(Message::Leaving(last_frame), to);
+        }
+        while players[..2]
+            .iter()
+            .any(|(session, _)| session.inputs.last_frame(2) < Some(last_frame))
+        {
+            tick(&mut players, &network, &clock);
+        }
+        while players[..2]
+            .iter()
+            .any(|(_, game)| game.disconnected.is_empty())
+        {
+            assert!(clock.now() < Duration::from_secs(5), "did not disconnect");
+            tick(&mut players[..2], &network, &clock);
+        }
+
+        for (session, _) in &players[..2] {
+            let membership = &session.membership;
+            assert!(membership.players_at(last_frame).contains(&2));
+            assert!(!membership.players_at(last_frame + 1).contains(&2));
+        }
+        let confirmed = players[0].1.confirmed.len();
+        while players[0].1.confirmed.len() < confirmed + 20 {
+            assert!(clock.now() < Duration::from_secs(5), "did not continue");
+            tick(&mut players[..2], &network, &clock);
+        }
+        for (frame, state) in &players[1].1.confirmed {
+            if let Some(expected) = players[0].1.confirmed.get(frame) {
+                assert_eq!(state, expected, "desync at frame {}", frame);
+            }
+        }
+    }
+
+    #[test]
+    fn only_the_host_or_the_player_changes_membership() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(3, &network, &clock);
+        while players.iter().any(|(_, game)| game.confirmed.len() < 20) {
+            tick(&mut players, &network, &clock);
+        }
+        let addrs = [1, 2, 3].map(|p| SocketAddr::from(([127, 0, 0, 1], p)));
+
+        // Player 2 is neither the host nor who these are about.
+        let frame = players[1].0.unconfirmed + 5;
+        let stranger = SocketAddr::from(([127, 0, 0, 1], 9));
+        for change in [
+            MembershipChange::Leave(0),
+            MembershipChange::Join(7, stranger),
+        ] {
+            players[2]
+                .0
+                .send_to_addr(Message::Membership(frame, change), addrs[1]);
+        }
+        tick(&mut players, &network, &clock);
+        assert_eq!(players[1].0.membership.pending().count(), 0);
+        assert_eq!(players[1].0.address_of(0), Some(addrs[0]));
+
+        // The host drops player 2, who stops responding. The others keep their address until the
+        // leave is confirmed.
+        players[0].0.disconnect_player(2).unwrap();
+        while players[1].0.membership.pending().count() == 0 {
+            assert!(clock.now() < Duration::from_secs(5), "leave not shared");
+            tick(&mut players[..2], &network, &clock);
+        }
+        assert_eq!(players[1].0.address_of(2), Some(addrs[2]));
+        while players[..2]
+            .iter()
+            .any(|(_, game)| game.disconnected.is_empty())
+        {
+            assert!(clock.now() < Duration::from_secs(5), "did not disconnect");
+            tick(&mut players[..2], &network, &clock);
+        }
+        let confirmed = players[0].1.confirmed.len();
+        while players[0].1.confirmed.len() < confirmed + 20 {
+            assert!(clock.now() < Duration::from_secs(5), "did not continue");
+            tick(&mut players[..2], &network, &clock);
+        }
+        for (session, game) in &players[..2] {
+            assert_eq!(game.disconnected, [2]);
+            assert_eq!(session.address_of(2), None);
+        }
+        for (frame, state) in &players[1].1.confirmed {
+            if let Some(expected) = players[0].1.confirmed.get(frame) {
+                assert_eq!(state, expected, "desync at frame {}", frame);
+            }
+        }
+    }
+
+    #[test]
+    fn spectators_drop_in_and_out() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh_with(2, &network, &clock, |b| b.accept_spectators());
+        while players.iter().any(|(_, game)| game.confirmed.len() < 200) {
+            tick(&mut players, &network, &clock);
+        }
+
+        let addrs = [1, 2].map(|p| SocketAddr::from(([127, 0, 0, 1], p)));
+        let spectator = SessionBuilder::default()
+            .remote_players(&addrs)
+            .spectator()
+            .step_size(Duration::from_millis(10))
+            .default_inputs(vec![0])
+            .with_socket(network.socket(SocketAddr::from(([127, 0, 0, 1], 3))))
+            .with_clock(clock.clone())
+            .start()
+            .unwrap();
+        players.push((spectator, Game::default()));
+        while players[2].1.confirmed.len() < 50 {
+            assert!(
+                clock.now() < Duration::from_secs(10),
+                "spectator did not follow"
+            );
+            tick(&mut players, &network, &clock);
+        }
+        assert!(*players[2].1.confirmed.keys().next().unwrap() > 100);
+        for (frame, state) in &players[2].1.confirmed {
+            assert_eq!(Some(state), players[0].1.confirmed.get(frame));
+        }
+        assert_eq!(players[0].0.spectators().count(), 1);
+
+        players[2].0.leave();
+        let confirmed = players[0].1.confirmed.len();
+        while players[0].1.confirmed.len() < confirmed + 50 {
+            tick(&mut players, &network, &clock);
+        }
+        assert_eq!(players[0].0.spectators().count(), 0);
+        assert_eq!(players[1].0.spectators().count(), 0);
+    }
+
+    #[test]
+    fn spectators_echo_a_cookie_and_keep_in_touch() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh_with(2, &network, &clock, |b| b.accept_spectators());
+        while players.iter().any(|(_, game)| game.confirmed.len() < 50) {
+            tick(&mut players, &network, &clock);
+        }
+
+        let host = SocketAddr::from(([127, 0, 0, 1], 1));
+        let mut watcher = network.socket(SocketAddr::from(([127, 0, 0, 1], 9)));
+        let spectate = |cookie| {
+            let body = BincodeCodec
+                .encode(&WireMessage(Message::Spectate(cookie)))
+                .unwrap();
+            codec::with_header(PacketKind::Whole, &body)
+        };
+
+        // Anyone could have sent this, so it only gets a cookie back, no state or inputs.
+        watcher.send(&spectate(None), host);
+        tick(&mut players, &network, &clock);
+        let (_, packet) = watcher.recv().expect("no cookie");
+        let (_, body) = codec::strip_header(packet).unwrap();
+        let cookie = match BincodeCodec.decode(body).unwrap() {
+            WireMessage(Message::SpectateCookie(c)) => c,
+            other => panic!("expected a cookie, got {:?}", other),
+        };
+        assert!(watcher.recv().is_none());
+        assert_eq!(players[0].0.spectators().count(), 0);
+
+        watcher.send(&spectate(Some(cookie ^ 1)), host);
+        tick(&mut players, &network, &clock);
+        assert_eq!(players[0].0.spectators().count(), 0);
+
+        watcher.send(&spectate(Some(cookie)), host);
+        tick(&mut players, &network, &clock);
+        assert_eq!(players[0].0.spectators().count(), 1);
+
+        // Then never heard from again.
+        let joined = clock.now();
+        while players[0].0.spectators().count() > 0 {
+            assert!(
+                clock.now() < joined + SPECTATOR_TIMEOUT + Duration::from_secs(1),
+                "silent spectator was kept"
+            );
+            tick(&mut players, &network, &clock);
+        }
+    }
+
+    #[test]
+    fn quiet_spectators_do_not_hold_back_pruning() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let absent = SocketAddr::from(([127, 0, 0, 1], 9));
+        let mut players = mesh_with(2, &network, &clock, |b| {
+            b.spectators(&[absent]).accept_spectators()
+        });
+        while players.iter().any(|(_, game)| game.confirmed.len() < 300) {
+            tick(&mut players, &network, &clock);
+        }
+        assert!(players[0].0.input_buffer_len() < 100);
+
+        let addrs = [1, 2].map(|p| SocketAddr::from(([127, 0, 0, 1], p)));
+        let spectator = SessionBuilder::default()
+            .remote_players(&addrs)
+            .spectator()
+            .step_size(Duration::from_millis(10))
+            .default_inputs(vec![0])
+            .with_socket(network.socket(SocketAddr::from(([127, 0, 0, 1], 3))))
+            .with_clock(clock.clone())
+            .start()
+            .unwrap();
+        players.push((spectator, Game::default()));
+        while players[2].1.confirmed.len() < 50 {
+            assert!(
+                clock.now() < Duration::from_secs(10),
+                "spectator never started"
+            );
+            tick(&mut players, &network, &clock);
+        }
+
+        // Stalls for longer than it holds pruning back, then comes back.
+        for _ in 0..3000 {
+            tick(&mut players[..2], &network, &clock);
+        }
+        assert!(players[0].0.input_buffer_len() < 100);
+        let confirmed = players[2].1.confirmed.len();
+        while players[2].1.confirmed.len() < confirmed + 50 {
+            assert!(
+                clock.now() < Duration::from_secs(20),
+                "spectator did not catch up"
+            );
+            tick(&mut players, &network, &clock);
+        }
+        assert_eq!(players[0].0.spectators().count(), 2);
+        for (frame, state) in &players[2].1.confirmed {
+            assert_eq!(Some(state), players[0].1.confirmed.get(frame));
+        }
+    }
+
+    #[test]
+    fn limits_and_accounts_for_spectators() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh_with(2, &network, &clock, |b| b.max_spectators(1));
+        let [first, second] = [3, 4].map(|p| SocketAddr::from(([127, 0, 0, 1], p)));
+        let _watching = network.socket(first);
+
+        assert_eq!(players[0].0.add_spectator(first), Ok(()));
+        assert!(players[0].0.add_spectator(second).is_err());
+        while clock.now() < Duration::from_secs(2) {
+            tick(&mut players, &network, &clock);
+        }
+
+        let host = players[0].0.network_stats();
+        assert!(host.spectator_outgoing.0 > 0 && host.player_outgoing.0 > 0);
+        let other = players[1].0.network_stats();
+        assert_eq!(other.spectator_outgoing.0, 0);
+    }
+
+    #[test]
+    fn verifies_confirmed_advances() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh_with(2, &network, &clock, |b| b.verify_confirmed_advances());
+
+        let mut detected = Vec::new();
+        let mut advances = 0u64;
+        while players.iter().any(|(_, game)| game.confirmed.len() < 50) {
+            assert!(clock.now() < Duration::from_secs(10), "did not converge");
+            for (i, (session, game)) in players.iter_mut().enumerate() {
+                let id = session.local_player_id();
+                while let ControlFlow::Continue(()) = session.next_request(|r: Request<'_>| {
+                    match r {
+                        Request::NondeterministicAdvance { offset, .. } => {
+                            detected.push((i, offset))
+                        }
+                        Request::Advance { .. } if i == 1 && game.confirmed.len() >= 20 => {
+                            // Leaks how many times it has advanced into the state.
+                            advances += 1;
+                            game.drift = advances;
+                            game.handle(id, r);
+                        }
+                        r => game.handle(id, r),
+                    }
+                }) {}
+            }
+            clock.advance(Duration::from_millis(1));
+            network.advance(Duration::from_millis(1));
+        }
+
+        assert!(!detected.is_empty());
+        assert!(detected.iter().all(|&(i, offset)| i == 1 && offset < 8));
+    }
+
+    #[test]
+    fn reports_remote_confirmed_frame() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(2, &network, &clock);
+        assert_eq!(players[0].0.remote_confirmed_frame(1), None);
+        while players.iter().any(|(_, game)| game.confirmed.len() < 20) {
+            tick(&mut players, &network, &clock);
+        }
+
+        // Paused, confirmation stops where the simulation does, and the reports catch up.
+        players[0].0.pause();
+        for _ in 0..1000 {
+            tick(&mut players, &network, &clock);
+        }
+        for (us, them) in [(0, 1), (1, 0)] {
+            assert_eq!(
+                players[us].0.remote_confirmed_frame(them as PlayerId),
+                Some(players[them].0.unconfirmed_frame() - 2)
+            );
+        }
+    }
+
+    #[test]
+    fn reports_confirmation_of_frames() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(2, &network, &clock);
+        while players.iter().any(|(_, game)| game.confirmed.len() < 5) {
+            tick(&mut players, &network, &clock);
+        }
+        for _ in 0..100 {
+            tick(&mut players[..1], &network, &clock);
+        }
+
+        let session = &players[0].0;
+        let unconfirmed = session.unconfirmed_frame();
+        let simulated = session.frame_progress().unwrap().simulated;
+        assert!(simulated > unconfirmed);
+        assert_eq!(
+            session.confirmation_of(unconfirmed - 2),
+            Some(Confirmation::Subsequent)
+        );
+        // Still waiting on player 1's input to advance from the latest confirmed state.
+        assert_eq!(
+            session.confirmation_of(unconfirmed - 1),
+            Some(Confirmation::Unconfirmed)
+        );
+        assert_eq!(
+            session.confirmation_of(simulated - 1),
+            Some(Confirmation::Unconfirmed)
+        );
+        assert_eq!(session.confirmation_of(simulated), None);
+    }
+
+    #[test]
+    fn reports_every_blocking_player() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(3, &network, &clock);
+
+        while players.iter().any(|(_, game)| game.confirmed.len() < 5) {
+            assert!(clock.now() < Duration::from_secs(10), "did not start");
+            tick(&mut players, &network, &clock);
+        }
+        assert_eq!(players[0].0.blocking_players(), Vec::<PlayerId>::new());
+
+        for _ in 0..100 {
+            tick(&mut players[..1], &network, &clock);
+        }
+        assert_eq!(players[0].0.blocking_players(), vec![1, 2]);
+    }
+
+    #[test]
+    fn detects_desync_and_resyncs() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(2, &network, &clock);
+
+        while players.iter().any(|(_, game)| game.confirmed.len() < 10) {
+            tick(&mut players, &network, &clock);
+        }
+        players[1].1.drift = 1;
+        while players[1].1.desyncs.is_empty() {
+            assert!(clock.now() < Duration::from_secs(10), "desync not detected");
+            tick(&mut players, &network, &clock);
+        }
+        assert_eq!(players[1].1.desyncs[0].1, 0);
+
+        players[1].1.drift = 0;
+        players[1].0.resync_from(0).unwrap();
+        for _ in 0..200 {
+            tick(&mut players, &network, &clock);
+        }
+        for (_, game) in &mut players {
+            game.desyncs.clear();
+        }
+        for _ in 0..2000 {
+            tick(&mut players, &network, &clock);
+        }
+        assert!(players.iter().all(|(_, game)| game.desyncs.is_empty()));
+
+        let (ours, theirs) = (&players[0].1.confirmed, &players[1].1.confirmed);
+        let last = ours
+            .keys()
+            .filter(|f| theirs.contains_key(f))
+            .max()
+            .unwrap();
+        assert_eq!(ours[last], theirs[last]);
+    }
+
+    #[test]
+    fn asks_again_for_a_state_to_diff_that_goes_missing() {
+        /// Loses the first state that arrives to diff against.
+        struct LosesFirstState {
+            inner: Box<dyn SessionPlugin>,
+            states: Arc<Mutex<u32>>,
+        }
+        impl SessionPlugin for LosesFirstState {
+            fn id(&self) -> &str {
+                self.inner.id()
+            }
+            fn on_confirmed_frame(&mut self, frame: Frame, serialized: &[u8]) {
+                self.inner.on_confirmed_frame(frame, serialized)
+            }
+            fn on_confirmed_checksum(&mut self, frame: Frame, checksum: u64) {
+                self.inner.on_confirmed_checksum(frame, checksum)
+            }
+            fn take_requests(&mut self) -> Vec<PluginRequest> {
+                self.inner.take_requests()
+            }
+            fn messages(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+                self.inner.messages()
+            }
+            fn receive(&mut self, from: SocketAddr, message: Vec<u8>) {
+                if let Ok(crate::plugin::Message::State(..)) = bincode::deserialize(&message) {
+                    let mut states = self.states.lock().unwrap();
+                    *states += 1;
+                    if *states == 1 {
+                        return;
                     }
                 }
+                self.inner.receive(from, message)
             }
         }
+
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh_with(2, &network, &clock, |b| b.diff_desynced_states());
+        let states = Arc::new(Mutex::new(0));
+        let id = "warn_remote_mismatched_checksum".to_owned();
+        let inner = players[0].0.plugins.remove(&id).unwrap();
+        let plugin = LosesFirstState {
+            inner,
+            states: Arc::clone(&states),
+        };
+        players[0].0.plugins.insert(id, Box::new(plugin));
+
+        while players.iter().any(|(_, game)| game.confirmed.len() < 10) {
+            tick(&mut players, &network, &clock);
+        }
+        players[1].1.drift = 1;
+        while *states.lock().unwrap() < 2 {
+            assert!(
+                clock.now() < Duration::from_secs(10),
+                "state not asked for again"
+            );
+            tick(&mut players, &network, &clock);
+        }
+        assert!(!players[0].1.desyncs.is_empty());
+
+        // Once it arrives, the state isn't asked for any more.
+        for _ in 0..2000 {
+            tick(&mut players, &network, &clock);
+        }
+        assert_eq!(*states.lock().unwrap(), 2);
     }
-}
 
-#[derive(Debug)]
-pub enum Player {
-    Local,
-    Remote(SocketAddr),
-}
+    #[test]
+    fn only_adopts_confirmed_states_it_asked_for() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(2, &network, &clock);
+        while players.iter().any(|(_, game)| game.confirmed.len() < 20) {
+            tick(&mut players, &network, &clock);
+        }
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize, Hash)]
-#[serde(transparent)]
-pub struct Frame(u32);
+        let to = SocketAddr::from(([127, 0, 0, 1], 1));
+        let bogus = 42u64.to_le_bytes().to_vec();
+        let frame = players[0].0.unconfirmed + 5;
+        let unasked = Message::ConfirmedState {
+            frame,
+            checksum: seahash::hash(&bogus),
+            state: bogus.clone(),
+        };
+        players[1].0.send_to_addr(unasked, to);
+        for _ in 0..500 {
+            tick(&mut players, &network, &clock);
+        }
+        assert!(players.iter().all(|(_, game)| game.desyncs.is_empty()));
 
-impl core::ops::Add<u32> for Frame {
-    type Output = Frame;
-    fn add(self, other: u32) -> Frame {
-        Frame(self.0 + other)
+        // Asked for, but not what its checksum says, so the real answer after it is taken instead.
+        players[0].0.resync_from(1).unwrap();
+        let mismatched = Message::ConfirmedState {
+            frame: players[0].0.unconfirmed,
+            checksum: 7,
+            state: bogus,
+        };
+        players[1].0.send_to_addr(mismatched, to);
+        for _ in 0..500 {
+            tick(&mut players, &network, &clock);
+        }
+        assert_eq!(players[0].0.resyncing_from, None);
+        assert!(players.iter().all(|(_, game)| game.desyncs.is_empty()));
+
+        let (ours, theirs) = (&players[0].1.confirmed, &players[1].1.confirmed);
+        let compared = ours
+            .iter()
+            .filter_map(|(frame, state)| Some((frame, state, theirs.get(frame)?)))
+            .inspect(|(frame, state, expected)| assert_eq!(state, expected, "desync at {}", frame))
+            .count();
+        assert!(compared >= 20);
     }
-}
 
-impl core::ops::Sub<u32> for Frame {
-    type Output = Frame;
-    fn sub(self, other: u32) -> Frame {
-        Frame(self.0 - other)
+    #[test]
+    fn resyncs_a_large_state_over_a_lossy_link() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(2, &network, &clock);
+        for (i, (session, _)) in players.iter_mut().enumerate() {
+            let lossy = BadSocketBuilder::default()
+                .drop_chance(0.1)
+                .latency(Latency::Fixed(Duration::ZERO))
+                .seed(i as u64);
+            let addr = SocketAddr::from(([127, 0, 0, 1], i as u16 + 1));
+            session.socket = Box::new(lossy.build(network.socket(addr)));
+        }
+
+        // States are padded out to a hundred or so fragments, the game's own being the first 8
+        // bytes.
+        let run = |players: &mut [(Session, Game)]| {
+            for (session, game) in players.iter_mut() {
+                let id = session.local_player_id();
+                while let ControlFlow::Continue(()) = session.next_request(|r: Request<'_>| match r
+                {
+                    Request::SaveTo(state) => {
+                        game.handle(id, Request::SaveTo(state));
+                        state.resize(100_000, 7);
+                    }
+                    Request::LoadFrom(state) => game.handle(id, Request::LoadFrom(&state[..8])),
+                    r => game.handle(id, r),
+                }) {}
+            }
+            clock.advance(Duration::from_millis(1));
+            network.advance(Duration::from_millis(1));
+        };
+        while players.iter().any(|(_, game)| game.confirmed.len() < 20) {
+            assert!(clock.now() < Duration::from_secs(10), "did not start");
+            run(&mut players);
+        }
+
+        // Asked for once, with what goes missing on the way asked for again.
+        players[0].0.resync_from(1).unwrap();
+        while players[0].0.resyncing_from.is_some() {
+            assert!(clock.now() < Duration::from_secs(10), "state never arrived");
+            run(&mut players);
+        }
+        assert!(players.iter().all(|(_, game)| game.desyncs.is_empty()));
     }
-}
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
-enum FrameState {
-    At(Frame),
-    After(Frame, Duration),
-}
+    #[test]
+    fn reports_round_trip_times() {
+        let network = MemoryNetwork::with_latency(Duration::from_millis(20));
+        let clock = ManualClock::new();
+        let mut players = mesh(2, &network, &clock);
 
-impl FrameState {
-    fn into_frame(self) -> Frame {
-        match self {
-            FrameState::At(f) => f,
-            FrameState::After(f, _) => f,
+        for _ in 0..2000 {
+            tick(&mut players, &network, &clock);
         }
+        let rtt = players[0].0.player_rtt(1).expect("no rtt");
+        assert!(rtt >= Duration::from_millis(40) && rtt < Duration::from_millis(50));
+        assert_eq!(players[0].0.player_rtt(0), None);
+
+        let stats = players[1].0.network_stats();
+        let (id, link) = stats.players[0];
+        assert_eq!((id, stats.players.len()), (0, 1));
+        assert!(link.worst_rtt >= link.average_rtt && link.rtt_samples >= 5);
+        assert_eq!(link.loss, Some(0.));
     }
-}
 
-#[derive(Serialize, Deserialize, Debug)]
-enum Message {
-    Inputs(BTreeMap<Frame, Vec<u8>>),
-    Unconfirmed(Frame),
-    Clock(time::ClockMessage),
-    Plugin(String, Vec<u8>),
-}
+    #[test]
+    fn input_buffer_stays_bounded() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh(2, &network, &clock);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let mut largest = 0;
+        while players
+            .iter()
+            .any(|(_, game)| game.confirmed.len() < 10_000)
+        {
+            for (session, game) in &mut players {
+                let id = session.local_player_id();
+                while let ControlFlow::Continue(()) =
+                    session.next_request(|r: Request<'_>| game.handle(id, r))
+                {}
+            }
+            clock.advance(Duration::from_millis(10));
+            network.advance(Duration::from_millis(10));
+            largest = std::cmp::max(largest, players[0].0.input_buffer_len());
+        }
+
+        // Every frame's input differs from the last, so without pruning this would be ~20k.
+        assert!(largest < 100, "held {} inputs", largest);
+    }
 
     #[test]
-    fn session_is_sync() {
-        fn is_sync<T: Sync>() -> bool {
-            true
+    fn stops_predicting_at_the_barrier() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh_with(2, &network, &clock, |b| b.max_prediction_frames(8));
+
+        while players.iter().any(|(_, game)| game.confirmed.len() < 5) {
+            assert!(clock.now() < Duration::from_secs(10), "did not start");
+            tick(&mut players, &network, &clock);
+        }
+        for _ in 0..500 {
+            tick(&mut players[..1], &network, &clock);
         }
 
-        assert!(is_sync::<Session>());
+        let (session, game) = &players[0];
+        let progress = session.frame_progress().unwrap();
+        assert_eq!(progress.simulated, session.unconfirmed.0 + 8);
+        assert!(progress.behind() > 0);
+        assert_eq!(game.stalls, 1);
+        assert_eq!(session.network_stats().prediction_stalls, 1);
     }
 
     #[test]
-    fn session_is_send() {
-        fn is_send<T: Send>() -> bool {
-            true
+    fn rolls_back_through_deltas() {
+        let run = |delta_saves: bool| {
+            let network = MemoryNetwork::with_latency(Duration::from_millis(40));
+            let clock = ManualClock::new();
+            let mut players = mesh_with(2, &network, &clock, |b| match delta_saves {
+                true => b.compress_snapshots(2).delta_saves(8),
+                false => b.compress_snapshots(2),
+            });
+
+            while players.iter().any(|(_, game)| game.confirmed.len() < 100) {
+                assert!(clock.now() < Duration::from_secs(10), "did not converge");
+                tick(&mut players, &network, &clock);
+            }
+            players
+                .into_iter()
+                .map(|(_, game)| game)
+                .collect::<Vec<_>>()
+        };
+
+        let (full, deltas) = (run(false), run(true));
+        assert!(deltas.iter().all(|game| game.delta_loads > 0));
+        for (full, deltas) in full.iter().zip(&deltas) {
+            for frame in 0..100 {
+                assert_eq!(
+                    deltas.confirmed.get(&frame),
+                    full.confirmed.get(&frame),
+                    "frame {}",
+                    frame
+                );
+            }
         }
+    }
 
-        assert!(is_send::<Session>());
+    #[test]
+    fn fills_absent_inputs_with_the_default() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let mut players = mesh_with(2, &network, &clock, |b| b.fill_absent_inputs());
+        let session = &mut players[0].0;
+        session.set_local_input(1, vec![1]);
+
+        let inputs = session.inputs(Frame(1)).unwrap();
+        assert_eq!(
+            inputs.get(&0),
+            Some(&ConfirmationStatus::Confirmed(vec![1]))
+        );
+        assert_eq!(
+            inputs.get(&1),
+            Some(&ConfirmationStatus::Unconfirmed(vec![0]))
+        );
+    }
+
+    #[test]
+    fn everyone_confirms_the_inputs_the_authority_confirmed() {
+        let network = MemoryNetwork::with_latency(Duration::from_millis(40));
+        let clock = ManualClock::new();
+        let mut players = mesh_with(3, &network, &clock, |b| b.authority(0));
+
+        while players.iter().any(|(_, game)| game.confirmed.len() < 100) {
+            assert!(clock.now() < Duration::from_secs(10), "did not converge");
+            tick(&mut players, &network, &clock);
+        }
+
+        let authority = &players[0].1.confirmed;
+        for (_, game) in &players[1..] {
+            for (frame, state) in &game.confirmed {
+                assert_eq!(authority.get(frame), Some(state), "at frame {}", frame);
+            }
+        }
+    }
+
+    #[test]
+    fn lockstep_never_predicts() {
+        let network = MemoryNetwork::with_latency(Duration::from_millis(40));
+        let clock = ManualClock::new();
+        let mut players = mesh_with(2, &network, &clock, |b| b.partial_advances().lockstep());
+
+        let mut advances = 0;
+        while players.iter().any(|(_, game)| game.confirmed.len() < 50) {
+            assert!(clock.now() < Duration::from_secs(10), "did not converge");
+            for (session, game) in &mut players {
+                let id = session.local_player_id();
+                while let ControlFlow::Continue(()) = session.next_request(|r: Request<'_>| {
+                    match &r {
+                        Request::LoadFrom(_) => panic!("rolled back in lockstep"),
+                        Request::Advance { confirmed, .. } => {
+                            assert_eq!(*confirmed, Confirmation::First);
+                            advances += 1;
+                        }
+                        _ => {}
+                    }
+                    game.handle(id, r)
+                }) {}
+            }
+            clock.advance(Duration::from_millis(1));
+            network.advance(Duration::from_millis(1));
+        }
+        assert!(advances >= 100);
+        let (first, second) = (&players[0].1.confirmed, &players[1].1.confirmed);
+        assert!((0..50).all(|frame| first.get(&frame) == second.get(&frame)));
     }
 }
@@ -0,0 +1,107 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use crate::{time::ClockHandle, PlayerId};
+
+/// Tracks when each remote player was last heard from, to tell a brief network blip apart from
+/// a player who is gone for good.
+pub(crate) struct Liveness {
+    interrupted_after: Duration,
+    disconnect_after: Duration,
+    last_heard: HashMap<PlayerId, Duration>,
+    interrupted: HashSet<PlayerId>,
+    clock: ClockHandle,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum LivenessEvent {
+    Interrupted(PlayerId, Duration),
+    Resumed(PlayerId),
+    TimedOut(PlayerId),
+}
+
+impl Liveness {
+    pub fn new(
+        interrupted_after: Duration,
+        disconnect_after: Duration,
+        clock: ClockHandle,
+    ) -> Self {
+        Liveness {
+            interrupted_after,
+            disconnect_after,
+            last_heard: Default::default(),
+            interrupted: Default::default(),
+            clock,
+        }
+    }
+
+    pub fn heard_from(&mut self, player: PlayerId) -> Option<LivenessEvent> {
+        self.last_heard.insert(player, self.clock.now());
+        if self.interrupted.remove(&player) {
+            Some(LivenessEvent::Resumed(player))
+        } else {
+            None
+        }
+    }
+
+    /// Players we have never heard from are timed from their first check.
+    pub fn check(&mut self, players: impl IntoIterator<Item = PlayerId>) -> Vec<LivenessEvent> {
+        let mut events = Vec::new();
+        for player in players {
+            let now = self.clock.now();
+            let since = now - *self.last_heard.entry(player).or_insert(now);
+
+            if since > self.disconnect_after {
+                self.forget(player);
+                events.push(LivenessEvent::TimedOut(player));
+            } else if since > self.interrupted_after && self.interrupted.insert(player) {
+                events.push(LivenessEvent::Interrupted(player, since));
+            }
+        }
+        events
+    }
+
+    pub fn forget(&mut self, player: PlayerId) {
+        self.last_heard.remove(&player);
+        self.interrupted.remove(&player);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::ManualClock;
+    use std::sync::Arc;
+
+    #[test]
+    fn blip_is_interrupted_then_resumed() {
+        let clock = ManualClock::new();
+        let mut liveness = Liveness::new(
+            Duration::from_millis(5),
+            Duration::from_secs(60),
+            Arc::new(clock.clone()),
+        );
+        liveness.check([1]);
+        clock.advance(Duration::from_millis(10));
+
+        assert!(matches!(
+            liveness.check([1]).as_slice(),
+            [LivenessEvent::Interrupted(1, _)]
+        ));
+        assert_eq!(liveness.check([1]), vec![]);
+        assert_eq!(liveness.heard_from(1), Some(LivenessEvent::Resumed(1)));
+        assert_eq!(liveness.heard_from(1), None);
+    }
+
+    #[test]
+    fn silence_times_out() {
+        let clock = ManualClock::new();
+        let mut liveness = Liveness::new(Duration::ZERO, Duration::ZERO, Arc::new(clock.clone()));
+        liveness.check([1]);
+        clock.advance(Duration::from_millis(1));
+
+        assert_eq!(liveness.check([1]), vec![LivenessEvent::TimedOut(1)]);
+    }
+}
@@ -0,0 +1,233 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use crate::{Frame, PlayerId};
+
+/// A change to the set of players, agreed to take effect at a specific frame.
+///
+/// The variant order is significant: when several changes take effect on the same frame, all
+/// leaves are applied before any joins, and ties are broken by `PlayerId`. This way a slot that
+/// is vacated and refilled on the same frame ends up occupied on every peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub(crate) enum MembershipChange {
+    Leave(PlayerId),
+    Join(PlayerId, SocketAddr),
+}
+
+/// What a session does once every remote player has left.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AllDisconnected {
+    /// Stop the session, issuing `Request::SessionEnded`.
+    End,
+    /// Keep simulating with only the local player, confirming frames on local input alone.
+    #[default]
+    ContinueSolo,
+    /// Pause the simulation until a player joins, ending the session if none does in time. The
+    /// simulation time stops too, as with `Session::pause`, so play continues from where it
+    /// stopped rather than catching up on the wait.
+    WaitForReconnect(Duration),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Tenure {
+    joined: Frame,
+    left: Option<Frame>,
+}
+
+impl Tenure {
+    fn contains(&self, frame: Frame) -> bool {
+        self.joined <= frame && self.left.is_none_or(|left| frame < left)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Membership {
+    tenures: BTreeMap<PlayerId, Vec<Tenure>>,
+    pending: BTreeMap<Frame, BTreeSet<MembershipChange>>,
+    /// Changes already taken, kept to share with peers that may not have heard of them until
+    /// `forget_before`.
+    taken: BTreeMap<Frame, BTreeSet<MembershipChange>>,
+}
+
+impl Membership {
+    pub fn from_start(players: impl IntoIterator<Item = PlayerId>) -> Self {
+        Membership {
+            tenures: players
+                .into_iter()
+                .map(|p| {
+                    let tenure = Tenure {
+                        joined: Frame(0),
+                        left: None,
+                    };
+                    (p, vec![tenure])
+                })
+                .collect(),
+            pending: Default::default(),
+            taken: Default::default(),
+        }
+    }
+
+    pub fn schedule(&mut self, frame: Frame, change: MembershipChange) {
+        self.pending.entry(frame).or_default().insert(change);
+    }
+
+    pub fn pending(&self) -> impl Iterator<Item = (Frame, MembershipChange)> + '_ {
+        self.pending
+            .iter()
+            .flat_map(|(f, changes)| changes.iter().map(move |c| (*f, *c)))
+    }
+
+    /// Changes taking effect at or after `frame`, whether taken yet or not.
+    pub fn changes_from(
+        &self,
+        frame: Frame,
+    ) -> impl Iterator<Item = (Frame, MembershipChange)> + '_ {
+        self.taken
+            .range(frame..)
+            .chain(self.pending.range(frame..))
+            .flat_map(|(f, changes)| changes.iter().map(move |c| (*f, *c)))
+    }
+
+    /// Whether `change` at `frame` is pending or was taken and not yet forgotten.
+    pub fn knows(&self, frame: Frame, change: MembershipChange) -> bool {
+        [&self.pending, &self.taken]
+            .iter()
+            .any(|changes| changes.get(&frame).is_some_and(|c| c.contains(&change)))
+    }
+
+    /// Stop keeping taken changes from before `frame`, once every peer is past them.
+    pub fn forget_before(&mut self, frame: Frame) {
+        self.taken = self.taken.split_off(&frame);
+    }
+
+    /// Removes and returns every change taking effect at or before `frame`, in the order they
+    /// must be applied.
+    pub fn take_through(&mut self, frame: Frame) -> Vec<(Frame, MembershipChange)> {
+        let later = self.pending.split_off(&(frame + 1));
+        let due = std::mem::replace(&mut self.pending, later);
+
+        let due = due
+            .into_iter()
+            .flat_map(|(f, changes)| changes.into_iter().map(move |c| (f, c)))
+            .collect::<Vec<_>>();
+        for (frame, change) in &due {
+            self.record(*frame, *change);
+            self.taken.entry(*frame).or_default().insert(*change);
+        }
+        due
+    }
+
+    fn record(&mut self, frame: Frame, change: MembershipChange) {
+        match change {
+            MembershipChange::Leave(player) => {
+                let current = self
+                    .tenures
+                    .get_mut(&player)
+                    .and_then(|t| t.last_mut())
+                    .filter(|t| t.left.is_none());
+                if let Some(tenure) = current {
+                    tenure.left = Some(frame);
+                }
+            }
+            MembershipChange::Join(player, _) => {
+                let tenures = self.tenures.entry(player).or_default();
+                if tenures.last().is_none_or(|t| t.left.is_some()) {
+                    tenures.push(Tenure {
+                        joined: frame,
+                        left: None,
+                    });
+                }
+            }
+        }
+    }
+
+    pub fn players_at(&self, frame: Frame) -> BTreeSet<PlayerId> {
+        self.tenures
+            .iter()
+            .filter(|(_, tenures)| tenures.iter().any(|t| t.contains(frame)))
+            .map(|(p, _)| *p)
+            .collect()
+    }
+
+    pub fn remote_count_at(&self, frame: Frame) -> usize {
+        self.players_at(frame).len().saturating_sub(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn apply_all(changes: &[(Frame, MembershipChange)]) -> BTreeSet<PlayerId> {
+        let mut membership = Membership::from_start([0, 1, 2]);
+        for (frame, change) in changes {
+            membership.schedule(*frame, *change);
+        }
+        membership.take_through(Frame(10));
+        membership.players_at(Frame(10))
+    }
+
+    #[test]
+    fn simultaneous_join_and_leave_agree() {
+        let leave = (Frame(5), MembershipChange::Leave(2));
+        let join = (Frame(5), MembershipChange::Join(3, addr(7003)));
+
+        let a = apply_all(&[leave, join]);
+        let b = apply_all(&[join, leave]);
+        assert_eq!(a, b);
+        assert_eq!(a, [0, 1, 3].into());
+    }
+
+    #[test]
+    fn rejoining_the_vacated_slot_on_the_same_frame() {
+        let leave = (Frame(5), MembershipChange::Leave(2));
+        let rejoin = (Frame(5), MembershipChange::Join(2, addr(8000)));
+
+        let a = apply_all(&[leave, rejoin]);
+        let b = apply_all(&[rejoin, leave]);
+        assert_eq!(a, b);
+        assert_eq!(a, [0, 1, 2].into());
+    }
+
+    #[test]
+    fn membership_before_change_is_unaffected() {
+        let mut membership = Membership::from_start([0, 1, 2]);
+        membership.schedule(Frame(5), MembershipChange::Leave(2));
+        membership.take_through(Frame(5));
+
+        assert_eq!(membership.players_at(Frame(4)), [0, 1, 2].into());
+        assert_eq!(membership.players_at(Frame(5)), [0, 1].into());
+    }
+
+    #[test]
+    fn only_takes_due_changes() {
+        let mut membership = Membership::from_start([0, 1]);
+        membership.schedule(Frame(5), MembershipChange::Leave(1));
+        membership.schedule(Frame(8), MembershipChange::Join(2, addr(7002)));
+
+        assert_eq!(membership.take_through(Frame(6)).len(), 1);
+        assert_eq!(membership.pending().count(), 1);
+    }
+
+    #[test]
+    fn keeps_taken_changes_until_forgotten() {
+        let mut membership = Membership::from_start([0, 1]);
+        let leave = MembershipChange::Leave(1);
+        membership.schedule(Frame(5), leave);
+        membership.take_through(Frame(5));
+
+        assert!(membership.knows(Frame(5), leave));
+        assert_eq!(membership.changes_from(Frame(5)).count(), 1);
+        membership.forget_before(Frame(6));
+        assert!(!membership.knows(Frame(5), leave));
+        assert_eq!(membership.changes_from(Frame(0)).count(), 0);
+    }
+}
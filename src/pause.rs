@@ -0,0 +1,187 @@
+//! Pausing every peer together, see `Session::pause`.
+//!
+//! The simulation time is the shared clock less an offset. Pausing picks a frame a little ahead
+//! of where the pauser is, far enough that the others hear of it before reaching it, and the
+//! simulation time stops there. Resuming picks a shared clock time a round trip out and continues
+//! from the paused frame once the shared clock reaches it, which fixes the new offset. The shared
+//! clock itself never stops, so it stays synchronized throughout, and every peer derives the same
+//! offset from the same decision however late it hears of it.
+//!
+//! A peer that hasn't heard of a pause yet carries on predicting past the paused frame, and on
+//! hearing of it stops where it is. Its simulation is only ahead in time, not wrong: inputs are
+//! per frame, so nothing is rolled back, and it waits at its frame for the others after the
+//! resume. A peer that hears of a resume late catches up the frames it missed, as after any
+//! stall.
+//!
+//! The latest decision is resent until a second after the resume, so a lost message is recovered
+//! from. Pauses are numbered, and a peer adopts any pause newer than its own. Within a pause,
+//! decisions only ever move it on, so they merge whatever order they arrive in: two peers pausing
+//! at once merge to the earlier frame, steps while paused are counted and the larger count wins,
+//! and either resuming resumes both, from a frame later for a step that raced the resume.
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::{step_schedule::StepSchedule, Frame};
+
+/// How long after a resume the decision is still resent.
+const ANNOUNCE_AFTER_RESUME: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct PauseState {
+    /// How many times the session has been paused.
+    generation: u32,
+    /// How far the simulation time is behind the shared clock from pauses before this one.
+    offset: Duration,
+    /// Where the simulation time stopped when paused, before any steps.
+    frame: Option<Frame>,
+    /// How many frames the pause has been stepped on by.
+    stepped: u32,
+    /// The shared clock time the simulation continues from `frame` at.
+    resume_at: Option<Duration>,
+}
+
+impl PauseState {
+    /// Where the simulation time stops, once paused.
+    fn stop_frame(&self) -> Option<Frame> {
+        Some(self.frame? + self.stepped)
+    }
+
+    /// The simulation time at `shared` clock time.
+    pub fn elapsed(&self, shared: Duration, steps: &StepSchedule) -> Duration {
+        let running = shared.saturating_sub(self.offset);
+        match (self.stop_frame(), self.resume_at) {
+            (Some(frame), Some(at)) if shared >= at => steps.start_of(frame) + (shared - at),
+            (Some(frame), _) => running.min(steps.start_of(frame)),
+            (None, _) => running,
+        }
+    }
+
+    /// Whether the simulation is stopped, or will stop once it reaches the paused frame.
+    pub fn is_paused(&self, shared: Duration) -> bool {
+        self.frame.is_some() && self.resume_at.is_none_or(|at| shared < at)
+    }
+
+    /// Stop at `frame`. Returns `false` if already paused.
+    pub fn pause(&mut self, frame: Frame, shared: Duration, steps: &StepSchedule) -> bool {
+        if self.is_paused(shared) {
+            return false;
+        }
+        if let (Some(paused), Some(at)) = (self.stop_frame(), self.resume_at) {
+            self.offset = at.saturating_sub(steps.start_of(paused));
+        }
+        self.generation += 1;
+        self.frame = Some(frame);
+        self.stepped = 0;
+        self.resume_at = None;
+        true
+    }
+
+    /// Continue at `shared` clock time, or once the simulation has reached the paused frame if
+    /// that is later. Returns `false` if not paused.
+    pub fn resume(&mut self, at: Duration, steps: &StepSchedule) -> bool {
+        let frame = match (self.stop_frame(), self.resume_at) {
+            (Some(frame), None) => frame,
+            _ => return false,
+        };
+        self.resume_at = Some(at.max(self.offset + steps.start_of(frame)));
+        true
+    }
+
+    /// Move the paused frame on by one. Returns `false` if not paused.
+    pub fn step(&mut self) -> bool {
+        match (self.frame, self.resume_at) {
+            (Some(_), None) => {
+                self.stepped += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Adopt a remote's decision. Returns whether anything changed.
+    pub fn merge(&mut self, theirs: PauseState) -> bool {
+        let before = *self;
+        if theirs.generation > self.generation {
+            *self = theirs;
+        } else if theirs.generation == self.generation {
+            self.frame = self.frame.min(theirs.frame).or(theirs.frame);
+            self.stepped = self.stepped.max(theirs.stepped);
+            self.resume_at = self.resume_at.max(theirs.resume_at);
+        }
+        *self != before
+    }
+
+    /// Whether the decision still needs resending at `shared` clock time.
+    pub fn should_announce(&self, shared: Duration) -> bool {
+        self.frame.is_some()
+            && self
+                .resume_at
+                .is_none_or(|at| shared < at + ANNOUNCE_AFTER_RESUME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steps() -> StepSchedule {
+        StepSchedule::new(Duration::from_millis(10))
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn stops_at_the_frame_and_continues_from_it() {
+        let mut state = PauseState::default();
+        assert!(state.pause(Frame(10), ms(95), &steps()));
+        assert_eq!(state.elapsed(ms(98), &steps()), ms(98));
+        assert_eq!(state.elapsed(ms(500), &steps()), ms(100));
+
+        assert!(state.resume(ms(1000), &steps()));
+        assert_eq!(state.elapsed(ms(999), &steps()), ms(100));
+        assert_eq!(state.elapsed(ms(1005), &steps()), ms(105));
+        assert!(!state.is_paused(ms(1005)));
+
+        // The second pause builds on the time lost to the first.
+        assert!(state.pause(Frame(20), ms(1050), &steps()));
+        assert_eq!(state.elapsed(ms(1090), &steps()), ms(190));
+        assert_eq!(state.elapsed(ms(1200), &steps()), ms(200));
+    }
+
+    #[test]
+    fn simultaneous_pauses_merge() {
+        let mut ours = PauseState::default();
+        let mut theirs = PauseState::default();
+        ours.pause(Frame(12), ms(100), &steps());
+        theirs.pause(Frame(11), ms(100), &steps());
+
+        assert!(ours.merge(theirs));
+        assert!(!theirs.merge(ours));
+        assert_eq!(ours, theirs);
+
+        theirs.resume(ms(300), &steps());
+        assert!(ours.merge(theirs));
+        assert_eq!(ours.elapsed(ms(310), &steps()), ms(120));
+    }
+
+    #[test]
+    fn a_step_racing_a_resume_still_resumes() {
+        let mut resumer = PauseState::default();
+        resumer.pause(Frame(10), ms(50), &steps());
+        let mut stepper = resumer;
+
+        assert!(resumer.resume(ms(300), &steps()));
+        assert!(stepper.step());
+        assert!(resumer.merge(stepper));
+        assert!(stepper.merge(resumer));
+        assert_eq!(resumer, stepper);
+
+        // Resumed, a frame on from where the resume was decided.
+        assert!(!stepper.is_paused(ms(300)));
+        assert_eq!(stepper.elapsed(ms(299), &steps()), ms(110));
+        assert_eq!(stepper.elapsed(ms(305), &steps()), ms(115));
+    }
+}
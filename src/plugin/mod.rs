@@ -1,17 +1,64 @@
 use std::net::SocketAddr;
 
-use crate::Frame;
+use crate::{Frame, SerializedState};
 
 mod warn_remote_mismatched_checksum;
 pub use warn_remote_mismatched_checksum::*;
 
+/// A confirmed frame whose checksum differs between us and `remote`.
+pub struct ChecksumMismatch {
+    pub frame: Frame,
+    pub remote: SocketAddr,
+    pub local: u64,
+    pub theirs: u64,
+}
+
+/// Something a plugin asks of the session. Requests are taken right after incoming messages are
+/// processed, so they are acted on before any of the session's own loads, saves or advances in
+/// the same `next_request` pass, and after everything it had already issued.
+pub enum PluginRequest {
+    /// Tell the game, as `Request::DesyncDetected`.
+    DesyncDetected(ChecksumMismatch),
+    /// Adopt `state` as the confirmed state at `frame` and load it with `Request::LoadFrom`, as
+    /// if it had arrived from a peer. Later frames are re-simulated from the inputs we have.
+    LoadConfirmed(Frame, SerializedState),
+    /// Ask `remote` for its latest confirmed state, like `Session::resync_from`.
+    ResyncFrom(SocketAddr),
+}
+
+/// Extra behaviour that runs alongside a session, added with `SessionBuilder::with_plugin`.
+///
+/// A plugin can talk to the same plugin on the remotes: `messages` is polled every time the
+/// session sends, and whatever arrives for it is handed to `receive`. Incoming bytes come straight
+/// off the network, so `receive` must not trust them.
 pub trait SessionPlugin: Send + Sync + 'static {
+    /// Identifies the plugin, both among a session's plugins and to the remotes' copies of it.
     fn id(&self) -> &str;
 
+    /// A confirmed state saved whole. States saved as deltas only get `on_confirmed_checksum`.
     fn on_confirmed_frame(&mut self, _frame: Frame, _serialized: &[u8]) {}
 
+    /// The checksum from `Request::ChecksumState`, or of the serialized state if the handler
+    /// hashed nothing.
+    fn on_confirmed_checksum(&mut self, _frame: Frame, _checksum: u64) {}
+
+    fn on_address_changed(&mut self, _old: SocketAddr, _new: SocketAddr) {}
+
+    /// The latest checksum each remote has reported, for plugins that track them.
+    fn remote_checksums(&self) -> Vec<(SocketAddr, Frame, u64)> {
+        Vec::new()
+    }
+
+    /// Requests for the session made since the last call, see `PluginRequest`.
+    fn take_requests(&mut self) -> Vec<PluginRequest> {
+        Vec::new()
+    }
+
+    /// Messages to send to the remotes' copies of this plugin.
     fn messages(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
         Vec::new()
     }
+
+    /// A message sent by `from`'s copy of this plugin.
     fn receive(&mut self, _from: SocketAddr, _message: Vec<u8>) {}
 }
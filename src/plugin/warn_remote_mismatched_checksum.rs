@@ -1,42 +1,94 @@
 use lru::LruCache;
 use serde::*;
-use std::{collections::BTreeMap, net::SocketAddr, time::Duration};
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::SocketAddr,
+    time::Duration,
+};
 
-use super::SessionPlugin;
-use crate::{Frame, Interval};
+use super::{ChecksumMismatch, PluginRequest, SessionPlugin};
+use crate::{
+    time::{ClockHandle, Interval},
+    Frame,
+};
 
 type ChecksumCache = LruCache<Frame, u64>;
 
+/// How many recent confirmed states to keep for diffing, see `with_state_diffs`.
+const DIFFED_STATES: usize = 32;
+
+/// At most this many differing runs are reported per diff, each cut to `DIFF_CHUNK` bytes.
+const DIFF_CHUNKS: usize = 16;
+const DIFF_CHUNK: usize = 64;
+
 pub struct WarnRemoteMismatchedChecksum {
     addrs: Vec<SocketAddr>,
     checksums: ChecksumCache,
     remote_checksums: BTreeMap<SocketAddr, ChecksumCache>,
+    latest_remote: BTreeMap<SocketAddr, (Frame, u64)>,
+    requests: Vec<PluginRequest>,
     send_every: Interval,
+    /// Recent whole states, kept only when diffing.
+    states: Option<LruCache<Frame, Vec<u8>>>,
+    /// Remotes we have asked for a state to diff, which is done once each.
+    diffed: HashSet<SocketAddr>,
+    /// The state asked of each remote and our own at that frame, kept past `DIFFED_STATES` and
+    /// asked for again every `send_every` until theirs arrives.
+    awaiting_states: BTreeMap<SocketAddr, (Frame, Vec<u8>)>,
+    /// The state last sent to each remote, sent again when it asks again after losing it.
+    answered: BTreeMap<SocketAddr, (Frame, Vec<u8>)>,
+    outgoing: Vec<(SocketAddr, Message)>,
 }
 
 impl WarnRemoteMismatchedChecksum {
-    pub fn with_addrs(addrs: impl IntoIterator<Item = SocketAddr>) -> Self {
+    pub fn with_addrs(addrs: impl IntoIterator<Item = SocketAddr>, clock: &ClockHandle) -> Self {
         WarnRemoteMismatchedChecksum {
             addrs: addrs.into_iter().collect(),
             checksums: LruCache::new(1024),
             remote_checksums: BTreeMap::default(),
-            send_every: Interval::new(Duration::from_millis(500)),
+            latest_remote: BTreeMap::default(),
+            requests: Vec::new(),
+            send_every: Interval::new(Duration::from_millis(500), clock),
+            states: None,
+            diffed: HashSet::new(),
+            awaiting_states: BTreeMap::new(),
+            answered: BTreeMap::new(),
+            outgoing: Vec::new(),
         }
     }
 
+    /// On the first mismatch with each remote, exchange the whole confirmed states and log where
+    /// they differ. Keeps the most recent states in memory and sends a whole state over the
+    /// network, so it is meant for debugging. A state that goes missing on the way is asked for
+    /// again until it arrives. Only states saved whole can be diffed, which with
+    /// `SessionBuilder::delta_saves` means keyframes.
+    pub fn with_state_diffs(mut self) -> Self {
+        self.states = Some(LruCache::new(DIFFED_STATES));
+        self
+    }
+
     fn typed_messages(&mut self) -> Vec<(SocketAddr, Message)> {
+        let mut messages = std::mem::take(&mut self.outgoing);
         if !self.send_every.is_time() {
-            return Vec::new();
+            return messages;
         }
 
+        messages.extend(
+            self.awaiting_states
+                .iter()
+                .map(|(remote, (frame, _))| (*remote, Message::StateRequest(*frame))),
+        );
+
         let (frame, checksum) = match self.checksums.iter().max_by_key(|(&frame, _)| frame) {
             Some(l) => l,
-            None => return Vec::new(),
+            None => return messages,
         };
-        self.addrs
-            .iter()
-            .map(|a| (*a, Message::FrameChecksum(*frame, *checksum)))
-            .collect()
+        messages.extend(
+            self.addrs
+                .iter()
+                .map(|a| (*a, Message::FrameChecksum(*frame, *checksum))),
+        );
+        messages
     }
 
     fn check_frame_match(&mut self, frame: Frame) {
@@ -51,18 +103,121 @@ impl WarnRemoteMismatchedChecksum {
                 None => continue,
             };
 
-            assert_eq!(
-                *ours, theirs,
-                "Checksum mismatch for frame {:?} with remote {}",
-                frame, remote
+            if *ours != theirs {
+                log::warn!(
+                    "checksum mismatch for frame {:?} with remote {}: {:x} != {:x}",
+                    frame,
+                    remote,
+                    ours,
+                    theirs
+                );
+                self.requests
+                    .push(PluginRequest::DesyncDetected(ChecksumMismatch {
+                        frame,
+                        remote: *remote,
+                        local: *ours,
+                        theirs,
+                    }));
+
+                let state = self.states.as_mut().and_then(|s| s.get(&frame));
+                if let Some(state) = state.filter(|_| self.diffed.insert(*remote)) {
+                    self.outgoing.push((*remote, Message::StateRequest(frame)));
+                    self.awaiting_states.insert(*remote, (frame, state.clone()));
+                }
+            }
+        }
+    }
+
+    fn diff_state(&mut self, from: SocketAddr, frame: Frame, theirs: &[u8]) {
+        let ours = match self.awaiting_states.remove(&from) {
+            Some((asked, ours)) if asked == frame => ours,
+            Some(other) => {
+                self.awaiting_states.insert(from, other);
+                return;
+            }
+            None => return,
+        };
+        let differences = diff_bytes(&ours, theirs);
+        let first = match differences.first() {
+            Some(d) => d,
+            None => {
+                log::warn!(
+                    "states at {:?} with remote {} are identical despite differing checksums, \
+                     does the checksum hash something the state doesn't serialize?",
+                    frame,
+                    from
+                );
+                return;
+            }
+        };
+        log::warn!(
+            "state at {:?} with remote {} first differs at byte {} of {}/{}: ours {:02x?}, theirs \
+             {:02x?}",
+            frame,
+            from,
+            first.offset,
+            ours.len(),
+            theirs.len(),
+            first.local,
+            first.remote
+        );
+        for d in &differences[1..] {
+            log::debug!(
+                "state at {:?} also differs at byte {}: ours {:02x?}, theirs {:02x?}",
+                frame,
+                d.offset,
+                d.local,
+                d.remote
             );
         }
     }
 }
 
+/// A run of bytes that differs between two serialized states.
+#[derive(Debug, PartialEq, Eq)]
+struct ByteDifference {
+    offset: usize,
+    local: Vec<u8>,
+    remote: Vec<u8>,
+}
+
+/// The runs where `local` and `remote` differ, including whatever one has past the end of the
+/// other, up to `DIFF_CHUNKS` runs of at most `DIFF_CHUNK` bytes.
+fn diff_bytes(local: &[u8], remote: &[u8]) -> Vec<ByteDifference> {
+    let common = local.len().min(remote.len());
+    let mut differences = Vec::new();
+    let mut at = 0;
+    while at < common && differences.len() < DIFF_CHUNKS {
+        if local[at] == remote[at] {
+            at += 1;
+            continue;
+        }
+        let len = (at..common).take_while(|&i| local[i] != remote[i]).count();
+        let shown = len.min(DIFF_CHUNK);
+        differences.push(ByteDifference {
+            offset: at,
+            local: local[at..at + shown].to_vec(),
+            remote: remote[at..at + shown].to_vec(),
+        });
+        at += len;
+    }
+    if local.len() != remote.len() && differences.len() < DIFF_CHUNKS {
+        let tail = |bytes: &[u8]| bytes[common..].iter().take(DIFF_CHUNK).copied().collect();
+        differences.push(ByteDifference {
+            offset: common,
+            local: tail(local),
+            remote: tail(remote),
+        });
+    }
+    differences
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum Message {
     FrameChecksum(Frame, u64),
+    /// Asks for the whole confirmed state at this frame, to diff against.
+    StateRequest(Frame),
+    State(Frame, Vec<u8>),
 }
 
 impl SessionPlugin for WarnRemoteMismatchedChecksum {
@@ -71,11 +226,44 @@ impl SessionPlugin for WarnRemoteMismatchedChecksum {
     }
 
     fn on_confirmed_frame(&mut self, frame: Frame, serialized: &[u8]) {
-        let checksum = seahash::hash(serialized);
+        if let Some(states) = &mut self.states {
+            states.put(frame, serialized.to_vec());
+        }
+    }
+
+    fn on_confirmed_checksum(&mut self, frame: Frame, checksum: u64) {
         self.checksums.put(frame, checksum);
         self.check_frame_match(frame);
     }
 
+    fn on_address_changed(&mut self, old: SocketAddr, new: SocketAddr) {
+        for addr in self.addrs.iter_mut().filter(|a| **a == old) {
+            *addr = new;
+        }
+        if let Some(checksums) = self.remote_checksums.remove(&old) {
+            self.remote_checksums.insert(new, checksums);
+        }
+        if let Some(latest) = self.latest_remote.remove(&old) {
+            self.latest_remote.insert(new, latest);
+        }
+        if self.diffed.remove(&old) {
+            self.diffed.insert(new);
+        }
+        if let Some(awaiting) = self.awaiting_states.remove(&old) {
+            self.awaiting_states.insert(new, awaiting);
+        }
+        if let Some(answered) = self.answered.remove(&old) {
+            self.answered.insert(new, answered);
+        }
+    }
+
+    fn remote_checksums(&self) -> Vec<(SocketAddr, Frame, u64)> {
+        self.latest_remote
+            .iter()
+            .map(|(addr, (frame, checksum))| (*addr, *frame, *checksum))
+            .collect()
+    }
+
     fn messages(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
         self.typed_messages()
             .into_iter()
@@ -83,16 +271,67 @@ impl SessionPlugin for WarnRemoteMismatchedChecksum {
             .collect()
     }
 
+    fn take_requests(&mut self) -> Vec<PluginRequest> {
+        std::mem::take(&mut self.requests)
+    }
+
     fn receive(&mut self, from: SocketAddr, message: Vec<u8>) {
-        let message = bincode::deserialize(&message).unwrap();
+        let message = match bincode::deserialize(&message) {
+            Ok(m) => m,
+            Err(e) => {
+                log::warn!("failed to decode checksum message from {}: {}", from, e);
+                return;
+            }
+        };
         match message {
             Message::FrameChecksum(frame, checksum) => {
+                let latest = self.latest_remote.entry(from).or_insert((frame, checksum));
+                if frame >= latest.0 {
+                    *latest = (frame, checksum);
+                }
                 self.remote_checksums
                     .entry(from)
                     .or_insert_with(|| LruCache::new(1024))
                     .put(frame, checksum);
                 self.check_frame_match(frame);
             }
+            Message::StateRequest(frame) => {
+                let state = self.states.as_mut().and_then(|s| s.get(&frame));
+                if let Some(state) = state {
+                    self.answered.insert(from, (frame, state.clone()));
+                }
+                if let Some((_, state)) = self.answered.get(&from).filter(|(f, _)| *f == frame) {
+                    let message = Message::State(frame, state.clone());
+                    self.outgoing.push((from, message));
+                }
+            }
+            Message::State(frame, state) => self.diff_state(from, frame, &state),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diffs_runs_and_tails() {
+        let local = [0, 1, 2, 3, 4, 5];
+        let remote = [0, 9, 9, 3, 4, 8, 7];
+        let differences = diff_bytes(&local, &remote);
+        let expected = [
+            (1, vec![1, 2], vec![9, 9]),
+            (5, vec![5], vec![8]),
+            (6, vec![], vec![7]),
+        ];
+        assert_eq!(
+            differences,
+            expected.map(|(offset, local, remote)| ByteDifference {
+                offset,
+                local,
+                remote
+            })
+        );
+        assert_eq!(diff_bytes(&local, &local), []);
+    }
+}
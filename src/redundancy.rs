@@ -0,0 +1,84 @@
+use std::time::Duration;
+
+use crate::time::{ClockHandle, Interval};
+
+const TARGET_DELIVERY_FAILURE: f64 = 0.01;
+
+/// Decides how many copies of each input message to send, based on the measured packet loss.
+pub(crate) struct RedundancyController {
+    current: u32,
+    max: u32,
+    adjust: Interval,
+}
+
+impl RedundancyController {
+    pub fn up_to(max: u32, clock: &ClockHandle) -> Self {
+        RedundancyController {
+            current: 1,
+            max: max.max(1),
+            adjust: Interval::new(Duration::from_millis(500), clock),
+        }
+    }
+
+    pub fn current(&self) -> u32 {
+        self.current
+    }
+
+    /// Moves one step towards the number of copies needed for all of them to be lost less than
+    /// `TARGET_DELIVERY_FAILURE` of the time. Stepping slowly avoids chasing a noisy estimate.
+    pub fn update(&mut self, loss: Option<f64>) {
+        if !self.adjust.is_time() {
+            return;
+        }
+
+        let target = match loss {
+            None => self.current,
+            Some(l) if l <= 0. => 1,
+            Some(l) if l >= 1. => self.max,
+            Some(l) => (TARGET_DELIVERY_FAILURE.ln() / l.ln()).ceil() as u32,
+        }
+        .clamp(1, self.max);
+
+        self.current = match target.cmp(&self.current) {
+            std::cmp::Ordering::Greater => self.current + 1,
+            std::cmp::Ordering::Less => self.current - 1,
+            std::cmp::Ordering::Equal => self.current,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::ManualClock;
+    use std::sync::Arc;
+
+    fn settle(
+        controller: &mut RedundancyController,
+        clock: &ManualClock,
+        loss: Option<f64>,
+    ) -> u32 {
+        for _ in 0..10 {
+            clock.advance(Duration::from_millis(500));
+            controller.update(loss);
+        }
+        controller.current()
+    }
+
+    #[test]
+    fn clean_link_sends_once() {
+        let clock = ManualClock::new();
+        let mut controller =
+            RedundancyController::up_to(4, &(Arc::new(clock.clone()) as ClockHandle));
+        assert_eq!(settle(&mut controller, &clock, Some(0.)), 1);
+    }
+
+    #[test]
+    fn lossy_link_is_bounded() {
+        let clock = ManualClock::new();
+        let mut controller =
+            RedundancyController::up_to(4, &(Arc::new(clock.clone()) as ClockHandle));
+        assert_eq!(settle(&mut controller, &clock, Some(0.3)), 4);
+        assert_eq!(settle(&mut controller, &clock, Some(0.05)), 2);
+    }
+}
@@ -0,0 +1,433 @@
+//! Recording confirmed inputs so a match can be re-run offline with `ReplaySession`.
+//!
+//! A replay is a `ReplayHeader` followed by one `ReplayEntry` per confirmed frame, each
+//! bincode-encoded back to back. Entries are flushed as they are written, so a replay cut short by
+//! a crash is still readable up to its last complete entry.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io::{self, Read, Write},
+    ops::ControlFlow,
+    time::Duration,
+};
+
+use crate::{
+    exponential_keeping, request_handler::ControlFlowExt, snapshots::SnapshotStore,
+    step_schedule::StepSchedule, Confirmation, ConfirmationStatus, Frame, PlayerId, PlayerInputs,
+    Request, RequestHandler, SerializedInput, StateHasher,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplayHeader {
+    /// The step size the session started with. Changes from `Session::set_step_size` are
+    /// recorded as `ReplayEntry::StepSize` from the frame they take effect.
+    pub step_size: Duration,
+    pub players: Vec<PlayerId>,
+    pub default_input: SerializedInput,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReplayEntry {
+    /// The inputs that changed since the previous frame. `None` means the player left.
+    Inputs {
+        frame: u32,
+        changed: BTreeMap<PlayerId, Option<SerializedInput>>,
+    },
+    /// The seed the session agreed on, recorded before any inputs.
+    Seed(u64),
+    /// Frames are `step_size` long from `frame` on, recorded before that frame's inputs.
+    StepSize { frame: u32, step_size: Duration },
+}
+
+pub(crate) struct ReplayRecorder {
+    writer: Box<dyn Write + Send + Sync>,
+    last: BTreeMap<PlayerId, SerializedInput>,
+    recorded: Option<Frame>,
+    step_size: Duration,
+}
+
+impl ReplayRecorder {
+    pub fn start(
+        mut writer: Box<dyn Write + Send + Sync>,
+        header: &ReplayHeader,
+    ) -> io::Result<Self> {
+        write_flushed(&mut writer, header)?;
+        Ok(ReplayRecorder {
+            writer,
+            last: Default::default(),
+            recorded: None,
+            step_size: header.step_size,
+        })
+    }
+
+    pub fn record_seed(&mut self, seed: u64) -> io::Result<()> {
+        write_flushed(&mut self.writer, &ReplayEntry::Seed(seed))
+    }
+
+    /// Record the confirmed `inputs` of `frame`, which is `step_size` long.
+    pub fn record(
+        &mut self,
+        frame: Frame,
+        inputs: &PlayerInputs,
+        step_size: Duration,
+    ) -> io::Result<()> {
+        // Resyncing re-confirms frames we already have.
+        if self.recorded.is_some_and(|r| frame <= r) {
+            return Ok(());
+        }
+        self.recorded = Some(frame);
+
+        if step_size != self.step_size {
+            self.step_size = step_size;
+            let entry = ReplayEntry::StepSize {
+                frame: frame.0,
+                step_size,
+            };
+            write_flushed(&mut self.writer, &entry)?;
+        }
+
+        let current = inputs
+            .iter()
+            .map(|(p, input)| (*p, input.as_inner().clone()))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut changed = current
+            .iter()
+            .filter(|(p, input)| self.last.get(p) != Some(input))
+            .map(|(p, input)| (*p, Some(input.clone())))
+            .collect::<BTreeMap<_, _>>();
+        changed.extend(
+            self.last
+                .keys()
+                .filter(|p| !current.contains_key(p))
+                .map(|p| (*p, None)),
+        );
+        self.last = current;
+
+        let entry = ReplayEntry::Inputs {
+            frame: frame.0,
+            changed,
+        };
+        write_flushed(&mut self.writer, &entry)
+    }
+}
+
+fn write_flushed(writer: &mut dyn Write, value: &impl Serialize) -> io::Result<()> {
+    bincode::serialize_into(&mut *writer, value).map_err(io::Error::other)?;
+    writer.flush()
+}
+
+/// Reads back a replay, yielding the full confirmed inputs of each recorded frame.
+pub struct ReplayReader<R: Read> {
+    reader: R,
+    header: ReplayHeader,
+    current: BTreeMap<PlayerId, SerializedInput>,
+    seed: Option<u64>,
+    steps: StepSchedule,
+}
+
+impl<R: Read> ReplayReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, bincode::Error> {
+        let header: ReplayHeader = bincode::deserialize_from(&mut reader)?;
+        Ok(ReplayReader {
+            reader,
+            steps: StepSchedule::new(header.step_size),
+            header,
+            current: Default::default(),
+            seed: None,
+        })
+    }
+
+    pub fn header(&self) -> &ReplayHeader {
+        &self.header
+    }
+
+    /// The session's seed, once read. It comes before the first frame's inputs.
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    /// How long `frame` is, as far as has been read. Changes come before the frame they take
+    /// effect at, so this is final for every frame read so far.
+    pub fn step_size_at(&self, frame: u32) -> Duration {
+        self.steps.at(Frame(frame))
+    }
+}
+
+impl<R: Read> Iterator for ReplayReader<R> {
+    type Item = (u32, BTreeMap<PlayerId, SerializedInput>);
+
+    /// Ends at the end of the stream or at a truncated entry.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = bincode::deserialize_from(&mut self.reader).ok()?;
+            match entry {
+                ReplayEntry::Inputs { frame, changed } => {
+                    for (player, input) in changed {
+                        match input {
+                            Some(input) => self.current.insert(player, input),
+                            None => self.current.remove(&player),
+                        };
+                    }
+                    return Some((frame, self.current.clone()));
+                }
+                ReplayEntry::Seed(seed) => self.seed = Some(seed),
+                ReplayEntry::StepSize { frame, step_size } => {
+                    self.steps.change(Frame(frame), step_size);
+                }
+            }
+        }
+    }
+}
+
+/// Plays a replay back through a `RequestHandler`, with no socket or clock.
+///
+/// Requests are issued exactly as a live session issues them for confirmed frames, so a
+/// deterministic game reproduces the recorded timeline bit for bit. Confirmed states are kept with
+/// the same exponential spacing as a live session, which `seek_to` rolls back to.
+pub struct ReplaySession<R: Read> {
+    reader: ReplayReader<R>,
+    inputs: BTreeMap<Frame, BTreeMap<PlayerId, SerializedInput>>,
+    states: SnapshotStore,
+    at: Frame,
+    seeded: bool,
+    frame_numbers: bool,
+}
+
+impl<R: Read> ReplaySession<R> {
+    pub fn new(reader: R) -> Result<Self, bincode::Error> {
+        Ok(ReplaySession {
+            reader: ReplayReader::new(reader)?,
+            inputs: Default::default(),
+            states: SnapshotStore::uncompressed(),
+            at: Frame(0),
+            seeded: false,
+            frame_numbers: false,
+        })
+    }
+
+    /// Issue the requests that carry frame numbers, like `SessionBuilder::frame_numbers`.
+    pub fn frame_numbers(mut self) -> Self {
+        self.frame_numbers = true;
+        self
+    }
+
+    pub fn header(&self) -> &ReplayHeader {
+        self.reader.header()
+    }
+
+    /// The next frame `step` will advance.
+    pub fn current_frame(&self) -> u32 {
+        self.at.0
+    }
+
+    /// Advance a single frame. Continues with `false` once the replay has run out of frames.
+    pub fn step<H: RequestHandler>(&mut self, mut handler: H) -> ControlFlow<H::Break, bool> {
+        self.step_with(&mut handler, false)
+    }
+
+    fn step_with<H: RequestHandler>(
+        &mut self,
+        handler: &mut H,
+        resimulation: bool,
+    ) -> ControlFlow<H::Break, bool> {
+        let inputs = match self.inputs_at(self.at) {
+            Some(i) => i,
+            None => return ControlFlow::Continue(false),
+        };
+        let step_size = self.reader.step_size_at(self.at.0);
+        // Like a live session, the seed comes before frame 0 is saved.
+        if let (false, Some(seed)) = (self.seeded, self.reader.seed()) {
+            handler
+                .handle_request(Request::Seed(seed))
+                .always(|| self.seeded = true)?;
+        }
+
+        if self.states.is_empty() {
+            let state = self.states.save_slot(Frame(0));
+            handler.handle_request(Request::save(state, 0, self.frame_numbers))?;
+        }
+
+        let kept = exponential_keeping::kept_set(self.at.0 + 1);
+        if kept.contains(&self.at.0) && !self.states.contains(self.at) {
+            let mut hasher = StateHasher::default();
+            handler.handle_request(Request::ChecksumState(&mut hasher))?;
+
+            self.states.retain(|frame| kept.contains(&frame.0));
+            let state = self.states.save_slot(self.at);
+            handler.handle_request(Request::save(state, self.at.0, self.frame_numbers))?;
+        }
+
+        handler
+            .handle_request(Request::Advance {
+                amount: step_size,
+                inputs: inputs
+                    .into_iter()
+                    .map(|(p, input)| (p, ConfirmationStatus::Confirmed(input)))
+                    .collect(),
+                confirmed: Confirmation::First,
+                current_frame: self.at.0,
+                resimulation,
+            })
+            .always(|| self.at = self.at + 1)?;
+        ControlFlow::Continue(true)
+    }
+
+    /// Move to `frame`, loading the closest kept state before it when going backwards. Continues
+    /// with `false` if the replay ends before `frame`.
+    pub fn seek_to<H: RequestHandler>(
+        &mut self,
+        frame: u32,
+        mut handler: H,
+    ) -> ControlFlow<H::Break, bool> {
+        let (frame, from) = (Frame(frame), self.at);
+        if frame < self.at {
+            if let Some((roll_to, state)) = self.states.latest_at_or_before(frame) {
+                handler
+                    .handle_request(state.load_request(roll_to, self.frame_numbers))
+                    .always(|| self.at = roll_to)?;
+            }
+        }
+
+        while self.at < frame {
+            if !self.step_with(&mut handler, self.at < from)? {
+                return ControlFlow::Continue(false);
+            }
+        }
+        ControlFlow::Continue(true)
+    }
+
+    fn inputs_at(&mut self, frame: Frame) -> Option<BTreeMap<PlayerId, SerializedInput>> {
+        while !self.inputs.contains_key(&frame) {
+            let (read, inputs) = self.reader.next()?;
+            self.inputs.insert(Frame(read), inputs);
+        }
+        self.inputs.get(&frame).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn inputs(inputs: &[(PlayerId, u8)]) -> PlayerInputs {
+        inputs
+            .iter()
+            .map(|&(p, i)| (p, ConfirmationStatus::Confirmed(vec![i])))
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_through_reader() {
+        let header = ReplayHeader {
+            step_size: Duration::from_millis(16),
+            players: vec![0, 1],
+            default_input: vec![0],
+        };
+        let frames = [
+            inputs(&[(0, 0), (1, 0)]),
+            inputs(&[(0, 1), (1, 0)]),
+            inputs(&[(0, 1)]),
+        ];
+
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut recorder =
+            ReplayRecorder::start(Box::new(Shared(buffer.clone())), &header).unwrap();
+        recorder.record_seed(42).unwrap();
+        let slow = Duration::from_millis(32);
+        for (f, i) in frames.iter().enumerate() {
+            let step_size = if f < 2 { header.step_size } else { slow };
+            recorder.record(Frame(f as u32), i, step_size).unwrap();
+        }
+
+        let bytes = buffer.lock().unwrap().clone();
+        let mut reader = ReplayReader::new(&bytes[..]).unwrap();
+        assert_eq!(reader.header(), &header);
+        let read = reader.by_ref().collect::<Vec<_>>();
+        assert_eq!(reader.seed(), Some(42));
+        assert_eq!(reader.step_size_at(1), header.step_size);
+        assert_eq!(reader.step_size_at(2), slow);
+        assert_eq!(
+            read,
+            vec![
+                (0, [(0, vec![0]), (1, vec![0])].into()),
+                (1, [(0, vec![1]), (1, vec![0])].into()),
+                (2, [(0, vec![1])].into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_replay_reads_complete_entries() {
+        let header = ReplayHeader {
+            step_size: Duration::from_millis(16),
+            players: vec![0],
+            default_input: vec![0],
+        };
+        let mut bytes = bincode::serialize(&header).unwrap();
+        for frame in 0..3 {
+            let entry = ReplayEntry::Inputs {
+                frame,
+                changed: [(0, Some(vec![frame as u8]))].into(),
+            };
+            bytes.extend(bincode::serialize(&entry).unwrap());
+        }
+        bytes.pop();
+
+        let reader = ReplayReader::new(&bytes[..]).unwrap();
+        assert_eq!(reader.count(), 2);
+    }
+
+    #[test]
+    fn replay_session_reproduces_and_seeks() {
+        let header = ReplayHeader {
+            step_size: Duration::from_millis(16),
+            players: vec![0, 1],
+            default_input: vec![0],
+        };
+        let mut bytes = bincode::serialize(&header).unwrap();
+        for frame in 0..20 {
+            let entry = ReplayEntry::Inputs {
+                frame,
+                changed: [(0, Some(vec![frame as u8])), (1, Some(vec![1]))].into(),
+            };
+            bytes.extend(bincode::serialize(&entry).unwrap());
+        }
+
+        let mut replay = ReplaySession::new(&bytes[..]).unwrap();
+        let state = std::cell::Cell::new(0u32);
+        let mut handle = |request: Request<'_>| match request {
+            Request::SaveTo(buf) => *buf = state.get().to_le_bytes().to_vec(),
+            Request::LoadFrom(buf) => state.set(u32::from_le_bytes(buf.try_into().unwrap())),
+            Request::Advance { inputs, .. } => {
+                let sum = inputs
+                    .iter()
+                    .map(|(_, i)| i.as_inner()[0] as u32)
+                    .sum::<u32>();
+                state.set(state.get().wrapping_mul(31) + sum);
+            }
+            _ => {}
+        };
+
+        assert_eq!(replay.seek_to(20, &mut handle), ControlFlow::Continue(true));
+        assert_eq!(replay.step(&mut handle), ControlFlow::Continue(false));
+        let end = state.get();
+
+        assert_eq!(replay.seek_to(7, &mut handle), ControlFlow::Continue(true));
+        assert_eq!(replay.current_frame(), 7);
+        assert_eq!(replay.seek_to(20, &mut handle), ControlFlow::Continue(true));
+        assert_eq!(state.get(), end);
+    }
+}
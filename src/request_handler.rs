@@ -1,6 +1,6 @@
-use crate::{PlayerInputs, SerializedInput, SerializedState};
+use crate::{PlayerId, PlayerInputs, SerializedInput, SerializedState};
 
-use std::{ops::ControlFlow, time::Duration};
+use std::{hash::Hasher, ops::ControlFlow, time::Duration};
 
 pub trait RequestHandler {
     type Break;
@@ -27,6 +27,7 @@ where
 pub trait MaybeMessage {
     type Message;
 
+    #[allow(clippy::wrong_self_convention)]
     fn as_message(self) -> Option<Self::Message>;
 }
 
@@ -62,7 +63,6 @@ pub trait ControlFlowExt {
     type Break;
 
     fn always<R>(self, f: impl FnOnce() -> R) -> ControlFlow<Self::Break, R>;
-    fn map_break<R>(self, f: impl FnOnce(Self::Break) -> R) -> ControlFlow<R>;
 }
 
 impl<B> ControlFlowExt for ControlFlow<B> {
@@ -73,33 +73,206 @@ impl<B> ControlFlowExt for ControlFlow<B> {
         self?;
         ControlFlow::Continue(ret)
     }
-
-    fn map_break<R>(self, f: impl FnOnce(B) -> R) -> ControlFlow<R> {
-        match self {
-            ControlFlow::Break(b) => ControlFlow::Break(f(b)),
-            ControlFlow::Continue(c) => ControlFlow::Continue(c),
-        }
-    }
 }
 
 #[derive(Debug)]
 #[non_exhaustive]
 pub enum Request<'s> {
+    /// The seed every peer agreed on, for seeding the game's random number generators. Issued
+    /// once, before frame 0 is saved or advanced. It is the seahash of each player's `u64`
+    /// proposal, as little endian `(player id, proposal)` pairs in player id order, so a recorded
+    /// session reproduces it exactly.
+    Seed(u64),
+    /// Serialize the current state into the buffer.
     SaveTo(&'s mut SerializedState),
+    /// Replace the current state with one from `SaveTo`, or from a peer.
     LoadFrom(&'s [u8]),
+    /// Only with `SessionBuilder::frame_numbers`, in place of `SaveTo`. Like the other state
+    /// requests, `frame` is the frame the state is at, the next one it would be advanced through.
+    #[non_exhaustive]
+    SaveFrame {
+        state: &'s mut SerializedState,
+        frame: u32,
+    },
+    /// Only with `SessionBuilder::frame_numbers`, in place of `LoadFrom`.
+    #[non_exhaustive]
+    LoadFrame { state: &'s [u8], frame: u32 },
+    /// Only with `SessionBuilder::delta_saves`. Save the current state into `out` as a delta
+    /// against `base`, a state from an earlier `SaveTo`.
+    #[non_exhaustive]
+    SaveDelta {
+        base: &'s [u8],
+        out: &'s mut SerializedState,
+        frame: u32,
+    },
+    /// Only with `SessionBuilder::delta_saves`. Load the state made by applying `delta`, from a
+    /// `SaveDelta`, to `base`.
+    #[non_exhaustive]
+    LoadDelta {
+        base: &'s [u8],
+        delta: &'s [u8],
+        frame: u32,
+    },
+    /// Step the state forward by `amount` with everyone's `inputs`. More fields may be added, so
+    /// match with `Request::Advance { amount, inputs, .. }` and name only the ones you use.
+    ///
+    /// `inputs` holds the bytes exactly as captured. To log or forward them as well as simulate,
+    /// decode through `inputs.as_ref().deep_map(..)` rather than consuming them.
     #[non_exhaustive]
     Advance {
         amount: Duration,
         inputs: PlayerInputs,
+        /// Whether `inputs` are all confirmed, and if so whether this frame was advanced with them
+        /// before.
         confirmed: Confirmation,
         current_frame: u32,
+        /// Whether this frame was advanced before, and is being run again after a rollback or a
+        /// replay's seek. Gate one-off effects on it together with `confirmed`:
+        ///
+        /// - `Unconfirmed`, not a resimulation: predicted for the first time. Play effects, but
+        ///   they may turn out not to have happened.
+        /// - `Unconfirmed` resimulation: re-predicted with some newly arrived inputs. Skip effects
+        ///   already played.
+        /// - `First`, not a resimulation: confirmed as it is first simulated, as always in lockstep.
+        ///   Play effects, they will not be undone.
+        /// - `First` resimulation: the prediction was rolled back and this is the final result.
+        ///   Correct or skip effects played while predicting.
+        /// - `Subsequent`: confirmed but not yet final, because the confirmed horizon is held up
+        ///   by an earlier frame. Treat like `Unconfirmed`.
+        /// - `Partial`: never a resimulation, but loaded back regardless, so play nothing.
+        resimulation: bool,
     },
+    /// Serialize the local player's input into the buffer.
     CaptureLocalInput(&'s mut SerializedInput),
+    /// In place of `CaptureLocalInput` with `SessionBuilder::frame_numbers`, or several
+    /// `SessionBuilder::local_players`. Serialize local `player`'s input for `frame` into
+    /// `input`, issued for each local player in turn.
+    #[non_exhaustive]
+    CaptureInput {
+        input: &'s mut SerializedInput,
+        frame: u32,
+        player: PlayerId,
+    },
+    /// Hash the parts of the current state that must agree between peers, for desync detection.
+    /// Leave out cosmetic state. If nothing is hashed, the whole serialized state is used.
+    /// `StateHasher::write_state` hashes alike across architectures.
+    ChecksumState(&'s mut StateHasher),
+    /// `id` left the session, and has no inputs after `last_frame`.
+    PlayerDisconnected { id: PlayerId, last_frame: u32 },
+    /// Nothing has been heard from `id` for `since`. The session keeps predicting their inputs.
+    ConnectionInterrupted { id: PlayerId, since: Duration },
+    /// `id` was interrupted but has been heard from again.
+    ConnectionResumed { id: PlayerId },
+    /// Every remote player has left. What happens next depends on the `AllDisconnected` policy.
+    AllPlayersDisconnected,
+    /// The session is over and will not issue any more requests.
+    SessionEnded,
+    /// The state is about to be rolled back `frames` frames to `to_frame`, to re-simulate them
+    /// with newly arrived inputs. The next request is its `LoadFrom` or `LoadDelta`; loads for
+    /// other reasons, such as a peer's state, are not announced. Advances until the state is back
+    /// where it was repeat frames already seen, so suppress sounds, particles and other one-off
+    /// effects for them.
+    ///
+    /// Most rollbacks are routine: while predicting ahead, every newly confirmed frame is
+    /// re-simulated from the latest confirmed state, and the frames come out as they were.
+    /// `mispredicted` is only `true` when an input, step size or player of a simulated frame has
+    /// changed since, so the re-simulated frames may differ from what was shown, such as to play a
+    /// correction effect only then.
+    Rollback {
+        to_frame: u32,
+        frames: u32,
+        mispredicted: bool,
+    },
+    /// `id`'s inputs from `since_frame` were mispredicted, and the state has now been re-simulated
+    /// with their real inputs. Purely advisory: compare their entities against what was last
+    /// rendered and ease the difference out over a few render frames instead of snapping.
+    PredictionCorrected { id: PlayerId, since_frame: u32 },
+    /// The session has predicted as far past `last_confirmed` as `max_prediction_frames` allows,
+    /// and won't advance until more inputs arrive. Issued once per stall; `blocking_players`
+    /// tells who is being waited on.
+    PredictionStalled { last_confirmed: u32 },
+    /// Our confirmed state at `frame` hashed to `local`, but `id`'s hashed to `remote`, so the
+    /// simulations have diverged. Carry on regardless, end the match, or call
+    /// `Session::resync_from` to adopt a trusted peer's state.
+    DesyncDetected {
+        frame: u32,
+        id: PlayerId,
+        local: u64,
+        remote: u64,
+    },
+    /// Only with `SessionBuilder::verify_confirmed_advances`. Advancing from the state before
+    /// `frame` twice with the same inputs saved differently, first at byte `offset`, which is the
+    /// shorter length if one save is a prefix of the other. The state carries on from the first.
+    NondeterministicAdvance { frame: u32, offset: usize },
+}
+
+impl<'s> Request<'s> {
+    /// `SaveFrame` with frame numbers, otherwise `SaveTo`.
+    pub(crate) fn save(state: &'s mut SerializedState, frame: u32, numbered: bool) -> Self {
+        match numbered {
+            true => Request::SaveFrame { state, frame },
+            false => Request::SaveTo(state),
+        }
+    }
+
+    /// `LoadFrame` with frame numbers, otherwise `LoadFrom`.
+    pub(crate) fn load(state: &'s [u8], frame: u32, numbered: bool) -> Self {
+        match numbered {
+            true => Request::LoadFrame { state, frame },
+            false => Request::LoadFrom(state),
+        }
+    }
+}
+
+/// A hasher that produces the same checksum on every peer for the same writes.
+///
+/// `Hash` implementations feed it native-endian bytes, so peers on different architectures should
+/// hash fixed-size integers rather than `usize`. Unordered collections must be hashed in a
+/// deterministic order. `write_state` avoids both.
+#[derive(Default)]
+pub struct StateHasher {
+    hasher: seahash::SeaHasher,
+    written: bool,
+}
+
+impl StateHasher {
+    /// Checksums `state` the same way on every architecture, see `determinism::Deterministic`.
+    pub fn write_state<T: crate::determinism::Deterministic + ?Sized>(&mut self, state: &T) {
+        state.checksum(self);
+    }
+
+    pub(crate) fn checksum(&self) -> Option<u64> {
+        self.written.then(|| self.hasher.finish())
+    }
+}
+
+impl Hasher for StateHasher {
+    fn finish(&self) -> u64 {
+        self.hasher.finish()
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.written = true;
+        self.hasher.write(bytes);
+    }
+}
+
+impl std::fmt::Debug for StateHasher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StateHasher")
+            .field("written", &self.written)
+            .finish()
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Confirmation {
+    /// Some inputs are predicted. Never issued by a `SessionBuilder::lockstep` session.
     Unconfirmed,
     First,
     Subsequent,
+    /// A step shorter than `step_size` from the last whole frame to realtime, only issued with
+    /// `SessionBuilder::partial_advances`. The state is loaded back to the whole frame before the
+    /// next real advance, so render it but don't act on it (play sounds, send events, ...).
+    Partial,
 }
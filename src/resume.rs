@@ -0,0 +1,117 @@
+//! Saving a live session to continue it later, such as after a crash.
+//!
+//! `Session::snapshot` captures what the session needs to carry on from its latest whole
+//! confirmed state: that state, every input from its frame on, which player is at which address,
+//! the membership history, the agreed seed and the step size of every frame.
+//! `SessionBuilder::resume_from` starts a session from it, configured as the original was
+//! otherwise.
+//!
+//! Everything measured about the network is not kept and starts over: the shared clock
+//! re-synchronizes with the remotes, and round-trip times, loss, redundancy, frame advantage and
+//! liveness are measured afresh. Older confirmed states and checksums, plugin state, stats and the
+//! determinism check's history are not kept either.
+//!
+//! On resume the session loads the saved state, then tells the remotes where it is. They resend
+//! their inputs from there, along with their latest confirmed state if they have moved on past
+//! what their inputs still cover.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+};
+
+use crate::{
+    inputs::InputsByPlayer, membership::Membership, step_schedule::StepSchedule, Frame, PlayerId,
+    SerializedState, Session,
+};
+
+/// Bumped whenever the snapshot format changes incompatibly.
+const SNAPSHOT_VERSION: u16 = 3;
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SessionSnapshot {
+    version: u16,
+    frame: Frame,
+    state: SerializedState,
+    inputs: InputsByPlayer,
+    joined_at: HashMap<PlayerId, Frame>,
+    local_id: PlayerId,
+    players: Vec<(SocketAddr, PlayerId)>,
+    membership: Membership,
+    seed: Option<u64>,
+    steps: StepSchedule,
+}
+
+impl SessionSnapshot {
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let snapshot: SessionSnapshot = bincode::deserialize(bytes).map_err(|e| e.to_string())?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "snapshot is version {}, expected {}",
+                snapshot.version, SNAPSHOT_VERSION
+            ));
+        }
+        Ok(snapshot)
+    }
+}
+
+impl Session {
+    /// Everything needed to continue this session later with `SessionBuilder::resume_from`, see
+    /// the `resume` module. `None` until the first state has been saved.
+    pub fn snapshot(&mut self) -> Option<Vec<u8>> {
+        let (frame, state) = self.confirmed_states.latest_full()?;
+        let state = state.into_owned();
+        let (inputs, joined_at) = self.inputs.export_from(frame);
+        let snapshot = SessionSnapshot {
+            version: SNAPSHOT_VERSION,
+            frame,
+            state,
+            inputs,
+            joined_at,
+            local_id: self.local_id,
+            players: self
+                .player_addresses
+                .iter()
+                .map(|(addr, id)| (*addr, *id))
+                .collect(),
+            membership: self.membership.clone(),
+            seed: self.seed,
+            steps: self.steps.clone(),
+        };
+        Some(bincode::serialize(&snapshot).expect("snapshots always serialize"))
+    }
+
+    pub(crate) fn restore(&mut self, snapshot: SessionSnapshot) -> Result<(), String> {
+        if snapshot.local_id != self.local_id {
+            return Err(format!(
+                "snapshot is of player {}, not {}",
+                snapshot.local_id, self.local_id
+            ));
+        }
+
+        let addrs = snapshot
+            .players
+            .iter()
+            .map(|(addr, _)| *addr)
+            .collect::<HashSet<_>>();
+        for old in self.player_addresses.keys() {
+            if !addrs.contains(old) {
+                self.shared_clock.remove_remote(*old);
+            }
+        }
+        for &addr in &addrs {
+            self.shared_clock.add_remote(addr);
+        }
+        self.player_addresses = snapshot.players.into_iter().collect();
+
+        self.membership = snapshot.membership;
+        self.seed = snapshot.seed;
+        self.steps = snapshot.steps;
+        self.inputs
+            .import(snapshot.frame, snapshot.inputs, snapshot.joined_at);
+        self.adopt_confirmed_state(snapshot.frame, snapshot.state);
+        self.resumed_at = Some(snapshot.frame);
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use crate::time::{ClockHandle, Interval};
+
+/// Where the send interval starts, and stays with the default bounds.
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Decides how often to send inputs, based on the round-trip time to the slowest remote. A fast
+/// link gets inputs sooner, while a slow, likely congested one isn't flooded with resends.
+pub(crate) struct SendRateController {
+    current: Duration,
+    min: Duration,
+    max: Duration,
+    adjust: Interval,
+}
+
+impl SendRateController {
+    pub fn between(min: Duration, max: Duration, clock: &ClockHandle) -> Self {
+        let (min, max) = (min.min(max), min.max(max));
+        SendRateController {
+            current: DEFAULT_INTERVAL.clamp(min, max),
+            min,
+            max,
+            adjust: Interval::new(Duration::from_secs(1), clock),
+        }
+    }
+
+    pub fn current(&self) -> Duration {
+        self.current
+    }
+
+    /// Moves halfway towards sending twice per round trip of `rtt`, a smoothed average. Once a
+    /// second at most, and only when off by more than a fifth, so a noisy or briefly spiking
+    /// RTT doesn't make the interval oscillate.
+    pub fn update(&mut self, rtt: Option<Duration>) {
+        if !self.adjust.is_time() {
+            return;
+        }
+        let target = match rtt {
+            Some(rtt) => (rtt / 2).clamp(self.min, self.max),
+            None => return,
+        };
+
+        let difference = target.abs_diff(self.current);
+        if difference * 5 > self.current {
+            self.current = ((self.current + target) / 2).clamp(self.min, self.max);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::ManualClock;
+    use std::sync::Arc;
+
+    fn settle(
+        controller: &mut SendRateController,
+        clock: &ManualClock,
+        rtt: Option<Duration>,
+    ) -> Duration {
+        for _ in 0..10 {
+            clock.advance(Duration::from_secs(1));
+            controller.update(rtt);
+        }
+        controller.current()
+    }
+
+    #[test]
+    fn follows_rtt_within_bounds() {
+        let (min, max) = (Duration::from_millis(10), Duration::from_millis(100));
+        let clock = ManualClock::new();
+        let mut controller =
+            SendRateController::between(min, max, &(Arc::new(clock.clone()) as ClockHandle));
+        assert_eq!(controller.current(), Duration::from_millis(50));
+
+        // Settles within a fifth of the target.
+        let near = |actual: Duration, target: Duration| actual.abs_diff(target) * 5 <= actual;
+        assert!(near(
+            settle(&mut controller, &clock, Some(Duration::from_millis(1))),
+            min
+        ));
+        let slow = settle(&mut controller, &clock, Some(Duration::from_millis(120)));
+        assert!(near(slow, Duration::from_millis(60)));
+        assert!(near(
+            settle(&mut controller, &clock, Some(Duration::from_secs(1))),
+            max
+        ));
+    }
+
+    #[test]
+    fn ignores_small_changes() {
+        let (min, max) = (Duration::from_millis(10), Duration::from_millis(100));
+        let clock = ManualClock::new();
+        let mut controller =
+            SendRateController::between(min, max, &(Arc::new(clock.clone()) as ClockHandle));
+        assert_eq!(
+            settle(&mut controller, &clock, Some(Duration::from_millis(110))),
+            Duration::from_millis(50)
+        );
+    }
+}
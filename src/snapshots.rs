@@ -0,0 +1,362 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashSet},
+    sync::{mpsc, Mutex},
+};
+
+use crate::{Frame, Request, SerializedState};
+
+/// The confirmed states kept for rolling back to.
+///
+/// When compression is enabled, all but the most recent snapshots are compressed one at a time on
+/// a background thread, or right away where there are no threads. Loading a snapshot that is
+/// still being compressed waits for it to finish.
+///
+/// With deltas enabled, most snapshots are stored as a delta against the latest full snapshot, a
+/// keyframe. Deltas are never taken against other deltas, so a snapshot is always one step from a
+/// full state and keyframes are kept for as long as a delta needs them.
+pub(crate) struct SnapshotStore {
+    states: BTreeMap<Frame, Snapshot>,
+    compress_after: Option<usize>,
+    keyframe_every: Option<u32>,
+    compressor: Compressor,
+}
+
+enum Snapshot {
+    Raw(SerializedState),
+    /// Remembers the length of the state being compressed, which is held until it is done. The
+    /// mutex only keeps the store `Sync`.
+    Compressing(usize, Mutex<mpsc::Receiver<Vec<u8>>>),
+    Compressed(Vec<u8>),
+    Delta {
+        base: Frame,
+        delta: Vec<u8>,
+    },
+}
+
+/// A stored snapshot, either whole or as a delta to apply to its keyframe.
+pub(crate) enum Stored<'s> {
+    Full(Cow<'s, [u8]>),
+    Delta {
+        base: Cow<'s, [u8]>,
+        delta: &'s [u8],
+    },
+}
+
+impl Stored<'_> {
+    /// The request to load this snapshot, taken at `frame`, see `Request::load`.
+    pub fn load_request(&self, frame: Frame, numbered: bool) -> Request<'_> {
+        match self {
+            Stored::Full(state) => Request::load(state, frame.0, numbered),
+            Stored::Delta { base, delta } => Request::LoadDelta {
+                base,
+                delta,
+                frame: frame.0,
+            },
+        }
+    }
+}
+
+impl SnapshotStore {
+    pub fn uncompressed() -> Self {
+        SnapshotStore {
+            states: Default::default(),
+            compress_after: None,
+            keyframe_every: None,
+            compressor: Compressor::default(),
+        }
+    }
+
+    /// Keep the `keep_recent` most recent snapshots uncompressed for fast rollback.
+    pub fn compressing(keep_recent: usize) -> Self {
+        SnapshotStore {
+            states: Default::default(),
+            compress_after: Some(keep_recent),
+            keyframe_every: None,
+            compressor: Compressor::default(),
+        }
+    }
+
+    /// Store snapshots as deltas, with a full keyframe at most every `keyframe_every` frames.
+    pub fn with_deltas(mut self, keyframe_every: u32) -> Self {
+        self.keyframe_every = Some(keyframe_every);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    pub fn contains(&self, frame: Frame) -> bool {
+        self.states.contains_key(&frame)
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(Frame) -> bool) {
+        let kept = self
+            .states
+            .keys()
+            .copied()
+            .filter(|f| keep(*f))
+            .collect::<HashSet<_>>();
+        let bases = self
+            .states
+            .iter()
+            .filter(|(frame, _)| kept.contains(frame))
+            .filter_map(|(_, snapshot)| match snapshot {
+                Snapshot::Delta { base, .. } => Some(*base),
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+        self.states
+            .retain(|frame, _| kept.contains(frame) || bases.contains(frame));
+    }
+
+    /// Replace every snapshot with a single `state` at `frame`.
+    pub fn reset_to(&mut self, frame: Frame, state: SerializedState) {
+        self.states = [(frame, Snapshot::Raw(state))].into();
+    }
+
+    /// An empty buffer to save the state at `frame` into.
+    pub fn save_slot(&mut self, frame: Frame) -> &mut SerializedState {
+        self.states.remove(&frame);
+        self.compress_old();
+
+        match self
+            .states
+            .entry(frame)
+            .or_insert(Snapshot::Raw(Vec::new()))
+        {
+            Snapshot::Raw(state) => state,
+            _ => unreachable!("just inserted a raw snapshot"),
+        }
+    }
+
+    /// The keyframe to save `frame` as a delta against, if it should be a delta.
+    pub fn delta_base(&mut self, frame: Frame) -> Option<(Frame, Cow<'_, [u8]>)> {
+        let every = self.keyframe_every?;
+        let (base, _) = self.keyframe_in(..frame)?;
+        if frame.0 - base.0 >= every {
+            return None;
+        }
+        Some((base, self.states.get_mut(&base)?.bytes()))
+    }
+
+    pub fn insert_delta(&mut self, frame: Frame, base: Frame, delta: Vec<u8>) {
+        debug_assert!(matches!(self.states.get(&base), Some(s) if !s.is_delta()));
+        self.compress_old();
+        self.states.insert(frame, Snapshot::Delta { base, delta });
+    }
+
+    /// Each stored frame with the bytes it takes as stored, in frame order.
+    pub fn sizes(&self) -> impl Iterator<Item = (Frame, usize)> + '_ {
+        self.states.iter().map(|(f, s)| (*f, s.stored_len()))
+    }
+
+    pub fn earliest_frame(&self) -> Option<Frame> {
+        self.states.keys().next().copied()
+    }
+
+    pub fn latest_frame(&self) -> Option<Frame> {
+        self.states.keys().next_back().copied()
+    }
+
+    /// The latest snapshot that is stored whole, for sending to peers.
+    pub fn latest_full(&mut self) -> Option<(Frame, Cow<'_, [u8]>)> {
+        let (frame, _) = self.keyframe_in(..)?;
+        Some((frame, self.states.get_mut(&frame)?.bytes()))
+    }
+
+    pub fn latest_at_or_before(&mut self, frame: Frame) -> Option<(Frame, Stored<'_>)> {
+        let (&at, snapshot) = self.states.range(..=frame).next_back()?;
+        let base = match snapshot {
+            Snapshot::Delta { base, .. } => *base,
+            _ => return Some((at, Stored::Full(self.states.get_mut(&at)?.bytes()))),
+        };
+
+        self.states.get_mut(&base)?.finish_compressing();
+        let base = self.states[&base].finished_bytes();
+        match &self.states[&at] {
+            Snapshot::Delta { delta, .. } => Some((at, Stored::Delta { base, delta })),
+            _ => unreachable!("checked above"),
+        }
+    }
+
+    fn keyframe_in(&self, range: impl std::ops::RangeBounds<Frame>) -> Option<(Frame, &Snapshot)> {
+        self.states
+            .range(range)
+            .rev()
+            .find(|(_, s)| !s.is_delta())
+            .map(|(f, s)| (*f, s))
+    }
+
+    fn compress_old(&mut self) {
+        let keep_recent = match self.compress_after {
+            Some(k) => k,
+            None => return,
+        };
+
+        // The slot about to be saved counts as one of the recent snapshots.
+        let old = self
+            .states
+            .len()
+            .saturating_sub(keep_recent.saturating_sub(1));
+        // Deltas will keep being taken against the latest keyframe, so leave it raw.
+        let keyframe = self.keyframe_in(..).map(|(f, _)| f);
+        let compressor = &mut self.compressor;
+        for (_, snapshot) in self
+            .states
+            .iter_mut()
+            .take(old)
+            .filter(|(f, _)| Some(**f) != keyframe)
+        {
+            if let Snapshot::Raw(state) = snapshot {
+                *snapshot = compressor.compress(std::mem::take(state));
+            }
+        }
+    }
+}
+
+fn compress(state: &[u8]) -> Vec<u8> {
+    miniz_oxide::deflate::compress_to_vec(state, 1)
+}
+
+/// Compresses snapshots in the order they are handed over, on a thread started with the first.
+#[derive(Default)]
+struct Compressor {
+    #[cfg(not(target_family = "wasm"))]
+    jobs: Option<mpsc::Sender<(SerializedState, mpsc::Sender<Vec<u8>>)>>,
+}
+
+impl Compressor {
+    #[cfg(not(target_family = "wasm"))]
+    fn compress(&mut self, state: SerializedState) -> Snapshot {
+        let jobs = self.jobs.get_or_insert_with(|| {
+            let (jobs, queue) = mpsc::channel::<(SerializedState, mpsc::Sender<Vec<u8>>)>();
+            std::thread::Builder::new()
+                .name("rbrb-compression".to_owned())
+                .spawn(move || {
+                    for (state, done) in queue {
+                        // The snapshot may have been dropped already.
+                        let _ = done.send(compress(&state));
+                    }
+                })
+                .expect("failed to start the compression thread");
+            jobs
+        });
+        let (done, compressed) = mpsc::channel();
+        let len = state.len();
+        jobs.send((state, done))
+            .expect("compression thread panicked");
+        Snapshot::Compressing(len, Mutex::new(compressed))
+    }
+
+    /// No threads to compress on, so compress in place.
+    #[cfg(target_family = "wasm")]
+    fn compress(&mut self, state: SerializedState) -> Snapshot {
+        Snapshot::Compressed(compress(&state))
+    }
+}
+
+impl Snapshot {
+    fn is_delta(&self) -> bool {
+        matches!(self, Snapshot::Delta { .. })
+    }
+
+    fn finish_compressing(&mut self) {
+        if let Snapshot::Compressing(_, compressed) = self {
+            let compressed = compressed.get_mut().unwrap().recv();
+            *self = Snapshot::Compressed(compressed.expect("compression thread panicked"));
+        }
+    }
+
+    /// How many bytes this snapshot takes as stored, compressed or as a delta.
+    fn stored_len(&self) -> usize {
+        match self {
+            Snapshot::Raw(state) => state.len(),
+            Snapshot::Compressing(len, _) => *len,
+            Snapshot::Compressed(compressed) => compressed.len(),
+            Snapshot::Delta { delta, .. } => delta.len(),
+        }
+    }
+
+    /// The bytes of a full snapshot.
+    fn bytes(&mut self) -> Cow<'_, [u8]> {
+        self.finish_compressing();
+        self.finished_bytes()
+    }
+
+    fn finished_bytes(&self) -> Cow<'_, [u8]> {
+        match self {
+            Snapshot::Raw(state) => Cow::Borrowed(state),
+            Snapshot::Compressed(compressed) => Cow::Owned(
+                miniz_oxide::inflate::decompress_to_vec(compressed)
+                    .expect("failed to decompress snapshot"),
+            ),
+            Snapshot::Compressing(..) => unreachable!("compression should be finished"),
+            Snapshot::Delta { .. } => unreachable!("deltas are not full snapshots"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn save(store: &mut SnapshotStore, frame: u32) {
+        *store.save_slot(Frame(frame)) = vec![frame as u8; 1000];
+    }
+
+    #[test]
+    fn compressed_snapshots_load_intact() {
+        let mut store = SnapshotStore::compressing(2);
+        for frame in 0..5 {
+            save(&mut store, frame);
+        }
+
+        for frame in 0..5 {
+            let (at, bytes) = store.latest_at_or_before(Frame(frame)).unwrap();
+            assert_eq!(at, Frame(frame));
+            assert!(matches!(bytes, Stored::Full(b) if *b == [frame as u8; 1000][..]));
+        }
+    }
+
+    #[test]
+    fn keeps_recent_snapshots_raw() {
+        let mut store = SnapshotStore::compressing(2);
+        for frame in 0..5 {
+            save(&mut store, frame);
+        }
+
+        let raw = store
+            .states
+            .values()
+            .filter(|s| matches!(s, Snapshot::Raw(_)))
+            .count();
+        assert_eq!(raw, 2);
+    }
+
+    #[test]
+    fn deltas_keep_their_keyframe() {
+        let mut store = SnapshotStore::uncompressed().with_deltas(4);
+        for frame in 0..6 {
+            match store.delta_base(Frame(frame)) {
+                Some((base, _)) => store.insert_delta(Frame(frame), base, vec![frame as u8]),
+                None => save(&mut store, frame),
+            }
+        }
+        assert_eq!(store.latest_full().unwrap().0, Frame(4));
+
+        store.retain(|frame| frame == Frame(3) || frame == Frame(5));
+        assert!(store.contains(Frame(0)) && store.contains(Frame(4)));
+
+        let (at, stored) = store.latest_at_or_before(Frame(3)).unwrap();
+        assert_eq!(at, Frame(3));
+        assert!(matches!(
+            stored,
+            Stored::Delta { base, delta } if *base == [0; 1000][..] && delta == [3]
+        ));
+
+        let sizes = store.sizes().map(|(f, len)| (f.0, len)).collect::<Vec<_>>();
+        assert_eq!(sizes, [(0, 1000), (3, 1), (4, 1000), (5, 1)]);
+    }
+}
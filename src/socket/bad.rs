@@ -1,4 +1,4 @@
-use super::{BasicUdpSocket, NonBlockingSocket};
+use super::{BasicUdpSocket, NonBlockingSocket, SocketErrors};
 
 use rand::{rngs::SmallRng, Rng, SeedableRng};
 use rand_distr::{Distribution, Poisson};
@@ -12,8 +12,10 @@ pub struct BadSocket<S: NonBlockingSocket> {
     socket: S,
 
     rng: SmallRng,
-    success_chance: f64,
-    lag: Poisson<f32>,
+    drop_chance: f64,
+    duplicate_chance: f64,
+    latency: Latency,
+    jitter: Duration,
 
     send_delays: BTreeMap<Instant, (Vec<u8>, SocketAddr)>,
     recv_delays: BTreeMap<Instant, (SocketAddr, Vec<u8>)>,
@@ -21,33 +23,142 @@ pub struct BadSocket<S: NonBlockingSocket> {
     owned_for_lifetime: Option<(SocketAddr, Vec<u8>)>,
 }
 
-impl BadSocket<BasicUdpSocket> {
-    pub fn bind(port: u16) -> std::io::Result<Self> {
-        Ok(Self::new(BasicUdpSocket::bind(port)?))
+/// How long each packet that isn't dropped is delayed by, before jitter.
+#[derive(Clone, Copy, Debug)]
+pub enum Latency {
+    Fixed(Duration),
+    /// Poisson distributed, in whole milliseconds.
+    Poisson {
+        mean: Duration,
+    },
+    Uniform {
+        min: Duration,
+        max: Duration,
+    },
+}
+
+impl Latency {
+    fn sample(&self, rng: &mut SmallRng) -> Duration {
+        match *self {
+            Latency::Fixed(d) => d,
+            Latency::Poisson { mean } => {
+                let millis = Poisson::new(mean.as_secs_f32() * 1000.)
+                    .expect("mean latency must be positive")
+                    .sample(rng);
+                Duration::from_millis(millis as u64)
+            }
+            Latency::Uniform { min, max } => rng.gen_range(min..=max),
+        }
     }
 }
 
-impl<S: NonBlockingSocket> BadSocket<S> {
-    pub fn new(socket: S) -> Self {
-        Self {
+/// Configures a `BadSocket`. Defaults to dropping 60% of packets and delaying the rest by a
+/// Poisson distributed 100ms, with no jitter or duplication.
+#[derive(Clone, Debug)]
+pub struct BadSocketBuilder {
+    drop_chance: f64,
+    duplicate_chance: f64,
+    latency: Latency,
+    jitter: Duration,
+    seed: Option<u64>,
+}
+
+impl Default for BadSocketBuilder {
+    fn default() -> Self {
+        BadSocketBuilder {
+            drop_chance: 0.6,
+            duplicate_chance: 0.,
+            latency: Latency::Poisson {
+                mean: Duration::from_millis(100),
+            },
+            jitter: Duration::ZERO,
+            seed: None,
+        }
+    }
+}
+
+impl BadSocketBuilder {
+    pub fn drop_chance(mut self, chance: f64) -> Self {
+        self.drop_chance = chance;
+        self
+    }
+
+    /// Chance that a received packet is delivered a second time, with its own latency.
+    pub fn duplicate_chance(mut self, chance: f64) -> Self {
+        self.duplicate_chance = chance;
+        self
+    }
+
+    pub fn latency(mut self, latency: Latency) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Add up to `jitter` of uniformly random extra delay to each packet.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Make every drop, delay and duplicate reproducible for the same sequence of packets.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn bind(self, port: u16) -> std::io::Result<BadSocket<BasicUdpSocket>> {
+        Ok(self.build(BasicUdpSocket::bind(port)?))
+    }
+
+    pub fn build<S: NonBlockingSocket>(self, socket: S) -> BadSocket<S> {
+        BadSocket {
             socket,
-            rng: SmallRng::from_entropy(),
-            success_chance: 0.4,
-            lag: Poisson::new(100.).unwrap(),
+            rng: match self.seed {
+                Some(seed) => SmallRng::seed_from_u64(seed),
+                None => SmallRng::from_entropy(),
+            },
+            drop_chance: self.drop_chance,
+            duplicate_chance: self.duplicate_chance,
+            latency: self.latency,
+            jitter: self.jitter,
             send_delays: Default::default(),
             recv_delays: Default::default(),
             owned_for_lifetime: None,
         }
     }
+}
+
+impl BadSocket<BasicUdpSocket> {
+    pub fn bind(port: u16) -> std::io::Result<Self> {
+        BadSocketBuilder::default().bind(port)
+    }
+
+    pub fn builder() -> BadSocketBuilder {
+        BadSocketBuilder::default()
+    }
+}
+
+impl<S: NonBlockingSocket> BadSocket<S> {
+    pub fn new(socket: S) -> Self {
+        BadSocketBuilder::default().build(socket)
+    }
 
     fn packet_behavior(&mut self) -> PacketBehavior {
-        if !self.rng.gen_bool(self.success_chance) {
+        if self.rng.gen_bool(self.drop_chance) {
             PacketBehavior::Drop
         } else {
-            let lag = self.lag.sample(&mut self.rng);
-            PacketBehavior::Delay(Duration::from_millis(lag as u64))
+            PacketBehavior::Delay(self.delay())
         }
     }
+
+    fn delay(&mut self) -> Duration {
+        let jitter = if self.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            self.rng.gen_range(Duration::ZERO..=self.jitter)
+        };
+        self.latency.sample(&mut self.rng) + jitter
+    }
 }
 
 enum PacketBehavior {
@@ -55,6 +166,14 @@ enum PacketBehavior {
     Delay(Duration),
 }
 
+/// Packets delayed to the same instant would overwrite each other, so nudge later ones back.
+fn insert_delayed<T>(map: &mut BTreeMap<Instant, T>, mut at: Instant, value: T) {
+    while map.contains_key(&at) {
+        at += Duration::from_nanos(1);
+    }
+    map.insert(at, value);
+}
+
 fn next_ready<T>(map: &mut BTreeMap<Instant, T>) -> Option<T> {
     let (&first_at, _) = map.range(..).next()?;
     if first_at <= Instant::now() {
@@ -73,8 +192,11 @@ impl<S: NonBlockingSocket> NonBlockingSocket for BadSocket<S> {
         match self.packet_behavior() {
             PacketBehavior::Drop => {}
             PacketBehavior::Delay(amount) => {
-                self.send_delays
-                    .insert(Instant::now() + amount, (message.to_vec(), addr));
+                insert_delayed(
+                    &mut self.send_delays,
+                    Instant::now() + amount,
+                    (message.to_vec(), addr),
+                );
             }
         }
     }
@@ -94,10 +216,83 @@ impl<S: NonBlockingSocket> NonBlockingSocket for BadSocket<S> {
                 }
                 PacketBehavior::Delay(amount) => {
                     let (from, bytes) = self.socket.recv()?;
-                    self.recv_delays
-                        .insert(Instant::now() + amount, (from, bytes.to_vec()));
+                    let packet = (from, bytes.to_vec());
+                    if self.rng.gen_bool(self.duplicate_chance) {
+                        let at = Instant::now() + self.delay();
+                        insert_delayed(&mut self.recv_delays, at, packet.clone());
+                    }
+                    insert_delayed(&mut self.recv_delays, Instant::now() + amount, packet);
+                }
+            }
+        }
+    }
+
+    fn errors(&self) -> SocketErrors {
+        self.socket.errors()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    #[derive(Default)]
+    struct QueueSocket {
+        incoming: VecDeque<(SocketAddr, Vec<u8>)>,
+        current: Vec<u8>,
+    }
+
+    impl NonBlockingSocket for QueueSocket {
+        fn send(&mut self, _: &[u8], _: SocketAddr) {}
+
+        fn recv(&mut self) -> Option<(SocketAddr, &[u8])> {
+            let (addr, bytes) = self.incoming.pop_front()?;
+            self.current = bytes;
+            Some((addr, &self.current))
+        }
+    }
+
+    fn received_with_seed(seed: u64) -> Vec<u8> {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 7000));
+        let mut socket = BadSocket::builder()
+            .drop_chance(0.3)
+            .duplicate_chance(0.3)
+            .latency(Latency::Fixed(Duration::ZERO))
+            .seed(seed)
+            .build(QueueSocket {
+                incoming: (0..100).map(|i| (addr, vec![i])).collect(),
+                ..Default::default()
+            });
+
+        let mut received = Vec::new();
+        let mut waited = false;
+        loop {
+            match socket.recv() {
+                Some((_, bytes)) => {
+                    received.push(bytes[0]);
+                    waited = false;
+                }
+                None if waited => return received,
+                None => {
+                    std::thread::sleep(Duration::from_millis(1));
+                    waited = true;
                 }
             }
         }
     }
+
+    #[test]
+    fn seeded_sockets_are_reproducible() {
+        let received = received_with_seed(42);
+        assert_eq!(received, received_with_seed(42));
+
+        let mut unique = received.clone();
+        unique.dedup();
+        assert!(unique.len() < 100, "should drop some packets");
+        assert!(
+            unique.len() < received.len(),
+            "should duplicate some packets"
+        );
+    }
 }
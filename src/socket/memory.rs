@@ -0,0 +1,181 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    task::Waker,
+    time::Duration,
+};
+
+use super::NonBlockingSocket;
+
+/// An in-process network connecting sessions for tests, without real UDP.
+///
+/// Packets are held in flight until the network's virtual time has advanced past their latency,
+/// so delays play out the same way on every run.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    bus: Arc<Mutex<Bus>>,
+}
+
+#[derive(Default)]
+struct Bus {
+    now: Duration,
+    latency: Duration,
+    sent: u64,
+    in_flight: BTreeMap<(Duration, u64), Packet>,
+    wakers: HashMap<SocketAddr, Waker>,
+}
+
+impl Bus {
+    /// Wake the sockets with packets that have arrived.
+    fn wake_arrived(&mut self) {
+        let now = self.now;
+        let arrived = self
+            .in_flight
+            .iter()
+            .take_while(|((arrives, _), _)| *arrives <= now);
+        for (_, packet) in arrived {
+            if let Some(waker) = self.wakers.remove(&packet.to) {
+                waker.wake();
+            }
+        }
+    }
+}
+
+struct Packet {
+    from: SocketAddr,
+    to: SocketAddr,
+    bytes: Vec<u8>,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every packet arrives `latency` of virtual time after it was sent.
+    pub fn with_latency(latency: Duration) -> Self {
+        let network = Self::default();
+        network.bus.lock().unwrap().latency = latency;
+        network
+    }
+
+    /// A socket on this network reachable at `addr`.
+    pub fn socket(&self, addr: SocketAddr) -> MemorySocket {
+        MemorySocket {
+            addr,
+            network: self.clone(),
+            current: Vec::new(),
+        }
+    }
+
+    /// Move virtual time forward, letting packets whose latency has passed be received.
+    pub fn advance(&self, by: Duration) {
+        let mut bus = self.bus.lock().unwrap();
+        bus.now += by;
+        bus.wake_arrived();
+    }
+
+    /// How many packets have been sent but not yet received.
+    pub fn in_flight(&self) -> usize {
+        self.bus.lock().unwrap().in_flight.len()
+    }
+}
+
+pub struct MemorySocket {
+    addr: SocketAddr,
+    network: MemoryNetwork,
+    current: Vec<u8>,
+}
+
+impl NonBlockingSocket for MemorySocket {
+    fn send(&mut self, message: &[u8], addr: SocketAddr) {
+        let mut bus = self.network.bus.lock().unwrap();
+        let key = (bus.now + bus.latency, bus.sent);
+        bus.sent += 1;
+        bus.in_flight.insert(
+            key,
+            Packet {
+                from: self.addr,
+                to: addr,
+                bytes: message.to_vec(),
+            },
+        );
+        bus.wake_arrived();
+    }
+
+    fn recv(&mut self) -> Option<(SocketAddr, &[u8])> {
+        let mut bus = self.network.bus.lock().unwrap();
+        let now = bus.now;
+        let key = *bus
+            .in_flight
+            .iter()
+            .take_while(|((arrives, _), _)| *arrives <= now)
+            .find(|(_, packet)| packet.to == self.addr)?
+            .0;
+        let packet = bus.in_flight.remove(&key).unwrap();
+        drop(bus);
+
+        self.current = packet.bytes;
+        Some((packet.from, &self.current))
+    }
+
+    fn wake_on_receive(&mut self, waker: &Waker) -> bool {
+        let mut bus = self.network.bus.lock().unwrap();
+        bus.wakers.insert(self.addr, waker.clone());
+        bus.wake_arrived();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        task::Wake,
+    };
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn delivers_after_latency() {
+        let network = MemoryNetwork::with_latency(Duration::from_millis(10));
+        let mut a = network.socket(addr(1));
+        let mut b = network.socket(addr(2));
+
+        a.send(&[1], addr(2));
+        assert!(b.recv().is_none());
+
+        network.advance(Duration::from_millis(10));
+        assert_eq!(b.recv(), Some((addr(1), &[1][..])));
+        assert!(b.recv().is_none());
+        assert!(a.recv().is_none());
+    }
+
+    #[test]
+    fn wakes_on_arrival() {
+        struct Flag(AtomicBool);
+        impl Wake for Flag {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let network = MemoryNetwork::with_latency(Duration::from_millis(10));
+        let mut a = network.socket(addr(1));
+        let mut b = network.socket(addr(2));
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        assert!(b.wake_on_receive(&Waker::from(Arc::clone(&flag))));
+
+        a.send(&[1], addr(2));
+        network.advance(Duration::from_millis(5));
+        assert!(!flag.0.load(Ordering::SeqCst));
+
+        network.advance(Duration::from_millis(5));
+        assert!(flag.0.load(Ordering::SeqCst));
+        assert!(b.recv().is_some());
+    }
+}
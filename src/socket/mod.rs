@@ -1,12 +1,19 @@
 use std::{
-    io::ErrorKind,
-    net::{SocketAddr, UdpSocket},
+    io::{self, ErrorKind},
+    net::{Ipv6Addr, SocketAddr, UdpSocket},
+    task::Waker,
 };
 
 use crate::stats::SocketStats;
 
 mod bad;
 pub use bad::*;
+mod memory;
+pub use memory::*;
+#[cfg(feature = "wasm")]
+mod webrtc;
+#[cfg(feature = "wasm")]
+pub use webrtc::*;
 
 pub trait NonBlockingSocket: Send + Sync + 'static {
     fn send(&mut self, message: &[u8], addr: SocketAddr);
@@ -15,43 +22,412 @@ pub trait NonBlockingSocket: Send + Sync + 'static {
     fn stats(&self) -> Option<SocketStats> {
         None
     }
+
+    /// How many sends and receives have failed without taking the socket down.
+    fn errors(&self) -> SocketErrors {
+        SocketErrors::default()
+    }
+
+    /// Wake `waker` once the next packet can be received, or right away if one already can,
+    /// replacing any waker given before. Returns `false` if the socket can't, in which case it
+    /// has to be polled. See `Session::next_wake`.
+    fn wake_on_receive(&mut self, _waker: &Waker) -> bool {
+        false
+    }
 }
 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SocketErrors {
+    pub send: u64,
+    pub recv: u64,
+}
+
+/// A non-blocking UDP socket. Use `BasicUdpSocketBuilder` to pick its address and buffer sizes.
+///
+/// Errors are treated as lost packets and counted in `errors`, never panicking. Ones that only
+/// affect the packet at hand are logged at debug level: `WouldBlock`, `Interrupted`, `TimedOut`,
+/// `ConnectionRefused`, `ConnectionReset`, `ConnectionAborted`, `HostUnreachable`,
+/// `NetworkUnreachable`, `NetworkDown` and `AddrNotAvailable`. Others, such as a full kernel
+/// buffer, a firewall's refusal or an oversized packet, are logged as warnings, and stop a
+/// `recv` for this poll in case the socket keeps failing.
+///
+/// It doesn't support `wake_on_receive`, as std has no way to wait on a socket without
+/// receiving from it, so a session on it must be polled rather than only woken, see
+/// `Session::next_wake`.
 pub struct BasicUdpSocket {
     socket: UdpSocket,
     buffer: Vec<u8>,
+    errors: SocketErrors,
+    /// IPv4 peers are reached through IPv4-mapped IPv6 addresses.
+    dual_stack: bool,
 }
 
 impl BasicUdpSocket {
+    /// Bind to `port` on every IPv4 interface.
     pub fn bind(port: u16) -> std::io::Result<Self> {
-        let socket = UdpSocket::bind(("0.0.0.0", port))?;
+        BasicUdpSocketBuilder::default().port(port).build()
+    }
+
+    /// Bind to `addr`, which may be IPv4 or IPv6. Only peers of the same family are reachable.
+    pub fn bind_addr(addr: SocketAddr) -> std::io::Result<Self> {
+        BasicUdpSocketBuilder::default().addr(addr).build()
+    }
+
+    /// Bind to `port` on every interface, reaching both IPv4 and IPv6 peers through one socket,
+    /// see `BasicUdpSocketBuilder::dual_stack`.
+    pub fn bind_dual_stack(port: u16) -> std::io::Result<Self> {
+        BasicUdpSocketBuilder::default().dual_stack(port).build()
+    }
+
+    /// The address the socket is bound to, such as to find the port picked for port 0.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// The current size of the buffer packets are received into. It doubles whenever a packet
+    /// fills it.
+    pub fn receive_buffer_len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn grow_buffer(&mut self) {
+        log::info!("doubling receive buffer to {} bytes", self.buffer.len() * 2);
+        self.buffer
+            .extend(std::iter::repeat_n(0, self.buffer.len()));
+    }
+
+    /// The kernel's receive and send buffer sizes for the socket, as it reports them. Linux
+    /// reports double what was asked for, as it counts its own bookkeeping.
+    #[cfg(not(target_family = "wasm"))]
+    pub fn kernel_buffer_sizes(&self) -> std::io::Result<(usize, usize)> {
+        let socket = socket2::SockRef::from(&self.socket);
+        Ok((socket.recv_buffer_size()?, socket.send_buffer_size()?))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Bind {
+    Addr(SocketAddr),
+    DualStack(u16),
+}
+
+/// Configures a `BasicUdpSocket`. Defaults to every IPv4 interface on a port the system picks,
+/// a 64 byte receive buffer that grows on demand, and the system's kernel buffer sizes.
+#[derive(Clone, Debug)]
+pub struct BasicUdpSocketBuilder {
+    bind: Bind,
+    receive_buffer: usize,
+    kernel_receive_buffer: Option<usize>,
+    kernel_send_buffer: Option<usize>,
+}
+
+impl Default for BasicUdpSocketBuilder {
+    fn default() -> Self {
+        BasicUdpSocketBuilder {
+            bind: Bind::Addr(SocketAddr::from(([0, 0, 0, 0], 0))),
+            receive_buffer: 64,
+            kernel_receive_buffer: None,
+            kernel_send_buffer: None,
+        }
+    }
+}
+
+impl BasicUdpSocketBuilder {
+    /// Bind to `port` on every IPv4 interface.
+    pub fn port(self, port: u16) -> Self {
+        self.addr(SocketAddr::from(([0, 0, 0, 0], port)))
+    }
+
+    /// Bind to `addr`, which may be IPv4 or IPv6. Only peers of the same family are reachable.
+    pub fn addr(mut self, addr: SocketAddr) -> Self {
+        self.bind = Bind::Addr(addr);
+        self
+    }
+
+    /// Bind to `port` on every interface, reaching both IPv4 and IPv6 peers through one socket.
+    ///
+    /// Binds `[::]` with `IPV6_V6ONLY` turned off. IPv4 peers are still sent to and reported by
+    /// their plain IPv4 addresses. Building fails on platforms without dual-stack sockets, such
+    /// as OpenBSD and the browser, or where IPv6 is disabled; fall back to `port` there. Some
+    /// systems also make `IPV6_V6ONLY` a fixed policy (e.g. `net.ipv6.bindv6only` on Linux),
+    /// which this overrides for the one socket where allowed.
+    pub fn dual_stack(mut self, port: u16) -> Self {
+        self.bind = Bind::DualStack(port);
+        self
+    }
+
+    /// Start the receive buffer at `bytes`, such as the largest packet expected, rather than
+    /// growing it as bigger packets arrive. A packet that fills the buffer may have been cut
+    /// short, so size it above the largest packet.
+    pub fn receive_buffer(mut self, bytes: usize) -> Self {
+        self.receive_buffer = bytes.max(1);
+        self
+    }
+
+    /// Ask the kernel for a receive buffer (`SO_RCVBUF`) of `bytes`, so bursts from many peers
+    /// between calls to `recv` aren't dropped. The kernel may cap it, see `kernel_buffer_sizes`.
+    pub fn kernel_receive_buffer(mut self, bytes: usize) -> Self {
+        self.kernel_receive_buffer = Some(bytes);
+        self
+    }
+
+    /// Ask the kernel for a send buffer (`SO_SNDBUF`) of `bytes`.
+    pub fn kernel_send_buffer(mut self, bytes: usize) -> Self {
+        self.kernel_send_buffer = Some(bytes);
+        self
+    }
+
+    pub fn build(self) -> std::io::Result<BasicUdpSocket> {
+        let socket = self.bind()?;
         socket.set_nonblocking(true)?;
         Ok(BasicUdpSocket {
             socket,
-            buffer: vec![0; 64],
+            buffer: vec![0; self.receive_buffer],
+            errors: SocketErrors::default(),
+            dual_stack: matches!(self.bind, Bind::DualStack(_)),
         })
     }
+
+    #[cfg(not(target_family = "wasm"))]
+    fn bind(&self) -> std::io::Result<UdpSocket> {
+        use socket2::{Domain, Protocol, Socket, Type};
+
+        let addr = match self.bind {
+            Bind::Addr(addr) => addr,
+            Bind::DualStack(port) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+        };
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+        if let Bind::DualStack(_) = self.bind {
+            socket.set_only_v6(false)?;
+        }
+        if let Some(bytes) = self.kernel_receive_buffer {
+            socket.set_recv_buffer_size(bytes)?;
+        }
+        if let Some(bytes) = self.kernel_send_buffer {
+            socket.set_send_buffer_size(bytes)?;
+        }
+        socket.bind(&addr.into())?;
+        Ok(socket.into())
+    }
+
+    #[cfg(target_family = "wasm")]
+    fn bind(&self) -> std::io::Result<UdpSocket> {
+        match self.bind {
+            Bind::Addr(addr) => UdpSocket::bind(addr),
+            Bind::DualStack(_) => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "no dual-stack sockets on this platform",
+            )),
+        }
+    }
+}
+
+/// Windows fails receives into a buffer too small for the packet, where others truncate it.
+fn is_message_too_long(error: &io::Error) -> bool {
+    const WSAEMSGSIZE: i32 = 10040;
+    cfg!(windows) && error.raw_os_error() == Some(WSAEMSGSIZE)
+}
+
+/// `addr` as an IPv4 address if it is an IPv4-mapped IPv6 one.
+fn unmap(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::from((v4, v6.port())),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// An ICMP unreachable reported back from an earlier send, a route or interface that is briefly
+/// gone, a full send buffer, or an interrupted call.
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::WouldBlock
+            | ErrorKind::Interrupted
+            | ErrorKind::TimedOut
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::HostUnreachable
+            | ErrorKind::NetworkUnreachable
+            | ErrorKind::NetworkDown
+            | ErrorKind::AddrNotAvailable
+    )
 }
 
 impl NonBlockingSocket for BasicUdpSocket {
     fn send(&mut self, message: &[u8], addr: SocketAddr) {
-        self.socket.send_to(message, addr).expect("failed to send");
+        let to = match addr {
+            SocketAddr::V4(v4) if self.dual_stack => {
+                SocketAddr::from((v4.ip().to_ipv6_mapped(), v4.port()))
+            }
+            _ => addr,
+        };
+        match self.socket.send_to(message, to) {
+            Ok(_) => {}
+            Err(e) if is_transient(&e) => {
+                log::debug!("failed to send to {}: {}", addr, e);
+                self.errors.send += 1;
+            }
+            Err(e) => {
+                log::warn!("failed to send to {}: {}", addr, e);
+                self.errors.send += 1;
+            }
+        }
     }
 
     fn recv(&mut self) -> Option<(SocketAddr, &[u8])> {
-        match self.socket.recv_from(&mut self.buffer[..]) {
-            Ok((amount, addr)) => {
-                if amount == self.buffer.len() {
-                    log::info!("doubling receive buffer to {} bytes", self.buffer.len() * 2);
-                    self.buffer
-                        .extend(std::iter::repeat(0).take(self.buffer.len()));
+        loop {
+            // A packet that fills the buffer may have been cut short, so peek at it until the
+            // buffer has room to spare before taking it off the socket.
+            match self.socket.peek_from(&mut self.buffer[..]) {
+                Ok((amount, _)) if amount == self.buffer.len() => {
+                    self.grow_buffer();
+                    continue;
+                }
+                Ok(_) => {}
+                Err(e) if is_message_too_long(&e) => {
+                    self.grow_buffer();
+                    continue;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return None,
+                Err(e) if is_transient(&e) => {
+                    log::debug!("failed to receive: {}", e);
+                    self.errors.recv += 1;
+                    continue;
+                }
+                Err(e) => {
+                    log::warn!("failed to receive: {}", e);
+                    self.errors.recv += 1;
+                    return None;
+                }
+            }
+
+            match self.socket.recv_from(&mut self.buffer[..]) {
+                Ok((amount, addr)) => return Some((unmap(addr), &self.buffer[0..amount])),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return None,
+                Err(e) if is_transient(&e) => {
+                    log::debug!("failed to receive: {}", e);
+                    self.errors.recv += 1;
+                }
+                Err(e) => {
+                    log::warn!("failed to receive: {}", e);
+                    self.errors.recv += 1;
+                    return None;
                 }
-                Some((addr, &self.buffer[0..amount]))
             }
-            Err(e) if e.kind() == ErrorKind::WouldBlock => None,
-            unhandled => {
-                unimplemented!("unhandled: {:?}", unhandled);
+        }
+    }
+
+    fn errors(&self) -> SocketErrors {
+        self.errors
+    }
+}
+
+/// Sends nowhere and never receives, for `SessionBuilder::offline`.
+pub(crate) struct OfflineSocket;
+
+impl NonBlockingSocket for OfflineSocket {
+    fn send(&mut self, _: &[u8], _: SocketAddr) {}
+
+    fn recv(&mut self) -> Option<(SocketAddr, &[u8])> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recv_blocking(socket: &mut BasicUdpSocket) -> (SocketAddr, Vec<u8>) {
+        for _ in 0..1000 {
+            if let Some((from, message)) = socket.recv() {
+                return (from, message.to_vec());
             }
+            std::thread::sleep(std::time::Duration::from_millis(1));
         }
+        panic!("nothing received");
+    }
+
+    #[test]
+    fn grows_buffer_for_large_messages() {
+        let localhost = SocketAddr::from(([127, 0, 0, 1], 0));
+        let mut receiver = BasicUdpSocket::bind_addr(localhost).unwrap();
+        let mut sender = BasicUdpSocket::bind_addr(localhost).unwrap();
+        let message = (0..1000).map(|i| i as u8).collect::<Vec<_>>();
+
+        sender.send(&message, receiver.local_addr().unwrap());
+        assert_eq!(recv_blocking(&mut receiver).1, message);
+        assert_eq!(receiver.receive_buffer_len(), 1024);
+    }
+
+    #[test]
+    fn buffer_sized_and_larger_datagrams_arrive_whole() {
+        let localhost = SocketAddr::from(([127, 0, 0, 1], 0));
+        let mut receiver = BasicUdpSocketBuilder::default()
+            .addr(localhost)
+            .receive_buffer(64)
+            .build()
+            .unwrap();
+        let mut sender = BasicUdpSocket::bind_addr(localhost).unwrap();
+        let to = receiver.local_addr().unwrap();
+
+        for len in [64, 65, 300] {
+            let message = vec![len as u8; len];
+            sender.send(&message, to);
+            assert_eq!(recv_blocking(&mut receiver).1, message);
+        }
+    }
+
+    #[test]
+    fn counts_failed_sends_as_lost() {
+        let localhost = SocketAddr::from(([127, 0, 0, 1], 0));
+        let mut receiver = BasicUdpSocket::bind_addr(localhost).unwrap();
+        let mut sender = BasicUdpSocket::bind_addr(localhost).unwrap();
+        let to = receiver.local_addr().unwrap();
+
+        // Larger than any UDP datagram can be.
+        sender.send(&vec![0; 70_000], to);
+        assert_eq!(sender.errors().send, 1);
+
+        sender.send(b"still works", to);
+        assert_eq!(recv_blocking(&mut receiver).1, b"still works");
+    }
+
+    #[test]
+    fn builder_sizes_buffers() {
+        let socket = BasicUdpSocketBuilder::default()
+            .addr(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .receive_buffer(4096)
+            .kernel_receive_buffer(64 * 1024)
+            .kernel_send_buffer(64 * 1024)
+            .build()
+            .unwrap();
+        assert_eq!(socket.receive_buffer_len(), 4096);
+        let (receive, send) = socket.kernel_buffer_sizes().unwrap();
+        assert!(receive >= 64 * 1024, "{}", receive);
+        assert!(send >= 64 * 1024, "{}", send);
+    }
+
+    #[test]
+    fn dual_stack_talks_to_ipv4_peers_by_ipv4_address() {
+        let mut dual = match BasicUdpSocket::bind_dual_stack(0) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("skipping, no dual-stack sockets here: {}", e);
+                return;
+            }
+        };
+        let dual_addr = SocketAddr::from(([127, 0, 0, 1], dual.local_addr().unwrap().port()));
+        let mut v4 = BasicUdpSocket::bind_addr(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let v4_addr = v4.local_addr().unwrap();
+
+        v4.send(b"ping", dual_addr);
+        assert_eq!(recv_blocking(&mut dual), (v4_addr, b"ping".to_vec()));
+
+        dual.send(b"pong", v4_addr);
+        assert_eq!(recv_blocking(&mut v4), (dual_addr, b"pong".to_vec()));
     }
 }
@@ -0,0 +1,136 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    task::Waker,
+};
+
+use js_sys::{ArrayBuffer, Uint8Array};
+use wasm_bindgen::{closure::Closure, JsCast};
+use web_sys::{MessageEvent, RtcDataChannel, RtcDataChannelType};
+
+use super::{NonBlockingSocket, SocketErrors};
+
+type Received = Arc<Mutex<VecDeque<(SocketAddr, Vec<u8>)>>>;
+type OnReceive = Arc<Mutex<Option<Waker>>>;
+
+/// A socket over WebRTC data channels, for running in the browser where there is no UDP.
+///
+/// Signaling is left to the game: connect an `RtcPeerConnection` to each peer however suits,
+/// create a data channel on it with `ordered: false` and `maxRetransmits: 0` so that it behaves
+/// like UDP, and hand the channel to `add_peer`. Peers are identified by made up addresses, such as
+/// those from `peer_addr`, which must match the ones given to `SessionBuilder::remote_players`.
+///
+/// Messages are queued by the channels' JS callbacks as they arrive and drained by `recv`, so the
+/// browser's event loop must get to run between calls to `Session::next_request`.
+#[derive(Default)]
+pub struct WebRtcSocket {
+    peers: HashMap<SocketAddr, Peer>,
+    received: Received,
+    on_receive: OnReceive,
+    current: Vec<u8>,
+    errors: SocketErrors,
+}
+
+struct Peer {
+    channel: RtcDataChannel,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+}
+
+// JS values are tied to the thread that created them, but without the `atomics` target feature a
+// wasm module only ever has the one thread.
+#[cfg(not(target_feature = "atomics"))]
+unsafe impl Send for WebRtcSocket {}
+#[cfg(not(target_feature = "atomics"))]
+unsafe impl Sync for WebRtcSocket {}
+
+impl WebRtcSocket {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A placeholder address to identify the peer at `index`.
+    pub fn peer_addr(index: u16) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, index))
+    }
+
+    /// Send to and receive from `addr` over `channel`, replacing any channel it already had.
+    pub fn add_peer(&mut self, addr: SocketAddr, channel: RtcDataChannel) {
+        channel.set_binary_type(RtcDataChannelType::Arraybuffer);
+
+        let received = Arc::clone(&self.received);
+        let on_receive = Arc::clone(&self.on_receive);
+        let on_message = Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+            match event.data().dyn_into::<ArrayBuffer>() {
+                Ok(buffer) => {
+                    let bytes = Uint8Array::new(&buffer).to_vec();
+                    received.lock().unwrap().push_back((addr, bytes));
+                    if let Some(waker) = on_receive.lock().unwrap().take() {
+                        waker.wake();
+                    }
+                }
+                Err(_) => log::warn!("ignoring non-binary message from {}", addr),
+            }
+        });
+        channel.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+        self.peers.insert(
+            addr,
+            Peer {
+                channel,
+                _on_message: on_message,
+            },
+        );
+    }
+
+    pub fn remove_peer(&mut self, addr: SocketAddr) {
+        self.peers.remove(&addr);
+        self.received
+            .lock()
+            .unwrap()
+            .retain(|(from, _)| *from != addr);
+    }
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        // The callback is freed with the closure, so JS must not call it afterwards.
+        self.channel.set_onmessage(None);
+    }
+}
+
+impl NonBlockingSocket for WebRtcSocket {
+    fn send(&mut self, message: &[u8], addr: SocketAddr) {
+        let peer = match self.peers.get(&addr) {
+            Some(p) => p,
+            None => {
+                log::debug!("no channel to {}, dropping message", addr);
+                return;
+            }
+        };
+        // Sending on a channel that isn't open throws, which is no worse than a lost packet.
+        if let Err(e) = peer.channel.send_with_u8_array(message) {
+            log::debug!("failed to send to {}: {:?}", addr, e);
+            self.errors.send += 1;
+        }
+    }
+
+    fn recv(&mut self) -> Option<(SocketAddr, &[u8])> {
+        let (from, bytes) = self.received.lock().unwrap().pop_front()?;
+        self.current = bytes;
+        Some((from, &self.current))
+    }
+
+    fn errors(&self) -> SocketErrors {
+        self.errors
+    }
+
+    fn wake_on_receive(&mut self, waker: &Waker) -> bool {
+        if self.received.lock().unwrap().is_empty() {
+            *self.on_receive.lock().unwrap() = Some(waker.clone());
+        } else {
+            waker.wake_by_ref();
+        }
+        true
+    }
+}
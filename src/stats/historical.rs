@@ -1,37 +1,39 @@
-use std::{
-    collections::BTreeMap,
-    time::{Duration, Instant},
-};
+use std::{collections::BTreeMap, time::Duration};
+
+use crate::time::ClockHandle;
 
 pub struct Historical {
-    map: BTreeMap<Instant, u64>,
+    map: BTreeMap<Duration, u64>,
     keep_for: Duration,
+    clock: ClockHandle,
 }
 
 impl Historical {
-    pub fn over_secs(secs: u64) -> Self {
+    pub fn over_secs(secs: u64, clock: ClockHandle) -> Self {
         Historical {
             map: Default::default(),
             keep_for: Duration::from_secs(secs),
+            clock,
         }
     }
 
     pub fn clean(&mut self) {
         match self.map.range(..).next() {
-            None => return,
-            Some((at, _)) if at.elapsed() < self.keep_for * 2 => return,
+            None => {}
+            Some((at, _)) if self.clock.since(*at) < self.keep_for * 2 => {}
             _ => {
-                self.map = self.map.split_off(&(Instant::now() - self.keep_for));
+                let keep_after = self.clock.now().saturating_sub(self.keep_for);
+                self.map = self.map.split_off(&keep_after);
             }
         }
     }
 
     pub fn increment(&mut self, amount: u64) {
-        self.map.insert(Instant::now(), amount);
+        *self.map.entry(self.clock.now()).or_default() += amount;
     }
 
     pub fn avg_per_sec(&self) -> u64 {
-        let include_after = Instant::now() - self.keep_for;
+        let include_after = self.clock.now().saturating_sub(self.keep_for);
         self.map
             .range(include_after..)
             .map(|(_, amt)| amt)
@@ -1,14 +1,173 @@
-use crate::{utils::Signed, NonBlockingSocket};
+use crate::{
+    time::{Clock, ClockHandle, DefaultClock},
+    utils::Signed,
+    NonBlockingSocket, PlayerId, SocketErrors,
+};
 use bytesize::*;
-use std::{net::SocketAddr, time::Duration};
+use std::{collections::BTreeMap, net::SocketAddr, sync::Arc, task::Waker, time::Duration};
 
 mod historical;
 use historical::*;
 
 pub struct NetworkStats {
     pub drift: Signed<Duration>,
+    /// How fast drift correction is moving the clock per second, as of its latest correction,
+    /// see `ClockTuning`.
+    pub drift_rate: Signed<Duration>,
     pub elapsed: Signed<Duration>,
     pub socket: Option<SocketStats>,
+    /// Sends and receives that failed, treated as lost packets.
+    pub socket_errors: SocketErrors,
+    /// How many copies of each input message are currently sent.
+    pub redundancy: u32,
+    /// How many updates have stopped short of realtime because of the `CatchUp` policy.
+    pub catch_up_throttled: u64,
+    /// How many times the session has stopped at `SessionBuilder::max_prediction_frames` to wait
+    /// for inputs.
+    pub prediction_stalls: u64,
+    /// The latest confirmed frame and its checksum, see `Session::confirmed_checksum`.
+    pub confirmed_checksum: Option<(u32, u64)>,
+    /// The latest `(player, frame, checksum)` each remote has reported.
+    pub remote_checksums: Vec<(PlayerId, u32, u64)>,
+    /// How the connection to each remote player is doing, in player order.
+    pub players: Vec<(PlayerId, LinkStats)>,
+    /// How many frames ahead of the remotes our inputs seem to run on average. Includes the delay
+    /// in sending inputs, which the remotes see too, so the session compares its advantage with
+    /// theirs and holds its clock back when it is the one ahead.
+    pub frame_advantage: Option<f32>,
+    /// How far the local clock has been steered in total to even out the frame advantage.
+    pub steering: Signed<Duration>,
+    /// Bytes of our own input history sent so far, as `(uncompressed, sent)`, once per message
+    /// before redundant copies. The two only differ with `SessionBuilder::compress_inputs`.
+    pub input_bytes: (u64, u64),
+    /// How often inputs are currently sent, see `SessionBuilder::send_interval_bounds`.
+    pub send_interval: Duration,
+    /// The input delay of each player, in player order: for the local player, the delay of our
+    /// own inputs, and for remotes, the delay agreed with them by
+    /// `SessionBuilder::negotiate_input_delay`, zero without it.
+    pub input_delays: Vec<(PlayerId, u32)>,
+    /// The bytes taken by every confirmed state kept for rolling back to, see
+    /// `Session::retained_states`.
+    pub retained_state_bytes: usize,
+    /// Bytes per second the session sends to players, averaged over the last few seconds.
+    /// Measured by the session, so available with any socket.
+    pub player_outgoing: ByteSize,
+    /// Bytes per second the session sends to spectators, averaged like `player_outgoing`.
+    pub spectator_outgoing: ByteSize,
+    /// What the session has received and dropped since it started.
+    pub received: ReceivedPackets,
+}
+
+/// Counts of the packets the session has received since it started, for telemetry. Every dropped
+/// packet is also logged, with repeats throttled.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReceivedPackets {
+    /// Every packet the socket handed over.
+    pub packets: u64,
+    /// Packets dropped for coming from an address that is neither a player nor a spectator, and
+    /// isn't asking to become one.
+    pub unknown_sender: u64,
+    /// Packets dropped for coming from a peer on another `PROTOCOL_VERSION`.
+    pub wrong_version: u64,
+    /// Packets from players and spectators dropped for not decoding: without an rbrb header, a
+    /// malformed fragment, or a message the codec rejects.
+    pub decode_failures: u64,
+    /// Messages decoded from players and spectators, by type, such as `"Inputs"`.
+    pub messages: BTreeMap<&'static str, u64>,
+    /// Inputs dropped for being further ahead of our clock than any peer could be, from a buggy
+    /// or malicious peer.
+    pub out_of_range_inputs: u64,
+}
+
+/// Round-trip times and loss of the clock pings to one remote. Each estimate is `None` until
+/// there have been enough pings for it.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkStats {
+    pub average_rtt: Option<Duration>,
+    pub worst_rtt: Option<Duration>,
+    /// How many recent round trips the RTTs are taken from.
+    pub rtt_samples: usize,
+    /// Fraction of recent pings that went unanswered.
+    pub loss: Option<f64>,
+}
+
+/// How far the session has got agreeing on a start time with the remotes, see
+/// `Session::sync_state`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncState {
+    /// How many round trips have been measured to each remote player, in player order. A start
+    /// time is proposed once there are enough to each, or at `SessionBuilder::sync_timeout`.
+    pub rtt_samples: Vec<(PlayerId, usize)>,
+    /// How long until the session starts, zero once it has. `None` while still measuring round
+    /// trips. The start time can still move later if a remote proposes a later one.
+    pub starts_in: Option<Duration>,
+    /// Remote players yet to confirm the start time, in player order.
+    pub unacked: Vec<PlayerId>,
+}
+
+impl SyncState {
+    /// Whether round trips are still being measured, before any start time is known.
+    pub fn is_measuring(&self) -> bool {
+        self.starts_in.is_none()
+    }
+}
+
+/// How many frames realtime calls for compared to how many the host has simulated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameProgress {
+    pub realtime: u32,
+    pub simulated: u32,
+}
+
+impl FrameProgress {
+    /// Positive when the simulation lags realtime, negative when it is ahead.
+    pub fn behind(&self) -> i64 {
+        self.realtime as i64 - self.simulated as i64
+    }
+}
+
+/// How far realtime has moved on from the latest simulated frame, for rendering smoothly between
+/// frames, see `Session::update`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interpolation {
+    /// The frame the game state is at.
+    pub frame: u32,
+    /// How far realtime is past `frame`, as a fraction of a step from 0 up to 1. Blend from the
+    /// state before `frame` to the one at it by `alpha`, trailing realtime by a step, or
+    /// extrapolate from `frame` by it. 1 when the simulation is behind realtime.
+    pub alpha: f32,
+}
+
+/// What the session sends, split between players and spectators.
+pub(crate) struct OutgoingTraffic {
+    players: Historical,
+    spectators: Historical,
+}
+
+impl OutgoingTraffic {
+    pub fn new(clock: &ClockHandle) -> Self {
+        OutgoingTraffic {
+            players: Historical::over_secs(3, clock.clone()),
+            spectators: Historical::over_secs(3, clock.clone()),
+        }
+    }
+
+    pub fn record(&mut self, bytes: usize, to_spectator: bool) {
+        let history = match to_spectator {
+            true => &mut self.spectators,
+            false => &mut self.players,
+        };
+        history.clean();
+        history.increment(bytes as u64);
+    }
+
+    pub fn players(&self) -> ByteSize {
+        ByteSize(self.players.avg_per_sec())
+    }
+
+    pub fn spectators(&self) -> ByteSize {
+        ByteSize(self.spectators.avg_per_sec())
+    }
 }
 
 pub struct SocketStats {
@@ -24,10 +183,16 @@ pub struct BandwidthRecordingSocket<S: NonBlockingSocket> {
 
 impl<S: NonBlockingSocket> BandwidthRecordingSocket<S> {
     pub fn new(socket: S) -> Self {
+        Self::with_clock(socket, DefaultClock::default())
+    }
+
+    /// Measure bandwidth over the time kept by `clock` rather than real time.
+    pub fn with_clock(socket: S, clock: impl Clock + 'static) -> Self {
+        let clock = Arc::new(clock);
         BandwidthRecordingSocket {
             socket,
-            incoming_bytes: Historical::over_secs(3),
-            outgoing_bytes: Historical::over_secs(3),
+            incoming_bytes: Historical::over_secs(3, clock.clone()),
+            outgoing_bytes: Historical::over_secs(3, clock),
         }
     }
 
@@ -59,4 +224,12 @@ impl<S: NonBlockingSocket> NonBlockingSocket for BandwidthRecordingSocket<S> {
             outgoing_bytes: ByteSize(self.outgoing_bytes.avg_per_sec()),
         })
     }
+
+    fn errors(&self) -> SocketErrors {
+        self.socket.errors()
+    }
+
+    fn wake_on_receive(&mut self, waker: &Waker) -> bool {
+        self.socket.wake_on_receive(waker)
+    }
 }
@@ -0,0 +1,172 @@
+//! Changing the step size mid-session, see `Session::set_step_size`.
+//!
+//! Frames are numbered the same whatever their length, so each change takes effect from a frame
+//! agreed ahead of time. The simulation time a frame starts at is the sum of the frames before it,
+//! each at the step size it had, and which frame a time falls in is found the same way: up to the
+//! change by the old step size, and from the time the change's frame starts by the new one. The
+//! shared clock runs on unchanged, so there is no jump in time, only in how quickly frames come.
+
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, time::Duration};
+
+use crate::{utils::div_duration, Frame, FrameState};
+
+/// The longest a frame may be. Keeps the simulation time of any frame well within `Duration`.
+pub const MAX_STEP_SIZE: Duration = Duration::from_secs(1);
+
+/// How many changes may be waiting to take effect at once, so a remote can't grow the schedule
+/// without bound.
+pub const MAX_PENDING_CHANGES: usize = 8;
+
+/// Fails if frames can't be `step_size` long.
+pub fn check(step_size: Duration) -> Result<(), String> {
+    if step_size.is_zero() || step_size > MAX_STEP_SIZE {
+        return Err(format!(
+            "step size {:?} is not between zero and {:?}",
+            step_size, MAX_STEP_SIZE
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct StepSchedule {
+    /// The step size from each frame on, from frame 0.
+    steps: BTreeMap<Frame, Duration>,
+}
+
+impl StepSchedule {
+    pub fn new(step_size: Duration) -> Self {
+        StepSchedule {
+            steps: BTreeMap::from([(Frame(0), step_size)]),
+        }
+    }
+
+    /// The length of `frame`.
+    pub fn at(&self, frame: Frame) -> Duration {
+        *self
+            .steps
+            .range(..=frame)
+            .next_back()
+            .expect("frame 0 always has a step")
+            .1
+    }
+
+    /// Take `step_size` from `frame` on. The longer step wins if peers pick the same frame, so
+    /// every peer ends up alike. Returns whether anything changed.
+    ///
+    /// Fails if `step_size` isn't a valid frame length, or if `MAX_PENDING_CHANGES` changes
+    /// already take effect from `pending_from` on.
+    pub fn try_change(
+        &mut self,
+        frame: Frame,
+        step_size: Duration,
+        pending_from: Frame,
+    ) -> Result<bool, String> {
+        check(step_size)?;
+        let pending = self.changes_from(pending_from).count();
+        if pending >= MAX_PENDING_CHANGES && !self.steps.contains_key(&frame) {
+            return Err(format!("{} step size changes already pending", pending));
+        }
+        Ok(self.change(frame, step_size))
+    }
+
+    pub fn change(&mut self, frame: Frame, step_size: Duration) -> bool {
+        let step = self
+            .steps
+            .get(&frame)
+            .map_or(step_size, |s| (*s).max(step_size));
+        self.steps.insert(frame, step) != Some(step)
+    }
+
+    /// Changes taking effect at or after `frame`.
+    pub fn changes_from(&self, frame: Frame) -> impl Iterator<Item = (Frame, Duration)> + '_ {
+        self.steps.range(frame..).map(|(f, s)| (*f, *s))
+    }
+
+    /// The simulation time `frame` starts at.
+    pub fn start_of(&self, frame: Frame) -> Duration {
+        let mut segments = self.steps.iter().peekable();
+        let mut time = Duration::ZERO;
+        while let Some((&from, &step)) = segments.next() {
+            let until = match segments.peek() {
+                Some((&next, _)) if next < frame => next,
+                _ => return time + step * (frame.0 - from.0),
+            };
+            time += step * (until.0 - from.0);
+        }
+        unreachable!("frame 0 always has a step")
+    }
+
+    /// The frame simulation time `at` falls in.
+    pub fn frame_at(&self, at: Duration) -> FrameState {
+        let mut segments = self.steps.iter().peekable();
+        let mut start = Duration::ZERO;
+        while let Some((&from, &step)) = segments.next() {
+            if let Some((&next, _)) = segments.peek() {
+                let end = start + step * (next.0 - from.0);
+                if end <= at {
+                    start = end;
+                    continue;
+                }
+            }
+            let (n, rem) = div_duration(at - start, step);
+            return match rem.is_zero() {
+                true => FrameState::At(from + n),
+                false => FrameState::After(from + n, rem),
+            };
+        }
+        unreachable!("frame 0 always has a step")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn frames_change_length_from_the_agreed_frame() {
+        let mut steps = StepSchedule::new(ms(10));
+        assert!(steps.change(Frame(5), ms(20)));
+        assert!(!steps.change(Frame(5), ms(15)));
+        assert!(steps.change(Frame(8), ms(5)));
+
+        assert_eq!(steps.at(Frame(4)), ms(10));
+        assert_eq!(steps.at(Frame(7)), ms(20));
+        assert_eq!(steps.start_of(Frame(5)), ms(50));
+        assert_eq!(steps.start_of(Frame(8)), ms(110));
+        assert_eq!(steps.start_of(Frame(10)), ms(120));
+
+        assert_eq!(steps.frame_at(ms(45)), FrameState::After(Frame(4), ms(5)));
+        assert_eq!(steps.frame_at(ms(50)), FrameState::At(Frame(5)));
+        assert_eq!(steps.frame_at(ms(105)), FrameState::After(Frame(7), ms(15)));
+        assert_eq!(steps.frame_at(ms(112)), FrameState::After(Frame(8), ms(2)));
+        for frame in (0..20).map(Frame) {
+            assert_eq!(steps.frame_at(steps.start_of(frame)), FrameState::At(frame));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_step_sizes_and_too_many_changes() {
+        let mut steps = StepSchedule::new(ms(10));
+        assert!(steps
+            .try_change(Frame(5), Duration::ZERO, Frame(0))
+            .is_err());
+        assert!(steps.try_change(Frame(5), Duration::MAX, Frame(0)).is_err());
+
+        for frame in 1..=MAX_PENDING_CHANGES as u32 {
+            assert_eq!(steps.try_change(Frame(frame), ms(20), Frame(1)), Ok(true));
+        }
+        assert!(steps.try_change(Frame(100), ms(20), Frame(1)).is_err());
+        assert_eq!(steps.try_change(Frame(1), ms(30), Frame(1)), Ok(true));
+        assert_eq!(steps.try_change(Frame(100), ms(20), Frame(5)), Ok(true));
+
+        let far = Frame(u32::MAX - 1);
+        assert_eq!(steps.try_change(far, MAX_STEP_SIZE, Frame(5)), Ok(true));
+        assert_eq!(steps.frame_at(steps.start_of(far)), FrameState::At(far));
+    }
+}
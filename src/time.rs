@@ -3,63 +3,397 @@ use serde::*;
 use std::{
     collections::{BTreeMap, HashMap, HashSet, VecDeque},
     net::SocketAddr,
-    sync::RwLock,
+    sync::{Arc, Mutex, RwLock},
     time::{Duration, Instant},
 };
 
-use crate::utils::Signed;
+use crate::{stats::LinkStats, utils::Signed};
+
+/// Where a session gets the current time from.
+///
+/// `now` is the time since some fixed point of the clock's choosing, only differences between
+/// readings matter. It must never go backwards.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Duration;
+}
+
+impl dyn Clock {
+    pub(crate) fn since(&self, at: Duration) -> Duration {
+        self.now().saturating_sub(at)
+    }
+}
+
+impl std::fmt::Debug for dyn Clock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Clock").field("now", &self.now()).finish()
+    }
+}
+
+pub(crate) type ClockHandle = Arc<dyn Clock>;
+
+/// Real time, measured from when the clock was created. The default, except in the browser where
+/// `Instant` panics, see `PerformanceClock`.
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock {
+    epoch: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        SystemClock {
+            epoch: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.epoch.elapsed()
+    }
+}
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen::prelude::wasm_bindgen]
+extern "C" {
+    // `performance` is global in windows and workers alike.
+    #[wasm_bindgen(js_namespace = performance, js_name = now)]
+    fn performance_now() -> f64;
+}
+
+/// Real time from the browser's `performance.now()`, measured from when the clock was created.
+/// The default with the `wasm` feature on a wasm target.
+#[cfg(feature = "wasm")]
+#[derive(Debug, Clone, Copy)]
+pub struct PerformanceClock {
+    epoch_ms: f64,
+}
+
+#[cfg(feature = "wasm")]
+impl PerformanceClock {
+    pub fn new() -> Self {
+        PerformanceClock {
+            epoch_ms: performance_now(),
+        }
+    }
+}
+
+#[cfg(feature = "wasm")]
+impl Default for PerformanceClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "wasm")]
+impl Clock for PerformanceClock {
+    fn now(&self) -> Duration {
+        Duration::from_secs_f64((performance_now() - self.epoch_ms).max(0.) / 1000.)
+    }
+}
+
+/// The clock sessions and sockets use unless given one.
+#[cfg(all(feature = "wasm", target_family = "wasm"))]
+pub(crate) type DefaultClock = PerformanceClock;
+#[cfg(not(all(feature = "wasm", target_family = "wasm")))]
+pub(crate) type DefaultClock = SystemClock;
+
+pub(crate) fn default_clock() -> ClockHandle {
+    Arc::new(DefaultClock::default())
+}
+
+/// Time that only moves when `advance` is called. Clones share the same time, so keep one to
+/// drive the clock given to a session.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    now: Arc<Mutex<Duration>>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+}
 
 #[derive(Debug)]
 pub struct Interval {
-    last: Option<Instant>,
+    last: Option<Duration>,
     every: Duration,
+    clock: ClockHandle,
 }
 
 impl Interval {
-    pub fn new(every: Duration) -> Self {
-        Interval { last: None, every }
+    pub fn new(every: Duration, clock: &ClockHandle) -> Self {
+        Interval {
+            last: None,
+            every,
+            clock: Arc::clone(clock),
+        }
     }
 
     pub fn is_time(&mut self) -> bool {
         match self.last.as_mut() {
-            Some(at) if at.elapsed() < self.every => return false,
+            Some(at) if self.clock.since(*at) < self.every => return false,
             Some(at) => *at += self.every,
-            None => self.last = Some(Instant::now()),
+            None => self.last = Some(self.clock.now()),
         }
         true
     }
 
+    /// How long until `is_time` is next true, zero if it already is.
+    pub fn time_until(&self) -> Duration {
+        match self.last {
+            Some(at) => (at + self.every).saturating_sub(self.clock.now()),
+            None => Duration::ZERO,
+        }
+    }
+
     pub fn set_every(&mut self, every: Duration) {
         self.every = every;
     }
+
+    /// Like `is_time`, but restarts the interval from now instead of firing repeatedly to catch
+    /// up on missed intervals.
+    pub fn is_time_restarting(&mut self) -> bool {
+        match self.last {
+            Some(at) if self.clock.since(at) < self.every => false,
+            _ => {
+                self.last = Some(self.clock.now());
+                true
+            }
+        }
+    }
 }
 
+/// Rate limits log messages that can repeat every frame, separately for each category. The
+/// first occurrence is always logged.
+#[derive(Debug)]
+pub struct LogThrottle {
+    every: Duration,
+    categories: HashMap<&'static str, (Interval, u32)>,
+    clock: ClockHandle,
+}
+
+impl LogThrottle {
+    pub fn new(every: Duration, clock: &ClockHandle) -> Self {
+        LogThrottle {
+            every,
+            categories: Default::default(),
+            clock: Arc::clone(clock),
+        }
+    }
+
+    /// Whether to log a message in `category` now.
+    pub fn check(&mut self, category: &'static str) -> Option<Suppressed> {
+        let (every, clock) = (self.every, &self.clock);
+        let (interval, suppressed) = self
+            .categories
+            .entry(category)
+            .or_insert_with(|| (Interval::new(every, clock), 0));
+        if interval.is_time_restarting() {
+            Some(Suppressed(std::mem::take(suppressed)))
+        } else {
+            *suppressed += 1;
+            None
+        }
+    }
+}
+
+/// How many messages were held back since the last one logged, formatted as a suffix.
+pub struct Suppressed(u32);
+
+impl std::fmt::Display for Suppressed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            0 => Ok(()),
+            n => write!(f, " ({} similar suppressed)", n),
+        }
+    }
+}
+
+/// How the shared clock corrects drift from the remotes, see `SessionBuilder::clock_tuning`.
+///
+/// Every time a remote tells us its elapsed time, at most once per `interval`, the clock compares
+/// its own against the average of the remotes', each advanced by the time since it was heard and
+/// half the round trip. It moves its drift to close that gap, plus `weighted_adjust` further in
+/// the direction it has drifted so far, which works through a steady difference in clock rates
+/// rather than only chasing it. The move is capped at `max_change` each time, so a single late
+/// message can't yank the clock.
+///
+/// On a jittery network, a longer `interval` or a smaller `max_change` keeps the clock from
+/// chasing the noise, at the cost of correcting real drift more slowly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockTuning {
+    /// Whether to correct drift at all. Peers on a LAN may keep close enough time without.
+    pub enabled: bool,
+    /// How often drift is corrected at most.
+    pub interval: Duration,
+    /// The most drift may change in one correction.
+    pub max_change: Duration,
+    /// How much further each correction moves in the direction drifted so far.
+    pub weighted_adjust: Duration,
+}
+
+impl Default for ClockTuning {
+    fn default() -> Self {
+        ClockTuning {
+            enabled: true,
+            interval: Duration::from_millis(100),
+            max_change: Duration::from_millis(1),
+            weighted_adjust: Duration::from_micros(100),
+        }
+    }
+}
+
+impl ClockTuning {
+    /// Never correct drift, leaving each peer on its own clock once started.
+    pub fn disabled() -> Self {
+        ClockTuning {
+            enabled: false,
+            ..Default::default()
+        }
+    }
+}
+
+/// How many times slower than realtime `SharedClock::elapsed` runs while the shared time is behind
+/// what it already handed out.
+const BEHIND_SLOWDOWN: u32 = 2;
+
+/// How far steering may take the local time from the shared time, either way.
+const MAX_STEERING: Duration = Duration::from_millis(250);
+
 #[derive(Debug)]
 pub struct SharedClock {
     state: ClockState,
     remotes: HashMap<SocketAddr, NetworkQuality>,
+    /// Remotes that are told the time but have no say in it.
+    followers: HashSet<SocketAddr>,
+    /// Whether this clock only takes its time from the remotes, as a follower.
+    following: bool,
     queue: VecDeque<(SocketAddr, ClockMessage)>,
 
-    remote_elapsed: HashMap<SocketAddr, (Signed<Duration>, Instant)>,
-    last_elapsed: RwLock<Duration>,
+    remote_elapsed: HashMap<SocketAddr, (Signed<Duration>, Duration)>,
+    /// The latest `elapsed` handed out and the clock reading it was at, once started.
+    last_elapsed: RwLock<Option<(Duration, Duration)>>,
     drift: Signed<Duration>,
+    tuning: ClockTuning,
     adjust_drift: Interval,
+    /// How fast drift correction moved the clock, per second, as of the latest correction.
+    drift_rate: Signed<Duration>,
+    last_adjusted: Duration,
+    injected_offset: Signed<Duration>,
+    steering: Signed<Duration>,
+    /// How long to wait for good RTT estimates before starting anyway.
+    sync_timeout: Option<Duration>,
+    created_at: Duration,
+    clock: ClockHandle,
 }
 
 impl SharedClock {
-    pub fn among_remotes(remotes: impl IntoIterator<Item = SocketAddr>) -> Self {
+    pub fn among_remotes(
+        remotes: impl IntoIterator<Item = SocketAddr>,
+        clock: ClockHandle,
+    ) -> Self {
         SharedClock {
             state: ClockState::Synchronizing,
             remotes: remotes
                 .into_iter()
-                .map(|addr| (addr, Default::default()))
+                .map(|addr| (addr, NetworkQuality::new(&clock)))
                 .collect(),
+            followers: Default::default(),
+            following: false,
             queue: Default::default(),
 
             remote_elapsed: Default::default(),
-            last_elapsed: RwLock::new(Duration::ZERO),
+            last_elapsed: RwLock::new(None),
             drift: Signed::Pos(Duration::ZERO),
-            adjust_drift: Interval::new(Duration::from_millis(100)),
+            tuning: ClockTuning::default(),
+            adjust_drift: Interval::new(ClockTuning::default().interval, &clock),
+            drift_rate: Signed::Pos(Duration::ZERO),
+            last_adjusted: clock.now(),
+            injected_offset: Signed::Pos(Duration::ZERO),
+            steering: Signed::Pos(Duration::ZERO),
+            sync_timeout: None,
+            created_at: clock.now(),
+            clock,
+        }
+    }
+
+    /// Once `timeout` has passed without enough pings to be confident in the start time, start
+    /// with whatever round-trip times have been measured, or none.
+    pub fn with_sync_timeout(mut self, timeout: Duration) -> Self {
+        self.sync_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_tuning(mut self, tuning: ClockTuning) -> Self {
+        self.adjust_drift = Interval::new(tuning.interval, &self.clock);
+        self.tuning = tuning;
+        self
+    }
+
+    fn sync_timed_out(&self) -> bool {
+        self.sync_timeout
+            .is_some_and(|timeout| self.clock.since(self.created_at) >= timeout)
+    }
+
+    /// A clock that never proposes a start time of its own, adopting the remotes' instead.
+    pub fn following(remotes: impl IntoIterator<Item = SocketAddr>, clock: ClockHandle) -> Self {
+        SharedClock {
+            following: true,
+            ..SharedClock::among_remotes(remotes, clock)
+        }
+    }
+
+    /// Share the time with `addr` without waiting on it to start or letting it affect drift.
+    pub fn add_follower(&mut self, addr: SocketAddr) {
+        self.add_remote(addr);
+        self.followers.insert(addr);
+    }
+
+    pub fn add_remote(&mut self, addr: SocketAddr) {
+        let clock = &self.clock;
+        self.remotes
+            .entry(addr)
+            .or_insert_with(|| NetworkQuality::new(clock));
+    }
+
+    pub fn remove_remote(&mut self, addr: SocketAddr) {
+        self.remotes.remove(&addr);
+        self.followers.remove(&addr);
+        self.remote_elapsed.remove(&addr);
+        self.queue.retain(|(a, _)| *a != addr);
+        if let ClockState::Start { unacked, .. } = &mut self.state {
+            unacked.remove(&addr);
+        }
+    }
+
+    /// Move a remote to a new address, discarding everything measured over the old path.
+    pub fn replace_remote(&mut self, old: SocketAddr, new: SocketAddr) {
+        self.remotes.remove(&old);
+        self.remotes.insert(new, NetworkQuality::new(&self.clock));
+        self.remote_elapsed.remove(&old);
+        self.queue.retain(|(addr, _)| *addr != old);
+
+        if let ClockState::Start { unacked, .. } = &mut self.state {
+            if unacked.remove(&old) {
+                unacked.insert(new);
+            }
         }
     }
 
@@ -76,19 +410,50 @@ impl SharedClock {
             })
     }
 
+    /// How long until `message` has something to send that isn't in answer to a remote.
+    pub fn next_message_in(&self) -> Duration {
+        if !self.queue.is_empty() {
+            return Duration::ZERO;
+        }
+        let sync_start = match &self.state {
+            ClockState::Start { sync_start, .. } if !self.following => {
+                Some(sync_start.time_until())
+            }
+            _ => None,
+        };
+        self.remotes
+            .values()
+            .map(|network| network.ping_interval.time_until())
+            .chain(sync_start)
+            .min()
+            .unwrap_or(Duration::MAX)
+    }
+
     fn start_message(&mut self) -> Option<(SocketAddr, ClockMessage)> {
+        if self.following {
+            return None;
+        }
+
         if let ClockState::Synchronizing = self.state {
+            let timed_out = self.sync_timed_out();
             let worst_rtt = self
                 .remotes
-                .values()
-                .map(|network| network.worst_case_rtt())
+                .iter()
+                .filter(|(addr, _)| !self.followers.contains(addr))
+                .map(|(_, network)| match network.worst_case_rtt() {
+                    None if timed_out => Some(network.best_guess_rtt()),
+                    rtt => rtt,
+                })
                 .collect::<Option<Vec<_>>>()?
                 .into_iter()
                 .max()
                 .unwrap_or_default();
+            if timed_out {
+                log::warn!("clock sync timed out, starting without confident round-trip times");
+            }
 
             let confident_start_in = 10 * worst_rtt;
-            self.update_start_time(Instant::now() + confident_start_in);
+            self.update_start_time(self.clock.now() + confident_start_in);
         }
 
         let message = ClockMessage::Elapsed(self.signed_elapsed()?);
@@ -102,14 +467,14 @@ impl SharedClock {
                     return None;
                 }
 
-                if unacked.len() == 0 {
+                if unacked.is_empty() {
                     sync_start.set_every(Duration::from_millis(500));
                     self.queue
-                        .extend(self.remotes.keys().map(|addr| (*addr, message.clone())));
+                        .extend(self.remotes.keys().map(|addr| (*addr, message)));
                 } else {
                     sync_start.set_every(Duration::from_millis(50));
                     self.queue
-                        .extend(unacked.iter().map(|addr| (*addr, message.clone())));
+                        .extend(unacked.iter().map(|addr| (*addr, message)));
                 }
                 self.queue.pop_front()
             }
@@ -123,17 +488,24 @@ impl SharedClock {
                 self.remotes.get_mut(&from).unwrap().receive_message(m);
             }
 
+            ClockMessage::Elapsed(_) if self.followers.contains(&from) => {}
+
             ClockMessage::Elapsed(amt) => {
                 self.record_remote_elapsed(from, amt);
                 self.adjust_drift();
 
-                if let Some(rtt) = self.remotes[&from].average_rtt() {
+                let network = &self.remotes[&from];
+                let rtt = match network.average_rtt() {
+                    None if self.sync_timed_out() => Some(network.best_guess_rtt()),
+                    rtt => rtt,
+                };
+                if let Some(rtt) = rtt {
                     let true_elapsed = amt - (rtt / 2).into();
-                    let start_at = true_elapsed.sub_from(Instant::now());
+                    let start_at = true_elapsed.sub_from(self.clock.now());
 
                     if self.update_start_time(start_at) {
                         log::info!("now starting in {:?}", self.signed_elapsed().unwrap());
-                    } else {
+                    } else if !self.following {
                         match &mut self.state {
                             ClockState::Start { unacked, .. } => {
                                 unacked.remove(&from);
@@ -155,17 +527,17 @@ impl SharedClock {
         let existing = self
             .remote_elapsed
             .entry(from)
-            .or_insert_with(|| (elapsed, Instant::now()));
+            .or_insert_with(|| (elapsed, self.clock.now()));
         if elapsed <= existing.0 {
             return;
         }
 
         existing.0 = elapsed;
-        existing.1 = Instant::now();
+        existing.1 = self.clock.now();
     }
 
     fn adjust_drift(&mut self) {
-        if !self.adjust_drift.is_time() {
+        if !self.tuning.enabled || !self.adjust_drift.is_time() {
             return;
         }
         let local_elapsed = match self.signed_elapsed() {
@@ -176,27 +548,34 @@ impl SharedClock {
             .remote_elapsed
             .iter()
             .filter_map(|(addr, &(elapsed, at))| {
-                let remote_elapsed =
-                    elapsed + at.elapsed().into() + (self.remotes[addr].average_rtt()? / 2).into();
+                let remote_elapsed = elapsed
+                    + self.clock.since(at).into()
+                    + (self.remotes[addr].average_rtt()? / 2).into();
                 let delta = local_elapsed - remote_elapsed;
                 Some(delta)
             })
             .sum::<Signed<Duration>>()
             / (self.remote_elapsed.len() as u32);
 
-        let weighted_adjust = self.drift.map(|_| Duration::from_micros(100));
+        let weighted_adjust = self.drift.map(|_| self.tuning.weighted_adjust);
         let delta = -avg_delta + weighted_adjust;
 
-        let max_change = Duration::from_millis(1);
+        let max_change = self.tuning.max_change;
         let change = delta.clamp(Signed::Neg(max_change), Signed::Pos(max_change));
         self.drift = self.drift + change;
+
+        let since = self.clock.since(self.last_adjusted).as_secs_f64();
+        if since > 0. {
+            self.drift_rate = change.map(|c| c.div_f64(since));
+        }
+        self.last_adjusted = self.clock.now();
     }
 
-    fn update_start_time(&mut self, new_at: Instant) -> bool {
+    fn update_start_time(&mut self, new_at: Duration) -> bool {
         match &self.state {
             ClockState::Synchronizing => {}
             ClockState::Start { at, .. } => {
-                if *at < Instant::now() {
+                if *at < self.clock.now() {
                     return false;
                 }
                 if duration_since(*at, new_at).abs() < Duration::from_millis(400) {
@@ -210,44 +589,140 @@ impl SharedClock {
 
         log::info!(
             "connected, starting in {:?}",
-            duration_since(new_at, Instant::now()),
+            duration_since(new_at, self.clock.now()),
         );
         self.state = ClockState::Start {
             at: new_at,
-            unacked: self.remotes.keys().cloned().collect(),
-            sync_start: Interval::new(Duration::from_millis(50)),
+            unacked: self
+                .remotes
+                .keys()
+                .filter(|addr| !self.followers.contains(addr))
+                .cloned()
+                .collect(),
+            sync_start: Interval::new(Duration::from_millis(50), &self.clock),
         };
         true
     }
 
+    /// The local simulation time, including steering. `None` until the start time.
+    ///
+    /// Never decreases, even when drift correction or steering moves the shared time backwards,
+    /// as frames already simulated can't be taken back. Holding the time still until the shared
+    /// time caught up would freeze the game for as long as the correction, so instead the time
+    /// runs at `1 / BEHIND_SLOWDOWN` speed while behind, absorbing the correction over a few
+    /// frames that each take a little longer.
     pub fn elapsed(&self) -> Option<Duration> {
-        let correct = self.signed_elapsed()?.pos()?;
+        let now = self.clock.now();
+        let correct = self.signed_elapsed_at(now)? + self.steering;
         let mut lock = self.last_elapsed.write().unwrap();
-        let never_decrease = std::cmp::max(correct, *lock);
-        *lock = never_decrease;
-        Some(never_decrease)
+
+        let elapsed = match (*lock, correct.pos()) {
+            (None, correct) => correct?,
+            (Some((last, _)), Some(correct)) if correct >= last => correct,
+            (Some((last, at)), _) => last + now.saturating_sub(at) / BEHIND_SLOWDOWN,
+        };
+        *lock = Some((elapsed, now));
+        Some(elapsed)
     }
 
     pub fn signed_elapsed(&self) -> Option<Signed<Duration>> {
+        self.signed_elapsed_at(self.clock.now())
+    }
+
+    /// The elapsed time when the clock reads `now`.
+    pub fn signed_elapsed_at(&self, now: Duration) -> Option<Signed<Duration>> {
         match self.state {
             ClockState::Synchronizing => None,
             ClockState::Start { at, .. } => {
-                let only_local = duration_since(Instant::now(), at);
-                Some(only_local + self.drift)
+                let only_local = duration_since(now, at);
+                Some(only_local + self.injected_offset + self.drift)
             }
         }
     }
 
+    /// How the link to `addr` is doing, if it takes part in the clock.
+    pub fn link_stats(&self, addr: SocketAddr) -> Option<LinkStats> {
+        self.remotes.get(&addr).map(NetworkQuality::stats)
+    }
+
+    /// The worst packet loss estimate among all remotes.
+    pub fn worst_loss(&self) -> Option<f64> {
+        self.remotes
+            .values()
+            .filter_map(NetworkQuality::loss)
+            .max_by(|a, b| a.total_cmp(b))
+    }
+
     pub fn drift(&self) -> Signed<Duration> {
         self.drift
     }
+
+    /// How fast drift correction moved the clock per second of real time, as of its latest
+    /// correction.
+    pub fn drift_rate(&self) -> Signed<Duration> {
+        self.drift_rate
+    }
+
+    /// How many round trips have been measured to each remote with a say in the start time.
+    pub fn rtt_samples(&self) -> impl Iterator<Item = (SocketAddr, usize)> + '_ {
+        self.remotes
+            .iter()
+            .filter(|(addr, _)| !self.followers.contains(addr))
+            .map(|(addr, network)| (*addr, network.rtts.len()))
+    }
+
+    /// How long until the agreed start time, zero once it has passed. `None` until there is one.
+    pub fn starts_in(&self) -> Option<Duration> {
+        match &self.state {
+            ClockState::Synchronizing => None,
+            ClockState::Start { at, .. } => Some(at.saturating_sub(self.clock.now())),
+        }
+    }
+
+    /// The remotes yet to confirm our start time.
+    pub fn unacked(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        let unacked = match &self.state {
+            ClockState::Synchronizing => None,
+            ClockState::Start { unacked, .. } => Some(unacked),
+        };
+        unacked.into_iter().flatten().copied()
+    }
+
+    /// Move the local simulation time by `by` on top of the shared time, to within
+    /// `MAX_STEERING` of it. Unlike drift, steering is never shared with or corrected towards the
+    /// remotes.
+    pub fn steer(&mut self, by: Signed<Duration>) {
+        self.steering = match self.steering + by {
+            Signed::Pos(t) => Signed::Pos(t.min(MAX_STEERING)),
+            Signed::Neg(t) => Signed::Neg(t.min(MAX_STEERING)),
+        };
+    }
+
+    /// Take up to `by` off the steering, back towards the shared time.
+    pub fn relax_steering(&mut self, by: Duration) {
+        self.steering = match self.steering {
+            Signed::Pos(t) => Signed::Pos(t.saturating_sub(by)),
+            Signed::Neg(t) => Signed::Neg(t.saturating_sub(by)),
+        };
+    }
+
+    pub fn steering(&self) -> Signed<Duration> {
+        self.steering
+    }
+
+    /// Skew the local elapsed time as if this clock ran fast or slow by `offset`. Drift
+    /// correction should converge to cancel it out.
+    #[cfg(any(test, feature = "debug-clock"))]
+    pub fn inject_offset(&mut self, offset: Signed<Duration>) {
+        self.injected_offset = offset;
+    }
 }
 
-fn duration_since(a: Instant, b: Instant) -> Signed<Duration> {
+fn duration_since(a: Duration, b: Duration) -> Signed<Duration> {
     if a > b {
-        Signed::Pos(a.duration_since(b))
+        Signed::Pos(a - b)
     } else {
-        Signed::Neg(b.duration_since(a))
+        Signed::Neg(b - a)
     }
 }
 
@@ -261,41 +736,51 @@ pub enum ClockMessage {
 enum ClockState {
     Synchronizing,
     Start {
-        at: Instant,
+        at: Duration,
         unacked: HashSet<SocketAddr>,
         sync_start: Interval,
     },
 }
 
+const PING_RESULTS: usize = 50;
+const PING_LOST_AFTER: Duration = Duration::from_secs(1);
+
 #[derive(Debug)]
 struct NetworkQuality {
-    rtts: BTreeMap<Instant, Duration>,
-    outgoing: HashMap<u64, Instant>,
-    pong_queue: VecDeque<(u64, Instant)>,
+    rtts: BTreeMap<Duration, Duration>,
+    outgoing: HashMap<u64, Duration>,
+    ping_results: VecDeque<(u64, Duration, bool)>,
+    pong_queue: VecDeque<(u64, Duration)>,
     ping_interval: Interval,
+    clock: ClockHandle,
 }
 
-impl Default for NetworkQuality {
-    fn default() -> Self {
+impl NetworkQuality {
+    fn new(clock: &ClockHandle) -> Self {
         NetworkQuality {
             outgoing: Default::default(),
-            ping_interval: Interval::new(Duration::from_millis(100)),
+            ping_results: Default::default(),
+            ping_interval: Interval::new(Duration::from_millis(100), clock),
             pong_queue: Default::default(),
             rtts: Default::default(),
+            clock: Arc::clone(clock),
         }
     }
-}
 
-impl NetworkQuality {
     fn message(&mut self) -> Option<NetworkAnalysisMessage> {
         use NetworkAnalysisMessage::*;
 
         if let Some((data, received_at)) = self.pong_queue.pop_front() {
-            return Some(Pong(data, received_at.elapsed()));
+            return Some(Pong(data, self.clock.since(received_at)));
         }
         if self.ping_interval.is_time() {
             let id = rand::thread_rng().gen();
-            self.outgoing.insert(id, Instant::now());
+            let now = self.clock.now();
+            self.outgoing.insert(id, now);
+            self.ping_results.push_back((id, now, false));
+            if self.ping_results.len() > PING_RESULTS {
+                self.ping_results.pop_front();
+            }
             return Some(Ping(id));
         }
         None
@@ -307,15 +792,21 @@ impl NetworkQuality {
 
         match message {
             Ping(data) => {
-                self.pong_queue.push_back((data, Instant::now()));
+                self.pong_queue.push_back((data, self.clock.now()));
             }
             Pong(data, remote_processing_time) => {
+                if let Some(result) = self.ping_results.iter_mut().find(|(id, ..)| *id == data) {
+                    result.2 = true;
+                }
                 let sent_at = match self.outgoing.remove(&data) {
                     Some(s) => s,
                     None => return,
                 };
-                self.rtts
-                    .insert(Instant::now(), sent_at.elapsed() - remote_processing_time);
+                let rtt = self
+                    .clock
+                    .since(sent_at)
+                    .saturating_sub(remote_processing_time);
+                self.rtts.insert(self.clock.now(), rtt);
             }
         }
     }
@@ -326,7 +817,7 @@ impl NetworkQuality {
         }
 
         while self.rtts.len() > 10 {
-            let front = self.rtts.keys().next().unwrap().clone();
+            let front = *self.rtts.keys().next().unwrap();
             self.rtts.remove(&front);
         }
 
@@ -352,12 +843,47 @@ impl NetworkQuality {
         Some(self.rtts.values().sum::<Duration>() / self.rtts.len() as u32)
     }
 
+    /// Fraction of recent pings that went unanswered, counting a ping as lost once it has been
+    /// outstanding for `PING_LOST_AFTER`.
+    fn loss(&self) -> Option<f64> {
+        let (answered, lost) = self.ping_results.iter().fold(
+            (0, 0),
+            |(answered, lost), (_, sent_at, was_answered)| {
+                if *was_answered {
+                    (answered + 1, lost)
+                } else if self.clock.since(*sent_at) > PING_LOST_AFTER {
+                    (answered, lost + 1)
+                } else {
+                    (answered, lost)
+                }
+            },
+        );
+        if answered + lost < 5 {
+            return None;
+        }
+        Some(lost as f64 / (answered + lost) as f64)
+    }
+
+    /// The worst RTT measured so far however few there are, zero without any.
+    fn best_guess_rtt(&self) -> Duration {
+        self.rtts.values().max().copied().unwrap_or_default()
+    }
+
     fn worst_case_rtt(&self) -> Option<Duration> {
         if self.rtts.len() < 5 {
             return None;
         }
         self.rtts.values().max().cloned()
     }
+
+    fn stats(&self) -> LinkStats {
+        LinkStats {
+            average_rtt: self.average_rtt(),
+            worst_rtt: self.worst_case_rtt(),
+            rtt_samples: self.rtts.len(),
+            loss: self.loss(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, Copy)]
@@ -365,3 +891,143 @@ pub enum NetworkAnalysisMessage {
     Ping(u64),
     Pong(u64, Duration),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle_logs_first_and_counts_suppressed() {
+        let clock = ManualClock::new();
+        let handle: ClockHandle = Arc::new(clock.clone());
+        let mut throttle = LogThrottle::new(Duration::from_millis(5), &handle);
+        assert_eq!(throttle.check("a").unwrap().0, 0);
+        assert!(throttle.check("a").is_none());
+        assert!(throttle.check("a").is_none());
+        assert!(throttle.check("b").is_some());
+
+        clock.advance(Duration::from_millis(10));
+        assert_eq!(throttle.check("a").unwrap().0, 2);
+        assert!(throttle.check("a").is_none());
+    }
+
+    #[test]
+    fn starts_without_pongs_after_the_sync_timeout() {
+        let clock = ManualClock::new();
+        let remote = SocketAddr::from(([127, 0, 0, 1], 1));
+        let mut shared = SharedClock::among_remotes([remote], Arc::new(clock.clone()))
+            .with_sync_timeout(Duration::from_secs(2));
+
+        for _ in 0..100 {
+            while shared.message().is_some() {}
+            clock.advance(Duration::from_millis(10));
+        }
+        assert_eq!(shared.signed_elapsed(), None);
+
+        for _ in 0..110 {
+            while shared.message().is_some() {}
+            clock.advance(Duration::from_millis(10));
+        }
+        assert!(shared.signed_elapsed().is_some());
+    }
+
+    #[test]
+    fn tuning_bounds_drift_correction() {
+        let remote = SocketAddr::from(([127, 0, 0, 1], 1));
+        let corrected = |tuning| {
+            let clock = ManualClock::new();
+            let mut shared =
+                SharedClock::among_remotes([remote], Arc::new(clock.clone())).with_tuning(tuning);
+            let network = shared.remotes.get_mut(&remote).unwrap();
+            for at in 0..3 {
+                network
+                    .rtts
+                    .insert(Duration::from_nanos(at), Duration::ZERO);
+            }
+            shared.update_start_time(Duration::ZERO);
+            // The remote runs 50ms behind us.
+            for i in 1..=10 {
+                clock.advance(Duration::from_millis(100));
+                let elapsed = Duration::from_millis(100 * i - 50);
+                shared.receive_message(remote, ClockMessage::Elapsed(Signed::Pos(elapsed)));
+            }
+            (shared.drift(), shared.drift_rate())
+        };
+
+        let (drift, rate) = corrected(ClockTuning::default());
+        assert_eq!(drift, Signed::Neg(Duration::from_millis(10)));
+        assert!(rate < Signed::Neg(Duration::from_millis(9)), "{:?}", rate);
+
+        let (drift, _) = corrected(ClockTuning {
+            max_change: Duration::from_millis(2),
+            ..Default::default()
+        });
+        assert_eq!(drift, Signed::Neg(Duration::from_millis(20)));
+
+        let (drift, rate) = corrected(ClockTuning::disabled());
+        assert_eq!(
+            (drift, rate),
+            (Signed::Pos(Duration::ZERO), Signed::Pos(Duration::ZERO))
+        );
+    }
+
+    #[test]
+    fn slows_down_rather_than_going_back() {
+        let clock = ManualClock::new();
+        let mut shared = SharedClock::among_remotes([], Arc::new(clock.clone()));
+        shared.update_start_time(Duration::from_millis(100));
+        assert_eq!(shared.elapsed(), None);
+
+        clock.advance(Duration::from_millis(200));
+        assert_eq!(shared.elapsed(), Some(Duration::from_millis(100)));
+
+        shared.steer(Signed::Neg(Duration::from_millis(50)));
+        clock.advance(Duration::from_millis(10));
+        assert_eq!(shared.elapsed(), Some(Duration::from_millis(105)));
+
+        // Caught up with the shared time, so follows it again.
+        clock.advance(Duration::from_millis(90));
+        assert_eq!(shared.elapsed(), Some(Duration::from_millis(150)));
+        clock.advance(Duration::from_millis(10));
+        assert_eq!(shared.elapsed(), Some(Duration::from_millis(160)));
+    }
+
+    #[test]
+    fn steering_is_bounded_and_relaxes() {
+        let mut shared = SharedClock::among_remotes([], Arc::new(ManualClock::new()));
+        for _ in 0..100 {
+            shared.steer(Signed::Neg(Duration::from_millis(10)));
+        }
+        assert_eq!(shared.steering(), Signed::Neg(MAX_STEERING));
+
+        shared.steer(Signed::Pos(Duration::from_millis(50)));
+        assert_eq!(
+            shared.steering(),
+            Signed::Neg(MAX_STEERING - Duration::from_millis(50))
+        );
+
+        shared.relax_steering(Duration::from_millis(150));
+        assert_eq!(shared.steering(), Signed::Neg(Duration::from_millis(50)));
+        shared.relax_steering(Duration::from_millis(150));
+        assert_eq!(shared.steering().abs(), Duration::ZERO);
+    }
+
+    #[test]
+    fn injected_offset_skews_elapsed() {
+        let mut clock = SharedClock::among_remotes([], Arc::new(ManualClock::new()));
+        let start = Duration::from_secs(1);
+        clock.update_start_time(start);
+
+        clock.inject_offset(Signed::Pos(Duration::from_millis(50)));
+        assert_eq!(
+            clock.signed_elapsed_at(start + Duration::from_millis(10)),
+            Some(Signed::Pos(Duration::from_millis(60)))
+        );
+
+        clock.inject_offset(Signed::Neg(Duration::from_millis(50)));
+        assert_eq!(
+            clock.signed_elapsed_at(start + Duration::from_millis(10)),
+            Some(Signed::Neg(Duration::from_millis(40)))
+        );
+    }
+}
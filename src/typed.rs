@@ -0,0 +1,200 @@
+//! Inputs as game types instead of bytes.
+//!
+//! `TypedSession` wraps a `Session` and encodes inputs with bincode, the same encoding
+//! `default_inputs` should be given in. Handlers fill in a `&mut I` to capture local input and
+//! receive `PlayerInputs` of `I` to advance with, and every other request passes through as is.
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::{ops::ControlFlow, time::Duration};
+
+use crate::{
+    request_handler::MaybeMessage, Confirmation, ConfirmationStatus, PlayerId, PlayerInputs,
+    Request, RequestHandler, Session,
+};
+
+#[non_exhaustive]
+pub enum TypedRequest<'s, I> {
+    /// Fill in the local player's input, which starts out as the default input.
+    CaptureLocalInput(&'s mut I),
+    /// `Request::CaptureInput`: fill in local `player`'s input for `frame`, which starts out as
+    /// the default input.
+    #[non_exhaustive]
+    CaptureInput {
+        input: &'s mut I,
+        frame: u32,
+        player: PlayerId,
+    },
+    /// `Request::Advance` with decoded inputs. An input that doesn't decode, such as from a buggy
+    /// or malicious peer, is replaced with the default input, which every peer does alike.
+    #[non_exhaustive]
+    Advance {
+        amount: Duration,
+        inputs: PlayerInputs<ConfirmationStatus<I>>,
+        confirmed: Confirmation,
+        current_frame: u32,
+        resimulation: bool,
+    },
+    /// Any other request, unchanged.
+    Other(Request<'s>),
+}
+
+/// A `Session` whose inputs are `I`, see the module docs.
+pub struct TypedSession<I> {
+    session: Session,
+    default: I,
+}
+
+impl<I: Serialize + DeserializeOwned + Clone> TypedSession<I> {
+    /// Fails if the session's `default_inputs` aren't a bincode encoded `I`.
+    pub fn new(session: Session) -> Result<Self, bincode::Error> {
+        let default = bincode::deserialize(session.inputs.default_input())?;
+        Ok(TypedSession { session, default })
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    pub fn session_mut(&mut self) -> &mut Session {
+        &mut self.session
+    }
+
+    pub fn into_inner(self) -> Session {
+        self.session
+    }
+
+    /// Like `Session::next_request`, with `TypedRequest`s.
+    pub fn next_request<F, M>(&mut self, handler: F) -> ControlFlow<(), M::Message>
+    where
+        F: FnMut(TypedRequest<'_, I>) -> M,
+        M: MaybeMessage,
+    {
+        self.session.next_request(Typed {
+            default: &self.default,
+            handler,
+        })
+    }
+}
+
+struct Typed<'d, I, F> {
+    default: &'d I,
+    handler: F,
+}
+
+impl<I, F, M> RequestHandler for Typed<'_, I, F>
+where
+    I: Serialize + DeserializeOwned + Clone,
+    F: FnMut(TypedRequest<'_, I>) -> M,
+    M: MaybeMessage,
+{
+    type Break = M::Message;
+
+    fn handle_request(&mut self, request: Request) -> ControlFlow<Self::Break> {
+        let message = match request {
+            Request::CaptureLocalInput(input) => {
+                let mut value = self.default.clone();
+                let message =
+                    (self.handler)(TypedRequest::CaptureLocalInput(&mut value)).as_message();
+                *input = bincode::serialize(&value).expect("inputs must encode with bincode");
+                message
+            }
+            Request::CaptureInput {
+                input,
+                frame,
+                player,
+            } => {
+                let mut value = self.default.clone();
+                let message = (self.handler)(TypedRequest::CaptureInput {
+                    input: &mut value,
+                    frame,
+                    player,
+                })
+                .as_message();
+                *input = bincode::serialize(&value).expect("inputs must encode with bincode");
+                message
+            }
+            Request::Advance {
+                amount,
+                inputs,
+                confirmed,
+                current_frame,
+                resimulation,
+            } => {
+                let inputs = inputs.deep_map(|bytes| {
+                    bincode::deserialize(&bytes).unwrap_or_else(|e| {
+                        log::warn!("using the default for an undecodable input: {}", e);
+                        self.default.clone()
+                    })
+                });
+                (self.handler)(TypedRequest::Advance {
+                    amount,
+                    inputs,
+                    confirmed,
+                    current_frame,
+                    resimulation,
+                })
+                .as_message()
+            }
+            other => (self.handler)(TypedRequest::Other(other)).as_message(),
+        };
+        match message {
+            Some(m) => ControlFlow::Break(m),
+            None => ControlFlow::Continue(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Clock, ManualClock, MemoryNetwork, SessionBuilder};
+    use std::{collections::BTreeMap, net::SocketAddr};
+
+    #[test]
+    fn undecodable_inputs_fall_back_to_the_default() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::new();
+        let addrs = [1, 2].map(|p| SocketAddr::from(([127, 0, 0, 1], p)));
+        let mut sessions = (0..2)
+            .map(|id| {
+                let session = SessionBuilder::default()
+                    .remote_players(&[addrs[1 - id]])
+                    .local_player(id as PlayerId)
+                    .step_size(Duration::from_millis(10))
+                    .default_inputs(bincode::serialize(&0u16).unwrap())
+                    .with_socket(network.socket(addrs[id]))
+                    .with_clock(clock.clone())
+                    .start()
+                    .unwrap();
+                TypedSession::<u16>::new(session).unwrap()
+            })
+            .collect::<Vec<_>>();
+        // Not a `u16`.
+        sessions[0].session_mut().set_local_input(5, vec![1]);
+
+        let mut confirmed = BTreeMap::new();
+        while !confirmed.contains_key(&10) {
+            assert!(clock.now() < Duration::from_secs(10), "did not converge");
+            for session in &mut sessions {
+                while let ControlFlow::Continue(()) = session.next_request(|r| match r {
+                    TypedRequest::CaptureLocalInput(input) => *input = 7,
+                    TypedRequest::Advance {
+                        inputs,
+                        confirmed: Confirmation::First,
+                        current_frame,
+                        ..
+                    } => {
+                        let inputs = inputs.into_values();
+                        confirmed.insert(current_frame, *inputs.get(&0).unwrap());
+                    }
+                    _ => {}
+                }) {}
+            }
+            clock.advance(Duration::from_millis(1));
+            network.advance(Duration::from_millis(1));
+        }
+
+        assert_eq!(confirmed[&5], 0);
+        assert_eq!(confirmed[&6], 7);
+    }
+}
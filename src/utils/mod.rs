@@ -8,7 +8,7 @@ use std::{
 
 pub fn div_duration(numerator: Duration, denominator: Duration) -> (u32, Duration) {
     let mut min = 0;
-    let mut max = core::u32::MAX;
+    let mut max = u32::MAX;
 
     while max - min > 1 {
         let mid = min + (max - min) / 2;